@@ -0,0 +1,294 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+
+struct Field {
+    ident: syn::Ident,
+    ty: syn::Type,
+    /// Component type in the ECS, defaults to the field type
+    component: syn::Type,
+}
+
+pub(crate) fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let ident = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "BundleSync does not support generic bundles",
+        ));
+    }
+
+    let mut tag: Option<syn::Path> = None;
+    let mut tag_value: Option<syn::Expr> = None;
+    let mut version: Option<syn::LitInt> = None;
+    let mut migrations: Option<syn::Path> = None;
+    for attr in input.attrs.iter() {
+        if !attr.path().is_ident("bundle_sync") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                tag = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("tag_value") {
+                tag_value = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("version") {
+                version = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("migrations") {
+                migrations = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error(
+                    "expected `tag = ...`, `tag_value = ...`, `version = ...`, or `migrations = ...`",
+                ))
+            }
+        })?;
+    }
+    let tag = tag.ok_or_else(|| {
+        syn::Error::new(
+            ident.span(),
+            "BundleSync requires `#[bundle_sync(tag = YourTag)]` to find entities of this bundle",
+        )
+    })?;
+    // unit struct tags can be used as their own value
+    let tag_value = tag_value.unwrap_or_else(|| syn::parse_quote!(#tag));
+
+    let named = match input.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(named),
+            ..
+        }) => named,
+        _ => {
+            return Err(syn::Error::new(
+                ident.span(),
+                "BundleSync can only be derived for structs with named fields",
+            ))
+        }
+    };
+
+    let mut fields = Vec::new();
+    let mut version_field: Option<syn::Ident> = None;
+    for field in named.named {
+        let mut component: Option<syn::Type> = None;
+        let mut is_version = false;
+        for attr in field.attrs.iter() {
+            if !attr.path().is_ident("bundle_sync") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("component") {
+                    component = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("version") {
+                    is_version = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `component = ...` or `version`"))
+                }
+            })?;
+        }
+        if is_version {
+            version_field = field.ident;
+            continue;
+        }
+        fields.push(Field {
+            ident: field.ident.expect("named field"),
+            component: component.unwrap_or_else(|| field.ty.clone()),
+            ty: field.ty,
+        });
+    }
+
+    // without it, documents could never be migrated
+    let version_field = version_field.ok_or_else(|| {
+        syn::Error::new(
+            ident.span(),
+            "BundleSync requires a `#[bundle_sync(version)] #[serde(rename = \"_v\", default)]` field of `DocumentVersion`",
+        )
+    })?;
+    let version = version.unwrap_or_else(|| syn::parse_quote!(0));
+    let migrations_fn = migrations.map(|migrations| {
+        quote! {
+            fn migrations() -> ::std::vec::Vec<::hn_app::database_plugin::migrations::Migration> {
+                #migrations()
+            }
+        }
+    });
+
+    let field_idents = fields.iter().map(|f| &f.ident).collect::<Vec<_>>();
+    let field_types = fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+    let component_types = fields.iter().map(|f| &f.component).collect::<Vec<_>>();
+    let view_idents = fields
+        .iter()
+        .map(|f| format_ident!("v_{}", f.ident))
+        .collect::<Vec<_>>();
+    let ref_idents = fields
+        .iter()
+        .map(|f| format_ident!("{}_ref", f.ident))
+        .collect::<Vec<_>>();
+    let field_strs = field_idents
+        .iter()
+        .map(|ident| ident.to_string())
+        .collect::<Vec<_>>();
+    let ident_str = ident.to_string();
+
+    Ok(quote! {
+        impl ::hn_app::database_plugin::BundleSync for #ident {
+            type Tag = #tag;
+
+            const VERSION: u32 = #version;
+
+            #migrations_fn
+
+            fn add_entities(
+                all_storages: &::hn_app::database_plugin::__private::AllStorages,
+                documents: ::std::vec::Vec<(::hn_app::HintedID, Self)>,
+            ) {
+                use ::hn_app::_ecs_::*;
+                use ::hn_app::database_plugin::{__private, BundleField, BundleSyncState, LastImport};
+                use ::hn_app::HintedID;
+
+                all_storages.run(
+                    |mut uvm_last_import: UniqueViewMut<LastImport>,
+                     mut uvm_state: UniqueViewMut<BundleSyncState>,
+                     mut uvm_problems: UniqueViewMut<__private::ImportProblems>,
+                     mut entities: EntitiesViewMut,
+                     mut vm_hinted_id: ViewMut<HintedID>,
+                     mut vm_tag: ViewMut<#tag>,
+                     #(mut #view_idents: ViewMut<#component_types>,)*| {
+                        for (id, bundle) in documents {
+                            let links = uvm_state.links();
+                            #(
+                                for linked in <#field_types as BundleField<#component_types>>::missing_links(
+                                    &bundle.#field_idents,
+                                    links,
+                                ) {
+                                    uvm_problems.as_mut().add::<Self>(
+                                        &id,
+                                        __private::ProblemKind::MissingLink { field: #field_strs, linked },
+                                    );
+                                }
+                            )*
+                            let components = (|| -> ::hn_app::_result_::Result<_> {
+                                Ok((
+                                    #(<#field_types as BundleField<#component_types>>::into_component(
+                                        bundle.#field_idents,
+                                        links,
+                                    )?,)*
+                                ))
+                            })();
+                            let (#(#ref_idents,)*) = match components {
+                                Ok(components) => components,
+                                Err(err) => {
+                                    uvm_problems.as_mut().add::<Self>(
+                                        &id,
+                                        __private::ProblemKind::Undecodable { error: format!("{err:#}") },
+                                    );
+                                    continue;
+                                }
+                            };
+                            let linked = ::std::iter::empty()
+                                #(.chain(<#field_types as BundleField<#component_types>>::linked_entities(&#ref_idents)))*
+                                .collect::<::std::vec::Vec<_>>();
+                            let entity = entities.add_entity(
+                                (&mut vm_hinted_id, &mut vm_tag, #(&mut #view_idents,)*),
+                                (id.clone(), #tag_value, #(#ref_idents,)*),
+                            );
+                            __private::record_links(uvm_state.as_mut(), entity, linked);
+                            __private::record_import::<Self>(
+                                uvm_state.as_mut(),
+                                uvm_last_import.as_mut(),
+                                uvm_problems.as_mut(),
+                                entity,
+                                id,
+                            );
+                        }
+                    },
+                )
+            }
+
+            fn export_changes(
+                all_storages: &::hn_app::database_plugin::__private::AllStorages,
+                db: &::hn_app::database_plugin::__private::Database,
+                batch: &mut ::hn_app::database_plugin::__private::ExportBatch,
+                audit: ::std::option::Option<&::hn_app::database_plugin::__private::AuditContext>,
+            ) {
+                use ::hn_app::_ecs_::*;
+                use ::hn_app::database_plugin::{__private, BundleField, BundleSyncState, LastImport};
+                use ::hn_app::HintedID;
+
+                all_storages.run(
+                    |mut uvm_last_import: UniqueViewMut<LastImport>,
+                     mut uvm_state: UniqueViewMut<BundleSyncState>,
+                     v_hinted_id: View<HintedID>,
+                     v_tag: View<#tag>,
+                     #(#view_idents: View<#component_types>,)*| {
+                        let last_import = uvm_last_import.as_mut();
+                        let state = uvm_state.as_mut();
+
+                        __private::delete_removed::<Self>(
+                            db,
+                            last_import,
+                            state,
+                            batch,
+                            audit,
+                            v_hinted_id
+                                .removed_or_deleted()
+                                .chain(v_tag.removed_or_deleted())
+                                #(.chain(#view_idents.removed_or_deleted()))*,
+                            |entity| {
+                                v_hinted_id.contains(entity)
+                                    && v_tag.contains(entity)
+                                    #(&& #view_idents.contains(entity))*
+                            },
+                        );
+
+                        for entity in __private::tagged_entities(&v_tag) {
+                            if last_import.skip_once(entity) {
+                                continue;
+                            }
+                            if !(__private::take_resave(state, entity)
+                                || v_hinted_id.is_inserted_or_modified(entity)
+                                || v_tag.is_inserted_or_modified(entity)
+                                #(|| #view_idents.is_inserted_or_modified(entity))*)
+                            {
+                                continue;
+                            }
+                            // if a component is missing, we'll check again once it's added
+                            let (Ok(id), #(Ok(#ref_idents),)*) =
+                                (v_hinted_id.get(entity), #(#view_idents.get(entity),)*)
+                            else {
+                                continue;
+                            };
+                            let bundle = (|| -> ::hn_app::_result_::Result<Self> {
+                                Ok(Self {
+                                    #version_field: __private::DocumentVersion(Self::VERSION),
+                                    #(#field_idents: <#field_types as BundleField<#component_types>>::from_component(
+                                        #ref_idents,
+                                        &v_hinted_id,
+                                    )?,)*
+                                })
+                            })();
+                            match bundle {
+                                Ok(bundle) => {
+                                    __private::record_links(
+                                        state,
+                                        entity,
+                                        ::std::iter::empty()
+                                            #(.chain(<#field_types as BundleField<#component_types>>::linked_entities(#ref_idents)))*,
+                                    );
+                                    __private::export_bundle::<Self>(db, state, batch, audit, entity, id, bundle);
+                                }
+                                Err(err) => {
+                                    ::hn_app::_tracing_::error!(?err, ?id, "failed to convert entity into document");
+                                    batch.add_error(format!("converting {id} into a document of {}: {err:#}", #ident_str));
+                                }
+                            }
+                        }
+                    },
+                );
+            }
+        }
+    })
+}
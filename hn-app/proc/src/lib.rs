@@ -4,6 +4,40 @@ extern crate proc_macro;
 
 use proc_macro2::{TokenStream, TokenTree};
 
+mod bundle_sync;
+
+/// Implements `hn_app::database_plugin::BundleSync` for a bundle saved to disk
+///
+/// example:
+/// ```rs
+/// #[derive(schema::Collection, BundleSync)]
+/// #[collection(name = "ecs-devices", primary_key = HintedID)]
+/// #[bundle_sync(tag = ecs::DeviceTag, version = 1, migrations = device_migrations)]
+/// #[ecs_bundle(DeviceTag)]
+/// pub struct DeviceBundle {
+///     // written on export, and checked on import to run migrations
+///     #[bundle_sync(version)]
+///     #[serde(rename = "_v", default)]
+///     version: DocumentVersion,
+///     // converted with `BundleField`
+///     #[bundle_sync(component = ecs::Linked<ecs::CredTag>)]
+///     c_linked_creds: LinkedBundle<CredBundle>,
+///     // stored as is
+///     c_authorized_keys: ecs::AuthorizedKeys,
+/// }
+/// ```
+///
+/// Use `#[bundle_sync(tag = ..., tag_value = ...)]` when the tag is not a unit struct.
+/// Components must be `#[track(All)]` so that changes and removals can be saved.
+#[proc_macro_derive(BundleSync, attributes(bundle_sync))]
+pub fn derive_bundle_sync(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    match bundle_sync::expand(input) {
+        Ok(output) => output.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
 /// Convenience macro for marking ecs saved types
 ///
 /// example:
@@ -14,13 +48,20 @@ use proc_macro2::{TokenStream, TokenTree};
 /// #[derive(serde::Serialize, serde::Deserialize)]
 /// struct ECSKey(String);
 /// ```
+///
+/// Fields marked `#[sensitive]` are sealed with the database key when saved, see
+/// `hn_app::sealed`.
 #[proc_macro_attribute]
 pub fn ecs_bundle(
     input: proc_macro::TokenStream,
     following: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
     let input = proc_macro2::TokenStream::from(input);
-    let following = proc_macro2::TokenStream::from(following);
+    let mut following = match syn::parse::<syn::DeriveInput>(following) {
+        Ok(following) => following,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    seal_sensitive_fields(&mut following.data);
     let mut idents = Vec::new();
 
     for item in input.into_iter() {
@@ -48,7 +89,7 @@ pub fn ecs_bundle(
         #[doc = #doc]
         #[derive(Clone, serde::Serialize, serde::Deserialize)]
     });
-    output.extend(following.into_iter());
+    output.extend(quote::quote!(#following));
     // // check that the reference links
     // output.extend(quote::quote! {
     //     type _ = #ident;
@@ -57,6 +98,30 @@ pub fn ecs_bundle(
     proc_macro::TokenStream::from(output)
 }
 
+/// Replaces `#[sensitive]` on fields with serializing them through `hn_app::sealed::sensitive`
+fn seal_sensitive_fields(data: &mut syn::Data) {
+    let fields: Vec<&mut syn::Field> = match data {
+        syn::Data::Struct(data) => data.fields.iter_mut().collect(),
+        syn::Data::Enum(data) => data
+            .variants
+            .iter_mut()
+            .flat_map(|variant| variant.fields.iter_mut())
+            .collect(),
+        syn::Data::Union(_) => Vec::new(),
+    };
+    for field in fields {
+        let attr_count = field.attrs.len();
+        field
+            .attrs
+            .retain(|attr| !attr.path().is_ident("sensitive"));
+        if field.attrs.len() != attr_count {
+            field.attrs.push(syn::parse_quote! {
+                #[serde(with = "::hn_app::sealed::sensitive")]
+            });
+        }
+    }
+}
+
 #[proc_macro_attribute]
 pub fn ecs_unique(
     input: proc_macro::TokenStream,
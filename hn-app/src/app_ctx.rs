@@ -11,10 +11,11 @@ use tokio::sync::Mutex;
 use crate::_ecs_::*;
 use crate::_result_::*;
 use crate::_tracing_::*;
+use crate::sealed::{self, SealingKey};
 
 pub type CommandSender = tokio::sync::mpsc::UnboundedSender<Command>;
 
-pub use cmd_loop::start_loop;
+pub use cmd_loop::{queued_command_count, start_loop};
 
 mod cmd_loop;
 
@@ -43,7 +44,7 @@ pub struct AppCtxPlugin(pub CommandSender);
 #[derive(Component, Clone)]
 pub struct AppCtx {
     app: Option<Arc<Mutex<App>>>,
-    db: Arc<Mutex<ArcResult<bonsaidb::local::Database>>>,
+    db: Arc<Mutex<LocalDatabase>>,
     commands: CommandSender,
     handle: tokio::runtime::Handle,
 }
@@ -64,6 +65,7 @@ impl Command {
     where
         S: IntoWorkloadSystem<B, R>,
     {
+        cmd_loop::QUEUED_COMMANDS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         Command {
             reason,
             immediate,
@@ -148,6 +150,11 @@ impl AppCtx {
     }
 
     pub async fn get_database(&self) -> ArcResult<bonsaidb::local::Database> {
+        self.db.lock().await.get_database()
+    }
+
+    /// Along with its sealing key, for reading documents with sensitive fields.
+    pub async fn get_local_database(&self) -> LocalDatabase {
         self.db.lock().await.clone()
     }
 }
@@ -160,7 +167,10 @@ impl Plugin for AppCtxPlugin {
         app.add_unique(AppCtx {
             app: Default::default(),
             // lovely...
-            db: Arc::new(Mutex::new(Arc::new(Err(anyhow!("not ready yet"))))),
+            db: Arc::new(Mutex::new(LocalDatabase(
+                Arc::new(Err(anyhow!("not ready yet"))),
+                None,
+            ))),
             commands: self.0.clone(),
             handle,
         });
@@ -182,17 +192,49 @@ fn keep_app_ctx_db_up_to_date(
             .db
             .try_lock()
             .expect("TODO: lucky lock?");
-        *db_lock = uv_database.get_database();
+        *db_lock = uv_database.clone();
     }
 }
 
+/// Reasons of the [Command]s run in the current iteration of the command loop, empty for
+/// the initial run. The loop only fills this in when a plugin added it.
 #[ecs_unique]
-pub struct LocalDatabase(pub(crate) ArcResult<local::Database>);
+#[derive(Clone, Debug, Default)]
+pub struct CommandReasons(pub Vec<&'static str>);
+
+impl CommandReasons {
+    /// Joined for display, e.g. in audit entries.
+    pub fn describe(&self) -> String {
+        if self.0.is_empty() {
+            "initial update".to_string()
+        } else {
+            self.0.join("; ")
+        }
+    }
+}
+
+/// The open database, and the key its `#[sensitive]` fields are sealed with.
+#[ecs_unique]
+#[derive(Clone)]
+pub struct LocalDatabase(
+    pub(crate) ArcResult<local::Database>,
+    pub(crate) Option<SealingKey>,
+);
 
 impl LocalDatabase {
     pub fn get_database(&self) -> ArcResult<local::Database> {
         self.0.clone()
     }
+
+    /// `None` if the database secret failed to load.
+    pub fn sealing_key(&self) -> Option<&SealingKey> {
+        self.1.as_ref()
+    }
+
+    /// Sensitive fields of documents read or written within `f` are sealed with the key.
+    pub fn sealed<R>(&self, f: impl FnOnce() -> R) -> R {
+        sealed::with_key(self.sealing_key(), f)
+    }
 }
 
 impl AsRef<Result<local::Database>> for LocalDatabase {
@@ -1,15 +1,24 @@
 use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{sync::Arc, time::Duration};
 
 use shipyard_app::App;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::Mutex;
 
-use super::AppCtx;
+use super::{AppCtx, CommandReasons};
 use crate::_ecs_::*;
 use crate::_result_::*;
 use crate::_tracing_::*;
 
+/// Incremented when a [super::Command] is created, and decremented once it's pulled off the channel.
+pub(super) static QUEUED_COMMANDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of commands waiting to be run by the command loop.
+pub fn queued_command_count() -> usize {
+    QUEUED_COMMANDS.load(Ordering::Relaxed)
+}
+
 pub async fn start_loop(
     app: App,
     workload: AppWorkload,
@@ -44,6 +53,7 @@ pub async fn start_loop(
         span,
     }) = recv.recv().await
     {
+        QUEUED_COMMANDS.fetch_sub(1, Ordering::Relaxed);
         i += 1;
         let _s = span.enter();
         let loop_span = tracing::info_span!("running command", ?i, ?reason);
@@ -60,6 +70,7 @@ pub async fn start_loop(
 
             let mut seen = BTreeSet::<(String, &'static str)>::new();
             seen.extend(dedup.map(|s| (s, reason)));
+            let mut reasons = vec![reason];
 
             let (name, builder) = async {
                 let name = format!("command-{i}");
@@ -74,6 +85,7 @@ pub async fn start_loop(
                     span: _,
                 }) = recv.try_recv()
                 {
+                    QUEUED_COMMANDS.fetch_sub(1, Ordering::Relaxed);
                     if let Some(dedup_str) = dedup {
                         let val = (dedup_str, reason);
                         if seen.contains(&val) {
@@ -85,6 +97,7 @@ pub async fn start_loop(
                     }
 
                     debug!(?i, ?reason, "adding command");
+                    reasons.push(reason);
                     builder = builder.with_system(system);
                 }
 
@@ -98,6 +111,9 @@ pub async fn start_loop(
                     .lock()
                     .instrument(info_span!("lock app for commands"))
                     .await;
+                if let Ok(mut uvm_reasons) = app.world.borrow::<UniqueViewMut<CommandReasons>>() {
+                    uvm_reasons.0 = reasons;
+                }
                 async {
                     let info = builder.add_to_world(&app.world).expect("adding workload");
                     app.world
@@ -1,14 +1,32 @@
-use std::collections::HashSet;
+use std::any::{type_name, TypeId};
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use bonsaidb::core::schema::Schema;
+use bonsaidb::core::schema::{Schema, SerializedCollection};
 use bonsaidb::local;
+use shipyard::AllStorages;
 
 use crate::_ecs_::*;
 use crate::_result_::*;
-use crate::app_ctx::LocalDatabase;
+use crate::_tracing_::*;
+use crate::app_ctx::{CommandReasons, LocalDatabase};
+use crate::sealed::SealingKey;
+use crate::{ecs_bundle, HintedID};
+
+pub mod audit;
+pub mod export;
+pub mod lazy;
+pub mod migrations;
+pub mod problems;
+
+use audit::{AuditContext, AuditSettings};
+use export::{BundleExporters, ExportBatch, ExportStatus};
+use lazy::LazyBundle;
+use migrations::{Migration, MigrationReport};
+use problems::ImportProblems;
 
 #[ecs_unique]
 #[derive(Default)]
@@ -21,22 +39,596 @@ impl LastImport {
     }
 }
 
+/// Unique, change it to close the current database and import everything from the new path.
+#[ecs_unique]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabasePath(pub PathBuf);
+
 /// Provides unique [LocalDatabase] and [LastImport] components for synchronization.
 pub struct LocalDatabasePlugin<DB> {
-    // get from configuration in the future?
+    /// Initial [DatabasePath]
     pub path: PathBuf,
+    /// Report what would be migrated without writing anything to the database.
+    pub dry_run_migrations: bool,
+    /// Secret which the key for `#[sensitive]` fields is derived from, created if missing.
+    pub secret_path: PathBuf,
+    /// Initial [AuditSettings]
+    pub audit: AuditSettings,
     pub mark: PhantomData<DB>,
 }
 
 impl<DB: Schema> Plugin for LocalDatabasePlugin<DB> {
     fn build(&self, app: &mut AppBuilder) {
         let _span = tracing::info_span!("LocalDatabase::build").entered();
-        let db: ArcResult<local::Database> = {
-            let mut storage_conf = local::config::StorageConfiguration::default();
-            storage_conf.path = Some(self.path.clone());
-            Arc::new(local::Database::open::<DB>(storage_conf).context("creating database"))
+        let key = match SealingKey::load_or_create(&self.secret_path) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                // sensitive fields fail to import and export, everything else still works
+                error!(?err, "failed to load database secret");
+                None
+            }
         };
-        app.add_unique(LocalDatabase(db));
+        app.add_unique(LocalDatabase(open_database::<DB>(&self.path), key));
+        app.add_unique(DatabasePath(self.path.clone()));
         app.add_unique(LastImport::default());
+        app.add_unique(BundleSyncState::default());
+        app.add_unique(MigrationReport {
+            dry_run: self.dry_run_migrations,
+            ..Default::default()
+        });
+        app.add_unique(ImportProblems::default());
+        app.add_unique(self.audit.clone());
+        app.add_unique(CommandReasons::default());
+        app.add_unique(BundleExporters::default());
+        app.add_unique(ExportBatch::default());
+        app.add_unique(ExportStatus::default());
+        app.add_system(reopen_database_system::<DB>);
+        app.add_system(audit::prune_audit_log_system);
+        app.add_reset_system(export::export_system, "save bundle changes to disk");
+    }
+}
+
+fn open_database<DB: Schema>(path: &PathBuf) -> ArcResult<local::Database> {
+    let mut storage_conf = local::config::StorageConfiguration::default();
+    storage_conf.path = Some(path.clone());
+    Arc::new(
+        local::Database::open::<DB>(storage_conf)
+            .with_context(|| format!("creating database at {path:?}")),
+    )
+}
+
+/// Each [BundleSyncPlugin] sees [LocalDatabase] modified, and imports from the new database.
+///
+/// If the new path fails to open, the previous database stays open and nothing is reimported.
+fn reopen_database_system<DB: Schema>(
+    uv_path: UniqueView<DatabasePath>,
+    mut uvm_local_database: UniqueViewMut<LocalDatabase>,
+    mut uvm_report: UniqueViewMut<MigrationReport>,
+    mut uvm_problems: UniqueViewMut<ImportProblems>,
+) {
+    if !uv_path.is_modified() {
+        return;
+    }
+    info!(path = ?uv_path.0, "reopening database");
+    let db = open_database::<DB>(&uv_path.0);
+    if let (Err(err), Ok(_)) = (&*db, &*uvm_local_database.0) {
+        error!(
+            ?err,
+            "failed to open the new database, keeping the previous one open"
+        );
+        return;
+    }
+    uvm_report.as_mut().collections.clear();
+    uvm_problems.as_mut().0.clear();
+    // the secret stays with the app, so moving the database keeps the key
+    let key = uvm_local_database.1.clone();
+    // as_mut marks it for modified
+    *uvm_local_database.as_mut() = LocalDatabase(db, key);
+}
+
+/// A BonsaiDB collection which is bundled into components in the ECS.
+///
+/// Implement with `#[derive(BundleSync)]`, and add a [BundleSyncPlugin] for each bundle,
+/// in the order that bundles link to each other (linked bundles first).
+///
+/// ```ignore
+/// #[derive(schema::Collection, BundleSync)]
+/// #[collection(name = "ecs-devices", primary_key = HintedID)]
+/// #[bundle_sync(tag = ecs::DeviceTag, version = 1, migrations = device_migrations)]
+/// #[ecs_bundle(DeviceTag)]
+/// pub struct DeviceBundle {
+///     #[bundle_sync(version)]
+///     #[serde(rename = "_v", default)]
+///     version: DocumentVersion,
+///     // converted with [BundleField]
+///     #[bundle_sync(component = ecs::Linked<ecs::CredTag>)]
+///     c_linked_creds: LinkedBundle<CredBundle>,
+///     // stored as is
+///     c_authorized_keys: ecs::AuthorizedKeys,
+/// }
+/// ```
+pub trait BundleSync:
+    SerializedCollection<PrimaryKey = HintedID, Contents = Self>
+    + serde::Serialize
+    + Send
+    + Sync
+    + Sized
+    + 'static
+{
+    /// Every entity with this component is saved as this bundle.
+    type Tag: Component + Send + Sync;
+
+    /// Saved with each document, bump it along with adding a [Migration].
+    const VERSION: u32;
+
+    /// Upgrades documents saved at older versions, see [migrations].
+    fn migrations() -> Vec<Migration> {
+        Vec::new()
+    }
+
+    /// Adds an entity for every document in the collection.
+    /// Documents which can't be imported are listed in [ImportProblems].
+    fn import(all_storages: &AllStorages) -> Result<()> {
+        let documents = problems::read_documents::<Self>(all_storages, None)?;
+        Self::add_entities(all_storages, documents);
+        Ok(())
+    }
+
+    /// Adds entities for the documents which aren't loaded yet, and returns the entity of
+    /// each document which exists, in order. See [lazy].
+    fn load(all_storages: &AllStorages, ids: &[HintedID]) -> Result<Vec<EntityId>> {
+        let missing = lazy::split_loaded::<Self>(all_storages, ids);
+        if !missing.is_empty() {
+            let documents = problems::read_documents::<Self>(all_storages, Some(&missing))?;
+            Self::add_entities(all_storages, documents);
+        }
+
+        Ok(all_storages.run(|uv_state: UniqueView<BundleSyncState>| {
+            let documents = uv_state.documents.get(&TypeId::of::<Self>());
+            ids.iter()
+                .filter_map(|id| uv_state.links.get(id))
+                .filter(|entity| {
+                    documents.map_or(false, |documents| documents.contains_key(entity))
+                })
+                .collect()
+        }))
+    }
+
+    /// Adds an entity for each document, skipping documents which fail to convert.
+    fn add_entities(all_storages: &AllStorages, documents: Vec<(HintedID, Self)>);
+
+    /// Adds writes for changed bundles, and deletes for documents of entities which no
+    /// longer have the whole bundle, to the batch saved at the end of each update.
+    fn export_changes(
+        all_storages: &AllStorages,
+        db: &local::Database,
+        batch: &mut ExportBatch,
+        audit: Option<&AuditContext>,
+    );
+}
+
+/// Converts a field of a [BundleSync] document into the component stored in the ECS, and back.
+///
+/// Fields without a `#[bundle_sync(component = ...)]` attribute are stored as is.
+/// Documents which fail to convert are skipped with an error.
+pub trait BundleField<C>: Sized {
+    fn into_component(self, links: &BundleLinks) -> Result<C>;
+    fn from_component(component: &C, v_hinted_id: &View<HintedID>) -> Result<Self>;
+
+    /// Linked documents which aren't imported, listed in [ImportProblems] before the links
+    /// are dropped by [BundleField::into_component].
+    fn missing_links(&self, _links: &BundleLinks) -> Vec<HintedID> {
+        Vec::new()
+    }
+
+    /// Entities the component links to, saved again once a linked document is deleted.
+    fn linked_entities(_component: &C) -> Vec<EntityId> {
+        Vec::new()
+    }
+}
+
+impl<C: Clone> BundleField<C> for C {
+    fn into_component(self, _links: &BundleLinks) -> Result<C> {
+        Ok(self)
+    }
+
+    fn from_component(component: &C, _v_hinted_id: &View<HintedID>) -> Result<Self> {
+        Ok(component.clone())
+    }
+}
+
+/// Document ids of imported entities, so bundles can link to each other.
+#[derive(Default)]
+pub struct BundleLinks(HashMap<HintedID, EntityId>);
+
+impl BundleLinks {
+    /// `None` if the linked document was not imported (yet).
+    pub fn get(&self, id: &HintedID) -> Option<EntityId> {
+        self.0.get(id).copied()
+    }
+}
+
+/// Links from one entity to entities of another bundle, saved as a [LinkedBundle].
+#[ecs_component("Links")]
+pub struct Linked<Tag: 'static> {
+    pub items: Vec<EntityId>,
+    _mark: PhantomData<Tag>,
+}
+
+impl<Tag: 'static> Linked<Tag> {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            _mark: PhantomData,
+        }
+    }
+    pub fn new_with<I>(entity_id: I) -> Self
+    where
+        I: IntoIterator<Item = EntityId>,
+    {
+        Self {
+            items: entity_id.into_iter().collect(),
+            _mark: PhantomData,
+        }
+    }
+}
+
+/// Saved form of [Linked], by document id.
+#[ecs_bundle]
+#[derive(Debug, Default)]
+pub struct LinkedBundle<Bundle: 'static> {
+    pub items: Vec<HintedID>,
+    #[serde(skip_serializing, default)]
+    _mark: PhantomData<Bundle>,
+}
+
+impl<Bundle: 'static> LinkedBundle<Bundle> {
+    pub fn new(items: Vec<HintedID>) -> Self {
+        Self {
+            items,
+            _mark: PhantomData,
+        }
+    }
+}
+
+impl<Tag, B> BundleField<Linked<Tag>> for LinkedBundle<B>
+where
+    B: BundleSync<Tag = Tag>,
+{
+    fn into_component(self, links: &BundleLinks) -> Result<Linked<Tag>> {
+        Ok(Linked::new_with(
+            self.items.iter().filter_map(|id| links.get(id)),
+        ))
+    }
+
+    /// e.g. the linked document was deleted
+    fn missing_links(&self, links: &BundleLinks) -> Vec<HintedID> {
+        self.items
+            .iter()
+            .filter(|id| links.get(id).is_none())
+            .cloned()
+            .collect()
+    }
+
+    fn linked_entities(component: &Linked<Tag>) -> Vec<EntityId> {
+        component.items.clone()
+    }
+
+    fn from_component(component: &Linked<Tag>, v_hinted_id: &View<HintedID>) -> Result<Self> {
+        Ok(LinkedBundle {
+            items: component
+                .items
+                .iter()
+                .filter_map(|entity_id| match v_hinted_id.get(*entity_id) {
+                    Ok(id) => Some(id.clone()),
+                    Err(err) => {
+                        // e.g. the linked entity was deleted
+                        debug!(?err, ?entity_id, "dropping link to entity without an id");
+                        None
+                    }
+                })
+                .collect(),
+            _mark: PhantomData,
+        })
+    }
+}
+
+/// Unique, added by [LocalDatabasePlugin] and maintained by each [BundleSyncPlugin].
+#[ecs_unique]
+#[derive(Default)]
+pub struct BundleSyncState {
+    links: BundleLinks,
+    /// For each bundle, the document each entity was last imported from or exported to,
+    /// so the document can be deleted once the entity no longer has the whole bundle.
+    documents: HashMap<TypeId, HashMap<EntityId, HintedID>>,
+    /// Bundles added with [BundleSyncPlugin::lazy]
+    lazy: HashMap<TypeId, LazyBundle>,
+    /// Saved at the next export even without changes, see [problems]
+    resave: HashSet<EntityId>,
+    /// For each linked entity, the entities which linked to it when last imported or
+    /// exported, so they're saved without the link once its document is deleted.
+    linked_by: HashMap<EntityId, HashSet<EntityId>>,
+}
+
+impl BundleSyncState {
+    pub fn links(&self) -> &BundleLinks {
+        &self.links
+    }
+
+    fn documents<B: 'static>(&mut self) -> &mut HashMap<EntityId, HintedID> {
+        self.documents.entry(TypeId::of::<B>()).or_default()
+    }
+
+    fn lazy<B: 'static>(&mut self) -> Option<&mut LazyBundle> {
+        self.lazy.get_mut(&TypeId::of::<B>())
+    }
+
+    /// Restarts the idle time of a lazy bundle's entity.
+    fn touch<B: 'static>(&mut self, entity: EntityId) {
+        if let Some(lazy) = self.lazy::<B>() {
+            lazy.touch(entity);
+        }
+    }
+}
+
+/// Imports the bundle when built and whenever the database is reopened, and exports
+/// changes to it after each update.
+pub struct BundleSyncPlugin<B> {
+    idle_after: Option<Duration>,
+    mark: PhantomData<B>,
+}
+
+impl<B> Default for BundleSyncPlugin<B> {
+    fn default() -> Self {
+        Self {
+            idle_after: None,
+            mark: PhantomData,
+        }
+    }
+}
+
+impl<B> BundleSyncPlugin<B> {
+    /// Nothing is imported, documents are loaded as they're referenced and evicted after
+    /// `idle_after` without being loaded or changed, see [lazy].
+    pub fn lazy(idle_after: Duration) -> Self {
+        Self {
+            idle_after: Some(idle_after),
+            mark: PhantomData,
+        }
+    }
+}
+
+impl<B: BundleSync> Plugin for BundleSyncPlugin<B> {
+    fn build(&self, app: &mut AppBuilder) {
+        let _span =
+            tracing::info_span!("BundleSyncPlugin::build", bundle = type_name::<B>()).entered();
+        app.depends_on_unique::<LocalDatabase>("to import and export documents");
+        app.depends_on_unique::<LastImport>("to see what was initially imported");
+        app.depends_on_unique::<BundleSyncState>("to link and delete documents");
+        app.depends_on_unique::<MigrationReport>("to report migrated documents");
+        app.depends_on_unique::<ImportProblems>("to list documents which failed to import");
+        app.depends_on_unique::<BundleExporters>("to save changes with the other bundles");
+        let dry_run = match self.idle_after {
+            Some(idle_after) => app.app.world.run(
+                |mut uvm_state: UniqueViewMut<BundleSyncState>,
+                 uv_report: UniqueView<MigrationReport>| {
+                    info!(?idle_after, "loading documents when referenced");
+                    uvm_state
+                        .as_mut()
+                        .lazy
+                        .insert(TypeId::of::<B>(), LazyBundle::new(idle_after));
+                    uv_report.dry_run
+                },
+            ),
+            // initial load
+            None => app
+                .app
+                .world
+                .run(|all_storages: AllStoragesViewMut| import_and_report::<B>(&all_storages)),
+        };
+        app.add_system(reimport_system::<B>);
+        if dry_run {
+            warn!("dry run of migrations, changes will not be saved");
+        } else {
+            app.app
+                .world
+                .run(|mut uvm_exporters: UniqueViewMut<BundleExporters>| {
+                    uvm_exporters.0.push(B::export_changes);
+                });
+        }
+    }
+}
+
+/// Returns whether this is a dry run.
+fn import_and_report<B: BundleSync>(all_storages: &AllStorages) -> bool {
+    if let Err(err) = B::import(all_storages) {
+        tracing::error!(?err, "failed to import previous values");
+    }
+
+    all_storages.run(|uv_report: UniqueView<MigrationReport>| {
+        let bundle = type_name::<B>();
+        if let Some(collection) = uv_report
+            .collections
+            .iter()
+            .find(|collection| collection.bundle == bundle)
+        {
+            info!(
+                imported = collection.imported,
+                migrated = collection.migrated.len(),
+                failed = collection.failed.len(),
+                version = collection.version,
+                bundle = collection.bundle,
+                "imported documents"
+            );
+        }
+        uv_report.dry_run
+    })
+}
+
+/// Replaces the entities from the previous database after [DatabasePath] changes.
+fn reimport_system<B: BundleSync>(mut all_storages: AllStoragesViewMut) {
+    let reopened = all_storages
+        .run(|uv_local_database: UniqueView<LocalDatabase>| uv_local_database.is_modified());
+    if !reopened {
+        return;
+    }
+
+    let _span = info_span!("reimport", bundle = type_name::<B>()).entered();
+    let previous = all_storages.run(|v_tag: View<B::Tag>| {
+        __private::tagged_entities(&v_tag)
+            .into_iter()
+            .collect::<HashSet<_>>()
+    });
+    let is_lazy = all_storages.run(|mut uvm_state: UniqueViewMut<BundleSyncState>| {
+        let state = uvm_state.as_mut();
+        // forget the documents first, so deleting the entities doesn't delete documents
+        state.documents::<B>().clear();
+        state.links.0.retain(|_, entity| !previous.contains(entity));
+        state
+            .linked_by
+            .retain(|linked, _| !previous.contains(linked));
+        state.lazy::<B>().map(LazyBundle::clear).is_some()
+    });
+    for entity in previous {
+        all_storages.delete_entity(entity);
+    }
+
+    if !is_lazy {
+        import_and_report::<B>(&all_storages);
+    }
+}
+
+/// Used by `#[derive(BundleSync)]`
+#[doc(hidden)]
+pub mod __private {
+    use super::*;
+
+    pub use shipyard::AllStorages;
+
+    pub use super::audit::AuditContext;
+    pub use super::export::ExportBatch;
+    pub use super::migrations::DocumentVersion;
+    pub use bonsaidb::local::Database;
+
+    pub use super::problems::{ImportProblems, ProblemKind};
+
+    pub fn record_import<B: BundleSync>(
+        state: &mut BundleSyncState,
+        last_import: &mut LastImport,
+        problems: &mut ImportProblems,
+        entity: EntityId,
+        id: HintedID,
+    ) {
+        match state.links.0.get(&id) {
+            Some(existing) if *existing != entity => {
+                problems.add::<B>(&id, ProblemKind::DuplicateId);
+            }
+            _ => {
+                state.links.0.insert(id.clone(), entity);
+            }
+        }
+        state.documents::<B>().insert(entity, id);
+        match state.lazy::<B>() {
+            // loaded by a command, so saved once more rather than skipping changes made
+            // before the next export
+            Some(lazy) => lazy.touch(entity),
+            None => {
+                last_import.0.insert(entity);
+            }
+        }
+    }
+
+    pub fn tagged_entities<T: Component>(v_tag: &View<T>) -> Vec<EntityId> {
+        v_tag.iter().ids().collect()
+    }
+
+    /// Whether a repair asked for the entity to be saved again.
+    pub fn take_resave(state: &mut BundleSyncState, entity: EntityId) -> bool {
+        state.resave.remove(&entity)
+    }
+
+    /// Remembers the entities linked by `entity`, see [BundleField::linked_entities].
+    pub fn record_links(
+        state: &mut BundleSyncState,
+        entity: EntityId,
+        linked: impl IntoIterator<Item = EntityId>,
+    ) {
+        for linked in linked {
+            state.linked_by.entry(linked).or_default().insert(entity);
+        }
+    }
+
+    /// Adds the write to the batch, and links the entity to the document.
+    pub fn export_bundle<B: BundleSync>(
+        db: &local::Database,
+        state: &mut BundleSyncState,
+        batch: &mut ExportBatch,
+        audit: Option<&AuditContext>,
+        entity: EntityId,
+        id: &HintedID,
+        bundle: B,
+    ) {
+        let _span = info_span!("updating document", ?id, bundle = type_name::<B>()).entered();
+        // read before the write replaces it, so the audit entries show what changed
+        let previous = audit.map(|_| batch.current::<B>(id, db));
+        if let Err(err) = batch.overwrite(id, &bundle) {
+            error!(?err, ?id, "failed to update document");
+            batch.add_error(format!("updating document {id}: {err:#}"));
+            return;
+        }
+        debug!(?id, "updating document");
+        state.documents::<B>().insert(entity, id.clone());
+        state.touch::<B>(entity);
+        if let (Some(ctx), Some(previous)) = (audit, previous) {
+            match previous {
+                Ok(previous) => {
+                    batch.add_audit(audit::changes(ctx, id, previous.as_ref(), Some(&bundle)))
+                }
+                Err(err) => warn!(?err, ?id, "failed to read previous document for audit"),
+            }
+        }
+    }
+
+    /// Deletes documents of entities which no longer have the whole bundle.
+    pub fn delete_removed<B: BundleSync>(
+        db: &local::Database,
+        last_import: &mut LastImport,
+        state: &mut BundleSyncState,
+        batch: &mut ExportBatch,
+        audit: Option<&AuditContext>,
+        removed: impl Iterator<Item = EntityId>,
+        has_bundle: impl Fn(EntityId) -> bool,
+    ) {
+        let removed = removed
+            .filter(|entity| state.documents::<B>().contains_key(entity) && !has_bundle(*entity))
+            .collect::<HashSet<_>>();
+
+        for entity in removed {
+            if last_import.skip_once(entity) {
+                continue;
+            }
+            let Some(id) = state.documents::<B>().remove(&entity) else {
+                continue;
+            };
+            if let Some(lazy) = state.lazy::<B>() {
+                lazy.forget(entity);
+            }
+
+            debug!(?id, bundle = type_name::<B>(), "deleting document");
+            if let Some(ctx) = audit {
+                match batch.current::<B>(&id, db) {
+                    Ok(Some(previous)) => {
+                        batch.add_audit(audit::changes::<B>(ctx, &id, Some(&previous), None))
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!(?err, ?id, "failed to read deleted document for audit"),
+                }
+            }
+            batch.delete::<B>(&id);
+            // bundles exported later pick these up in the same batch
+            if let Some(linking) = state.linked_by.remove(&entity) {
+                state.resave.extend(linking);
+            }
+        }
     }
 }
@@ -0,0 +1,260 @@
+//! History of changes to bundles saved by a [BundleSync].
+//!
+//! When [AuditSettings::enabled], every exported change appends an [AuditEntry] per changed
+//! field, with the [CommandReasons] of the loop iteration which made the change. The schema
+//! of the database must include the [AuditEntry] collection.
+use std::time::{Duration, SystemTime};
+
+use bonsaidb::core::connection::Connection;
+use bonsaidb::core::document::{CollectionDocument, Emit};
+use bonsaidb::core::schema::{
+    Collection, CollectionViewSchema, SerializedCollection, View, ViewMapResult,
+};
+use bonsaidb::local;
+use serde_json::{Map, Value as JSON};
+
+use super::migrations::VERSION_KEY;
+use super::BundleSync;
+use crate::_ecs_::*;
+use crate::_result_::*;
+use crate::_tracing_::*;
+use crate::app_ctx::{CommandReasons, LocalDatabase};
+use crate::sealed;
+use crate::HintedID;
+
+/// Stored in BonsaiDB, in order of creation.
+#[derive(Clone, Debug, Collection, serde::Serialize, serde::Deserialize)]
+#[collection(name = "audit-log", primary_key = u64, views = [AuditByDocument])]
+pub struct AuditEntry {
+    /// Document of the changed entity
+    pub document: HintedID,
+    pub collection: String,
+    /// Field of the bundle, which is (or converts to) a component
+    pub component: String,
+    /// JSON of the field, `None` if the document was created. Sensitive values are
+    /// replaced with a fingerprint.
+    pub previous: Option<JSON>,
+    /// `None` if the document was deleted.
+    pub new: Option<JSON>,
+    pub at: SystemTime,
+    pub reason: String,
+}
+
+/// Entries of a document, for viewing its history.
+#[derive(Clone, Debug, View)]
+#[view(collection = AuditEntry, name = "by-document", key = HintedID)]
+pub struct AuditByDocument;
+
+impl CollectionViewSchema for AuditByDocument {
+    type View = Self;
+
+    fn map(&self, entry: CollectionDocument<AuditEntry>) -> ViewMapResult<Self::View> {
+        entry.header.emit_key_and_value(entry.contents.document, ())
+    }
+}
+
+/// Unique, added by [super::LocalDatabasePlugin].
+#[ecs_unique]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditSettings {
+    pub enabled: bool,
+    /// Older entries are deleted when the database is opened and whenever this changes.
+    pub max_age: Option<Duration>,
+}
+
+/// What's needed to record entries during one export.
+pub struct AuditContext {
+    reason: String,
+    at: SystemTime,
+}
+
+impl AuditContext {
+    pub fn new(settings: &AuditSettings, reasons: &CommandReasons) -> Option<Self> {
+        settings.enabled.then(|| AuditContext {
+            reason: reasons.describe(),
+            at: SystemTime::now(),
+        })
+    }
+}
+
+/// An entry for each field which differs, either side is `None` when the document was
+/// created or deleted. Entries are written along with the change, see [super::export].
+///
+/// Errors are logged rather than returned, so a failing audit log never blocks saving.
+pub(crate) fn changes<B: BundleSync>(
+    ctx: &AuditContext,
+    id: &HintedID,
+    previous: Option<&B>,
+    new: Option<&B>,
+) -> Vec<AuditEntry> {
+    let snapshots = (|| -> Result<_> {
+        Ok((
+            previous.map(snapshot).transpose()?,
+            new.map(snapshot).transpose()?,
+        ))
+    })();
+    let (previous, new) = match snapshots {
+        Ok(snapshots) => snapshots,
+        Err(err) => {
+            error!(?err, ?id, "failed to snapshot document for audit");
+            return Vec::new();
+        }
+    };
+
+    let empty = Map::new();
+    let (previous, new) = (
+        previous.as_ref().unwrap_or(&empty),
+        new.as_ref().unwrap_or(&empty),
+    );
+    let mut components = previous.keys().chain(new.keys()).collect::<Vec<_>>();
+    components.sort();
+    components.dedup();
+
+    components
+        .into_iter()
+        .filter(|component| {
+            *component != VERSION_KEY && previous.get(*component) != new.get(*component)
+        })
+        .map(|component| AuditEntry {
+            document: id.clone(),
+            collection: B::collection_name().to_string(),
+            component: component.clone(),
+            previous: previous.get(component).cloned(),
+            new: new.get(component).cloned(),
+            at: ctx.at,
+            reason: ctx.reason.clone(),
+        })
+        .collect()
+}
+
+/// Top-level fields of a bundle, with sensitive values redacted.
+fn snapshot<B: serde::Serialize>(bundle: &B) -> Result<Map<String, JSON>> {
+    match sealed::redacted(|| serde_json::to_value(bundle)).context("serializing for audit")? {
+        JSON::Object(fields) => Ok(fields),
+        other => anyhow::bail!("expected bundle to serialize to an object, found {other}"),
+    }
+}
+
+/// Newest first.
+pub fn document_history(db: &local::Database, id: &HintedID) -> Result<Vec<AuditEntry>> {
+    let mapped = db
+        .view::<AuditByDocument>()
+        .with_key(id.clone())
+        .query_with_collection_docs()
+        .with_context(|| format!("querying audit entries of {id}"))?;
+    let mut entries = Vec::with_capacity(mapped.len());
+    for mapped in &mapped {
+        entries.push((mapped.document.header.id, mapped.document.contents.clone()));
+    }
+    entries.sort_by_key(|(entry_id, _)| std::cmp::Reverse(*entry_id));
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Newest first.
+pub fn recent_entries(db: &local::Database, limit: u32) -> Result<Vec<AuditEntry>> {
+    Ok(AuditEntry::all(db)
+        .descending()
+        .limit(limit)
+        .query()
+        .context("querying recent audit entries")?
+        .into_iter()
+        .map(|doc| doc.contents)
+        .collect())
+}
+
+/// Returns the number of deleted entries.
+fn prune(db: &local::Database, max_age: Duration) -> Result<usize> {
+    let Some(cutoff) = SystemTime::now().checked_sub(max_age) else {
+        return Ok(0);
+    };
+    let mut deleted = 0;
+    loop {
+        // entries are appended in order, so the oldest come first
+        let oldest = AuditEntry::all(db)
+            .limit(100)
+            .query()
+            .context("querying oldest audit entries")?;
+        let expired = oldest
+            .iter()
+            .take_while(|doc| doc.contents.at < cutoff)
+            .collect::<Vec<_>>();
+        for doc in expired.iter() {
+            doc.delete(db).context("deleting expired audit entry")?;
+        }
+        deleted += expired.len();
+        if expired.len() < oldest.len() || oldest.is_empty() {
+            return Ok(deleted);
+        }
+    }
+}
+
+pub(super) fn prune_audit_log_system(
+    uv_settings: UniqueView<AuditSettings>,
+    uv_local_database: UniqueView<LocalDatabase>,
+) {
+    if !(uv_settings.is_inserted_or_modified() || uv_local_database.is_inserted_or_modified()) {
+        return;
+    }
+    let (true, Some(max_age)) = (uv_settings.enabled, uv_settings.max_age) else {
+        return;
+    };
+    let Ok(db) = uv_local_database.as_ref().as_ref() else {
+        // reported when importing
+        return;
+    };
+    match prune(db, max_age) {
+        Ok(0) => {}
+        Ok(deleted) => info!(deleted, ?max_age, "pruned audit log"),
+        Err(err) => error!(?err, "failed to prune audit log"),
+    }
+}
+
+#[test]
+fn test_prune_deletes_entries_past_max_age() {
+    let path = std::env::temp_dir().join(format!(
+        "hn-audit-{}.bonsaidb",
+        HintedID::generate("test").to_id_string()
+    ));
+    let mut storage_conf = local::config::StorageConfiguration::default();
+    storage_conf.path = Some(path.clone());
+    let db = local::Database::open::<AuditEntry>(storage_conf).unwrap();
+    let day = Duration::from_secs(24 * 60 * 60);
+    let document = HintedID::generate("dev");
+    for age_days in [40, 31, 2, 0] {
+        AuditEntry {
+            document: document.clone(),
+            collection: "test-docs".to_string(),
+            component: "c_test".to_string(),
+            previous: None,
+            new: Some(serde_json::json!(age_days)),
+            at: SystemTime::now() - day * age_days,
+            reason: "test".to_string(),
+        }
+        .push_into(&db)
+        .unwrap();
+    }
+
+    let world = shipyard::World::new();
+    world.add_unique(AuditSettings {
+        enabled: true,
+        max_age: Some(day * 30),
+    });
+    world.add_unique(LocalDatabase(std::sync::Arc::new(Ok(db.clone())), None));
+    world.run(prune_audit_log_system);
+    let kept = document_history(&db, &document)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.new.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(kept, vec![serde_json::json!(0), serde_json::json!(2)]);
+
+    // e.g. lowering retention_days
+    world.run(|mut uvm_settings: UniqueViewMut<AuditSettings>| {
+        uvm_settings.as_mut().max_age = Some(day);
+    });
+    world.run(prune_audit_log_system);
+    assert_eq!(document_history(&db, &document).unwrap().len(), 1);
+
+    drop((world, db));
+    let _ = std::fs::remove_dir_all(&path);
+}
@@ -0,0 +1,408 @@
+//! Saving every change of one command-loop iteration in a single transaction.
+//!
+//! Each [BundleSync] adds its writes to the [ExportBatch], which is then applied all at once,
+//! so a device is never saved pointing at a cred which failed to save. A batch which fails is
+//! kept, merged with later changes, and retried with a backoff; see [ExportStatus].
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use bonsaidb::core::connection::LowLevelConnection;
+use bonsaidb::core::document::DocumentId;
+use bonsaidb::core::schema::{CollectionName, SerializedCollection};
+use bonsaidb::core::transaction::{Operation, Transaction};
+use bonsaidb::local;
+use shipyard::AllStorages;
+
+use super::audit::{AuditContext, AuditEntry, AuditSettings};
+use super::{lazy, BundleSync};
+use crate::_ecs_::*;
+use crate::_result_::*;
+use crate::_tracing_::*;
+use crate::app_ctx::{AppCtx, CommandReasons, LocalDatabase};
+use crate::HintedID;
+
+/// Longest wait between retries of a failed batch.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Adds the changes of one bundle to the batch, see [BundleSync::export_changes].
+pub type ExportFn = fn(&AllStorages, &local::Database, &mut ExportBatch, Option<&AuditContext>);
+
+/// Unique, added by [super::LocalDatabasePlugin] and filled in by each [super::BundleSyncPlugin].
+#[ecs_unique]
+#[derive(Default)]
+pub struct BundleExporters(pub(super) Vec<ExportFn>);
+
+/// Unique, how saving changes to disk is going.
+#[ecs_unique]
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ExportStatus {
+    /// Documents waiting for a retry, since the last transaction failed.
+    pub pending: usize,
+    /// Failed transactions in a row, reset once one succeeds.
+    pub failed_attempts: u32,
+    pub last_error: Option<String>,
+    pub last_saved_at: Option<SystemTime>,
+    /// Changes until then are only added to the pending batch, so the backoff is kept.
+    pub retry_scheduled: bool,
+    /// Changes which could not be added to the batch, e.g. failing to serialize. Kept until
+    /// the batch they were left out of is saved.
+    pub batch_errors: Vec<String>,
+}
+
+enum PendingWrite {
+    Overwrite(Vec<u8>),
+    Delete,
+}
+
+struct PendingDocument {
+    collection: CollectionName,
+    id: HintedID,
+    write: PendingWrite,
+}
+
+/// Unique, writes waiting to be applied in one transaction.
+#[ecs_unique]
+#[derive(Default)]
+pub struct ExportBatch {
+    /// Only the latest write of each document is kept.
+    documents: BTreeMap<(String, HintedID), PendingDocument>,
+    audit: Vec<AuditEntry>,
+    /// Documents which could not be added, e.g. failing to serialize.
+    errors: Vec<String>,
+}
+
+impl ExportBatch {
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty() && self.audit.is_empty()
+    }
+
+    pub(super) fn overwrite<B: BundleSync>(&mut self, id: &HintedID, bundle: &B) -> Result<()> {
+        let contents = B::serialize(bundle).context("serializing document")?;
+        self.insert::<B>(id, PendingWrite::Overwrite(contents));
+        Ok(())
+    }
+
+    pub(super) fn delete<B: BundleSync>(&mut self, id: &HintedID) {
+        self.insert::<B>(id, PendingWrite::Delete);
+    }
+
+    /// For documents which can't be read as their bundle, so no audit entry is recorded.
+    pub(super) fn delete_document(&mut self, collection: CollectionName, id: HintedID) {
+        self.documents.insert(
+            (collection.to_string(), id.clone()),
+            PendingDocument {
+                collection,
+                id,
+                write: PendingWrite::Delete,
+            },
+        );
+    }
+
+    pub(super) fn add_audit(&mut self, entries: Vec<AuditEntry>) {
+        self.audit.extend(entries);
+    }
+
+    /// Reported in [ExportStatus], for changes which could not be added to the batch.
+    pub fn add_error(&mut self, error: String) {
+        self.errors.push(error);
+    }
+
+    fn insert<B: BundleSync>(&mut self, id: &HintedID, write: PendingWrite) {
+        let collection = B::collection_name();
+        self.documents.insert(
+            (collection.to_string(), id.clone()),
+            PendingDocument {
+                collection,
+                id: id.clone(),
+                write,
+            },
+        );
+    }
+
+    /// The document as it will be once the batch is saved, so changes are audited against
+    /// writes which are still waiting for a retry rather than what's on disk.
+    pub(super) fn current<B: BundleSync>(
+        &self,
+        id: &HintedID,
+        db: &local::Database,
+    ) -> Result<Option<B>> {
+        match self
+            .documents
+            .get(&(B::collection_name().to_string(), id.clone()))
+        {
+            Some(PendingDocument {
+                write: PendingWrite::Overwrite(contents),
+                ..
+            }) => Ok(Some(
+                B::deserialize(contents).context("deserializing pending document")?,
+            )),
+            Some(PendingDocument {
+                write: PendingWrite::Delete,
+                ..
+            }) => Ok(None),
+            None => Ok(B::get(id, db)
+                .context("reading saved document")?
+                .map(|doc| doc.contents)),
+        }
+    }
+
+    /// Applies every write, or none of them.
+    fn commit(&self, db: &local::Database) -> Result<()> {
+        let mut transaction = Transaction::new();
+        for doc in self.documents.values() {
+            let doc_id = DocumentId::new(doc.id.clone())
+                .with_context(|| format!("encoding id of document {}", doc.id))?;
+            match &doc.write {
+                PendingWrite::Overwrite(contents) => {
+                    transaction.push(Operation::overwrite(
+                        doc.collection.clone(),
+                        doc_id,
+                        contents.clone(),
+                    ));
+                }
+                PendingWrite::Delete => {
+                    // deleting needs the current revision
+                    match db
+                        .get_from_collection(doc_id, &doc.collection)
+                        .with_context(|| format!("getting document {} to delete", doc.id))?
+                    {
+                        Some(found) => transaction
+                            .push(Operation::delete(doc.collection.clone(), found.header)),
+                        None => warn!(id = ?doc.id, "document to delete was already missing"),
+                    }
+                }
+            }
+        }
+        for entry in self.audit.iter() {
+            transaction.push(
+                Operation::push_serialized::<AuditEntry>(entry)
+                    .context("serializing audit entry")?,
+            );
+        }
+
+        db.apply_transaction(transaction)
+            .context("applying transaction")?;
+        Ok(())
+    }
+}
+
+/// Reset system added by [super::LocalDatabasePlugin], so it runs once every bundle changed.
+pub(super) fn export_system(mut all_storages: AllStoragesViewMut) {
+    let exporters =
+        all_storages.run(|uv_exporters: UniqueView<BundleExporters>| uv_exporters.0.clone());
+    let (local_database, audit) = all_storages.run(
+        |uv_local_database: UniqueView<LocalDatabase>,
+         uv_audit_settings: UniqueView<AuditSettings>,
+         uv_command_reasons: UniqueView<CommandReasons>| {
+            (
+                uv_local_database.clone(),
+                AuditContext::new(&uv_audit_settings, &uv_command_reasons),
+            )
+        },
+    );
+    let db = local_database.get_database();
+    let db = match db.as_err_arc_ref() {
+        Ok(db) => db,
+        Err(err) => {
+            error!(?err, "no database to save changes to");
+            return;
+        }
+    };
+
+    // changes are added to the batch waiting for a retry, replacing writes to the same documents
+    let mut batch = all_storages
+        .run(|mut uvm_pending: UniqueViewMut<ExportBatch>| std::mem::take(uvm_pending.as_mut()));
+    local_database.sealed(|| {
+        for export in exporters {
+            export(&all_storages, db, &mut batch, audit.as_ref());
+        }
+    });
+
+    all_storages.run(
+        |mut uvm_pending: UniqueViewMut<ExportBatch>,
+         mut uvm_status: UniqueViewMut<ExportStatus>| {
+            if batch.is_empty() && batch.errors.is_empty() {
+                return;
+            }
+            let mut pending = batch;
+            let status = uvm_status.as_mut();
+            if pending.is_empty() {
+                status.batch_errors = std::mem::take(&mut pending.errors);
+                return;
+            }
+            if status.retry_scheduled {
+                debug!(
+                    documents = pending.documents.len(),
+                    "waiting for the retry to save changes"
+                );
+                status.pending = pending.documents.len();
+                status.batch_errors = pending.errors.clone();
+                *uvm_pending.as_mut() = pending;
+                return;
+            }
+
+            let _span = info_span!("export", documents = pending.documents.len()).entered();
+            match pending.commit(db) {
+                Ok(()) => {
+                    info!(
+                        documents = pending.documents.len(),
+                        audit_entries = pending.audit.len(),
+                        "saved changes"
+                    );
+                    *status = ExportStatus {
+                        pending: 0,
+                        failed_attempts: 0,
+                        last_error: None,
+                        last_saved_at: Some(SystemTime::now()),
+                        retry_scheduled: false,
+                        // still report documents which never made it into the batch
+                        batch_errors: std::mem::take(&mut pending.errors),
+                    };
+                }
+                Err(err) => {
+                    error!(?err, "failed to save changes, retrying");
+                    status.pending = pending.documents.len();
+                    status.failed_attempts += 1;
+                    status.last_error = Some(format!("{err:#}"));
+                    status.batch_errors = pending.errors.clone();
+                    *uvm_pending.as_mut() = pending;
+                    schedule_retry(&all_storages, status);
+                }
+            }
+        },
+    );
+
+    // evicted entities can be loaded again, so only once nothing is waiting to be saved
+    let saved = all_storages.run(|uv_pending: UniqueView<ExportBatch>| uv_pending.is_empty());
+    if saved {
+        lazy::evict_idle(&mut all_storages);
+    }
+}
+
+/// Without an [AppCtx] (e.g. in tests), the retry waits for the next command.
+fn schedule_retry(all_storages: &AllStorages, status: &mut ExportStatus) {
+    let Ok(uv_app_ctx) = all_storages.borrow::<UniqueView<AppCtx>>() else {
+        return;
+    };
+    if status.retry_scheduled {
+        return;
+    }
+    status.retry_scheduled = true;
+    let delay = Duration::from_secs(1 << status.failed_attempts.min(6)).min(MAX_RETRY_DELAY);
+    let app_ctx = uv_app_ctx.clone();
+    uv_app_ctx.spawn(async move {
+        tokio::time::sleep(delay).await;
+        // the export system runs after every command
+        app_ctx.schedule_system(
+            "retry saving changes to disk",
+            |mut uvm_status: UniqueViewMut<ExportStatus>| {
+                uvm_status.as_mut().retry_scheduled = false;
+            },
+        );
+        Ok(())
+    });
+}
+
+#[test]
+fn test_failed_batch_is_merged_and_retried() {
+    use bonsaidb::core::schema::Collection;
+
+    /// Any schema without the audit log, so saving audit entries fails.
+    #[derive(Debug, Collection, serde::Serialize, serde::Deserialize)]
+    #[collection(name = "test-docs")]
+    struct TestDoc;
+
+    /// Changes for the next export.
+    #[ecs_unique]
+    #[derive(Default)]
+    struct TestChanges {
+        audit: Vec<AuditEntry>,
+        errors: Vec<String>,
+    }
+
+    fn export_test_changes(
+        all_storages: &AllStorages,
+        _db: &local::Database,
+        batch: &mut ExportBatch,
+        _audit: Option<&AuditContext>,
+    ) {
+        let changes = all_storages.run(|mut uvm_changes: UniqueViewMut<TestChanges>| {
+            std::mem::take(uvm_changes.as_mut())
+        });
+        batch.add_audit(changes.audit);
+        for error in changes.errors {
+            batch.add_error(error);
+        }
+    }
+
+    let entry = |reason: &str| AuditEntry {
+        document: HintedID::generate("test"),
+        collection: "test-docs".to_string(),
+        component: "c_test".to_string(),
+        previous: None,
+        new: Some(serde_json::json!(reason)),
+        at: SystemTime::now(),
+        reason: reason.to_string(),
+    };
+    let open = |name: &str| {
+        let mut storage_conf = local::config::StorageConfiguration::default();
+        storage_conf.path = Some(std::env::temp_dir().join(format!(
+            "hn-export-{name}-{}.bonsaidb",
+            HintedID::generate("test").to_id_string()
+        )));
+        storage_conf
+    };
+    let without_audit = local::Database::open::<TestDoc>(open("without-audit")).unwrap();
+    let with_audit = local::Database::open::<AuditEntry>(open("with-audit")).unwrap();
+
+    let world = shipyard::World::new();
+    world.add_unique(BundleExporters(vec![export_test_changes]));
+    world.add_unique(LocalDatabase(std::sync::Arc::new(Ok(without_audit)), None));
+    world.add_unique(AuditSettings::default());
+    world.add_unique(CommandReasons::default());
+    world.add_unique(ExportBatch::default());
+    world.add_unique(ExportStatus::default());
+    world.add_unique(super::BundleSyncState::default());
+    world.add_unique(super::LastImport::default());
+    world.add_unique(TestChanges {
+        audit: vec![entry("first")],
+        errors: Vec::new(),
+    });
+    let status = || world.run(|uv_status: UniqueView<ExportStatus>| uv_status.clone());
+
+    world.run(export_system);
+    assert_eq!(status().failed_attempts, 1);
+    assert!(status().last_error.is_some());
+
+    // later changes are merged into the failed batch
+    world.run(|mut uvm_changes: UniqueViewMut<TestChanges>| {
+        *uvm_changes.as_mut() = TestChanges {
+            audit: vec![entry("second")],
+            errors: vec!["serializing a test document".to_string()],
+        };
+    });
+    world.run(export_system);
+    assert_eq!(status().failed_attempts, 2);
+    assert_eq!(status().batch_errors, vec!["serializing a test document"]);
+    assert_eq!(
+        world.run(|uv_pending: UniqueView<ExportBatch>| uv_pending.audit.len()),
+        2
+    );
+
+    world.run(|mut uvm_local_database: UniqueViewMut<LocalDatabase>| {
+        uvm_local_database.as_mut().0 = std::sync::Arc::new(Ok(with_audit.clone()));
+    });
+    world.run(export_system);
+    let status = status();
+    assert_eq!(status.failed_attempts, 0);
+    assert_eq!(status.pending, 0);
+    assert!(status.last_error.is_none());
+    assert!(status.last_saved_at.is_some());
+    assert_eq!(
+        status.batch_errors,
+        vec!["serializing a test document"],
+        "still reported once the batch is saved"
+    );
+    assert_eq!(AuditEntry::all(&with_audit).count().unwrap(), 2);
+    assert!(world.run(|uv_pending: UniqueView<ExportBatch>| uv_pending.is_empty()));
+}
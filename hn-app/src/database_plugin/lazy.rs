@@ -0,0 +1,172 @@
+//! Loading documents of a [BundleSync] into the ECS when they're referenced, rather than
+//! importing every document at startup.
+//!
+//! Add the bundle with [super::BundleSyncPlugin::lazy], then [AppCtx::load] (or
+//! [AppCtx::load_by_view]) its documents before a command looks for their entities. Entities
+//! which weren't loaded or changed for the idle time are evicted from the ECS once every change
+//! is saved, and their documents stay on disk.
+//!
+//! Only bundles which no other bundle links to can be lazy, since links to documents which
+//! aren't loaded are dropped on import.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bonsaidb::core::connection::Connection;
+use bonsaidb::core::document::BorrowedDocument;
+use bonsaidb::core::schema::SerializedView;
+use shipyard::AllStorages;
+
+use super::migrations::migrate_bytes;
+use super::{BundleSync, BundleSyncState, LastImport};
+use crate::_ecs_::*;
+use crate::_result_::*;
+use crate::_tracing_::*;
+use crate::app_ctx::AppCtx;
+use crate::HintedID;
+
+pub(super) struct LazyBundle {
+    idle_after: Duration,
+    last_used: HashMap<EntityId, Instant>,
+}
+
+impl LazyBundle {
+    pub(super) fn new(idle_after: Duration) -> Self {
+        Self {
+            idle_after,
+            last_used: HashMap::new(),
+        }
+    }
+
+    pub(super) fn touch(&mut self, entity: EntityId) {
+        self.last_used.insert(entity, Instant::now());
+    }
+
+    pub(super) fn forget(&mut self, entity: EntityId) {
+        self.last_used.remove(&entity);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.last_used.clear();
+    }
+}
+
+impl AppCtx {
+    /// Adds entities for documents of `B` which aren't in the ECS yet, and returns the entity
+    /// of each document in order, skipping documents which don't exist.
+    ///
+    /// Every returned entity counts as used, so it won't be evicted for the idle time.
+    pub async fn load<B: BundleSync>(&self, ids: Vec<HintedID>) -> Result<Vec<EntityId>> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<Vec<EntityId>>>();
+        let tx = std::sync::Mutex::new(Some(tx));
+        self.run_system("load documents", move |all_storages: AllStoragesView| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(B::load(&all_storages, &ids));
+            }
+        });
+        rx.await.context("receiving loaded entities")?
+    }
+
+    /// Loads every document which the view maps to `key`, see [AppCtx::load].
+    pub async fn load_by_view<V>(&self, key: V::Key) -> Result<Vec<EntityId>>
+    where
+        V: SerializedView,
+        V::Collection: BundleSync,
+    {
+        let db = self.get_database().await;
+        let db = db.as_err_arc_ref()?;
+        let mapped = db
+            .view::<V>()
+            .with_key(key)
+            .query()
+            .context("querying view to load documents")?;
+        let mut ids = Vec::with_capacity(mapped.len());
+        for mapping in mapped {
+            let id = mapping
+                .source
+                .id
+                .deserialize::<HintedID>()
+                .context("reading id of mapped document")?;
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        self.load::<V::Collection>(ids).await
+    }
+}
+
+/// Contents of a document for mapping a view, migrated if it was saved at an older version,
+/// since documents of lazy bundles are only migrated once they're loaded.
+///
+/// `None` (and an error logged) for documents which can't be read, which leaves them out of
+/// the view. Only map bundles without sensitive fields, since those can't be read once the
+/// database secret changes.
+pub fn view_contents<B: BundleSync>(document: &BorrowedDocument<'_>) -> Option<B> {
+    match migrate_bytes::<B>(&document.contents) {
+        Ok((bundle, _)) => Some(bundle),
+        Err(err) => {
+            error!(?err, id = ?document.header.id, "failed to read document for view");
+            None
+        }
+    }
+}
+
+/// Marks documents of `B` which are already loaded as used, and returns the ids of the others.
+pub(super) fn split_loaded<B: BundleSync>(
+    all_storages: &AllStorages,
+    ids: &[HintedID],
+) -> Vec<HintedID> {
+    all_storages.run(|mut uvm_state: UniqueViewMut<BundleSyncState>| {
+        let state = uvm_state.as_mut();
+        let mut missing = Vec::new();
+        for id in ids {
+            match state.links.get(id) {
+                Some(entity) if state.documents::<B>().contains_key(&entity) => {
+                    state.touch::<B>(entity);
+                }
+                _ if missing.contains(id) => {}
+                _ => missing.push(id.clone()),
+            }
+        }
+        missing
+    })
+}
+
+/// Called by [super::export::export_system] once every change is saved.
+pub(super) fn evict_idle(all_storages: &mut AllStorages) {
+    let now = Instant::now();
+    let evicted = all_storages.run(
+        |mut uvm_state: UniqueViewMut<BundleSyncState>,
+         mut uvm_last_import: UniqueViewMut<LastImport>| {
+            let state = uvm_state.as_mut();
+            let mut evicted = Vec::new();
+            for (type_id, lazy) in state.lazy.iter_mut() {
+                let idle = lazy
+                    .last_used
+                    .iter()
+                    .filter(|(_, used)| now.duration_since(**used) >= lazy.idle_after)
+                    .map(|(entity, _)| *entity)
+                    .collect::<Vec<_>>();
+                for entity in idle {
+                    lazy.forget(entity);
+                    evicted.push((*type_id, entity));
+                }
+            }
+            for (type_id, entity) in evicted.iter() {
+                // forget the document first, so deleting the entity doesn't delete it
+                let documents = state.documents.get_mut(type_id);
+                if let Some(id) = documents.and_then(|documents| documents.remove(entity)) {
+                    state.links.0.remove(&id);
+                }
+                uvm_last_import.0.remove(entity);
+            }
+            evicted
+        },
+    );
+
+    if !evicted.is_empty() {
+        debug!(evicted = evicted.len(), "evicting idle entities");
+    }
+    for (_, entity) in evicted {
+        all_storages.delete_entity(entity);
+    }
+}
@@ -0,0 +1,329 @@
+//! Upgrading documents saved by older versions of a [BundleSync].
+//!
+//! Every document stores the version of its bundle under [VERSION_KEY] (missing means `0`).
+//! When importing, documents at an older version are decoded into a [DocumentValue], passed
+//! through each [Migration] in order, and only then deserialized as the current bundle.
+//! Documents which fail are reported in [MigrationReport] and left on disk untouched.
+use std::any::type_name;
+
+use bonsaidb::core::connection::{Connection, LowLevelConnection};
+use bonsaidb::core::document::{DocumentId, OwnedDocument};
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local;
+use pot::Value;
+use serde_json::Value as JSON;
+
+use super::BundleSync;
+use crate::_ecs_::*;
+use crate::_result_::*;
+use crate::_tracing_::*;
+use crate::HintedID;
+
+pub const VERSION_KEY: &str = "_v";
+
+/// Stored with every document, use as a field marked `#[bundle_sync(version)]`.
+///
+/// ```ignore
+/// #[bundle_sync(version)]
+/// #[serde(rename = "_v", default)]
+/// version: DocumentVersion,
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct DocumentVersion(pub u32);
+
+/// Upgrades a document from `from_version` to `from_version + 1`.
+pub struct Migration {
+    pub from_version: u32,
+    pub description: &'static str,
+    pub migrate: fn(&mut DocumentValue) -> Result<()>,
+}
+
+/// Top-level fields of a document before it's deserialized.
+#[derive(Debug)]
+pub struct DocumentValue(Vec<(Value<'static>, Value<'static>)>);
+
+impl DocumentValue {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match pot::from_slice::<Value<'_>>(bytes)
+            .context("decoding document")?
+            .into_static()
+        {
+            Value::Mappings(fields) => Ok(DocumentValue(fields)),
+            other => anyhow::bail!("expected document to be a map, found {other:?}"),
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        pot::to_vec(&Value::Mappings(self.0)).context("encoding document")
+    }
+
+    pub fn version(&self) -> Result<u32> {
+        match self.get(VERSION_KEY) {
+            None => Ok(0),
+            Some(Value::Integer(integer)) => integer
+                .as_u32()
+                .with_context(|| format!("expected `{VERSION_KEY}` to be a u32")),
+            Some(other) => {
+                anyhow::bail!("expected `{VERSION_KEY}` to be an integer, found {other:?}")
+            }
+        }
+    }
+
+    fn set_version(&mut self, version: u32) -> Result<()> {
+        self.insert_serialized(VERSION_KEY, &DocumentVersion(version))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value<'static>> {
+        self.0
+            .iter()
+            .find(|(k, _)| is_key(k, key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value<'static>> {
+        let index = self.0.iter().position(|(k, _)| is_key(k, key))?;
+        Some(self.0.remove(index).1)
+    }
+
+    /// Returns the previous value.
+    pub fn insert(&mut self, key: &str, value: Value<'static>) -> Option<Value<'static>> {
+        let previous = self.remove(key);
+        self.0.push((Value::String(key.to_string().into()), value));
+        previous
+    }
+
+    pub fn insert_serialized<T: serde::Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let value = pot::from_slice::<Value<'_>>(&encode(value)?)
+            .context("decoding inserted value")?
+            .into_static();
+        self.insert(key, value);
+        Ok(())
+    }
+
+    /// Errors if `from` is missing, so renames don't silently drop data.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let value = self
+            .remove(from)
+            .with_context(|| format!("expected `{from}` to rename to `{to}`"))?;
+        self.insert(to, value);
+        Ok(())
+    }
+}
+
+fn is_key(value: &Value<'_>, key: &str) -> bool {
+    matches!(value, Value::String(str) if str == key)
+}
+
+/// Encodes in the format documents are stored in, e.g. for testing migrations.
+pub fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
+    pot::to_vec(value).context("encoding with pot")
+}
+
+/// Deserializes a stored document, migrating it first if it's at an older version.
+///
+/// Returns the version it was migrated from.
+pub fn migrate_bytes<B: BundleSync>(bytes: &[u8]) -> Result<(B, Option<u32>)> {
+    let mut value = DocumentValue::from_bytes(bytes)?;
+    let Some(from_version) = migrate_value::<B>(&mut value)? else {
+        return Ok((
+            B::deserialize(bytes).context("deserializing document")?,
+            None,
+        ));
+    };
+
+    let bundle = B::deserialize(&value.into_bytes()?)
+        .with_context(|| format!("deserializing document migrated from version {from_version}"))?;
+    Ok((bundle, Some(from_version)))
+}
+
+/// Like [migrate_bytes], for a document serialized as JSON, e.g. in a backup.
+pub fn migrate_json<B: BundleSync + serde::de::DeserializeOwned>(
+    contents: JSON,
+) -> Result<(B, Option<u32>)> {
+    let mut value = DocumentValue::from_bytes(&encode(&contents)?)?;
+    let Some(from_version) = migrate_value::<B>(&mut value)? else {
+        return Ok((
+            serde_json::from_value(contents).context("deserializing document")?,
+            None,
+        ));
+    };
+
+    let migrated = serde_json::to_value(Value::Mappings(value.0))
+        .context("converting migrated document to JSON")?;
+    let bundle = serde_json::from_value(migrated)
+        .with_context(|| format!("deserializing document migrated from version {from_version}"))?;
+    Ok((bundle, Some(from_version)))
+}
+
+/// Runs the migrations from the document's version, returns `None` if it was current.
+fn migrate_value<B: BundleSync>(value: &mut DocumentValue) -> Result<Option<u32>> {
+    let from_version = value.version()?;
+    if from_version == B::VERSION {
+        return Ok(None);
+    }
+    anyhow::ensure!(
+        from_version < B::VERSION,
+        "document is at version {from_version}, which is newer than {}",
+        B::VERSION
+    );
+
+    let migrations = B::migrations();
+    for version in from_version..B::VERSION {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.from_version == version)
+            .with_context(|| format!("no migration from version {version}"))?;
+        (migration.migrate)(value).with_context(|| {
+            format!(
+                "migrating from version {version}: {}",
+                migration.description
+            )
+        })?;
+    }
+    value.set_version(B::VERSION)?;
+
+    Ok(Some(from_version))
+}
+
+/// Unique, added by [super::LocalDatabasePlugin] and filled in as each bundle is imported.
+#[ecs_unique]
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct MigrationReport {
+    /// Migrated documents are not written back, and no changes are exported.
+    pub dry_run: bool,
+    pub collections: Vec<CollectionReport>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CollectionReport {
+    pub bundle: &'static str,
+    pub version: u32,
+    pub imported: usize,
+    pub migrated: Vec<MigratedDocument>,
+    pub failed: Vec<FailedDocument>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct MigratedDocument {
+    pub id: HintedID,
+    pub from_version: u32,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FailedDocument {
+    /// Raw id if the id itself failed to deserialize
+    pub id: String,
+    pub error: String,
+    /// A sensitive field couldn't be unsealed, see [crate::sealed::detect_unseal_failure]
+    pub unsealable: bool,
+}
+
+/// Reads every document of the collection, migrating and (unless this is a dry run)
+/// saving documents which were at an older version.
+pub fn read_documents<B: BundleSync>(
+    db: &local::Database,
+    report: &mut MigrationReport,
+) -> Result<Vec<(HintedID, B)>> {
+    let _span = info_span!("read_documents", bundle = type_name::<B>()).entered();
+    let docs = db
+        .collection::<B>()
+        .all()
+        .query()
+        .with_context(|| format!("getting all {}", type_name::<B>()))?;
+    Ok(migrate_documents::<B>(db, docs, report))
+}
+
+/// Like [read_documents], for only the given documents. Missing documents are skipped.
+pub fn read_documents_by_id<B: BundleSync>(
+    db: &local::Database,
+    ids: &[HintedID],
+    report: &mut MigrationReport,
+) -> Result<Vec<(HintedID, B)>> {
+    let _span = info_span!("read_documents_by_id", bundle = type_name::<B>()).entered();
+    let doc_ids = ids
+        .iter()
+        .map(|id| DocumentId::new(id.clone()).with_context(|| format!("encoding id {id}")))
+        .collect::<Result<Vec<_>>>()?;
+    let docs = db
+        .get_multiple_from_collection(&doc_ids, &B::collection_name())
+        .with_context(|| format!("getting {} documents of {}", ids.len(), type_name::<B>()))?;
+    Ok(migrate_documents::<B>(db, docs, report))
+}
+
+/// Counts are added to the report of the bundle, so loading documents later adds up.
+fn migrate_documents<B: BundleSync>(
+    db: &local::Database,
+    docs: Vec<OwnedDocument>,
+    report: &mut MigrationReport,
+) -> Vec<(HintedID, B)> {
+    let dry_run = report.dry_run;
+    let collection = match report
+        .collections
+        .iter()
+        .position(|collection| collection.bundle == type_name::<B>())
+    {
+        Some(index) => &mut report.collections[index],
+        None => {
+            report.collections.push(CollectionReport {
+                bundle: type_name::<B>(),
+                version: B::VERSION,
+                imported: 0,
+                migrated: Vec::new(),
+                failed: Vec::new(),
+            });
+            report.collections.last_mut().expect("just pushed")
+        }
+    };
+    let mut documents = Vec::with_capacity(docs.len());
+    for doc in docs {
+        let id = match doc.header.id.deserialize::<HintedID>() {
+            Ok(id) => id,
+            Err(err) => {
+                error!(?err, id = ?doc.header.id, "failed to read document id");
+                collection.failed.push(FailedDocument {
+                    id: format!("{:?}", doc.header.id),
+                    error: format!("{err:#}"),
+                    unsealable: false,
+                });
+                continue;
+            }
+        };
+        let (migrated, unsealable) =
+            crate::sealed::detect_unseal_failure(|| migrate_bytes::<B>(&doc.contents));
+        match migrated {
+            Ok((bundle, None)) => documents.push((id, bundle)),
+            Ok((bundle, Some(from_version))) => {
+                info!(?id, from_version, dry_run, "migrated document");
+                collection.migrated.push(MigratedDocument {
+                    id: id.clone(),
+                    from_version,
+                });
+                let bundle = if dry_run {
+                    bundle
+                } else {
+                    match B::overwrite(&id, bundle, db) {
+                        Ok(doc) => doc.contents,
+                        Err(err) => {
+                            // still import it, and try again next time
+                            error!(err = ?err.error, ?id, "failed to save migrated document");
+                            err.contents
+                        }
+                    }
+                };
+                documents.push((id, bundle));
+            }
+            Err(err) => {
+                error!(?err, ?id, "failed to migrate document, skipping");
+                collection.failed.push(FailedDocument {
+                    id: id.to_string(),
+                    error: format!("{err:#}"),
+                    unsealable,
+                });
+            }
+        }
+    }
+
+    collection.imported += documents.len();
+    documents
+}
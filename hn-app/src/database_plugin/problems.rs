@@ -0,0 +1,226 @@
+//! Problems found while importing documents, which are skipped or dropped instead of
+//! stopping the import.
+//!
+//! Everything which can be imported still is, and each problem is listed in [ImportProblems]
+//! until it's repaired with [AppCtx::repair_import_problems] or the database is reopened.
+use std::any::type_name;
+
+use bonsaidb::core::schema::CollectionName;
+use shipyard::AllStorages;
+
+use super::export::ExportBatch;
+use super::migrations::{self, FailedDocument, MigrationReport};
+use super::{BundleSync, BundleSyncState};
+use crate::_ecs_::*;
+use crate::_result_::*;
+use crate::_tracing_::*;
+use crate::app_ctx::{AppCtx, LocalDatabase};
+use crate::HintedID;
+
+/// Unique, added by [super::LocalDatabasePlugin] and filled in as bundles are imported.
+#[ecs_unique]
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ImportProblems(pub Vec<ImportProblem>);
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ImportProblem {
+    pub bundle: &'static str,
+    pub collection: String,
+    #[serde(skip)]
+    collection_name: CollectionName,
+    /// Raw id if the id itself failed to deserialize
+    pub document: String,
+    pub kind: ProblemKind,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The linked document doesn't exist, so the link was dropped. Repairing saves the
+    /// document without it.
+    MissingLink {
+        field: &'static str,
+        linked: HintedID,
+    },
+    /// Failed to decode, migrate, or convert into components, so the document was skipped
+    /// and left on disk. Repairing can delete it.
+    Undecodable { error: String },
+    /// A sensitive field couldn't be unsealed, e.g. the database secret changed, so the
+    /// document was skipped. It's never deleted by repairing, restore the secret instead.
+    Unsealable { error: String },
+    /// Another bundle already imported a document with this id, so links to it keep
+    /// pointing at the first one.
+    DuplicateId,
+}
+
+impl ImportProblems {
+    /// Problems found again, e.g. when a lazy bundle loads a document twice, are listed once.
+    pub fn add<B: BundleSync>(&mut self, document: impl ToString, kind: ProblemKind) {
+        let collection_name = B::collection_name();
+        let problem = ImportProblem {
+            bundle: type_name::<B>(),
+            collection: collection_name.to_string(),
+            collection_name,
+            document: document.to_string(),
+            kind,
+        };
+        if self.0.contains(&problem) {
+            return;
+        }
+        warn!(?problem, "problem importing document");
+        self.0.push(problem);
+    }
+}
+
+/// What [AppCtx::repair_import_problems] should do besides saving documents again.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct RepairOptions {
+    /// Delete documents which can't be decoded, they're otherwise kept for a fix.
+    ///
+    /// Refused while the database secret isn't loaded, and documents which only fail to unseal
+    /// are always kept.
+    #[serde(default)]
+    pub delete_undecodable: bool,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct RepairSummary {
+    /// Documents saved again without their missing links
+    pub resaved: usize,
+    pub deleted: usize,
+    /// Problems which need fixing by hand
+    pub remaining: usize,
+}
+
+impl AppCtx {
+    /// Saves documents with missing links again (and deletes undecodable documents if asked),
+    /// along with the other changes at the end of the update.
+    pub async fn repair_import_problems(&self, options: RepairOptions) -> Result<RepairSummary> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<RepairSummary>>();
+        let tx = std::sync::Mutex::new(Some(tx));
+        self.run_system(
+            "repair import problems",
+            move |all_storages: AllStoragesView| {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    let _ = tx.send(repair_import_problems(&all_storages, &options));
+                }
+            },
+        );
+        rx.await.context("receiving repair summary")?
+    }
+}
+
+/// See [AppCtx::repair_import_problems], the repaired documents are saved by the next export.
+pub fn repair_import_problems(
+    all_storages: &AllStorages,
+    options: &RepairOptions,
+) -> Result<RepairSummary> {
+    // without a key, sealed documents can't be told apart from corrupt ones
+    let has_key = all_storages.run(|uv_local_database: UniqueView<LocalDatabase>| {
+        uv_local_database.sealing_key().is_some()
+    });
+    anyhow::ensure!(
+        !options.delete_undecodable || has_key,
+        "refusing to delete undecodable documents while the database secret isn't loaded"
+    );
+    Ok(all_storages.run(
+        |mut uvm_problems: UniqueViewMut<ImportProblems>,
+         mut uvm_state: UniqueViewMut<BundleSyncState>,
+         mut uvm_batch: UniqueViewMut<ExportBatch>| {
+            repair(
+                uvm_problems.as_mut(),
+                uvm_state.as_mut(),
+                uvm_batch.as_mut(),
+                options,
+            )
+        },
+    ))
+}
+
+fn repair(
+    problems: &mut ImportProblems,
+    state: &mut BundleSyncState,
+    batch: &mut ExportBatch,
+    options: &RepairOptions,
+) -> RepairSummary {
+    let mut summary = RepairSummary::default();
+    problems.0.retain(|problem| {
+        let id = HintedID::try_from(problem.document.as_str()).ok();
+        match (&problem.kind, id) {
+            (ProblemKind::MissingLink { .. }, Some(id)) => match state.links.get(&id) {
+                Some(entity) => {
+                    // several missing links of one document are saved once
+                    if state.resave.insert(entity) {
+                        summary.resaved += 1;
+                    }
+                    false
+                }
+                // e.g. a lazy document which was evicted, it's found again once loaded
+                None => true,
+            },
+            (ProblemKind::Undecodable { .. }, Some(id)) if options.delete_undecodable => {
+                info!(
+                    ?id,
+                    collection = problem.collection,
+                    "deleting undecodable document"
+                );
+                batch.delete_document(problem.collection_name.clone(), id);
+                summary.deleted += 1;
+                false
+            }
+            _ => true,
+        }
+    });
+    summary.remaining = problems.0.len();
+    info!(?summary, "repaired import problems");
+    summary
+}
+
+/// Reads every document (or only `ids`), listing documents which fail to decode or migrate.
+pub(super) fn read_documents<B: BundleSync>(
+    all_storages: &AllStorages,
+    ids: Option<&[HintedID]>,
+) -> Result<Vec<(HintedID, B)>> {
+    all_storages.run(
+        |uv_local_database: UniqueView<LocalDatabase>,
+         mut uvm_report: UniqueViewMut<MigrationReport>,
+         mut uvm_problems: UniqueViewMut<ImportProblems>|
+         -> Result<_> {
+            let db = uv_local_database.get_database();
+            let db = db.as_err_arc_ref()?;
+            let report = uvm_report.as_mut();
+            let failed_before = failed_documents::<B>(report).len();
+            let documents = uv_local_database.sealed(|| match ids {
+                Some(ids) => migrations::read_documents_by_id::<B>(db, ids, report),
+                None => migrations::read_documents::<B>(db, report),
+            })?;
+            let failed = &failed_documents::<B>(report)[failed_before..];
+            if !failed.is_empty() {
+                let problems = uvm_problems.as_mut();
+                for FailedDocument {
+                    id,
+                    error,
+                    unsealable,
+                } in failed
+                {
+                    let error = error.clone();
+                    let kind = if *unsealable {
+                        ProblemKind::Unsealable { error }
+                    } else {
+                        ProblemKind::Undecodable { error }
+                    };
+                    problems.add::<B>(id, kind);
+                }
+            }
+            Ok(documents)
+        },
+    )
+}
+
+fn failed_documents<B: 'static>(report: &MigrationReport) -> &[FailedDocument] {
+    report
+        .collections
+        .iter()
+        .find(|collection| collection.bundle == type_name::<B>())
+        .map_or(&[], |collection| collection.failed.as_slice())
+}
@@ -81,7 +81,7 @@ pub mod _result_ {
 }
 
 pub use hn_hinted_id::HintedID;
-pub use i_hn_app_proc::ecs_bundle;
+pub use i_hn_app_proc::{ecs_bundle, BundleSync};
 
 pub mod _ecs_ {
     //! ECS prelude
@@ -187,3 +187,4 @@ pub mod _ecs_ {
 
 pub mod app_ctx;
 pub mod database_plugin;
+pub mod sealed;
@@ -0,0 +1,277 @@
+//! Sealing sensitive bundle fields at rest.
+//!
+//! Fields marked `#[sensitive]` in an `#[ecs_bundle]` are encrypted with the [SealingKey] of
+//! the database when serialized within [with_key], and decrypted when deserialized. Outside
+//! of it they fail to serialize (unless [redacted]), so they never end up in plain text.
+//! Values saved before a field was marked sensitive are still read as plain values, and get
+//! sealed the next time they're saved.
+//!
+//! ```ignore
+//! #[ecs_bundle(CredTag)]
+//! pub struct EcsDiscordCred {
+//!     #[sensitive]
+//!     pub access_token: String,
+//! }
+//! ```
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::_result_::*;
+use crate::_tracing_::*;
+
+const NONCE_LEN: usize = 24;
+const REDACTED: &str = "«sealed»";
+
+thread_local! {
+    static KEY: RefCell<Option<SealingKey>> = RefCell::new(None);
+    static REDACTING: Cell<bool> = Cell::new(false);
+    static UNSEAL_FAILED: Cell<bool> = Cell::new(false);
+}
+
+/// Database-level key, derived from a secret which never leaves the machine.
+#[derive(Clone)]
+pub struct SealingKey(chacha20poly1305::Key);
+
+impl SealingKey {
+    pub fn derive(secret: &[u8]) -> Self {
+        let mut key = chacha20poly1305::Key::default();
+        hkdf::Hkdf::<sha2::Sha256>::new(None, secret)
+            .expand(b"here-now database sealing v1", &mut key)
+            .expect("32 bytes is a valid length for sha256 hkdf");
+        SealingKey(key)
+    }
+
+    /// Reads the secret at `path`, failing if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        let secret =
+            std::fs::read(path).with_context(|| format!("reading database secret at {path:?}"))?;
+        Self::from_secret(path, &secret)
+    }
+
+    /// Reads the secret at `path`, or generates one if it doesn't exist, yet.
+    ///
+    /// Losing this file means sealed values can no longer be read.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(secret) => Self::from_secret(path, &secret),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let secret: [u8; 32] = rand::random();
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("creating directory for secret at {parent:?}"))?;
+                }
+                write_secret(path, &secret)
+                    .with_context(|| format!("writing database secret to {path:?}"))?;
+                warn!(?path, "generated database secret");
+                Ok(SealingKey::derive(&secret))
+            }
+            Err(err) => Err(err).with_context(|| format!("reading database secret at {path:?}")),
+        }
+    }
+
+    fn from_secret(path: &Path, secret: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            secret.len() >= 32,
+            "expected secret at {path:?} to be 32 bytes"
+        );
+        Ok(SealingKey::derive(secret))
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(&self.0);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            cipher
+                .encrypt(&nonce, plaintext)
+                .map_err(|_| anyhow::anyhow!("sealing value"))?,
+        );
+        Ok(sealed)
+    }
+
+    /// Changes along with the value, without revealing it to anyone without the key.
+    fn fingerprint(&self, plaintext: &[u8]) -> String {
+        use sha2::Digest;
+
+        let hash = sha2::Sha256::new()
+            .chain_update(self.0)
+            .chain_update(plaintext)
+            .finalize();
+        hash[..4].iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        anyhow::ensure!(sealed.len() > NONCE_LEN, "sealed value is too short");
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        XChaCha20Poly1305::new(&self.0)
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("unsealing value, was the database secret changed?"))
+    }
+}
+
+#[cfg(unix)]
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(secret)
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, secret)
+}
+
+/// Sensitive fields serialized or deserialized within `f` are sealed with `key`, e.g. while
+/// BonsaiDB reads and writes documents, see [crate::app_ctx::LocalDatabase::sealed].
+pub fn with_key<R>(key: Option<&SealingKey>, f: impl FnOnce() -> R) -> R {
+    let previous = KEY.with(|current| current.replace(key.cloned()));
+    let result = f();
+    KEY.with(|current| current.replace(previous));
+    result
+}
+
+fn current_key() -> Result<SealingKey> {
+    KEY.with(|current| current.borrow().clone())
+        .context("no sealing key for sensitive fields")
+}
+
+/// Sensitive fields serialized within `f` are replaced with a placeholder, e.g. for display.
+///
+/// Within [with_key], the placeholder includes a fingerprint so changes can be told apart.
+pub fn redacted<R>(f: impl FnOnce() -> R) -> R {
+    let previous = REDACTING.with(|redacting| redacting.replace(true));
+    let result = f();
+    REDACTING.with(|redacting| redacting.set(previous));
+    result
+}
+
+/// Also returns whether a sensitive field deserialized within `f` couldn't be unsealed,
+/// e.g. because there's no key or the database secret changed.
+///
+/// Such documents aren't corrupt, so they shouldn't be treated like undecodable ones.
+pub fn detect_unseal_failure<R>(f: impl FnOnce() -> R) -> (R, bool) {
+    let previous = UNSEAL_FAILED.with(|failed| failed.replace(false));
+    let result = f();
+    let failed = UNSEAL_FAILED.with(|failed| failed.replace(previous));
+    (result, failed)
+}
+
+fn unseal_failed<E: serde::de::Error>(err: impl std::fmt::Display) -> E {
+    UNSEAL_FAILED.with(|failed| failed.set(true));
+    E::custom(err)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Repr<T> {
+    Sealed { sealed: String },
+    Plain(T),
+}
+
+/// Added by `#[sensitive]` as `#[serde(with = "::hn_app::sealed::sensitive")]`
+pub mod sensitive {
+    use serde::{
+        de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer,
+    };
+
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let redacting = REDACTING.with(Cell::get);
+        let key = match current_key() {
+            Ok(key) => key,
+            Err(_) if redacting => return serializer.serialize_str(REDACTED),
+            Err(err) => return Err(S::Error::custom(format!("{err:#}"))),
+        };
+        let plaintext = pot::to_vec(value).map_err(S::Error::custom)?;
+        if redacting {
+            let fingerprint = key.fingerprint(&plaintext);
+            return serializer.serialize_str(&format!("{REDACTED} {fingerprint}"));
+        }
+        let sealed = key
+            .seal(&plaintext)
+            .map_err(|err| S::Error::custom(format!("{err:#}")))?;
+        Repr::<()>::Sealed {
+            sealed: base64::engine::general_purpose::STANDARD.encode(sealed),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: serde::de::DeserializeOwned,
+        D: Deserializer<'de>,
+    {
+        match Repr::<T>::deserialize(deserializer)? {
+            Repr::Plain(value) => Ok(value),
+            Repr::Sealed { sealed } => {
+                let key = current_key().map_err(|err| unseal_failed(format!("{err:#}")))?;
+                let sealed = base64::engine::general_purpose::STANDARD
+                    .decode(sealed)
+                    .map_err(D::Error::custom)?;
+                let plaintext = key
+                    .unseal(&sealed)
+                    .map_err(|err| unseal_failed(format!("{err:#}")))?;
+                pot::from_slice(&plaintext).map_err(D::Error::custom)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sensitive_round_trip() {
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct Cred {
+        #[serde(with = "sensitive")]
+        token: String,
+    }
+
+    let key = SealingKey::derive(b"test secret");
+    let cred = Cred {
+        token: "hunter2".to_string(),
+    };
+    assert!(
+        serde_json::to_string(&cred).is_err(),
+        "never serialized in plain text without a key"
+    );
+    let json = with_key(Some(&key), || serde_json::to_string(&cred).unwrap());
+    assert!(!json.contains("hunter2"));
+    let (unsealed, failed) = detect_unseal_failure(|| serde_json::from_str::<Cred>(&json));
+    assert!(unsealed.is_err() && failed, "can't be read without the key");
+    with_key(Some(&key), || {
+        assert_eq!(serde_json::from_str::<Cred>(&json).unwrap(), cred);
+        // saved before the field was sensitive
+        assert_eq!(
+            serde_json::from_str::<Cred>(r#"{"token":"hunter2"}"#).unwrap(),
+            cred
+        );
+        let redacted_json = redacted(|| serde_json::to_string(&cred).unwrap());
+        assert!(redacted_json.contains(REDACTED) && !redacted_json.contains("hunter2"));
+        assert_eq!(
+            redacted_json,
+            redacted(|| serde_json::to_string(&cred).unwrap()),
+            "fingerprint only changes with the value"
+        );
+    });
+    let other_key = SealingKey::derive(b"other secret");
+    let (unsealed, failed) = detect_unseal_failure(|| {
+        with_key(Some(&other_key), || serde_json::from_str::<Cred>(&json))
+    });
+    assert!(
+        unsealed.is_err() && failed,
+        "keys of other databases don't fit"
+    );
+}
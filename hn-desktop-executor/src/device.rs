@@ -15,7 +15,28 @@ pub(super) async fn _start() {
             label: "desktop".to_string(),
         })
         .await
-        .expect("created device on server");
+        .expect("created device on server")
+        .expect("server accepted device");
 
-    let _ = dbg!(resp);
+    let pairing = client
+        .send(api::StartPairing {
+            device_id: resp.device_id,
+            redirect_uri: None,
+        })
+        .await
+        .expect("started pairing")
+        .expect("server accepted pairing");
+
+    tracing::info!(
+        login_url = %pairing.login_url,
+        code = %pairing.code,
+        "open the login url and check the code matches"
+    );
+
+    let status = client
+        .wait_for_pairing(&pairing.code, std::time::Duration::from_secs(2))
+        .await
+        .expect("waited for pairing");
+
+    tracing::info!(?status, "pairing finished");
 }
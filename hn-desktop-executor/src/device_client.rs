@@ -65,6 +65,28 @@ impl DeviceClient {
 
         Ok(verified.into_data())
     }
+
+    /// Poll the server until the browser log in started from [api::StartPairingResponse::login_url]
+    /// finishes, or the pairing expires.
+    #[tracing::instrument]
+    pub async fn wait_for_pairing(
+        &self,
+        code: &str,
+        poll_every: std::time::Duration,
+    ) -> Result<api::ServerResult<api::CheckPairing>> {
+        loop {
+            let status = self
+                .send(api::CheckPairing {
+                    code: code.to_string(),
+                })
+                .await
+                .context("checking pairing status")?;
+            match status {
+                Ok(api::PairingStatus::Pending) => tokio::time::sleep(poll_every).await,
+                other => return Ok(other),
+            }
+        }
+    }
 }
 
 fn expect_serde_eq<S: serde::Serialize>(a: &S, b: &S) -> Result {
@@ -4,7 +4,7 @@ use hn_app::{
     _ecs_::*,
     _tracing_::*,
     app_ctx::{AppCtxPlugin, Command},
-    database_plugin::LocalDatabasePlugin,
+    database_plugin::{BundleSyncPlugin, LocalDatabasePlugin},
 };
 
 use self::windows_plugin::WindowsPlugin;
@@ -13,8 +13,6 @@ pub struct DevicePlugin(pub tokio::sync::mpsc::UnboundedSender<Command>);
 
 mod data;
 mod ecs;
-mod export_data;
-mod import_data;
 mod windows_plugin;
 
 impl Plugin for DevicePlugin {
@@ -24,14 +22,15 @@ impl Plugin for DevicePlugin {
         builder.add_plugin(AppCtxPlugin(self.0.clone()));
         builder.add_plugin(LocalDatabasePlugin::<data::DBSchema> {
             path: PathBuf::from("./data/desktop-db.bonsaidb"),
+            dry_run_migrations: false,
+            secret_path: PathBuf::from("./data/desktop-db.secret"),
+            audit: Default::default(),
             mark: PhantomData,
         });
         builder.add_plugin(WindowsPlugin::default());
-        builder.add_system(import_data::import_data_from_database_system);
-        builder.add_reset_system(
-            export_data::sync_changes_to_database_system,
-            "synchronize any changed data back into the database",
-        );
+        // profiles first, so servers can link to their profile
+        builder.add_plugin(BundleSyncPlugin::<data::ProfileBundle>::default());
+        builder.add_plugin(BundleSyncPlugin::<data::ProfileServerBundle>::default());
     }
 }
 
@@ -1,20 +1,46 @@
 use std::marker::PhantomData;
 
 use bonsaidb::core::schema;
-use hn_app::{ecs_bundle, HintedID};
+use hn_app::{
+    _ecs_::*,
+    _result_::{AnyhowContext, Result},
+    database_plugin::{
+        migrations::{DocumentValue, DocumentVersion, Migration},
+        BundleField, BundleLinks,
+    },
+    ecs_bundle, BundleSync, HintedID,
+};
+
+use super::ecs;
 
 /// Stored in BonsaiDB
-#[derive(Debug, schema::Collection)]
+#[derive(Debug, schema::Collection, BundleSync)]
 #[collection(name = "client-profiles", primary_key = HintedID)]
+#[bundle_sync(tag = ecs::ProfileTag, version = 1, migrations = profile_migrations)]
 /// Bundled in the ECS
 #[ecs_bundle(ProfileTag)]
 pub struct ProfileBundle {
+    #[bundle_sync(version)]
+    #[serde(rename = "_v", default)]
+    pub version: DocumentVersion,
     /// Maps to [super::ecs::UserLabel]
+    #[bundle_sync(component = ecs::UserLabel)]
     pub c_label: Option<String>,
     /// Maps to [super::ecs::ProfileKeys]
+    #[bundle_sync(component = ecs::ProfileKeys)]
+    #[sensitive]
     pub c_keys: hn_keys::LocalKeys,
 }
 
+fn profile_migrations() -> Vec<Migration> {
+    vec![Migration {
+        from_version: 0,
+        description: "seal profile keys which were saved in plain text",
+        // plain values are still read, and saving the migrated document seals them
+        migrate: |_doc: &mut DocumentValue| Ok(()),
+    }]
+}
+
 #[ecs_bundle]
 #[derive(Debug)]
 pub struct BundleRef<Bundle: 'static> {
@@ -33,19 +59,27 @@ impl<Bundle: 'static> BundleRef<Bundle> {
 }
 
 /// Stored in BonsaiDB
-#[derive(Debug, schema::Collection)]
+#[derive(Debug, schema::Collection, BundleSync)]
 #[collection(name = "client-servers", primary_key = HintedID)]
+#[bundle_sync(tag = ecs::PServerTag)]
 /// Bundled in the ECS
 #[ecs_bundle(PServerTag)]
 pub struct ProfileServerBundle {
+    #[bundle_sync(version)]
+    #[serde(rename = "_v", default)]
+    pub version: DocumentVersion,
     /// Maps to [super::ecs::UserLabel]
+    #[bundle_sync(component = ecs::UserLabel)]
     pub c_label: Option<String>,
     /// Maps to [super::ecs::PServerSettingURL]
+    #[bundle_sync(component = ecs::PServerSettingURL)]
     pub c_url: Option<String>,
     /// Maps to [super::ecs::PServerPublicKey]
+    #[bundle_sync(component = ecs::PServerPublicKey)]
     pub c_server_key: Option<ServerPublicKey>,
     /// Maps to [super::ecs::PServerAssocProfile]
-    pub c_assoc_profile: BundleRef<ProfileServerBundle>,
+    #[bundle_sync(component = ecs::PServerAssocProfile)]
+    pub c_assoc_profile: BundleRef<ProfileBundle>,
 }
 
 /// See [super::ecs::PServerPublicKey]
@@ -60,3 +94,71 @@ pub struct ServerPublicKey {
 #[derive(schema::Schema)]
 #[schema(name = "DesktopDBSchema", collections = [ProfileBundle, ProfileServerBundle])]
 pub struct DBSchema;
+
+impl BundleField<ecs::UserLabel> for Option<String> {
+    fn into_component(self, _links: &BundleLinks) -> Result<ecs::UserLabel> {
+        Ok(ecs::UserLabel(self))
+    }
+
+    fn from_component(component: &ecs::UserLabel, _v_hinted_id: &View<HintedID>) -> Result<Self> {
+        Ok(component.0.clone())
+    }
+}
+
+impl BundleField<ecs::ProfileKeys> for hn_keys::LocalKeys {
+    fn into_component(self, _links: &BundleLinks) -> Result<ecs::ProfileKeys> {
+        Ok(ecs::ProfileKeys(self))
+    }
+
+    fn from_component(component: &ecs::ProfileKeys, _v_hinted_id: &View<HintedID>) -> Result<Self> {
+        Ok(component.0.clone())
+    }
+}
+
+impl BundleField<ecs::PServerSettingURL> for Option<String> {
+    fn into_component(self, _links: &BundleLinks) -> Result<ecs::PServerSettingURL> {
+        Ok(ecs::PServerSettingURL(self))
+    }
+
+    fn from_component(
+        component: &ecs::PServerSettingURL,
+        _v_hinted_id: &View<HintedID>,
+    ) -> Result<Self> {
+        Ok(component.0.clone())
+    }
+}
+
+impl BundleField<ecs::PServerPublicKey> for Option<ServerPublicKey> {
+    fn into_component(self, _links: &BundleLinks) -> Result<ecs::PServerPublicKey> {
+        Ok(ecs::PServerPublicKey(
+            self.as_setup_err("Server key not loaded, yet."),
+        ))
+    }
+
+    fn from_component(
+        component: &ecs::PServerPublicKey,
+        _v_hinted_id: &View<HintedID>,
+    ) -> Result<Self> {
+        Ok(component.0.as_ref().ok().cloned())
+    }
+}
+
+impl BundleField<ecs::PServerAssocProfile> for BundleRef<ProfileBundle> {
+    fn into_component(self, links: &BundleLinks) -> Result<ecs::PServerAssocProfile> {
+        links
+            .get(&self.id)
+            .map(ecs::PServerAssocProfile)
+            .with_context(|| format!("Assoc profile not inserted ({:?})", self.id))
+    }
+
+    fn from_component(
+        component: &ecs::PServerAssocProfile,
+        v_hinted_id: &View<HintedID>,
+    ) -> Result<Self> {
+        let id = v_hinted_id
+            .get(component.0)
+            .map_err(|err| anyhow::anyhow!("{err:?}"))
+            .context("Failed to find associated profile for server")?;
+        Ok(BundleRef::new(id.clone()))
+    }
+}
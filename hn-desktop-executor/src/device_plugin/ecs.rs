@@ -21,3 +21,10 @@ pub struct PServerSettingURL(pub Option<String>);
 
 #[ecs_component(PServerTag)]
 pub struct PServerPublicKey(pub SetupResult<data::ServerPublicKey>);
+
+pub(crate) type VPServer<'a> = (
+    View<'a, PServerTag>,
+    View<'a, PServerAssocProfile>,
+    View<'a, PServerSettingURL>,
+    View<'a, PServerPublicKey>,
+);
@@ -1,6 +1,6 @@
 use hn_app::{HintedID, _ecs_::*, _tracing_::*};
 
-use super::{ecs, export_data, Messages};
+use super::{ecs, Messages};
 
 #[ecs_unique]
 pub struct UIWindowState {
@@ -48,7 +48,7 @@ fn handle_open_messages(
     mut uvm_ui_messages: UniqueViewMut<Messages<ui::ToUI>>,
     v_uid: View<HintedID>,
     v_label: View<ecs::UserLabel>,
-    v_pserver: export_data::VPServer,
+    v_pserver: ecs::VPServer,
 ) {
     warn!("handle open settings {:?}", uvm_executor_messages.0);
     if !uvm_executor_messages.is_inserted_or_modified() {
@@ -39,6 +39,16 @@ impl std::fmt::Debug for PublicKeyKind {
     }
 }
 
+impl PartialEq for PublicKeyKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::X25519HkdfSha256(a), Self::X25519HkdfSha256(b)) => a.to_bytes() == b.to_bytes(),
+        }
+    }
+}
+
+impl Eq for PublicKeyKind {}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub enum PrivateKeyKind {
     #[serde(serialize_with = "serde_ser_key", deserialize_with = "serde_des_key")]
@@ -7,9 +7,12 @@ pub enum ToServer {
     Ping(Ping),
     CreateDevice(create_device::CreateDevice),
     Device(create_device::CreateDevice),
+    StartPairing(pairing::StartPairing),
+    CheckPairing(pairing::CheckPairing),
 }
 
 pub use create_device::{CreateDevice, CreateDeviceResponse};
+pub use pairing::{CheckPairing, PairingStatus, StartPairing, StartPairingResponse};
 
 pub type ServerResult<M> = Result<<M as Mutation>::Success, ServerRejection>;
 
@@ -103,11 +106,73 @@ mod create_device {
     }
 }
 
+mod pairing {
+    //! Handoff between the desktop app and a browser login.
+    //!
+    //! 1. The desktop app creates its device with [CreateDevice].
+    //! 2. The desktop app calls [StartPairing] and opens the returned `login_url` in a browser.
+    //! 3. The browser completes the OAuth flow, which links credentials to the device.
+    //! 4. The desktop app polls [CheckPairing] until it's no longer [PairingStatus::Pending],
+    //!    or it is woken up by the browser being redirected to its `redirect_uri`.
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct StartPairing {
+        /// From [CreateDeviceResponse], the sender must be an authorized key of this device.
+        pub device_id: String,
+        /// Where the browser is sent after credentials are linked.
+        /// Must be a custom URL scheme (e.g. `here-now://paired`) or a loopback
+        /// listener (e.g. `http://127.0.0.1:51234/paired`).
+        pub redirect_uri: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct StartPairingResponse {
+        /// Short code for the user to compare between the desktop app and the browser.
+        pub code: String,
+        /// Open this in the browser to begin log in.
+        pub login_url: String,
+        pub expires_in: std::time::Duration,
+    }
+
+    impl Mutation for StartPairing {
+        type Success = StartPairingResponse;
+        fn into_request(self) -> ToServer {
+            ToServer::StartPairing(self)
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct CheckPairing {
+        /// From [StartPairingResponse]
+        pub code: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    pub enum PairingStatus {
+        /// Browser has not finished logging in, yet.
+        Pending,
+        /// Credentials were linked to the device.
+        Linked { device_id: String, cred_count: usize },
+        /// Pairing code expired before log in completed.
+        Expired,
+    }
+
+    impl Mutation for CheckPairing {
+        type Success = PairingStatus;
+        fn into_request(self) -> ToServer {
+            ToServer::CheckPairing(self)
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerRejection {
     InternalError(String),
     BadRequest(String),
     Unauthorized(String),
+    /// Sent with a `429` status when the client or sender key is over its rate limit.
+    TooManyRequests { retry_after: std::time::Duration },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
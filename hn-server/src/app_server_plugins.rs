@@ -1,13 +1,18 @@
-use std::time::Duration;
-
-use crate::prelude::*;
-use hn_app::_ecs_::*;
+use crate::{config_plugins::TypedConfig, prelude::*};
+use hn_app::{_ecs_::*, app_ctx::LocalDatabase};
 
 mod app_server_config_plugin;
+mod diagnostics;
 mod discord;
 mod public_server;
+mod rebind;
 
-pub use app_server_config_plugin::PublicServerBaseURL;
+pub use app_server_config_plugin::{AppServerConfigFile, AppServerSettings, PublicServerBaseURL};
+pub use diagnostics::ConfigDiagnostics;
+pub(crate) use discord::refresh_discord_token;
+pub use discord::{DiscordClientID, DiscordClientSecret, DiscordSettings};
+pub use public_server::rate_limit::RateLimitSettings;
+pub use rebind::PublicServerStatus;
 
 #[derive(Default)]
 pub struct AppServerPlugin {
@@ -22,8 +27,22 @@ impl Plugin for AppServerPlugin {
         app.add_plugin(discord::DiscordSettingsPlugin::default());
         app.add_unique(PublicServer {
             current_handle: None,
+            generation: 0,
+            current_tls: None,
+            serving_with: None,
+            rate_limiter: Default::default(),
+            metrics: Default::default(),
         });
+        app.add_tracked_value(public_server::health::ServerReadiness::default());
+        app.add_tracked_value(PublicServerStatus::default());
+        app.add_tracked_value(ConfigDiagnostics::default());
+        app.depends_on_unique::<LocalDatabase>("to report readiness once the database is open");
         app.add_system(maintain_public_server_system);
+        app.add_system(update_rate_limits_system);
+        app.add_system(update_metrics_exposure_system);
+        app.add_system(public_server::pairing::expire_pairing_sessions_system);
+        app.add_system(index_readiness_system);
+        app.add_system(diagnostics::index_config_diagnostics_system);
         info!("Setting up app server plugin");
     }
 }
@@ -33,49 +52,358 @@ impl Plugin for AppServerPlugin {
 struct PublicServer {
     // handle?
     current_handle: Option<axum_server::Handle>,
+    /// Incremented for each (re)start, so stale binds are dropped.
+    generation: usize,
+    /// Set while serving over TLS, so new certificates can be swapped in without a restart.
+    current_tls: Option<crate::tls::ReloadableTls>,
+    /// What the current server was started with, to start it again if it had to stop
+    /// before a rebind which then failed.
+    serving_with: Option<(std::net::SocketAddr, Option<crate::tls::TlsPems>)>,
+    /// Kept across restarts of the server, so clients can't reset their limits.
+    rate_limiter: Arc<public_server::rate_limit::RateLimiter>,
+    /// Kept across restarts of the server, so counters don't reset.
+    metrics: Arc<public_server::health::Metrics>,
 }
 
 #[tracing::instrument(skip_all)]
 fn maintain_public_server_system(
     uv_app_ctx: UniqueView<AppCtx>,
     uv_public_bind_address: UniqueView<app_server_config_plugin::PublicServerBindAddress>,
+    uv_public_tls: UniqueView<app_server_config_plugin::PublicServerTls>,
     mut uvm_public_server: UniqueViewMut<PublicServer>,
+    mut uvm_status: UniqueViewMut<PublicServerStatus>,
 ) {
-    if uv_public_bind_address.is_inserted_or_modified() {
-        if uvm_public_server.current_handle.is_some() {
-            info!("Shutting down previous server gracefully");
-            let current = uvm_public_server.current_handle.take().unwrap();
-            // Hmmm: If you replace the addr from `0.0.0.0:80` to `127.0.0.1:80`, will the port conflict?
-            current.graceful_shutdown(Some(Duration::from_secs(20)));
+    if uv_public_tls.is_inserted_or_modified() && !uv_public_bind_address.is_inserted_or_modified()
+    {
+        match (
+            uvm_public_server.current_tls.clone(),
+            uv_public_tls.0.as_ref(),
+        ) {
+            (Some(reloadable), Ok(Some(pems))) => {
+                info!("reloading public server tls certificate");
+                let pems = pems.clone();
+                uv_app_ctx.spawn(async move { reloadable.load(&pems).await.map(|_| ()) });
+                return;
+            }
+            (Some(_), Err(err)) => {
+                // e.g. the certificate is in the middle of being replaced
+                error!(
+                    ?err,
+                    "invalid tls certificate, keeping the previous certificate"
+                );
+                return;
+            }
+            // turning tls on or off requires a restart
+            _ => {}
         }
-        // shutdown gradefully if running
-        let listener = uv_public_bind_address
-            .as_ref()
+    }
+
+    if uv_public_bind_address.is_inserted_or_modified() || uv_public_tls.is_inserted_or_modified() {
+        // any bind still retrying for a previous address is now stale
+        uvm_public_server.generation += 1;
+        let generation = uvm_public_server.generation;
+
+        let start_with = uv_public_bind_address
             .0
-            .as_ref()
-            .as_ref()
-            .map_err(|err| anyhow::anyhow!("{err:?}"))
+            .as_err_arc_ref()
+            .cloned()
             .and_then(|addr| {
-                // Hmmm: If you replace the addr from `0.0.0.0:80` to `127.0.0.1:80`, will the port conflict?
-                Ok((
-                    std::net::TcpListener::bind(addr).context("starting public server listener")?,
-                    addr,
-                ))
+                let tls = uv_public_tls
+                    .0
+                    .as_err_arc_ref()
+                    .cloned()
+                    .context("tls is enabled, but the certificate is not available")?;
+                Ok((addr, tls))
             });
 
-        let updated = match listener {
-            Ok((listener, addr)) => Some(public_server::start_server_from_tcp_listener(
-                listener,
-                addr,
-                uv_app_ctx.clone(),
-            )),
+        let (addr, tls) = match start_with {
+            Ok(start_with) => start_with,
             Err(err) => {
-                // need to be able to surface this somehow?
-                error!(?err, "failed to start public server");
-                None
+                error!(?err, "not (re)starting public server");
+                let status = uvm_status.as_mut();
+                status.pending = None;
+                status.last_error = Some(format!("{err:#}"));
+                return;
             }
         };
 
-        uvm_public_server.current_handle = updated;
+        let mut restore = None;
+        {
+            let status = uvm_status.as_mut();
+            let same_port = status
+                .serving
+                .as_ref()
+                .map_or(false, |serving| serving.addr.port() == addr.port());
+            // without a shared port, the previous server has to stop accepting first
+            if same_port && !rebind::SHARES_PORTS {
+                if let Some(previous) = uvm_public_server.current_handle.take() {
+                    info!(
+                        ?addr,
+                        "draining previous public server before rebinding its port"
+                    );
+                    previous.graceful_shutdown(Some(rebind::DRAIN_TIMEOUT));
+                    status.serving = None;
+                    restore = uvm_public_server.serving_with.take();
+                }
+            }
+            status.pending = Some(rebind::PendingBind { addr, attempt: 0 });
+            status.last_error = None;
+        }
+
+        spawn_public_server_start(&uv_app_ctx, generation, addr, tls, restore, None);
     }
 }
+
+/// Binds `addr` and swaps the new server in, unless a newer (re)start began meanwhile.
+///
+/// When binding fails, the stopped previous server in `restore` is started again, and
+/// `failed` is the error to keep reporting once it's serving.
+fn spawn_public_server_start(
+    app_ctx: &AppCtx,
+    generation: usize,
+    addr: std::net::SocketAddr,
+    tls: Option<crate::tls::TlsPems>,
+    restore: Option<(std::net::SocketAddr, Option<crate::tls::TlsPems>)>,
+    failed: Option<String>,
+) {
+    let spawn_ctx = app_ctx.clone();
+    app_ctx.spawn(async move {
+        let app_ctx = spawn_ctx;
+        let listener_res = rebind::bind_with_backoff(addr, &app_ctx, generation).await;
+        let start_with = std::sync::Mutex::new(Some((listener_res, tls, restore, failed)));
+        app_ctx.run_system(
+            "swap public server",
+            move |uv_app_ctx: UniqueView<AppCtx>,
+                  mut uvm_public_server: UniqueViewMut<PublicServer>,
+                  mut uvm_status: UniqueViewMut<PublicServerStatus>| {
+                let Some((listener_res, tls, restore, failed)) = start_with.lock().unwrap().take()
+                else {
+                    return;
+                };
+                if uvm_public_server.generation != generation {
+                    debug!(?addr, "dropping stale public server listener");
+                    return;
+                }
+
+                let listener = match listener_res {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        let serving = uvm_status.serving.clone();
+                        error!(?err, ?addr, ?serving, "failed to bind public server");
+                        let status = uvm_status.as_mut();
+                        status.pending = None;
+                        status.last_error = Some(format!("{err:#}"));
+                        if let Some((previous_addr, previous_tls)) = restore {
+                            warn!(?previous_addr, "starting the previous public server again");
+                            spawn_public_server_start(
+                                &uv_app_ctx,
+                                generation,
+                                previous_addr,
+                                previous_tls,
+                                None,
+                                Some(format!("{err:#}")),
+                            );
+                        }
+                        return;
+                    }
+                };
+
+                let public_server = uvm_public_server.as_mut();
+                let current_tls = tls.as_ref().map(|_| crate::tls::ReloadableTls::default());
+                let handle = public_server::start_server_from_tcp_listener(
+                    listener,
+                    &addr,
+                    uv_app_ctx.clone(),
+                    public_server.rate_limiter.clone(),
+                    public_server.metrics.clone(),
+                    tls.clone().zip(current_tls.clone()),
+                );
+
+                // only drain the previous server once the new one is accepting
+                if let Some(previous) = public_server.current_handle.replace(handle) {
+                    info!("draining previous public server");
+                    previous.graceful_shutdown(Some(rebind::DRAIN_TIMEOUT));
+                }
+
+                *uvm_status.as_mut() = PublicServerStatus {
+                    serving: Some(rebind::ServingStatus {
+                        addr,
+                        tls: current_tls.is_some(),
+                    }),
+                    pending: None,
+                    last_error: failed,
+                };
+                public_server.current_tls = current_tls;
+                public_server.serving_with = Some((addr, tls));
+            },
+        );
+        Ok(())
+    });
+}
+
+#[tracing::instrument(skip_all)]
+fn update_rate_limits_system(
+    uv_rate_limits: UniqueView<app_server_config_plugin::PublicServerRateLimits>,
+    uv_public_server: UniqueView<PublicServer>,
+) {
+    if uv_rate_limits.is_inserted_or_modified() {
+        match uv_rate_limits.0.as_ref() {
+            Ok(settings) => {
+                info!(?settings, "updating rate limits");
+                uv_public_server.rate_limiter.set_settings(settings.clone());
+            }
+            Err(err) => {
+                error!(?err, "invalid rate limit settings, keeping previous limits");
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+fn update_metrics_exposure_system(
+    uv_app_config: UniqueView<TypedConfig<AppServerSettings>>,
+    uv_public_server: UniqueView<PublicServer>,
+) {
+    if uv_app_config.is_inserted_or_modified() {
+        if let Ok(public_metrics) = uv_app_config.get(|settings| settings.public_metrics) {
+            info!(public_metrics, "updating metrics exposure");
+            uv_public_server.metrics.set_exposed(public_metrics);
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+fn index_readiness_system(
+    uv_local_database: UniqueView<LocalDatabase>,
+    uv_app_config: UniqueView<TypedConfig<AppServerSettings>>,
+    uv_discord_config: UniqueView<TypedConfig<discord::DiscordSettings>>,
+    mut uvm_readiness: UniqueViewMut<public_server::health::ServerReadiness>,
+) {
+    if uv_local_database.is_inserted_or_modified()
+        || uv_app_config.is_inserted_or_modified()
+        || uv_discord_config.is_inserted_or_modified()
+    {
+        fn config_loaded<T: Send + Sync + 'static>(content: &TypedConfig<T>) -> Result<(), String> {
+            match content.0.as_ref() {
+                Ok(_) => Ok(()),
+                Err(err) => Err(format!("{err}")),
+            }
+        }
+
+        let readiness = uvm_readiness.as_mut();
+        readiness.database = uv_local_database
+            .as_ref()
+            .as_ref()
+            .map(|_| ())
+            .map_err(|err| format!("{err:#}"));
+        readiness.app_config = config_loaded(&uv_app_config);
+        readiness.discord_config = config_loaded(&uv_discord_config);
+        info!(
+            ready = readiness.is_ready(),
+            ?readiness,
+            "updated readiness"
+        );
+    }
+}
+
+#[cfg(test)]
+struct TestPublicServerPlugin {
+    sender: hn_app::app_ctx::CommandSender,
+    addr: std::net::SocketAddr,
+}
+
+#[cfg(test)]
+impl Plugin for TestPublicServerPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(hn_app::app_ctx::AppCtxPlugin(self.sender.clone()));
+        app.add_tracked_value(app_server_config_plugin::PublicServerBindAddress(Arc::new(
+            Ok(self.addr),
+        )));
+        app.add_tracked_value(app_server_config_plugin::PublicServerTls(Arc::new(Ok(
+            None,
+        ))));
+        app.add_unique(PublicServer {
+            current_handle: None,
+            generation: 0,
+            current_tls: None,
+            serving_with: None,
+            rate_limiter: Default::default(),
+            metrics: Default::default(),
+        });
+        app.add_tracked_value(PublicServerStatus::default());
+        app.add_system(maintain_public_server_system);
+    }
+}
+
+/// Polls the status until `done` holds for it.
+#[cfg(test)]
+async fn wait_for_status(
+    app_ctx: &AppCtx,
+    done: impl Fn(&PublicServerStatus) -> bool,
+) -> PublicServerStatus {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        let (tx, rx) = tokio::sync::oneshot::channel::<PublicServerStatus>();
+        let tx = std::sync::Mutex::new(Some(tx));
+        app_ctx.run_system(
+            "read public server status",
+            move |uv_status: UniqueView<PublicServerStatus>| {
+                if let Some(tx) = tx.lock().unwrap().take() {
+                    let _ = tx.send(uv_status.clone());
+                }
+            },
+        );
+        let status = rx.await.unwrap();
+        if done(&status) {
+            return status;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "timed out with {status:?}"
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_failed_rebind_keeps_previous_server() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    let (sender, recv) = tokio::sync::mpsc::unbounded_channel();
+    let mut app = shipyard_app::App::new();
+    let workload = app.add_plugin_workload(TestPublicServerPlugin { sender, addr });
+    let app_ctx = app
+        .world
+        .run(|uv_app_ctx: UniqueView<AppCtx>| uv_app_ctx.clone());
+    tokio::spawn(hn_app::app_ctx::start_loop(
+        app,
+        workload,
+        recv,
+        |_: &shipyard_app::App| {},
+    ));
+    wait_for_status(&app_ctx, |status| status.serving.is_some()).await;
+
+    // same port, on an address (TEST-NET-1) which isn't ours
+    let unavailable = std::net::SocketAddr::from(([192, 0, 2, 1], port));
+    app_ctx.run_system(
+        "change public bind address",
+        move |mut uvm_bind_address: UniqueViewMut<
+            app_server_config_plugin::PublicServerBindAddress,
+        >| {
+            uvm_bind_address.as_mut().0 = Arc::new(Ok(unavailable));
+        },
+    );
+    let status = wait_for_status(&app_ctx, |status| {
+        // without shared ports, the previous server is started again first
+        status.serving.is_some() && status.pending.is_none() && status.last_error.is_some()
+    })
+    .await;
+    assert_eq!(status.serving.map(|serving| serving.addr), Some(addr));
+    tokio::net::TcpStream::connect(addr)
+        .await
+        .expect("the previous server is still accepting");
+}
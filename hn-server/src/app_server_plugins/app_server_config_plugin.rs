@@ -1,9 +1,10 @@
+use super::public_server::rate_limit::RateLimitSettings;
 use crate::{
     config_plugins::{self, ReadConfigFile},
     prelude::*,
+    tls::{self, TlsSettings},
 };
 use hn_app::_ecs_::*;
-use std::str::FromStr;
 
 #[derive(Default)]
 pub struct AppServerConfigPlugin {
@@ -22,30 +23,42 @@ impl Plugin for AppServerConfigPlugin {
                 .map(|socket| format!("http://{socket}"))
                 .context("default socket address not configured"),
         )));
-        app.add_plugin(config_plugins::ConfigFilePlugin(AppServerConfigFile(())));
+        app.add_tracked_value(PublicServerRateLimits(Arc::new(Ok(
+            RateLimitSettings::default(),
+        ))));
+        app.add_tracked_value(PublicServerTlsSettings(Arc::new(Ok(TlsSettings::Off))));
+        app.add_tracked_value(PublicServerTls(Arc::new(Ok(None))));
+        app.add_plugin(
+            config_plugins::TypedConfigFilePlugin::<AppServerSettings>::new("here-now-app.toml"),
+        );
+        app.add_plugin(config_plugins::ConfigFilePlugin(tls::TlsCertFile {
+            relative_path: tls::SELF_SIGNED_CERT_PATH.to_string(),
+        }));
+        app.add_plugin(config_plugins::ConfigFilePlugin(tls::TlsKeyFile {
+            relative_path: tls::SELF_SIGNED_KEY_PATH.to_string(),
+        }));
         app.add_system(index_bind_address_system);
+        app.add_system(index_tls_files_system);
+        app.add_system(index_tls_system);
     }
 }
 
-#[derive(Component, Clone)]
-#[track(All)]
-pub struct AppServerConfigFile(());
-
-impl ReadConfigFile for AppServerConfigFile {
-    type Content = toml_edit::Document;
-    type Error = anyhow::Error;
-
-    fn relative_path(&self) -> &str {
-        "here-now-app.toml"
-    }
-
-    fn load(&self, bytes: &[u8]) -> Result<Self::Content, Self::Error> {
-        let str = String::from_utf8(bytes.to_vec()).with_context(|| "loading toml config")?;
-        let doc = toml_edit::Document::from_str(&str).with_context(|| "parsing toml as toml")?;
-        Ok(doc)
-    }
+/// `here-now-app.toml`, the other plugins only read the parts they need from it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AppServerSettings {
+    pub public_host_base_url: String,
+    pub public_bind_address: std::net::SocketAddr,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub tls: TlsSettings,
+    /// Serve `/metrics` on the public server, off by default since it's unauthenticated.
+    #[serde(default)]
+    pub public_metrics: bool,
 }
 
+pub type AppServerConfigFile = config_plugins::TomlConfigFile<AppServerSettings>;
+
 /// Unique
 #[derive(Component)]
 #[track(All)]
@@ -56,44 +69,35 @@ pub struct PublicServerBindAddress(pub ArcResult<std::net::SocketAddr>);
 #[track(All)]
 pub struct PublicServerBaseURL(pub ArcResult<String>);
 
+/// Unique
+#[derive(Component)]
+#[track(All)]
+pub struct PublicServerRateLimits(pub ArcResult<RateLimitSettings>);
+
+/// Unique
+#[derive(Component)]
+#[track(All)]
+pub struct PublicServerTlsSettings(pub ArcResult<TlsSettings>);
+
+/// Unique, the certificate the public server should serve with, `Ok(None)` when TLS is off.
+#[derive(Component)]
+#[track(All)]
+pub struct PublicServerTls(pub ArcResult<Option<tls::TlsPems>>);
+
 #[tracing::instrument(skip_all)]
 fn index_bind_address_system(
-    uv_config: UniqueView<config_plugins::ConfigFileContent<AppServerConfigFile>>,
+    uv_settings: UniqueView<config_plugins::TypedConfig<AppServerSettings>>,
     mut uvm_public_bind_address: UniqueViewMut<PublicServerBindAddress>,
     mut uvm_public_base_url: UniqueViewMut<PublicServerBaseURL>,
+    mut uvm_rate_limits: UniqueViewMut<PublicServerRateLimits>,
+    mut uvm_tls_settings: UniqueViewMut<PublicServerTlsSettings>,
 ) {
-    if uv_config.is_inserted_or_modified() {
-        let new_host_base_url_res = uv_config
-            .get_content()
-            .context("expected config to have content")
-            .and_then(|inner| inner.content.as_err_arc_ref())
-            .and_then(|doc| {
-                doc.get("public_host_base_url")
-                    .context("Toml has public_host_base_url key defined")
-            })
-            .and_then(|item| {
-                item.as_str()
-                    .context("expected public_host_base_url to be a string")
-            })
-            .map(String::from);
-
-        let new_bind_address_res = uv_config
-            .get_content()
-            .context("expected config to have content")
-            .and_then(|inner| inner.content.as_err_arc_ref())
-            .and_then(|doc| {
-                doc.get("public_bind_address")
-                    .context("Toml has public_bind_address key defined")
-            })
-            .and_then(|item| {
-                item.as_str()
-                    .context("expected public_bind_address to be a string")
-            })
-            .and_then(|str| {
-                str.parse::<std::net::SocketAddr>()
-                    .context("expected public_bind_address to be parseable as a socket address")
-            })
-            .map_err(|e| anyhow::anyhow!("{e:?}"));
+    if uv_settings.is_inserted_or_modified() {
+        let new_bind_address_res = uv_settings.get(|settings| settings.public_bind_address);
+        let new_host_base_url_res =
+            uv_settings.get(|settings| settings.public_host_base_url.clone());
+        let new_rate_limits_res = uv_settings.get(|settings| settings.rate_limit.clone());
+        let new_tls_settings_res = uv_settings.get(|settings| settings.tls.clone());
 
         if uvm_public_bind_address.0.as_ref().as_ref().ok() != new_bind_address_res.as_ref().ok() {
             // as_mut marks it for modified
@@ -104,5 +108,88 @@ fn index_bind_address_system(
             // as_mut marks it for modified
             uvm_public_base_url.as_mut().0 = Arc::new(new_host_base_url_res);
         }
+
+        if uvm_rate_limits.0.as_ref().as_ref().ok() != new_rate_limits_res.as_ref().ok() {
+            // as_mut marks it for modified
+            uvm_rate_limits.as_mut().0 = Arc::new(new_rate_limits_res);
+        }
+
+        if uvm_tls_settings.0.as_ref().as_ref().ok() != new_tls_settings_res.as_ref().ok() {
+            // as_mut marks it for modified
+            uvm_tls_settings.as_mut().0 = Arc::new(new_tls_settings_res);
+        }
+    }
+}
+
+/// Points the watched certificate files at the configured paths.
+#[tracing::instrument(skip_all)]
+fn index_tls_files_system(
+    uv_dir: UniqueView<config_plugins::ConfigFilesDirectory>,
+    uv_tls_settings: UniqueView<PublicServerTlsSettings>,
+    mut uvm_cert_file: UniqueViewMut<tls::TlsCertFile>,
+    mut uvm_key_file: UniqueViewMut<tls::TlsKeyFile>,
+) {
+    if uv_dir.is_inserted_or_modified() || uv_tls_settings.is_inserted_or_modified() {
+        let Ok(settings) = uv_tls_settings.0.as_ref() else {
+            return;
+        };
+
+        if let Some(ref dir) = uv_dir.path {
+            if let Err(err) = settings.prepare(dir) {
+                error!(?err, "failed to prepare tls certificate");
+            }
+        }
+
+        if let Some((cert_path, key_path)) = settings.pem_paths() {
+            if uvm_cert_file.relative_path != cert_path {
+                uvm_cert_file.as_mut().relative_path = cert_path.to_string();
+            }
+            if uvm_key_file.relative_path != key_path {
+                uvm_key_file.as_mut().relative_path = key_path.to_string();
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+fn index_tls_system(
+    uv_tls_settings: UniqueView<PublicServerTlsSettings>,
+    uv_cert: UniqueView<config_plugins::ConfigFileContent<tls::TlsCertFile>>,
+    uv_key: UniqueView<config_plugins::ConfigFileContent<tls::TlsKeyFile>>,
+    mut uvm_tls: UniqueViewMut<PublicServerTls>,
+) {
+    if uv_tls_settings.is_inserted_or_modified()
+        || uv_cert.is_inserted_or_modified()
+        || uv_key.is_inserted_or_modified()
+    {
+        fn pem_content<C: ReadConfigFile<Content = Vec<u8>, Error = anyhow::Error>>(
+            content: &config_plugins::ConfigFileContent<C>,
+        ) -> Result<Vec<u8>> {
+            content
+                .get_content()
+                .context("expected pem file to be loaded")
+                .and_then(|inner| inner.content.as_err_arc_ref().cloned())
+        }
+
+        let new_tls_res =
+            uv_tls_settings
+                .0
+                .as_err_arc_ref()
+                .and_then(|settings| match settings.pem_paths() {
+                    None => Ok(None),
+                    Some(_) => Ok(Some(tls::TlsPems {
+                        cert: pem_content(&uv_cert).context("tls certificate")?,
+                        key: pem_content(&uv_key).context("tls private key")?,
+                    })),
+                });
+
+        if let Err(ref err) = new_tls_res {
+            warn!(?err, "tls is not available");
+        }
+
+        if uvm_tls.0.as_ref().as_ref().ok() != new_tls_res.as_ref().ok() {
+            // as_mut marks it for modified
+            uvm_tls.as_mut().0 = Arc::new(new_tls_res);
+        }
     }
 }
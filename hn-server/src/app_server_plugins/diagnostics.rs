@@ -0,0 +1,254 @@
+//! Problems with the configuration which would otherwise only be logged, shown next to their
+//! section on the config server's home page and at `/;diagnostics`.
+use hn_app::{_ecs_::*, app_ctx::LocalDatabase};
+
+use super::{app_server_config_plugin, discord, AppServerSettings, PublicServerStatus};
+use crate::{
+    config_plugins::{TypedConfig, TypedConfigError},
+    prelude::{bonsai_::local, *},
+};
+
+const APP_SECTION: &str = "here-now-app";
+const DISCORD_SECTION: &str = "discord";
+
+/// Unique, rebuilt whenever one of its sources changes.
+#[derive(Component, Clone, Debug, Default, PartialEq, Serialize)]
+#[track(All)]
+pub struct ConfigDiagnostics(pub Vec<ConfigDiagnostic>);
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigDiagnostic {
+    /// Config file name without `.toml`, matching the config server's sections
+    pub section: &'static str,
+    /// Dotted key within the file, if the problem is with one setting
+    pub field: Option<String>,
+    pub kind: DiagnosticKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticKind {
+    /// The file couldn't be read, parsed, or a value is invalid
+    LoadError,
+    MissingSetting,
+    BindFailure,
+    TlsUnavailable,
+    DatabaseUnavailable,
+}
+
+impl ConfigDiagnostics {
+    pub fn for_section<'a>(
+        &'a self,
+        section: &'a str,
+    ) -> impl Iterator<Item = &'a ConfigDiagnostic> + 'a {
+        self.0.iter().filter(move |diag| diag.section == section)
+    }
+
+    fn push(
+        &mut self,
+        section: &'static str,
+        field: Option<&str>,
+        kind: DiagnosticKind,
+        message: String,
+    ) {
+        self.0.push(ConfigDiagnostic {
+            section,
+            field: field.map(String::from),
+            kind,
+            message,
+        });
+    }
+
+    fn push_typed<T: Send + Sync + 'static>(
+        &mut self,
+        section: &'static str,
+        config: &TypedConfig<T>,
+    ) {
+        let Err(err) = config.0.as_ref() else {
+            return;
+        };
+        let TypedConfigError { field, message } = err;
+        // serde reports missing fields on the table which is missing them
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(name, _)| name);
+        match (missing, field) {
+            (Some(name), Some(table)) => self.push(
+                section,
+                Some(&format!("{table}.{name}")),
+                DiagnosticKind::MissingSetting,
+                message.clone(),
+            ),
+            (Some(name), None) => self.push(
+                section,
+                Some(name),
+                DiagnosticKind::MissingSetting,
+                message.clone(),
+            ),
+            (None, field) => self.push(
+                section,
+                field.as_deref(),
+                DiagnosticKind::LoadError,
+                message.clone(),
+            ),
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub(super) fn index_config_diagnostics_system(
+    uv_app_config: UniqueView<TypedConfig<AppServerSettings>>,
+    uv_discord_config: UniqueView<TypedConfig<discord::DiscordSettings>>,
+    uv_public_tls: UniqueView<app_server_config_plugin::PublicServerTls>,
+    uv_status: UniqueView<PublicServerStatus>,
+    uv_local_database: UniqueView<LocalDatabase>,
+    mut uvm_diagnostics: UniqueViewMut<ConfigDiagnostics>,
+) {
+    if !(uv_app_config.is_inserted_or_modified()
+        || uv_discord_config.is_inserted_or_modified()
+        || uv_public_tls.is_inserted_or_modified()
+        || uv_status.is_inserted_or_modified()
+        || uv_local_database.is_inserted_or_modified())
+    {
+        return;
+    }
+
+    let diagnostics = collect_diagnostics(
+        &uv_app_config,
+        &uv_discord_config,
+        &uv_public_tls,
+        &uv_status,
+        uv_local_database.as_ref().as_ref(),
+    );
+
+    if *uvm_diagnostics != diagnostics {
+        for diag in diagnostics.0.iter() {
+            warn!(?diag, "config diagnostic");
+        }
+        // as_mut marks it for modified
+        *uvm_diagnostics.as_mut() = diagnostics;
+    }
+}
+
+fn collect_diagnostics(
+    app_config: &TypedConfig<AppServerSettings>,
+    discord_config: &TypedConfig<discord::DiscordSettings>,
+    public_tls: &app_server_config_plugin::PublicServerTls,
+    status: &PublicServerStatus,
+    database: &Result<local::Database>,
+) -> ConfigDiagnostics {
+    let mut diagnostics = ConfigDiagnostics::default();
+    diagnostics.push_typed(APP_SECTION, app_config);
+    diagnostics.push_typed(DISCORD_SECTION, discord_config);
+    // otherwise these repeat the config's error
+    if app_config.0.is_ok() {
+        if let Err(err) = public_tls.0.as_ref() {
+            diagnostics.push(
+                APP_SECTION,
+                Some("tls"),
+                DiagnosticKind::TlsUnavailable,
+                format!("{err:#}"),
+            );
+        }
+        if let Some(ref err) = status.last_error {
+            diagnostics.push(
+                APP_SECTION,
+                Some("public_bind_address"),
+                DiagnosticKind::BindFailure,
+                err.clone(),
+            );
+        }
+    }
+    if let Err(err) = database {
+        diagnostics.push(
+            APP_SECTION,
+            Some("database.path"),
+            DiagnosticKind::DatabaseUnavailable,
+            format!("{err:#}"),
+        );
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+fn test_diagnostics(
+    app_toml: &str,
+    status: &PublicServerStatus,
+) -> Vec<(Option<String>, DiagnosticKind)> {
+    let diagnostics = collect_diagnostics(
+        &TypedConfig::from_toml(app_toml),
+        &TypedConfig::from_toml("client_id = \"id\"\nclient_secret = \"secret\""),
+        &app_server_config_plugin::PublicServerTls(Arc::new(Ok(None))),
+        status,
+        &Err(anyhow::anyhow!("database not opened in tests")),
+    );
+    diagnostics
+        .for_section(APP_SECTION)
+        .filter(|diag| diag.kind != DiagnosticKind::DatabaseUnavailable)
+        .map(|diag| (diag.field.clone(), diag.kind))
+        .collect()
+}
+
+#[test]
+fn test_missing_settings_are_reported_on_their_field() {
+    let status = PublicServerStatus::default();
+    assert_eq!(
+        test_diagnostics("public_host_base_url = \"http://localhost\"", &status),
+        [(
+            Some("public_bind_address".to_string()),
+            DiagnosticKind::MissingSetting
+        )]
+    );
+    assert_eq!(
+        test_diagnostics(
+            "public_host_base_url = \"http://localhost\"\n\
+            public_bind_address = \"0.0.0.0:9000\"\n\
+            [rate_limit.per_ip]\nburst = 5",
+            &status,
+        ),
+        [(
+            Some("rate_limit.per_ip.per_minute".to_string()),
+            DiagnosticKind::MissingSetting
+        )],
+        "nested tables are included in the field"
+    );
+}
+
+#[test]
+fn test_invalid_settings_are_load_errors() {
+    let status = PublicServerStatus {
+        last_error: Some("address in use".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        test_diagnostics(
+            "public_host_base_url = \"http://localhost\"\npublic_bind_address = 9000",
+            &status,
+        ),
+        [(
+            Some("public_bind_address".to_string()),
+            DiagnosticKind::LoadError
+        )],
+        "the last bind failure isn't repeated while the config is invalid"
+    );
+}
+
+#[test]
+fn test_bind_failures_are_reported_on_the_bind_address() {
+    let valid =
+        "public_host_base_url = \"http://localhost\"\npublic_bind_address = \"0.0.0.0:9000\"";
+    assert!(test_diagnostics(valid, &PublicServerStatus::default()).is_empty());
+    let status = PublicServerStatus {
+        last_error: Some("address in use".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        test_diagnostics(valid, &status),
+        [(
+            Some("public_bind_address".to_string()),
+            DiagnosticKind::BindFailure
+        )]
+    );
+}
@@ -1,10 +1,6 @@
 use hn_app::_ecs_::*;
-use std::str::FromStr;
 
-use crate::{
-    config_plugins::{self, ReadConfigFile},
-    prelude::*,
-};
+use crate::{config_plugins, prelude::*};
 
 #[derive(Default)]
 pub struct DiscordSettingsPlugin(());
@@ -17,7 +13,9 @@ impl Plugin for DiscordSettingsPlugin {
         app.add_tracked_value(DiscordClientSecret(Arc::new(Err(anyhow::anyhow!(
             "Discord settings not set, yet"
         )))));
-        app.add_plugin(config_plugins::ConfigFilePlugin(DiscordConfigFile(())));
+        app.add_plugin(
+            config_plugins::TypedConfigFilePlugin::<DiscordSettings>::new("discord.toml"),
+        );
         app.add_system(index_discord_settings_system);
     }
 }
@@ -32,57 +30,26 @@ pub struct DiscordClientID(pub ArcResult<String>);
 #[track(All)]
 pub struct DiscordClientSecret(pub ArcResult<String>);
 
-#[derive(Component, Clone)]
-#[track(All)]
-pub struct DiscordConfigFile(());
-
-impl ReadConfigFile for DiscordConfigFile {
-    type Content = toml_edit::Document;
-    type Error = anyhow::Error;
-
-    fn relative_path(&self) -> &str {
-        "discord.toml"
-    }
-
-    fn load(&self, bytes: &[u8]) -> Result<Self::Content, Self::Error> {
-        let str = String::from_utf8(bytes.to_vec()).with_context(|| "loading toml config")?;
-        let doc = toml_edit::Document::from_str(&str).with_context(|| "parsing toml as toml")?;
-        Ok(doc)
-    }
+/// `discord.toml`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DiscordSettings {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Edited on the config server, but nothing connects the bot, yet.
+    #[serde(default)]
+    pub bot_token: Option<String>,
 }
 
+pub type DiscordConfigFile = config_plugins::TomlConfigFile<DiscordSettings>;
+
 fn index_discord_settings_system(
-    uv_config: UniqueView<config_plugins::ConfigFileContent<DiscordConfigFile>>,
+    uv_settings: UniqueView<config_plugins::TypedConfig<DiscordSettings>>,
     mut uvm_client_id: UniqueViewMut<DiscordClientID>,
     mut uvm_client_secret: UniqueViewMut<DiscordClientSecret>,
 ) {
-    if uv_config.is_inserted_or_modified() {
-        let new_client_id_res = uv_config
-            .get_content()
-            .context("expected config to have content")
-            .and_then(|inner| inner.content.as_err_arc_ref())
-            .and_then(|doc| {
-                doc.get("client_id")
-                    .context("Toml has client_id key defined")
-            })
-            .and_then(|item| {
-                item.as_str()
-                    .context("expected client_id to be a string")
-                    .map(String::from)
-            });
-        let new_client_secret_res = uv_config
-            .get_content()
-            .context("expected config to have content")
-            .and_then(|inner| inner.content.as_err_arc_ref())
-            .and_then(|doc| {
-                doc.get("client_secret")
-                    .context("Toml has client_secret key defined")
-            })
-            .and_then(|item| {
-                item.as_str()
-                    .context("expected client_secret to be a string")
-                    .map(String::from)
-            });
+    if uv_settings.is_inserted_or_modified() {
+        let new_client_id_res = uv_settings.get(|settings| settings.client_id.clone());
+        let new_client_secret_res = uv_settings.get(|settings| settings.client_secret.clone());
 
         if uvm_client_id.0.as_ref().as_ref().ok() != new_client_id_res.as_ref().ok() {
             // as_mut marks it for modified
@@ -97,3 +64,72 @@ fn index_discord_settings_system(
         }
     }
 }
+
+/// Token response from `https://discord.com/api/oauth2/token`, for example
+/// ```json
+/// {
+///     "token_type": "Bearer",
+///     "access_token": "mtrv1234DsMWomqBiooo6RdnCs7zjR",
+///     "expires_in": 604800,
+///     "refresh_token": "KTdYabcdMBUeXJ3cvRmtdeIXwBnLro",
+///     "scope": "identify"
+/// }
+/// ```
+#[derive(Deserialize)]
+#[allow(unused)]
+pub(crate) struct DiscordToken {
+    pub token_type: String,
+    pub access_token: String,
+    /// in seconds
+    pub expires_in: u64,
+    pub refresh_token: String,
+    pub scope: String,
+}
+
+/// Error response of the token endpoint, only the error code is kept since the rest of the
+/// body isn't safe to show.
+#[derive(Deserialize)]
+struct DiscordTokenError {
+    error: String,
+}
+
+/// Exchanges a refresh token for a new access token, which also replaces the refresh token.
+///
+/// Errors never include the response body, which may contain tokens, since they're shown
+/// in the data browser.
+pub(crate) async fn refresh_discord_token(
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+) -> Result<DiscordToken> {
+    let form = [
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ];
+    let res = reqwest::Client::new()
+        .post("https://discord.com/api/oauth2/token")
+        .form(&form)
+        .send()
+        .await
+        .context("sending discord token refresh")?;
+    let status = res.status();
+    let text = res
+        .text()
+        .await
+        .context("reading text from discord token refresh")?;
+    if !status.is_success() {
+        let code = serde_json::from_str::<DiscordTokenError>(&text)
+            .map_or_else(|_| "unknown error".to_string(), |err| err.error);
+        anyhow::bail!("discord token refresh failed with {status}: {code}");
+    }
+    // serde_json errors can quote the invalid value
+    serde_json::from_str::<DiscordToken>(&text).map_err(|err| {
+        anyhow::anyhow!(
+            "deserializing refreshed discord token failed at line {}, column {}",
+            err.line(),
+            err.column()
+        )
+    })
+}
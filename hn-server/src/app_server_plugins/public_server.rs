@@ -19,22 +19,30 @@ use http::{header::LOCATION, StatusCode};
 
 use tower_http::{services::ServeDir, trace::TraceLayer};
 
-use crate::{ecs::HintedID, http::OrInternalError, svelte_templates};
+use crate::{ecs::HintedID, http::OrInternalError, svelte_templates, tls};
 
 use super::{discord, PublicServerBaseURL};
+use health::Metrics;
+use pairing::PairingSession;
+use rate_limit::{ClientIp, RateLimitedRequest, RateLimiter};
 use verified::Verified;
 
+pub(crate) mod health;
+pub(crate) mod pairing;
 mod post_mutate;
+pub(crate) mod rate_limit;
 mod verified;
 
 pub fn start_server_from_tcp_listener(
     listener: std::net::TcpListener,
     addr: &std::net::SocketAddr,
     app_ctx: AppCtx,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    tls: Option<(tls::TlsPems, tls::ReloadableTls)>,
 ) -> axum_server::Handle {
-    info!(?addr, "starting public server");
+    info!(?addr, tls = tls.is_some(), "starting public server");
     let handle = axum_server::Handle::new();
-    let server = axum_server::from_tcp(listener).handle(handle.clone());
     // perhaps the client should need to download the public key everytime the server starts up?
     // This would mean there should probably be some kind special respose indicating that the
     // client needs to re-request a new public key for the server.
@@ -51,22 +59,52 @@ pub fn start_server_from_tcp_listener(
         .route("/_mutate", post(post_mutate))
         .route("/login-discord", get(login_discord))
         .route("/callback-discord", get(callback_discord))
+        .route("/healthz", get(health::get_healthz))
+        .route("/readyz", get(health::get_readyz))
+        .route("/metrics", get(health::get_metrics))
         .nest_service("/public", ServeDir::new(templates_path.join("./public")))
+        .layer(axum::middleware::from_fn_with_state(
+            rate_limiter.clone(),
+            rate_limit::rate_limit_middleware,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            metrics.clone(),
+            health::metrics_middleware,
+        ))
         .layer(TraceLayer::new_for_http().make_span_with(|request: &http::Request<_>| {
             info_span!("public-request", method = %request.method(), uri = %request.uri())
         }))
         .layer(Extension(app_ctx.clone()))
         .layer(Extension(local_keys))
+        .layer(Extension(rate_limiter))
+        .layer(Extension(metrics))
         .layer(Extension(svelte_templates::SvelteTemplates {
             dev_path: Arc::new(templates_path),
         }));
 
-    app_ctx.spawn(async {
-        server
-            .serve(app.into_make_service())
-            .await
-            .context("serving public app")
-    });
+    let make_service = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    match tls {
+        None => {
+            let server = axum_server::from_tcp(listener).handle(handle.clone());
+            app_ctx.spawn(async {
+                server
+                    .serve(make_service)
+                    .await
+                    .context("serving public app")
+            });
+        }
+        Some((pems, reloadable)) => {
+            let handle = handle.clone();
+            app_ctx.spawn(async move {
+                let rustls_config = reloadable.load(&pems).await?;
+                axum_server::from_tcp_rustls(listener, rustls_config)
+                    .handle(handle)
+                    .serve(make_service)
+                    .await
+                    .context("serving public app over tls")
+            });
+        }
+    }
 
     handle
 }
@@ -106,6 +144,8 @@ fn generate_svelte_templates() {
 struct LoginDiscordQuery {
     bot: Option<String>,
     device_id: Option<HintedID>,
+    /// Pairing code from [api::StartPairingResponse]
+    pair: Option<String>,
 }
 
 #[instrument(skip_all)]
@@ -120,23 +160,58 @@ async fn get_public_key(
 async fn post_mutate(
     Extension(app_ctx): Extension<AppCtx>,
     Extension(local_keys): Extension<hn_keys::LocalKeys>,
+    Extension(rate_limiter): Extension<Arc<RateLimiter>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    rate_limited: Option<Extension<RateLimitedRequest>>,
+    client_ip: Option<Extension<ClientIp>>,
     Verified(message): Verified<api::ToServer>,
 ) -> HttpResult<impl IntoResponse> {
     warn!(sender = ?message.sender(), data = ?message.data(), "verified, now we need to do something for the client...");
 
     use post_mutate::Mutation;
 
-    let mutate_result = match message.data() {
-        api::ToServer::Ping(_) => Ok(RawWireResult::from_ok(api::Pong)),
-        api::ToServer::CreateDevice(create_device) => create_device
-            .mutate(message.sender(), app_ctx)
-            .await
-            .map(RawWireResult::from_ok),
-        api::ToServer::Device(device) => Err(api::ServerRejection::InternalError(format!(
-            "api::ToServer::Device: Not implemented: {device:#?}"
-        ))),
+    let within_rate_limit = match rate_limited {
+        Some(Extension(RateLimitedRequest { retry_after })) => Err(retry_after),
+        None => rate_limiter.check_sender_key(message.sender()),
+    }
+    .and_then(|()| match (message.data(), client_ip) {
+        (api::ToServer::CreateDevice(_), Some(Extension(ClientIp(ip)))) => {
+            rate_limiter.check_create_device(ip)
+        }
+        _ => Ok(()),
+    });
+
+    let mutate_result = match within_rate_limit {
+        Err(retry_after) => {
+            warn!(sender = ?message.sender(), ?retry_after, "rate limited mutation");
+            Err(api::ServerRejection::TooManyRequests { retry_after })
+        }
+        Ok(()) => match message.data() {
+            api::ToServer::Ping(_) => Ok(RawWireResult::from_ok(api::Pong)),
+            api::ToServer::CreateDevice(create_device) => create_device
+                .mutate(message.sender(), app_ctx)
+                .await
+                .map(RawWireResult::from_ok),
+            api::ToServer::Device(device) => Err(api::ServerRejection::InternalError(format!(
+                "api::ToServer::Device: Not implemented: {device:#?}"
+            ))),
+            api::ToServer::StartPairing(start_pairing) => start_pairing
+                .mutate(message.sender(), app_ctx)
+                .await
+                .map(RawWireResult::from_ok),
+            api::ToServer::CheckPairing(check_pairing) => check_pairing
+                .mutate(message.sender(), app_ctx)
+                .await
+                .map(RawWireResult::from_ok),
+        },
     };
 
+    metrics.record_mutate_outcome(mutate_result.as_ref().err());
+
+    let retry_after = match &mutate_result {
+        Err(api::ServerRejection::TooManyRequests { retry_after }) => Some(*retry_after),
+        _ => None,
+    };
     let (status, raw_result) = match mutate_result {
         Ok(res) => (StatusCode::OK, res),
         Err(rejection) => (
@@ -144,6 +219,7 @@ async fn post_mutate(
                 api::ServerRejection::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
                 api::ServerRejection::BadRequest(_) => StatusCode::BAD_REQUEST,
                 api::ServerRejection::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+                api::ServerRejection::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             },
             RawWireResult::from_err(rejection),
         ),
@@ -151,12 +227,25 @@ async fn post_mutate(
 
     let wire_message = local_keys.send(raw_result, message.sender()).err_500()?;
 
-    Ok((status, axum::body::Bytes::from(wire_message.to_bytes())))
+    Ok((
+        status,
+        AppendHeaders(retry_after.map(|retry_after| {
+            (
+                http::header::RETRY_AFTER,
+                rate_limit::retry_after_secs(retry_after).to_string(),
+            )
+        })),
+        axum::body::Bytes::from(wire_message.to_bytes()),
+    ))
 }
 
 async fn login_discord(
     Extension(app_ctx): Extension<AppCtx>,
-    Query(LoginDiscordQuery { bot, device_id }): Query<LoginDiscordQuery>,
+    Query(LoginDiscordQuery {
+        bot,
+        device_id,
+        pair,
+    }): Query<LoginDiscordQuery>,
 ) -> HttpResult<impl IntoResponse> {
     use axum::response::*;
 
@@ -207,7 +296,14 @@ async fn login_discord(
     }
 
     // don't actually create the device until the handoff.
-    let device_id = device_id.unwrap_or_else(|| HintedID::generate("web"));
+    let device_id = match pair {
+        // the pairing session id is passed through the state, so the callback can complete the handoff
+        Some(code) => find_pairing_session_id(&app_ctx, code)
+            .await
+            .context("finding pairing session")
+            .err_400()?,
+        None => device_id.unwrap_or_else(|| HintedID::generate("web")),
+    };
 
     let scopes = scopes.join("%20");
     let redirect_uri = format!("{public_server_base_url}/callback-discord");
@@ -230,6 +326,46 @@ async fn login_discord(
     ))
 }
 
+/// Resolves a pairing code to the [HintedID] of its [PairingSession] entity.
+async fn find_pairing_session_id(app_ctx: &AppCtx, code: String) -> Result<HintedID> {
+    let (tx, rx) = tokio::sync::oneshot::channel::<Result<HintedID>>();
+    let tx = std::sync::Mutex::new(Some(tx));
+    let code = pairing::normalize_code(&code);
+    app_ctx.run_system(
+        "find pairing session",
+        move |v_hinted_id: View<HintedID>, v_pairing: View<PairingSession>| {
+            let tx = tx.lock().unwrap().take().unwrap();
+            let now = SystemTime::now();
+            let found = (&v_hinted_id, &v_pairing)
+                .iter()
+                .find(|(_, session)| pairing::normalize_code(&session.code) == code)
+                .context("pairing code not found")
+                .and_then(|(id, session)| {
+                    if session.linked_cred.is_some() {
+                        Err(anyhow::anyhow!("pairing code was already used"))
+                    } else if session.is_expired(now) {
+                        Err(anyhow::anyhow!("pairing code expired"))
+                    } else {
+                        Ok(id.clone())
+                    }
+                });
+            let _ = tx.send(found);
+        },
+    );
+
+    rx.await.context("receiving pairing session id")?
+}
+
+/// Outcome of linking a new credential, sent back from the ECS to the callback.
+enum LinkedCred {
+    Device,
+    Pairing {
+        code: String,
+        redirect_uri: Option<String>,
+    },
+    NotFound,
+}
+
 #[derive(Deserialize, Serialize, Codegen)]
 #[codegen(tags = "templates")]
 struct CallbackError {
@@ -274,7 +410,7 @@ async fn callback_discord(
     Query(query): Query<DiscordCallbackQuery>,
     Extension(app_ctx): Extension<AppCtx>,
     Extension(templates): Extension<svelte_templates::SvelteTemplates>,
-) -> HttpResult {
+) -> HttpResult<axum::response::Response> {
     let client_id = app_ctx
         .get_unique::<discord::DiscordClientID>("to get current settings for discord callback")
         .await;
@@ -315,7 +451,7 @@ async fn callback_discord(
             .await
             .context("reading text from discord code excange")
             .and_then(|text| {
-                serde_json::from_str::<DiscordToken>(&text)
+                serde_json::from_str::<discord::DiscordToken>(&text)
                     .with_context(|| format!("deserializing discord token: `{text}`"))
             })
             .err_400()?;
@@ -323,8 +459,16 @@ async fn callback_discord(
         let expires_at = SystemTime::now().add(Duration::from_secs(token.expires_in));
         let access_token = token.access_token.clone();
         let device_id = query.state.clone();
+        // a pairing session id loads nothing, its device was loaded when pairing started
+        app_ctx
+            .load::<crate::ecs::import_export::DeviceBundle>(vec![device_id.clone()])
+            .await
+            .context("loading device to link the credential to")
+            .err_500()?;
         let span = info_span!("insert new credential", ?device_id);
-        app_ctx.schedule_system(
+        let (linked_tx, linked_rx) = tokio::sync::oneshot::channel::<LinkedCred>();
+        let linked_tx = std::sync::Mutex::new(Some(linked_tx));
+        app_ctx.run_system(
             "insert new credential",
             move |mut entities: EntitiesViewMut,
                   mut vm_hinted_id: ViewMut<HintedID>,
@@ -332,8 +476,22 @@ async fn callback_discord(
                   mut vm_cred_tag: ViewMut<ecs::CredTag>,
                   mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>,
                   // device
-                  mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>| {
+                  mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>,
+                  // handoff to desktop app
+                  mut vm_pairing: ViewMut<PairingSession>| {
                 let _span = span.enter();
+                let linked_tx = linked_tx.lock().unwrap().take().unwrap();
+
+                let already_linked = (&vm_hinted_id, &vm_pairing)
+                    .iter()
+                    .find(|(id, _)| *id == &device_id)
+                    .map_or(false, |(_, session)| session.linked_cred.is_some());
+                if already_linked {
+                    // e.g. the login url was opened twice before either finished
+                    warn!(?device_id, "pairing session was already linked");
+                    let _ = linked_tx.send(LinkedCred::NotFound);
+                    return;
+                }
 
                 // find an existing discord access token and replace
                 let cred_entity_id = (&vm_hinted_id, &mut vm_discord_cred)
@@ -370,25 +528,73 @@ async fn callback_discord(
                         )
                     });
 
-                match vm_hinted_id
+                let linked = match vm_hinted_id
                     .iter()
                     .with_id()
                     .find(|(_entity_id, id)| *id == &device_id)
                 {
+                    Some((pairing_entity_id, _id)) if vm_pairing.contains(pairing_entity_id) => {
+                        let mut session = (&mut vm_pairing)
+                            .get(pairing_entity_id)
+                            .todo(f!("pairing session exists"));
+                        info!(code = ?session.code, "completed pairing session");
+                        session.as_mut().link(cred_entity_id, SystemTime::now());
+                        let mut linked_creds = (&mut vm_linked_creds)
+                            .get(session.device)
+                            .todo(f!("paired device has linked creds"));
+                        if !linked_creds.items.contains(&cred_entity_id) {
+                            linked_creds.as_mut().items.push(cred_entity_id);
+                        }
+                        LinkedCred::Pairing {
+                            code: session.code.clone(),
+                            redirect_uri: session.redirect_uri.clone(),
+                        }
+                    }
                     Some((entity_id, _id)) => {
                         // update
                         let mut linked_creds = (&mut vm_linked_creds)
                             .get(entity_id)
                             .todo(f!("query state was a device id with linked creds"));
                         linked_creds.as_mut().items.push(cred_entity_id);
+                        LinkedCred::Device
                     }
                     None => {
                         error!(?device_id, "unexpected new device");
+                        LinkedCred::NotFound
                     }
-                }
+                };
+
+                let _ = linked_tx.send(linked);
             },
         );
 
+        match linked_rx
+            .await
+            .context("receiving linked credential outcome")
+            .err_500()?
+        {
+            LinkedCred::Pairing {
+                code,
+                redirect_uri: Some(redirect_uri),
+            } => {
+                use axum::response::*;
+                let location = pairing::redirect_uri_with_status(&redirect_uri, &code, "linked");
+                return Ok(
+                    (StatusCode::SEE_OTHER, AppendHeaders([(LOCATION, location)])).into_response(),
+                );
+            }
+            LinkedCred::Pairing {
+                code,
+                redirect_uri: None,
+            } => {
+                info!(
+                    ?code,
+                    "pairing has no redirect, desktop app will find out by polling"
+                );
+            }
+            LinkedCred::Device | LinkedCred::NotFound => {}
+        }
+
         client
             .get("https://discord.com/api/users/@me")
             .bearer_auth(access_token)
@@ -415,39 +621,20 @@ async fn callback_discord(
         )
         .context("rendering login page")
         .err_500()
-        .map(Html)
-}
-
-/// For example
-/// ```json
-/// {
-///     "token_type": "Bearer",
-///     "access_token": "mtrv1234DsMWomqBiooo6RdnCs7zjR",
-///     "expires_in": 604800,
-///     "refresh_token": "KTdYabcdMBUeXJ3cvRmtdeIXwBnLro",
-///     "scope": "identify"
-/// }
-/// ```
-#[derive(Deserialize)]
-#[allow(unused)]
-struct DiscordToken {
-    token_type: String,
-    access_token: String,
-    /// in seconds
-    expires_in: u64,
-    refresh_token: String,
-    scope: String,
+        .map(|html| Html(html).into_response())
 }
 
 #[derive(Deserialize)]
 struct LoginPageQuery {
     device_id: Option<HintedID>,
+    /// Pairing code from [api::StartPairingResponse]
+    pair: Option<String>,
 }
 
 #[instrument(skip_all)]
 async fn login_page(
     Extension(templates): Extension<svelte_templates::SvelteTemplates>,
-    Query(LoginPageQuery { device_id }): Query<LoginPageQuery>,
+    Query(LoginPageQuery { device_id, pair }): Query<LoginPageQuery>,
 ) -> HttpResult {
     // LoginURL {
     //     label: "Continue with Slack".to_string(),
@@ -457,7 +644,18 @@ async fn login_page(
     //     label: "Continue with Google Workspace".to_string(),
     //     url: "login-google-workspace".to_string(),
     // },
-    let props = if let Some(device_id) = device_id {
+    let props = if let Some(code) = pair {
+        let encoded = urlencoding::encode(&code);
+        LoginProps {
+            note: Some(format!(
+                "Pairing code {code}. Check that this matches the code shown in your app, you will be redirected to your app after log in is complete."
+            )),
+            loginURLs: vec![LoginURL {
+                label: "Continue with Discord".to_string(),
+                url: format!("login-discord?pair={encoded}"),
+            }],
+        }
+    } else if let Some(device_id) = device_id {
         LoginProps {
             note: Some(format!(
                 "You will be redirected to your app after log in is complete."
@@ -0,0 +1,269 @@
+//! `/healthz`, `/readyz`, and Prometheus `/metrics` for running behind a load balancer.
+//! `/metrics` is only served when `public_metrics` is set in `here-now-app.toml`.
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use axum::{
+    extract::{MatchedPath, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use http::{header::CONTENT_TYPE, Request, StatusCode};
+
+use crate::{http::OrInternalError, prelude::*};
+use hn_app::_ecs_::*;
+
+use super::PairingSession;
+
+/// Request and mutation counters, shared across restarts of the public server.
+#[derive(Default)]
+pub struct Metrics {
+    /// (method, route, status) to count
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// outcome to count
+    mutate_outcomes: Mutex<BTreeMap<&'static str, u64>>,
+    /// `public_metrics` from `here-now-app.toml`, `/metrics` is a 404 otherwise.
+    exposed: AtomicBool,
+}
+
+impl Metrics {
+    pub fn set_exposed(&self, exposed: bool) {
+        self.exposed.store(exposed, Ordering::Relaxed);
+    }
+
+    /// `None` when the mutation succeeded.
+    pub fn record_mutate_outcome(&self, rejection: Option<&api::ServerRejection>) {
+        let outcome = rejection.map(rejection_label).unwrap_or("Ok");
+        *self
+            .mutate_outcomes
+            .lock()
+            .unwrap()
+            .entry(outcome)
+            .or_default() += 1;
+    }
+
+    fn record_request(&self, method: String, route: String, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method, route, status))
+            .or_default() += 1;
+    }
+
+    fn render(&self, entities: &EntityCounts) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP hn_http_requests_total Public server requests by route and status.\n");
+        out.push_str("# TYPE hn_http_requests_total counter\n");
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "hn_http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape_label(route)
+            );
+        }
+
+        out.push_str("# HELP hn_mutate_outcomes_total Outcomes of /_mutate calls by ServerRejection variant.\n");
+        out.push_str("# TYPE hn_mutate_outcomes_total counter\n");
+        for (outcome, count) in self.mutate_outcomes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "hn_mutate_outcomes_total{{outcome=\"{outcome}\"}} {count}"
+            );
+        }
+
+        out.push_str(
+            "# HELP hn_command_queue_depth Commands waiting to be run by the command loop.\n",
+        );
+        out.push_str("# TYPE hn_command_queue_depth gauge\n");
+        let _ = writeln!(
+            out,
+            "hn_command_queue_depth {}",
+            hn_app::app_ctx::queued_command_count()
+        );
+
+        out.push_str("# HELP hn_ecs_entities ECS entities by tag.\n");
+        out.push_str("# TYPE hn_ecs_entities gauge\n");
+        for (tag, count) in [
+            ("Device", entities.devices),
+            ("Cred", entities.creds),
+            ("Pairing", entities.pairings),
+        ] {
+            let _ = writeln!(out, "hn_ecs_entities{{tag=\"{tag}\"}} {count}");
+        }
+
+        out
+    }
+}
+
+fn rejection_label(rejection: &api::ServerRejection) -> &'static str {
+    match rejection {
+        api::ServerRejection::InternalError(_) => "InternalError",
+        api::ServerRejection::BadRequest(_) => "BadRequest",
+        api::ServerRejection::Unauthorized(_) => "Unauthorized",
+        api::ServerRejection::TooManyRequests { .. } => "TooManyRequests",
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Use with [axum::middleware::from_fn_with_state] on the public router.
+pub async fn metrics_middleware<B>(
+    State(metrics): State<Arc<Metrics>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = req.method().to_string();
+    // use the matched route, so we don't create a series per unique uri
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let res = next.run(req).await;
+    metrics.record_request(method, route, res.status().as_u16());
+    res
+}
+
+/// Unique, updated as the database and config files are loaded.
+#[derive(Component, Clone, Debug, Serialize)]
+#[track(All)]
+pub struct ServerReadiness {
+    pub database: Result<(), String>,
+    pub app_config: Result<(), String>,
+    pub discord_config: Result<(), String>,
+}
+
+impl Default for ServerReadiness {
+    fn default() -> Self {
+        Self {
+            database: Err("not loaded, yet".to_string()),
+            app_config: Err("not loaded, yet".to_string()),
+            discord_config: Err("not loaded, yet".to_string()),
+        }
+    }
+}
+
+impl ServerReadiness {
+    pub fn is_ready(&self) -> bool {
+        self.database.is_ok() && self.app_config.is_ok() && self.discord_config.is_ok()
+    }
+}
+
+struct EntityCounts {
+    devices: usize,
+    creds: usize,
+    pairings: usize,
+}
+
+/// Liveness, always ok if the server is able to respond.
+pub async fn get_healthz() -> &'static str {
+    "ok"
+}
+
+#[instrument(skip_all)]
+pub async fn get_readyz(Extension(app_ctx): Extension<AppCtx>) -> impl IntoResponse {
+    let readiness = app_ctx
+        .get_unique::<ServerReadiness>("to check if the server is ready")
+        .await;
+    readiness_response(readiness)
+}
+
+fn readiness_response(readiness: ServerReadiness) -> Response {
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness)).into_response()
+}
+
+#[instrument(skip_all)]
+pub async fn get_metrics(
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+) -> HttpResult<Response> {
+    if !metrics.exposed.load(Ordering::Relaxed) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let (tx, rx) = tokio::sync::oneshot::channel::<EntityCounts>();
+    let tx = std::sync::Mutex::new(Some(tx));
+    app_ctx.run_system(
+        "count entities for metrics",
+        move |v_device_tag: View<ecs::DeviceTag>,
+              v_cred_tag: View<ecs::CredTag>,
+              v_pairing: View<PairingSession>| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(EntityCounts {
+                    devices: v_device_tag.len(),
+                    creds: v_cred_tag.len(),
+                    pairings: v_pairing.len(),
+                });
+            }
+        },
+    );
+
+    let entities = rx.await.context("receiving entity counts").err_500()?;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(&entities),
+    )
+        .into_response())
+}
+
+#[tokio::test]
+async fn test_healthz_and_readyz() {
+    use axum::body::HttpBody;
+
+    assert_eq!(get_healthz().await, "ok");
+
+    let res = readiness_response(ServerReadiness::default());
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = res.into_body().data().await.unwrap().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["database"]["Err"], "not loaded, yet");
+
+    let res = readiness_response(ServerReadiness {
+        database: Ok(()),
+        app_config: Ok(()),
+        discord_config: Err("missing client_secret".to_string()),
+    });
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let res = readiness_response(ServerReadiness {
+        database: Ok(()),
+        app_config: Ok(()),
+        discord_config: Ok(()),
+    });
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn test_metrics_off_by_default() {
+    let metrics = Metrics::default();
+    assert!(!metrics.exposed.load(Ordering::Relaxed));
+    metrics.record_mutate_outcome(None);
+    metrics.set_exposed(true);
+    let rendered = metrics.render(&EntityCounts {
+        devices: 1,
+        creds: 0,
+        pairings: 0,
+    });
+    assert!(rendered.contains("hn_mutate_outcomes_total{outcome=\"Ok\"} 1"));
+    assert!(rendered.contains("hn_ecs_entities{tag=\"Device\"} 1"));
+}
@@ -0,0 +1,183 @@
+//! Pairing sessions hand off a browser log in back to the desktop app which started it.
+//!
+//! These are only kept in the ECS (never exported to disk), since they only
+//! live for as long as [PAIRING_EXPIRES_IN].
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use crate::prelude::*;
+use hn_app::_ecs_::*;
+use rand::Rng;
+
+pub(crate) const PAIRING_EXPIRES_IN: Duration = Duration::from_secs(10 * 60);
+/// Once linked, sessions are kept just long enough for the desktop app to see the outcome.
+pub(crate) const LINKED_KEPT_FOR: Duration = Duration::from_secs(2 * 60);
+
+/// Excludes look-alike characters like `0`/`O` and `1`/`I` so codes can be read aloud.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+#[ecs_component("Pairing")]
+#[derive(Debug)]
+pub struct PairingSession {
+    /// Short code shown in both the desktop app and the browser.
+    pub code: String,
+    /// The device entity which credentials get linked to.
+    pub device: EntityId,
+    /// Only this key may check on the status of the pairing.
+    pub requested_by: hn_keys::PublicKeyKind,
+    pub redirect_uri: Option<String>,
+    pub expires_at: SystemTime,
+    /// Set once the browser completes log in, after which the code can't be used again.
+    pub linked_cred: Option<EntityId>,
+}
+
+impl PairingSession {
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at <= now
+    }
+
+    /// Consumes the session, which expires shortly after so the desktop app can still check it.
+    pub fn link(&mut self, cred: EntityId, now: SystemTime) {
+        self.linked_cred = Some(cred);
+        self.expires_at = self.expires_at.min(now + LINKED_KEPT_FOR);
+    }
+}
+
+/// Removes expired sessions, whether or not they were linked.
+pub(crate) fn expire_pairing_sessions_system(mut all_storages: AllStoragesViewMut) {
+    let now = SystemTime::now();
+    let expired = all_storages.run(|v_pairing: View<PairingSession>| {
+        v_pairing
+            .iter()
+            .with_id()
+            .filter(|(_, session)| session.is_expired(now))
+            .map(|(entity_id, _)| entity_id)
+            .collect::<Vec<_>>()
+    });
+    for entity_id in expired {
+        all_storages.delete_entity(entity_id);
+    }
+}
+
+/// e.g. `"KX4P-9QTA"`
+pub fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code = String::with_capacity(9);
+    for i in 0..8 {
+        if i == 4 {
+            code.push('-');
+        }
+        code.push(CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char);
+    }
+    code
+}
+
+/// Retries while the code is used by a session, in [normalize_code]'s form, since sessions
+/// are found by their code.
+pub fn generate_unused_code(used: &HashSet<String>) -> String {
+    loop {
+        let code = generate_code();
+        if !used.contains(&normalize_code(&code)) {
+            return code;
+        }
+    }
+}
+
+/// Codes are compared case-insensitively and without the dash, since they may be typed in.
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// We only redirect back to the desktop app, so we don't become an open redirect.
+/// Allowed are custom URL schemes (`here-now://paired`) and loopback listeners
+/// (`http://127.0.0.1:51234/paired`).
+pub fn validate_redirect_uri(uri: &str) -> Result<()> {
+    let (scheme, rest) = uri
+        .split_once("://")
+        .with_context(|| format!("redirect uri {uri:?} must have a scheme"))?;
+    match scheme {
+        "http" => {
+            let host_and_port = rest.split(['/', '?', '#']).next().unwrap_or_default();
+            let host = host_and_port
+                .rsplit_once(':')
+                .map(|(host, _port)| host)
+                .unwrap_or(host_and_port);
+            match host {
+                "127.0.0.1" | "localhost" | "[::1]" => Ok(()),
+                other => Err(anyhow::anyhow!(
+                    "redirect uri host {other:?} must be a loopback address"
+                )),
+            }
+        }
+        "https" | "javascript" | "data" | "file" => Err(anyhow::anyhow!(
+            "redirect uri scheme {scheme:?} is not allowed"
+        )),
+        custom => {
+            if !custom.is_empty()
+                && custom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+            {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("redirect uri scheme {custom:?} is invalid"))
+            }
+        }
+    }
+}
+
+/// Appends the pairing outcome to the desktop app's redirect uri.
+pub fn redirect_uri_with_status(redirect_uri: &str, code: &str, status: &str) -> String {
+    let sep = if redirect_uri.contains('?') { '&' } else { '?' };
+    format!(
+        "{redirect_uri}{sep}code={}&status={}",
+        urlencoding::encode(code),
+        urlencoding::encode(status)
+    )
+}
+
+#[test]
+fn test_linked_sessions_expire() {
+    let now = SystemTime::now();
+    let mut session = PairingSession {
+        code: generate_code(),
+        device: EntityId::dead(),
+        requested_by: hn_keys::init().public_key().clone(),
+        redirect_uri: None,
+        expires_at: now + PAIRING_EXPIRES_IN,
+        linked_cred: None,
+    };
+    session.link(EntityId::dead(), now);
+    assert!(!session.is_expired(now));
+    assert!(session.is_expired(now + LINKED_KEPT_FOR));
+}
+
+#[test]
+fn test_validate_redirect_uri() {
+    assert!(validate_redirect_uri("here-now://paired").is_ok());
+    assert!(validate_redirect_uri("http://127.0.0.1:51234/paired").is_ok());
+    assert!(validate_redirect_uri("http://localhost:51234").is_ok());
+    assert!(validate_redirect_uri("http://example.com/paired").is_err());
+    assert!(validate_redirect_uri("http://127.0.0.1.example.com/").is_err());
+    assert!(validate_redirect_uri("https://example.com/").is_err());
+    assert!(validate_redirect_uri("javascript://alert(1)").is_err());
+    assert!(validate_redirect_uri("no-scheme").is_err());
+    assert_eq!(
+        normalize_code(&generate_code()).len(),
+        8,
+        "codes are 8 characters plus a dash"
+    );
+}
+
+#[test]
+fn test_generated_codes_skip_used_codes() {
+    let used = (0..1000)
+        .map(|_| normalize_code(&generate_code()))
+        .collect::<HashSet<_>>();
+    for _ in 0..100 {
+        assert!(!used.contains(&normalize_code(&generate_unused_code(&used))));
+    }
+}
@@ -3,6 +3,7 @@ use async_trait::async_trait;
 use crate::app_ctx::AppCtx;
 
 mod create_device;
+mod pairing;
 
 /// A mutation is a request to change the state of the server.
 /// This is usually a verified request from a client `POST` to the `/_mutate` public endpoint.
@@ -0,0 +1,172 @@
+use std::time::SystemTime;
+
+use super::*;
+use crate::app_server_plugins::public_server::pairing::{self, PairingSession};
+use crate::app_server_plugins::PublicServerBaseURL;
+use crate::prelude::*;
+use hn_app::_ecs_::*;
+
+#[async_trait]
+impl Mutation for api::StartPairing {
+    #[instrument(skip(app_ctx), name = "start pairing mutation")]
+    async fn mutate(
+        &self,
+        sender: &hn_keys::PublicKeyKind,
+        app_ctx: AppCtx,
+    ) -> api::ServerResult<Self> {
+        let device_id = ecs::HintedID::try_from(self.device_id.as_str())
+            .map_err(|err| api::ServerRejection::BadRequest(format!("invalid device id: {err}")))?;
+        if let Some(ref redirect_uri) = self.redirect_uri {
+            pairing::validate_redirect_uri(redirect_uri)
+                .map_err(|err| api::ServerRejection::BadRequest(format!("{err:#}")))?;
+        }
+        // devices may only be loaded when referenced, see `[database] idle_device_minutes`,
+        // and only the sender's devices need to be
+        let key = ecs::import_export::authorized_key_view_key(sender)
+            .map_err(|err| api::ServerRejection::InternalError(format!("{err:#}")))?;
+        app_ctx
+            .load_by_view::<ecs::import_export::DevicesByAuthorizedKey>(key)
+            .await
+            .map_err(|err| api::ServerRejection::InternalError(format!("{err:#}")))?;
+
+        let public_server_base_url = app_ctx
+            .get_unique::<PublicServerBaseURL>("to create the login url for pairing")
+            .await;
+        let public_server_base_url = public_server_base_url
+            .0
+            .as_err_arc_ref()
+            .map_err(|err| api::ServerRejection::InternalError(format!("{err:#}")))?
+            .clone();
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<api::ServerResult<Self>>();
+        let tx = std::sync::Mutex::new(Some(tx));
+        let redirect_uri = self.redirect_uri.clone();
+        let sender = sender.clone();
+        app_ctx.run_system(
+            "start pairing",
+            move |mut entities: EntitiesViewMut,
+                  mut vm_hinted_id: ViewMut<ecs::HintedID>,
+                  v_device_tag: View<ecs::DeviceTag>,
+                  v_authorized_keys: View<ecs::AuthorizedKeys>,
+                  mut vm_pairing: ViewMut<PairingSession>| {
+                let tx = tx.lock().unwrap().take().unwrap();
+                let now = SystemTime::now();
+
+                let device = (&vm_hinted_id, &v_device_tag, &v_authorized_keys)
+                    .iter()
+                    .with_id()
+                    .find(|(_, (id, _, _))| *id == &device_id);
+                let result = match device {
+                    None => Err(api::ServerRejection::BadRequest(format!(
+                        "device {device_id} not found"
+                    ))),
+                    Some((_, (_, _, authorized_keys)))
+                        if !authorized_keys.keys.iter().any(|a| a.key == sender) =>
+                    {
+                        Err(api::ServerRejection::Unauthorized(format!(
+                            "sender is not an authorized key of device {device_id}"
+                        )))
+                    }
+                    Some((device, _)) => {
+                        let used_codes = vm_pairing
+                            .iter()
+                            .map(|session| pairing::normalize_code(&session.code))
+                            .collect();
+                        let code = pairing::generate_unused_code(&used_codes);
+                        let pairing_id = ecs::HintedID::generate("pair");
+                        info!(?pairing_id, ?device_id, "starting pairing session");
+                        entities.add_entity(
+                            (&mut vm_hinted_id, &mut vm_pairing),
+                            (
+                                pairing_id,
+                                PairingSession {
+                                    code: code.clone(),
+                                    device,
+                                    requested_by: sender.clone(),
+                                    redirect_uri: redirect_uri.clone(),
+                                    expires_at: now + pairing::PAIRING_EXPIRES_IN,
+                                    linked_cred: None,
+                                },
+                            ),
+                        );
+                        Ok(api::StartPairingResponse {
+                            login_url: format!(
+                                "{public_server_base_url}/?pair={}",
+                                urlencoding::encode(&code)
+                            ),
+                            code,
+                            expires_in: pairing::PAIRING_EXPIRES_IN,
+                        })
+                    }
+                };
+
+                let _ = tx.send(result);
+            },
+        );
+
+        rx.await
+            .map_err(|err| api::ServerRejection::InternalError(err.to_string()))?
+    }
+}
+
+#[async_trait]
+impl Mutation for api::CheckPairing {
+    #[instrument(skip(app_ctx), name = "check pairing mutation")]
+    async fn mutate(
+        &self,
+        sender: &hn_keys::PublicKeyKind,
+        app_ctx: AppCtx,
+    ) -> api::ServerResult<Self> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<api::ServerResult<Self>>();
+        let tx = std::sync::Mutex::new(Some(tx));
+        let code = pairing::normalize_code(&self.code);
+        let sender = sender.clone();
+        app_ctx.run_system(
+            "check pairing",
+            move |v_hinted_id: View<ecs::HintedID>,
+                  v_linked_creds: View<ecs::Linked<ecs::CredTag>>,
+                  v_pairing: View<PairingSession>| {
+                let tx = tx.lock().unwrap().take().unwrap();
+                let result = match v_pairing
+                    .iter()
+                    .find(|session| pairing::normalize_code(&session.code) == code)
+                {
+                    None => Err(api::ServerRejection::BadRequest(
+                        "pairing code not found".to_string(),
+                    )),
+                    Some(session) if session.requested_by != sender => {
+                        Err(api::ServerRejection::Unauthorized(
+                            "pairing was started by a different key".to_string(),
+                        ))
+                    }
+                    // linked sessions expire soon after, but still report the link
+                    Some(PairingSession {
+                        linked_cred: Some(_),
+                        device,
+                        ..
+                    }) => match v_hinted_id.get(*device) {
+                        Ok(device_id) => Ok(api::PairingStatus::Linked {
+                            device_id: device_id.to_id_string(),
+                            cred_count: v_linked_creds
+                                .get(*device)
+                                .map(|linked| linked.items.len())
+                                .unwrap_or_default(),
+                        }),
+                        Err(err) => Err(api::ServerRejection::InternalError(format!(
+                            "paired device is missing: {err:?}"
+                        ))),
+                    },
+                    Some(session) if session.is_expired(SystemTime::now()) => {
+                        Ok(api::PairingStatus::Expired)
+                    }
+                    Some(_) => Ok(api::PairingStatus::Pending),
+                };
+
+                let _ = tx.send(result);
+            },
+        );
+
+        rx.await
+            .map_err(|err| api::ServerRejection::InternalError(err.to_string()))?
+    }
+}
@@ -0,0 +1,279 @@
+//! Token bucket rate limits for the public server.
+//!
+//! Requests are limited per client IP by [rate_limit_middleware], and `/_mutate` calls are
+//! additionally limited per verified sender key in the `post_mutate` handler.
+//! Settings come from the `[rate_limit]` table of `here-now-app.toml` and are swapped in
+//! without restarting the server.
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header::RETRY_AFTER, Request, StatusCode};
+use lru::LruCache;
+
+use crate::prelude::*;
+
+/// Paths where the response body is an encrypted [api::ServerResult], so the handler
+/// must encode the rejection itself (see [RateLimitedRequest]).
+const ENCRYPTED_PATHS: &[&str] = &["/_mutate"];
+
+/// Most clients tracked at once, the least recently seen client's bucket is forgotten first.
+const MAX_BUCKETS: usize = 65_536;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BucketSettings {
+    /// Sustained rate that tokens are refilled at.
+    pub per_minute: u32,
+    /// How many requests can be made at once with a full bucket.
+    pub burst: u32,
+}
+
+impl BucketSettings {
+    fn refill_per_sec(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+/// `[rate_limit]` in `here-now-app.toml`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Applies to every request to the public server.
+    pub per_ip: BucketSettings,
+    /// Applies to each verified `/_mutate` call.
+    pub per_sender_key: BucketSettings,
+    /// Additional limit for creating devices, since each one is persisted.
+    pub create_device_per_ip: BucketSettings,
+    /// Use the first `X-Forwarded-For` address as the client IP,
+    /// only enable when behind a trusted reverse proxy.
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            per_ip: BucketSettings {
+                per_minute: 300,
+                burst: 60,
+            },
+            per_sender_key: BucketSettings {
+                per_minute: 120,
+                burst: 30,
+            },
+            create_device_per_ip: BucketSettings {
+                per_minute: 2,
+                burst: 5,
+            },
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Ip(IpAddr),
+    CreateDevice(IpAddr),
+    /// Base64 encoded public key
+    SenderKey(String),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, settings: &BucketSettings, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * settings.refill_per_sec()).min(settings.burst as f64);
+        self.updated_at = now;
+    }
+
+    /// Returns how long until a token is available if the bucket is empty.
+    fn take(&mut self, settings: &BucketSettings, now: Instant) -> Result<(), Duration> {
+        self.refill(settings, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if settings.per_minute == 0 {
+            Err(Duration::from_secs(60))
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / settings.refill_per_sec(),
+            ))
+        }
+    }
+}
+
+/// Shared between the public server and the ECS, which updates the settings.
+pub struct RateLimiter {
+    settings: RwLock<RateLimitSettings>,
+    buckets: Mutex<LruCache<BucketKey, TokenBucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimitSettings::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings: RwLock::new(settings),
+            buckets: Mutex::new(LruCache::new(MAX_BUCKETS)),
+        }
+    }
+
+    pub fn set_settings(&self, settings: RateLimitSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    pub fn settings(&self) -> RateLimitSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    fn check(&self, key: BucketKey, settings: &BucketSettings) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(&key) {
+            return bucket.take(settings, now);
+        }
+        let mut bucket = TokenBucket {
+            tokens: settings.burst as f64,
+            updated_at: now,
+        };
+        let taken = bucket.take(settings, now);
+        // evicts the least recently seen bucket when full
+        buckets.put(key, bucket);
+        taken
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Duration> {
+        let settings = self.settings.read().unwrap().per_ip;
+        self.check(BucketKey::Ip(ip), &settings)
+    }
+
+    pub fn check_create_device(&self, ip: IpAddr) -> Result<(), Duration> {
+        let settings = self.settings.read().unwrap().create_device_per_ip;
+        self.check(BucketKey::CreateDevice(ip), &settings)
+    }
+
+    pub fn check_sender_key(&self, sender: &hn_keys::PublicKeyKind) -> Result<(), Duration> {
+        let settings = self.settings.read().unwrap().per_sender_key;
+        let key = serde_json::to_string(sender).unwrap_or_default();
+        self.check(BucketKey::SenderKey(key), &settings)
+    }
+
+    fn client_ip<B>(&self, req: &Request<B>, remote: SocketAddr) -> IpAddr {
+        if self.settings.read().unwrap().trust_forwarded_for {
+            if let Some(forwarded) = req
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .and_then(|first| first.trim().parse::<IpAddr>().ok())
+            {
+                return forwarded;
+            }
+        }
+        remote.ip()
+    }
+}
+
+/// Inserted into request extensions for [ENCRYPTED_PATHS] when the client is over its limit,
+/// so the handler can respond with an encrypted [api::ServerRejection::TooManyRequests].
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitedRequest {
+    pub retry_after: Duration,
+}
+
+/// Client IP found by [rate_limit_middleware], for handlers applying their own limits.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after_secs(retry_after).to_string())],
+        "Too Many Requests",
+    )
+        .into_response()
+}
+
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// Use with [axum::middleware::from_fn_with_state]; requires the server to be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+pub async fn rate_limit_middleware<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = limiter.client_ip(&req, remote);
+    req.extensions_mut().insert(ClientIp(ip));
+    if let Err(retry_after) = limiter.check_ip(ip) {
+        warn!(?ip, uri = %req.uri(), ?retry_after, "rate limited");
+        if ENCRYPTED_PATHS.contains(&req.uri().path()) {
+            req.extensions_mut()
+                .insert(RateLimitedRequest { retry_after });
+        } else {
+            return too_many_requests(retry_after);
+        }
+    }
+
+    next.run(req).await
+}
+
+#[test]
+fn test_rate_limiter_forgets_least_recent_clients() {
+    let limiter = RateLimiter::new(RateLimitSettings {
+        per_ip: BucketSettings {
+            per_minute: 0,
+            burst: 1,
+        },
+        ..Default::default()
+    });
+    let first = IpAddr::from([10, 0, 0, 1]);
+    assert!(limiter.check_ip(first).is_ok());
+    assert!(limiter.check_ip(first).is_err());
+    for i in 0..MAX_BUCKETS as u32 {
+        let _ = limiter.check_ip(IpAddr::from((i + 1).to_be_bytes()));
+    }
+    assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_BUCKETS);
+    assert!(
+        limiter.check_ip(first).is_ok(),
+        "evicted, so it starts full"
+    );
+}
+
+#[test]
+fn test_token_bucket() {
+    let settings = BucketSettings {
+        per_minute: 60,
+        burst: 2,
+    };
+    let start = Instant::now();
+    let mut bucket = TokenBucket {
+        tokens: settings.burst as f64,
+        updated_at: start,
+    };
+    assert!(bucket.take(&settings, start).is_ok());
+    assert!(bucket.take(&settings, start).is_ok());
+    let retry_after = bucket.take(&settings, start).unwrap_err();
+    assert_eq!(retry_after_secs(retry_after), 1);
+    assert!(bucket
+        .take(&settings, start + Duration::from_secs(1))
+        .is_ok());
+}
@@ -0,0 +1,134 @@
+//! Moving the public server to a new address without dropping it in between.
+//!
+//! The new listener is bound first (retrying with backoff while the port is still held),
+//! and only once it's accepting do we drain the previous server. If binding never succeeds,
+//! the previous server keeps running and the failure is reported in [PublicServerStatus].
+//!
+//! Where the platform allows it ([SHARES_PORTS]), listeners share their port, so this also
+//! holds when the port stays the same (e.g. only TLS is toggled). Otherwise the previous
+//! server stops accepting first, and is started again if the new address can't be bound.
+use std::{net::SocketAddr, time::Duration};
+
+use crate::prelude::*;
+use hn_app::_ecs_::*;
+
+const BIND_ATTEMPTS: u32 = 8;
+const BIND_BACKOFF_START: Duration = Duration::from_millis(250);
+const BIND_BACKOFF_MAX: Duration = Duration::from_secs(8);
+
+/// How long in-flight requests get to finish on the previous server.
+pub(super) const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Unique, displayed by the config server.
+#[derive(Component, Clone, Debug, Default, Serialize)]
+#[track(All)]
+pub struct PublicServerStatus {
+    /// Where the public server is currently accepting connections.
+    pub serving: Option<ServingStatus>,
+    /// A new listener being bound, while the current server keeps serving.
+    pub pending: Option<PendingBind>,
+    /// Why the last start or rebind failed.
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServingStatus {
+    pub addr: SocketAddr,
+    pub tls: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingBind {
+    pub addr: SocketAddr,
+    pub attempt: u32,
+}
+
+/// Whether a new listener can bind the port of the server it replaces, with SO_REUSEPORT.
+pub(super) const SHARES_PORTS: bool = cfg!(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos"))
+));
+
+/// Allows binding a port which still has connections draining from a stopped listener, or
+/// (see [SHARES_PORTS]) which the previous server is still accepting on.
+///
+/// SO_REUSEPORT is limited to sockets of the same user on Linux, so other users can't take
+/// a share of our connections.
+fn bind_reusable(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .context("creating socket")?;
+    // on windows, reuse address allows stealing the port from other processes
+    #[cfg(unix)]
+    {
+        socket
+            .set_reuse_address(true)
+            .context("setting SO_REUSEADDR")?;
+    }
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    {
+        socket
+            .set_reuse_port(true)
+            .context("setting SO_REUSEPORT")?;
+    }
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("binding {addr}"))?;
+    socket.listen(1024).context("listening")?;
+    socket
+        .set_nonblocking(true)
+        .context("setting nonblocking")?;
+
+    Ok(socket.into())
+}
+
+/// `generation` is used to report progress only while this is still the latest bind.
+pub(super) async fn bind_with_backoff(
+    addr: SocketAddr,
+    app_ctx: &AppCtx,
+    generation: usize,
+) -> Result<std::net::TcpListener> {
+    let mut delay = BIND_BACKOFF_START;
+    let mut attempt = 1;
+    loop {
+        match bind_reusable(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) if attempt >= BIND_ATTEMPTS => {
+                return Err(err.context(format!("giving up after {attempt} attempts")));
+            }
+            // e.g. an address which isn't ours, which waiting won't fix
+            Err(err) if !is_addr_in_use(&err) => return Err(err),
+            Err(err) => {
+                warn!(
+                    ?err,
+                    ?addr,
+                    attempt,
+                    ?delay,
+                    "public server bind failed, retrying"
+                );
+                let last_error = format!("{err:#}");
+                app_ctx.run_system(
+                    "report public server bind attempt",
+                    move |uv_public_server: UniqueView<super::PublicServer>,
+                          mut uvm_status: UniqueViewMut<PublicServerStatus>| {
+                        if uv_public_server.generation == generation {
+                            let status = uvm_status.as_mut();
+                            status.pending = Some(PendingBind { addr, attempt });
+                            status.last_error = Some(last_error.clone());
+                        }
+                    },
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(BIND_BACKOFF_MAX);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn is_addr_in_use(err: &anyhow::Error) -> bool {
+    err.root_cause()
+        .downcast_ref::<std::io::Error>()
+        .map_or(false, |err| err.kind() == std::io::ErrorKind::AddrInUse)
+}
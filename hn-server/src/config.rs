@@ -1,6 +1,6 @@
 use serde::de::DeserializeOwned;
 
-use crate::prelude::*;
+use crate::{config_plugins::ConfigOverrides, prelude::*};
 use std::{path::PathBuf, str::FromStr};
 
 /// The root settings container for all configurable things in the app.
@@ -8,6 +8,7 @@ use std::{path::PathBuf, str::FromStr};
 pub struct Settings {
     config_files_directory: std::path::PathBuf,
     configurables: Vec<Arc<Box<dyn Configurable>>>,
+    overrides: ConfigOverrides,
 }
 
 impl Settings {
@@ -15,14 +16,29 @@ impl Settings {
         Settings {
             config_files_directory,
             configurables: Vec::new(),
+            overrides: ConfigOverrides::default(),
         }
     }
 
+    /// Overridden values are shown instead of the file's, and can't be edited.
+    pub fn with_overrides(mut self, overrides: ConfigOverrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
     pub fn with<C: Configurable + 'static>(mut self, configurable: C) -> Self {
         self.configurables.push(Arc::new(Box::new(configurable)));
         self
     }
 
+    pub fn config_files_directory(&self) -> &std::path::Path {
+        &self.config_files_directory
+    }
+
+    pub fn overrides(&self) -> &ConfigOverrides {
+        &self.overrides
+    }
+
     #[deprecated = "use .entries()"]
     pub fn configurables(&self) -> impl Iterator<Item = &Arc<Box<dyn Configurable>>> {
         self.configurables.iter()
@@ -37,6 +53,8 @@ impl Settings {
 }
 
 pub mod config_directory_setup;
+pub mod history;
+pub mod schema;
 
 pub trait Configurable: Send + Sync + Debug {
     // TODO: some way to embed into binaries automatically?
@@ -52,6 +70,20 @@ pub trait Configurable: Send + Sync + Debug {
         fields: &serde_json::Value,
         section: &mut dyn toml_edit::TableLike,
     ) -> Result<JSON, JSON>;
+    /// Fields saved into a new file when the section's file is missing, so it starts out
+    /// with the documented keys.
+    fn default_fields(&self) -> JSON {
+        JSON::Object(Default::default())
+    }
+    /// Top-level keys whose values are masked in diffs of the section's file.
+    fn secret_keys(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+    /// JSON schema of the fields [Configurable::save] takes, for the admin API's OpenAPI
+    /// description.
+    fn json_schema(&self) -> JSON {
+        serde_json::json!({ "type": "object" })
+    }
 }
 
 pub struct SettingEntry<'a, 'b> {
@@ -63,6 +95,20 @@ pub struct SettingEntry<'a, 'b> {
 impl<'a, 'b> SettingEntry<'a, 'b> {
     /// Private
     async fn read_toml_and_file(&self) -> Result<Option<(toml_edit::Document, String)>> {
+        let file = match self.read_file().await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let doc = toml_edit::Document::from_str(&file)
+            .with_context(|| format!("parsing toml for {:?}", self.section_name))?;
+
+        // TODO: break the configurations out into sections
+        // TODO: caching & reloading?
+        Ok(Some((doc, file)))
+    }
+
+    /// Unparsed, so rolling back works even when the file isn't valid toml.
+    async fn read_file(&self) -> Result<Option<String>> {
         let mut file_path = self
             .settings
             .config_files_directory
@@ -70,27 +116,27 @@ impl<'a, 'b> SettingEntry<'a, 'b> {
         file_path.set_extension("toml");
         let file_path = &file_path;
         let file_res = tokio::fs::read_to_string(file_path).await;
-        let file = match file_res {
-            Ok(file) => file,
+        match file_res {
+            Ok(file) => Ok(Some(file)),
             Err(err) => match err.kind() {
-                std::io::ErrorKind::NotFound => return Ok(None),
-                other => {
-                    return Err(err).with_context(|| {
-                        format!("reading config file from {file_path:?} ({other:?})")
-                    })
-                }
+                std::io::ErrorKind::NotFound => Ok(None),
+                other => Err(err)
+                    .with_context(|| format!("reading config file from {file_path:?} ({other:?})")),
             },
-        };
-
-        let doc = toml_edit::Document::from_str(&file)
-            .with_context(|| format!("parsing toml at {file_path:?}"))?;
-
-        // TODO: break the configurations out into sections
-        // TODO: caching & reloading?
-        Ok(Some((doc, file)))
+        }
     }
 
-    async fn write_file(&self, content: &str) -> Result<()> {
+    /// Records `previous` in the section's [history] before replacing it.
+    async fn write_file(&self, previous: Option<&str>, content: &str) -> Result<()> {
+        if let Some(previous) = previous {
+            history::record(
+                &self.settings.config_files_directory,
+                &self.section_name,
+                previous,
+            )
+            .await
+            .with_context(|| format!("recording history of {:?}", self.section_name))?;
+        }
         let mut file_path = self
             .settings
             .config_files_directory
@@ -105,28 +151,63 @@ impl<'a, 'b> SettingEntry<'a, 'b> {
         Ok(())
     }
 
+    fn overridden_keys(&self) -> JSON {
+        self.settings
+            .overrides
+            .for_section(&self.section_name)
+            .map(|item| (item.key.clone(), JSON::String(item.source.to_string())))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// With overrides applied
     pub async fn get_toml_and_parse<T: DeserializeOwned>(&self) -> Result<Option<T>> {
-        let (document, _str) = match self.read_toml_and_file().await? {
+        let (mut document, _str) = match self.read_toml_and_file().await? {
             Some(a) => a,
             None => return Ok(None),
         };
+        self.settings
+            .overrides
+            .apply(&self.section_name, document.as_table_mut())?;
         Ok(Some(
             toml_edit::de::from_document::<T>(document).with_context(|| "parsing document")?,
         ))
     }
 
+    /// With overrides applied
     pub async fn get_toml_or_empty(&self) -> Result<Box<dyn toml_edit::TableLike>> {
-        Ok(self
+        let mut table = self
             .read_toml_and_file()
             .await?
             .map(|a| a.0.as_table().to_owned())
-            .map(|a| Box::new(a) as Box<dyn toml_edit::TableLike>)
-            .unwrap_or_else(|| Box::new(toml_edit::Table::new()) as Box<dyn toml_edit::TableLike>))
+            .unwrap_or_default();
+        self.settings
+            .overrides
+            .apply(&self.section_name, &mut table)?;
+        Ok(Box::new(table))
     }
 
+    /// Template variables, with `overrides` mapping overridden keys to where they were set.
     pub async fn get_view_json(&self) -> Result<JSON> {
         let toml = self.get_toml_or_empty().await?;
-        Ok(self.configurable.vars(&toml)?)
+        let mut json = self.configurable.vars(&toml)?;
+        if let Some(obj) = json.as_object_mut() {
+            obj.insert("overrides".to_string(), self.overridden_keys());
+        }
+        Ok(json)
+    }
+
+    /// Saves [Configurable::default_fields] if the file doesn't exist, returns whether it did.
+    pub async fn write_default_file(&self) -> Result<bool> {
+        if self.read_toml_and_file().await?.is_some() {
+            return Ok(false);
+        }
+        let default_fields = self.configurable.default_fields();
+        self.save_with(&default_fields)
+            .await?
+            .map_err(|err| anyhow::anyhow!("{err}"))
+            .with_context(|| format!("saving default {:?} settings", self.section_name))?;
+        Ok(true)
     }
 
     pub async fn save_with(&self, json: &JSON) -> Result<Result<(JSON, bool), JSON>> {
@@ -134,38 +215,148 @@ impl<'a, 'b> SettingEntry<'a, 'b> {
             .await
     }
 
+    /// Lines which saving `json` would change, without saving.
+    pub async fn preview_with(&self, json: &JSON) -> Result<Result<Vec<history::DiffLine>, JSON>> {
+        let updated = self
+            .updated_file_with(|table_like| self.configurable.save(json, table_like))
+            .await?;
+        Ok(updated.map(|(_ok, file, updated)| {
+            let file = file.unwrap_or_default();
+            history::diff_lines(&file, &updated, &self.configurable.secret_keys())
+        }))
+    }
+
     /// E would usually be a JSON passed up
     pub async fn update_toml_with<T, E>(
         &self,
         f: impl FnOnce(&mut dyn toml_edit::TableLike) -> Result<T, E>,
     ) -> Result<Result<(T, bool), E>> {
-        let (mut doc, file) = self
+        let (ok, file, updated_doc_str) = match self.updated_file_with(f).await? {
+            Err(inner) => return Ok(Err(inner)),
+            Ok(updated) => updated,
+        };
+        if file.as_deref() != Some(updated_doc_str.as_str()) {
+            // TODO: break the configurations out into sections
+            // TODO: caching & reloading?
+            self.write_file(file.as_deref(), &updated_doc_str)
+                .await
+                .with_context(|| format!("writing updated settings"))?;
+            return Ok(Ok((ok, true)));
+        }
+
+        Ok(Ok((ok, false)))
+    }
+
+    /// The current file, if there is one, and what it would be after `f`.
+    async fn updated_file_with<T, E>(
+        &self,
+        f: impl FnOnce(&mut dyn toml_edit::TableLike) -> Result<T, E>,
+    ) -> Result<Result<(T, Option<String>, String), E>> {
+        let (mut doc, file) = match self
             .read_toml_and_file()
             .await
             .with_context(|| format!("reading toml for updating"))?
-            .unwrap_or_default();
+        {
+            Some((doc, file)) => (doc, Some(file)),
+            None => Default::default(),
+        };
 
-        let _original = doc.clone();
+        let original = doc.clone();
         let ok = match f(doc.as_table_mut()) {
             Err(inner) => return Ok(Err(inner)),
             Ok(ok) => ok,
         };
+        // overridden keys are read-only, so keep what the file had
+        for item in self.settings.overrides.for_section(&self.section_name) {
+            restore_key(doc.as_table_mut(), original.as_table(), &item.key);
+        }
 
-        let updated_doc_str = doc.to_string();
-        if file != updated_doc_str {
-            // TODO: break the configurations out into sections
-            // TODO: caching & reloading?
-            self.write_file(&updated_doc_str)
-                .await
-                .with_context(|| format!("writing updated settings"))?;
-            return Ok(Ok((ok, true)));
+        Ok(Ok((ok, file, doc.to_string())))
+    }
+
+    /// Earlier versions of the file, newest first.
+    pub async fn history(&self) -> Result<Vec<history::HistoryVersion>> {
+        history::list(&self.settings.config_files_directory, &self.section_name).await
+    }
+
+    /// Lines which rolling back to `version` would change.
+    pub async fn history_diff(&self, version: &str) -> Result<Vec<history::DiffLine>> {
+        let earlier = history::read(
+            &self.settings.config_files_directory,
+            &self.section_name,
+            version,
+        )
+        .await?;
+        let file = self.read_file().await?.unwrap_or_default();
+        Ok(history::diff_lines(
+            &file,
+            &earlier,
+            &self.configurable.secret_keys(),
+        ))
+    }
+
+    /// Writes `version` back, recording the current file first so the rollback can be undone.
+    /// The config watcher then reloads the file like any other edit. Returns whether it changed.
+    pub async fn rollback(&self, version: &str) -> Result<bool> {
+        let earlier = history::read(
+            &self.settings.config_files_directory,
+            &self.section_name,
+            version,
+        )
+        .await?;
+        toml_edit::Document::from_str(&earlier)
+            .with_context(|| format!("parsing config history version {version:?}"))?;
+        let file = self.read_file().await?;
+        if file.as_deref() == Some(earlier.as_str()) {
+            return Ok(false);
         }
+        self.write_file(file.as_deref(), &earlier)
+            .await
+            .with_context(|| format!("rolling back to version {version:?}"))?;
+        Ok(true)
+    }
+}
 
-        Ok(Ok((ok, false)))
+fn restore_key(table: &mut toml_edit::Table, original: &toml_edit::Table, key: &str) {
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return;
+    };
+    let mut table: &mut dyn toml_edit::TableLike = table;
+    let mut original = Some(original as &dyn toml_edit::TableLike);
+    for part in parts {
+        original = original
+            .and_then(|original| original.get(part))
+            .and_then(|item| item.as_table_like());
+        table = match table
+            .get_mut(part)
+            .and_then(|item| item.as_table_like_mut())
+        {
+            Some(table) => table,
+            None => return,
+        };
+    }
+    match original.and_then(|original| original.get(last)) {
+        Some(item) => {
+            table.insert(last, item.clone());
+        }
+        None => {
+            table.remove(last);
+        }
     }
 }
 
 impl Settings {
+    /// Writes a default file for each section which doesn't have one, e.g. in a new directory.
+    pub async fn write_default_files(&self) -> Result<()> {
+        for entry in self.entries() {
+            if entry.write_default_file().await? {
+                info!(section = ?entry.section_name, "wrote default config file");
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_entry<'a, 'b: 'a>(&'a self, section_name: &'b str) -> Option<SettingEntry> {
         self.configurables
             .iter()
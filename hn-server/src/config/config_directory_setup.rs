@@ -1,55 +1,211 @@
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use crate::prelude::*;
+
 static CONFIG_FOLDER_ENV_VAR: &'static str = "HERE_NOW_CONFIG_FOLDER";
+const CONFIG_DIR_FLAG: &str = "--config-dir";
+/// Never prompt, e.g. under systemd or in a container
+const HEADLESS_FLAG: &str = "--headless";
+
+/// Finds the config directory from `--config-dir`, then `HERE_NOW_CONFIG_FOLDER`, then the
+/// platform config directory, creating it if it's missing.
+///
+/// Creating it asks first when started from a terminal, unless `--headless` is passed.
+pub fn init_config_directory(args: &[String]) -> Result<PathBuf> {
+    let headless = args.iter().any(|arg| arg == HEADLESS_FLAG) || !std::io::stdin().is_terminal();
+    config_directory_at(configured_config_directory(args)?, headless)
+}
+
+/// Like [init_config_directory], but fails instead of creating it, e.g. for `backup`.
+pub fn find_config_directory(args: &[String]) -> Result<PathBuf> {
+    let found = configured_config_directory(args)?;
+    let path = found
+        .canonicalize()
+        .with_context(|| format!("finding config folder at {found:?}"))?;
+    anyhow::ensure!(
+        path.is_dir(),
+        "config folder at {path:?} is not a directory"
+    );
+    Ok(path)
+}
 
-pub fn init_config_directory() -> PathBuf {
-    match std::env::var(CONFIG_FOLDER_ENV_VAR) {
-        Ok(found) => {
-            return expect_config_directory_at(PathBuf::from(found)).0;
+fn configured_config_directory(args: &[String]) -> Result<PathBuf> {
+    match config_dir_flag(args)? {
+        Some(path) => Ok(path),
+        None => match std::env::var(CONFIG_FOLDER_ENV_VAR) {
+            Ok(found) => Ok(PathBuf::from(found)),
+            Err(std::env::VarError::NotPresent) => default_config_directory(),
+            Err(std::env::VarError::NotUnicode(err)) => {
+                anyhow::bail!("{CONFIG_FOLDER_ENV_VAR} env variable was not valid unicode: {err:?}")
+            }
+        },
+    }
+}
+
+fn config_dir_flag(args: &[String]) -> Result<Option<PathBuf>> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_DIR_FLAG {
+            let path = args
+                .next()
+                .with_context(|| format!("expected a directory after {CONFIG_DIR_FLAG}"))?;
+            return Ok(Some(PathBuf::from(path)));
         }
-        Err(std::env::VarError::NotPresent) => {}
-        Err(std::env::VarError::NotUnicode(err)) => {
-            panic!("{CONFIG_FOLDER_ENV_VAR} env variable was not valid unicode: {err:?}");
+        if let Some(path) = arg.strip_prefix("--config-dir=") {
+            return Ok(Some(PathBuf::from(path)));
         }
     }
-    eprintln!("No config folder passed in environment");
-    let user = directories::UserDirs::new().expect("no user directories found");
-    let found = user
-        .desktop_dir()
-        .expect("finding desktop directory")
-        .join("here-now-config");
-    return expect_config_directory_at(found).0;
+    Ok(None)
+}
+
+/// The platform config directory, like `~/.config/here-now-server` on Linux, unless only the
+/// old `~/Desktop/here-now-config` exists.
+fn default_config_directory() -> Result<PathBuf> {
+    let platform = directories::ProjectDirs::from("app", "here-now", "here-now-server")
+        .map(|dirs| dirs.config_dir().to_path_buf());
+    let legacy = directories::UserDirs::new().and_then(|user| {
+        user.desktop_dir()
+            .map(|desktop| desktop.join("here-now-config"))
+    });
+    prefer_legacy_directory(platform, legacy)
 }
-struct AppConfigFolder(PathBuf);
 
-fn expect_config_directory_at(found: PathBuf) -> AppConfigFolder {
+fn prefer_legacy_directory(platform: Option<PathBuf>, legacy: Option<PathBuf>) -> Result<PathBuf> {
+    match (platform, legacy) {
+        (Some(platform), Some(legacy)) if !platform.exists() && legacy.is_dir() => {
+            warn!(?legacy, ?platform, "using the previous config folder, consider moving it");
+            Ok(legacy)
+        }
+        (Some(platform), _) => Ok(platform),
+        (None, _) => anyhow::bail!(
+            "no platform config directory found, pass {CONFIG_DIR_FLAG} or set {CONFIG_FOLDER_ENV_VAR}"
+        ),
+    }
+}
+
+fn config_directory_at(found: PathBuf, headless: bool) -> Result<PathBuf> {
     match found.canonicalize() {
         Ok(path) => {
             if !path.is_dir() {
-                panic!("{path:?} is not a directory.")
+                anyhow::bail!("config folder at {path:?} is not a directory");
             }
-            return AppConfigFolder(path);
+            Ok(path)
         }
-        Err(err) => match err.kind() {
-            std::io::ErrorKind::NotFound => {
-                eprintln!("Directory not found for configuration files at {found:?}.");
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            warn!(?found, "config folder not found");
+            if !headless {
                 let create = inquire::Select::new(
                     "Would you like to create this directory?",
                     vec![true, false],
                 )
                 .prompt_skippable()
-                .expect("selecting option for creating the directory");
-                if let Some(true) = create {
-                    std::fs::create_dir_all(&found).expect("creating directory for config files");
-                    return AppConfigFolder(
-                        found
-                            .canonicalize()
-                            .expect("finding config directory after creation"),
-                    );
-                } else {
-                    panic!("Directory does not exist.")
+                .context("selecting option for creating the directory")?;
+                if create != Some(true) {
+                    anyhow::bail!("config folder at {found:?} does not exist");
                 }
             }
-            _ => panic!("Failed to find config folder at {found:?}: {err}"),
-        },
+            create_config_directory(&found)
+        }
+        Err(err) => Err(err).with_context(|| format!("finding config folder at {found:?}")),
     }
 }
+
+fn create_config_directory(found: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(found)
+        .with_context(|| format!("creating config folder at {found:?}"))?;
+    info!(?found, "created config folder");
+    found
+        .canonicalize()
+        .with_context(|| format!("finding config folder at {found:?} after creation"))
+}
+
+#[test]
+fn test_config_dir_flag() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        config_dir_flag(&args(&["--headless", "--config-dir", "/etc/here-now"])).unwrap(),
+        Some(PathBuf::from("/etc/here-now"))
+    );
+    assert_eq!(
+        config_dir_flag(&args(&["--config-dir=/etc/here-now"])).unwrap(),
+        Some(PathBuf::from("/etc/here-now"))
+    );
+    assert_eq!(config_dir_flag(&args(&["--headless"])).unwrap(), None);
+    assert!(
+        config_dir_flag(&args(&["--config-dir"])).is_err(),
+        "the directory is missing"
+    );
+}
+
+#[test]
+fn test_missing_directory_is_created_when_headless() {
+    let temp_dir = std::env::temp_dir().join(format!("hn-config-dir-{}", xid::new()));
+    let missing = temp_dir.join("config");
+    let args = vec![format!("--config-dir={}", missing.display())];
+    assert!(
+        find_config_directory(&args).is_err(),
+        "only created when starting the server"
+    );
+
+    let created = config_directory_at(missing.clone(), true).unwrap();
+    assert!(created.is_dir());
+    assert_eq!(created, missing.canonicalize().unwrap());
+    assert_eq!(find_config_directory(&args).unwrap(), created);
+    assert_eq!(config_directory_at(missing, true).unwrap(), created);
+
+    let file = temp_dir.join("file");
+    std::fs::write(&file, "").unwrap();
+    assert!(config_directory_at(file, true).is_err());
+
+    let _ = std::fs::remove_dir_all(&temp_dir);
+}
+
+#[test]
+fn test_legacy_directory_is_used_until_the_platform_one_exists() {
+    let temp_dir = std::env::temp_dir().join(format!("hn-config-dir-{}", xid::new()));
+    let platform = temp_dir.join("platform");
+    let legacy = temp_dir.join("Desktop/here-now-config");
+    let prefer = || prefer_legacy_directory(Some(platform.clone()), Some(legacy.clone()));
+
+    assert_eq!(prefer().unwrap(), platform, "no legacy folder");
+    std::fs::create_dir_all(&legacy).unwrap();
+    assert_eq!(prefer().unwrap(), legacy);
+    std::fs::create_dir_all(&platform).unwrap();
+    assert_eq!(prefer().unwrap(), platform);
+    assert_eq!(
+        prefer_legacy_directory(Some(platform.clone()), None).unwrap(),
+        platform
+    );
+    assert!(prefer_legacy_directory(None, Some(legacy.clone())).is_err());
+
+    let _ = std::fs::remove_dir_all(&temp_dir);
+}
+
+#[tokio::test]
+async fn test_default_files_are_written_once() {
+    use crate::config::Settings;
+    use crate::config_html_server::{app, discord};
+
+    let temp_dir = std::env::temp_dir().join(format!("hn-config-dir-{}", xid::new()));
+    let config_dir = config_directory_at(temp_dir.clone(), true).unwrap();
+    let settings = Settings::new(config_dir.clone())
+        .with(app::app_settings())
+        .with(discord::discord_settings());
+    settings.write_default_files().await.unwrap();
+
+    let app_file = config_dir.join("here-now-app.toml");
+    let written = std::fs::read_to_string(&app_file).unwrap();
+    assert!(written.contains("public_bind_address"), "{written}");
+    assert!(config_dir.join("discord.toml").is_file());
+
+    // edits are kept
+    std::fs::write(&app_file, "public_bind_address = \"127.0.0.1:8000\"\n").unwrap();
+    settings.write_default_files().await.unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&app_file).unwrap(),
+        "public_bind_address = \"127.0.0.1:8000\"\n"
+    );
+
+    let _ = std::fs::remove_dir_all(&temp_dir);
+}
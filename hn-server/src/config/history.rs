@@ -0,0 +1,207 @@
+//! Earlier versions of each section's file, kept under `.history/<section>/<version>.toml` in
+//! the config directory and written before the section's file is replaced.
+//!
+//! The config watcher doesn't look into subdirectories it wasn't asked for, so writing history
+//! doesn't reload anything, while rolling back writes the section's file and does.
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use similar::{ChangeTag, TextDiff};
+
+use crate::prelude::*;
+
+const HISTORY_DIR: &str = ".history";
+/// Older versions are removed when a section has more than this
+const MAX_VERSIONS: usize = 50;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryVersion {
+    /// Milliseconds since the unix epoch when this content was replaced
+    pub version: String,
+    /// Like "5 minutes ago"
+    pub replaced_ago: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DiffLine {
+    pub tag: DiffTag,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffTag {
+    Equal,
+    Delete,
+    Insert,
+    /// Between groups of changes, in place of unchanged lines
+    Skipped,
+}
+
+fn section_history_dir(config_dir: &Path, section: &str) -> PathBuf {
+    config_dir.join(HISTORY_DIR).join(section)
+}
+
+/// Versions are only ever named by [record], so anything else could be a path.
+fn version_path(config_dir: &Path, section: &str, version: &str) -> Result<PathBuf> {
+    if version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        anyhow::bail!("invalid config history version {version:?}");
+    }
+    Ok(section_history_dir(config_dir, section).join(format!("{version}.toml")))
+}
+
+/// Saves the content being replaced, and removes the oldest versions past [MAX_VERSIONS].
+pub async fn record(config_dir: &Path, section: &str, previous: &str) -> Result<String> {
+    let dir = section_history_dir(config_dir, section);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("creating config history folder at {dir:?}"))?;
+
+    let mut millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time before unix epoch")?
+        .as_millis();
+    let existing = list_versions(config_dir, section).await?;
+    // saves within the same millisecond still get their own version
+    if let Some(latest) = existing.first().and_then(|v| v.parse::<u128>().ok()) {
+        millis = millis.max(latest + 1);
+    }
+    let version = millis.to_string();
+    let path = version_path(config_dir, section, &version)?;
+    tokio::fs::write(&path, previous)
+        .await
+        .with_context(|| format!("writing config history to {path:?}"))?;
+
+    for old in existing.iter().skip(MAX_VERSIONS - 1) {
+        let path = version_path(config_dir, section, old)?;
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            warn!(?err, ?path, "failed to remove old config history");
+        }
+    }
+
+    Ok(version)
+}
+
+/// Newest first
+pub async fn list(config_dir: &Path, section: &str) -> Result<Vec<HistoryVersion>> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    Ok(list_versions(config_dir, section)
+        .await?
+        .into_iter()
+        .map(|version| {
+            let millis = version.parse::<u128>().unwrap_or_default();
+            HistoryVersion {
+                replaced_ago: ago(now.saturating_sub(millis) / 1000),
+                version,
+            }
+        })
+        .collect())
+}
+
+pub async fn read(config_dir: &Path, section: &str, version: &str) -> Result<String> {
+    let path = version_path(config_dir, section, version)?;
+    tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("reading config history from {path:?}"))
+}
+
+async fn list_versions(config_dir: &Path, section: &str) -> Result<Vec<String>> {
+    let dir = section_history_dir(config_dir, section);
+    let mut read_dir = match tokio::fs::read_dir(&dir).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading config history folder {dir:?}"))
+        }
+    };
+    let mut versions = Vec::new();
+    while let Some(entry) = read_dir
+        .next_entry()
+        .await
+        .with_context(|| format!("reading config history folder {dir:?}"))?
+    {
+        let name = entry.file_name();
+        let Some(version) = name.to_str().and_then(|name| name.strip_suffix(".toml")) else {
+            continue;
+        };
+        if let Ok(millis) = version.parse::<u128>() {
+            versions.push((millis, version.to_string()));
+        }
+    }
+    versions.sort_by(|a, b| b.0.cmp(&a.0));
+    Ok(versions.into_iter().map(|(_, version)| version).collect())
+}
+
+fn ago(secs: u128) -> String {
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} minutes ago", secs / 60),
+        3600..=86399 => format!("{} hours ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+/// Changed lines with a few lines of context, masking the values of `secret_keys`.
+pub fn diff_lines(old: &str, new: &str, secret_keys: &[&str]) -> Vec<DiffLine> {
+    let diff = TextDiff::from_lines(old, new);
+    let mut lines = Vec::new();
+    for (i, group) in diff.grouped_ops(3).iter().enumerate() {
+        if i > 0 {
+            lines.push(DiffLine {
+                tag: DiffTag::Skipped,
+                text: "...".to_string(),
+            });
+        }
+        for op in group {
+            for change in diff.iter_changes(op) {
+                let tag = match change.tag() {
+                    ChangeTag::Equal => DiffTag::Equal,
+                    ChangeTag::Delete => DiffTag::Delete,
+                    ChangeTag::Insert => DiffTag::Insert,
+                };
+                let text = change.value().trim_end_matches(['\r', '\n']);
+                lines.push(DiffLine {
+                    tag,
+                    text: mask_secret(text, secret_keys),
+                });
+            }
+        }
+    }
+    lines
+}
+
+fn mask_secret(line: &str, secret_keys: &[&str]) -> String {
+    match line.split_once('=') {
+        Some((key, value)) if secret_keys.contains(&key.trim()) && !value.trim().is_empty() => {
+            format!("{key}= \"XXXXXXXX\"")
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[test]
+fn test_diff_lines_masks_secrets() {
+    let old = "# Client ID\nclient_id = \"1\"\nclient_secret = \"old\"\n";
+    let new = "# Client ID\nclient_id = \"2\"\nclient_secret = \"new\"\n";
+    let lines = diff_lines(old, new, &["client_secret"]);
+    let tagged = |tag| {
+        lines
+            .iter()
+            .filter(|line| line.tag == tag)
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        tagged(DiffTag::Delete),
+        ["client_id = \"1\"", "client_secret = \"XXXXXXXX\""]
+    );
+    assert_eq!(
+        tagged(DiffTag::Insert),
+        ["client_id = \"2\"", "client_secret = \"XXXXXXXX\""]
+    );
+    assert_eq!(tagged(DiffTag::Equal), ["# Client ID"]);
+    assert!(version_path(Path::new("/config"), "discord", "../secrets").is_err());
+}
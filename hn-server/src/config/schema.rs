@@ -0,0 +1,462 @@
+//! [Configurable] sections described by their fields, rather than hand-written `vars` and
+//! `save`, rendered with `schema.configurable.html.j2` unless a template is given.
+//!
+//! ```ignore
+//! SchemaConfigurable::new("discord")
+//!     .field(SchemaField::text("client_id", "Client ID").comment("Client ID of your application."))
+//!     .field(SchemaField::text("client_secret", "Client Secret").secret())
+//! ```
+//!
+//! Tables like `[tls]` are edited as TOML with [SchemaField::table], which checks that the
+//! text deserializes into the settings read from that table.
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use toml_edit::{Document, Formatted, Item, Value};
+
+use super::Configurable;
+use crate::config_html_server::edit;
+use crate::prelude::*;
+
+#[derive(Debug)]
+pub struct SchemaConfigurable {
+    section_name: &'static str,
+    template: HTMXPartial,
+    fields: Vec<SchemaField>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SchemaField {
+    /// Top-level key in the file
+    pub key: &'static str,
+    pub label: &'static str,
+    /// Shown under the input, may contain html
+    pub help: Option<&'static str>,
+    /// Written above the key when it's added to the file, existing comments are kept
+    pub comment: Option<&'static str>,
+    pub kind: FieldKind,
+    /// Shown masked, and only saved when changed
+    pub secret: bool,
+    /// Saved into new files, see [Configurable::default_fields]
+    pub default: Option<JSON>,
+    /// Applied to text before validating and saving, e.g. trimming slashes
+    pub normalize: Option<fn(&str) -> String>,
+    pub validate: Option<fn(&str) -> Result<(), String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Text,
+    Checkbox,
+    /// A table, like `[tls]`, edited as TOML
+    Table,
+}
+
+impl SchemaConfigurable {
+    pub fn new(section_name: &'static str) -> Self {
+        SchemaConfigurable {
+            section_name,
+            template: htmx_partial!("schema.configurable.html.j2"),
+            fields: Vec::new(),
+        }
+    }
+
+    /// For sections with more than a list of inputs, the template gets each field's value by
+    /// its key as well as the `fields` list.
+    pub fn template(mut self, template: HTMXPartial) -> Self {
+        self.template = template;
+        self
+    }
+
+    pub fn field(mut self, field: SchemaField) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    fn view_value(field: &SchemaField, section: &dyn toml_edit::TableLike) -> JSON {
+        let item = section.get(field.key);
+        match field.kind {
+            FieldKind::Checkbox => JSON::Bool(item.and_then(Item::as_bool).unwrap_or_default()),
+            FieldKind::Text => {
+                let value = match item.and_then(Item::as_value) {
+                    Some(Value::String(str)) => str.value().clone(),
+                    Some(other) => other.to_string().trim().to_string(),
+                    None => String::new(),
+                };
+                if field.secret {
+                    JSON::String(value.chars().map(|_| 'X').collect())
+                } else {
+                    JSON::String(value)
+                }
+            }
+            FieldKind::Table => {
+                let table = item
+                    .and_then(|item| item.clone().into_table().ok())
+                    .unwrap_or_default();
+                let mut doc = Document::new();
+                *doc.as_table_mut() = table;
+                JSON::String(doc.to_string().trim().to_string())
+            }
+        }
+    }
+
+    /// `None` when the field should be left as it is.
+    fn save_value(field: &SchemaField, fields: &JSON) -> Result<Option<Item>, String> {
+        let submitted = fields.get(field.key).and_then(JSON::as_str);
+        match field.kind {
+            // unchecked boxes aren't submitted
+            FieldKind::Checkbox => Ok(Some(Item::Value(Value::Boolean(Formatted::new(
+                submitted == Some("on"),
+            ))))),
+            FieldKind::Text => {
+                let raw = submitted.unwrap_or_default().trim();
+                let value = match field.normalize {
+                    Some(normalize) => normalize(raw),
+                    None => raw.to_string(),
+                };
+                if field.secret
+                    && (value.is_empty() || value == "none" || value.chars().all(|c| c == 'X'))
+                {
+                    // still the masked value
+                    return Ok(None);
+                }
+                if let Some(validate) = field.validate {
+                    validate(&value)?;
+                }
+                Ok(Some(Item::Value(Value::String(Formatted::new(value)))))
+            }
+            FieldKind::Table => {
+                let raw = submitted.unwrap_or_default().trim();
+                if raw.is_empty() {
+                    // removed, so the settings' defaults apply
+                    return Ok(Some(Item::Table(toml_edit::Table::new())));
+                }
+                if let Some(validate) = field.validate {
+                    validate(raw)?;
+                }
+                let doc = raw.parse::<Document>().map_err(|err| err.to_string())?;
+                let mut table = doc.as_table().clone();
+                table.set_implicit(false);
+                Ok(Some(Item::Table(table)))
+            }
+        }
+    }
+}
+
+fn validate_table<T: DeserializeOwned>(value: &str) -> Result<(), String> {
+    toml_edit::de::from_str::<T>(value)
+        .map(|_| ())
+        .map_err(|err| err.to_string().trim().to_string())
+}
+
+impl SchemaField {
+    pub fn text(key: &'static str, label: &'static str) -> Self {
+        SchemaField {
+            key,
+            label,
+            help: None,
+            comment: None,
+            kind: FieldKind::Text,
+            secret: false,
+            default: None,
+            normalize: None,
+            validate: None,
+        }
+    }
+
+    pub fn checkbox(key: &'static str, label: &'static str) -> Self {
+        SchemaField {
+            kind: FieldKind::Checkbox,
+            ..SchemaField::text(key, label)
+        }
+    }
+
+    /// Edited as TOML, which is rejected unless it deserializes into `T`.
+    pub fn table<T: DeserializeOwned>(key: &'static str, label: &'static str) -> Self {
+        SchemaField {
+            kind: FieldKind::Table,
+            validate: Some(validate_table::<T>),
+            ..SchemaField::text(key, label)
+        }
+    }
+
+    pub fn help(mut self, help: &'static str) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    pub fn comment(mut self, comment: &'static str) -> Self {
+        self.comment = Some(comment);
+        self
+    }
+
+    pub fn secret(mut self) -> Self {
+        self.secret = true;
+        self
+    }
+
+    /// Form value, like `"on"` for checked boxes.
+    pub fn default(mut self, default: impl Into<JSON>) -> Self {
+        self.default = Some(default.into());
+        self
+    }
+
+    pub fn normalize(mut self, normalize: fn(&str) -> String) -> Self {
+        self.normalize = Some(normalize);
+        self
+    }
+
+    pub fn validate(mut self, validate: fn(&str) -> Result<(), String>) -> Self {
+        self.validate = Some(validate);
+        self
+    }
+}
+
+impl Configurable for SchemaConfigurable {
+    fn template(&self) -> HTMXPartial {
+        self.template
+    }
+
+    fn section_name(&self) -> Cow<'static, str> {
+        self.section_name.into()
+    }
+
+    fn vars(&self, section: &Box<dyn toml_edit::TableLike>) -> Result<JSON> {
+        let mut vars = serde_json::Map::new();
+        let mut fields = Vec::new();
+        for field in self.fields.iter() {
+            let value = Self::view_value(field, &**section);
+            vars.insert(field.key.to_string(), value.clone());
+            fields.push(json!({
+                "key": field.key,
+                "label": field.label,
+                "help": field.help,
+                "kind": field.kind,
+                "type": match field.kind {
+                    FieldKind::Checkbox => "checkbox",
+                    FieldKind::Text if field.secret => "password",
+                    FieldKind::Text => "text",
+                    FieldKind::Table => "textarea",
+                },
+                "value": value,
+            }));
+        }
+        vars.insert("fields".to_string(), JSON::Array(fields));
+        Ok(JSON::Object(vars))
+    }
+
+    fn save(&self, fields: &JSON, toml: &mut dyn toml_edit::TableLike) -> Result<JSON, JSON> {
+        // validate every field before changing any
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        for field in self.fields.iter() {
+            match Self::save_value(field, fields) {
+                Ok(Some(item)) => items.push((field, item)),
+                Ok(None) => {}
+                Err(err) => errors.push(format!("{}: {err}", field.label)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(JSON::String(errors.join("\n")));
+        }
+
+        for (field, item) in items {
+            match item {
+                Item::Table(table) if table.is_empty() => {
+                    toml.remove(field.key);
+                }
+                Item::Table(mut table) => {
+                    // the comment goes above the header, keys' decor would go inside the brackets
+                    match toml.get(field.key).and_then(Item::as_table) {
+                        Some(existing) => {
+                            *table.decor_mut() = existing.decor().clone();
+                            if let Some(position) = existing.position() {
+                                table.set_position(position);
+                            }
+                        }
+                        None => table.decor_mut().set_prefix(
+                            field
+                                .comment
+                                .map(|comment| format!("\n# {comment}\n"))
+                                .unwrap_or_else(|| "\n".to_string()),
+                        ),
+                    }
+                    toml.insert(field.key, Item::Table(table));
+                }
+                item => edit::update_toml_key(
+                    toml,
+                    field.key,
+                    item,
+                    field.comment.map(String::from),
+                    false,
+                ),
+            }
+        }
+
+        Ok(json!({ "saved": true }))
+    }
+
+    fn default_fields(&self) -> JSON {
+        self.fields
+            .iter()
+            .filter_map(|field| Some((field.key.to_string(), field.default.clone()?)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    fn secret_keys(&self) -> Vec<&'static str> {
+        self.fields
+            .iter()
+            .filter(|field| field.secret)
+            .map(|field| field.key)
+            .collect()
+    }
+
+    fn json_schema(&self) -> JSON {
+        let properties = self
+            .fields
+            .iter()
+            .map(|field| {
+                let mut schema = match field.kind {
+                    FieldKind::Checkbox => json!({ "type": "boolean" }),
+                    FieldKind::Text if field.secret => json!({
+                        "type": "string",
+                        "format": "password",
+                        "description": "Read back masked with X, which leaves it unchanged when saved.",
+                    }),
+                    FieldKind::Text => json!({ "type": "string" }),
+                    FieldKind::Table => json!({ "type": "string", "format": "toml" }),
+                };
+                let obj = schema.as_object_mut().expect("object");
+                obj.insert("title".to_string(), field.label.into());
+                if let Some(comment) = field.comment {
+                    obj.insert("description".to_string(), comment.into());
+                }
+                match (field.kind, &field.default) {
+                    (FieldKind::Checkbox, Some(default)) => {
+                        obj.insert("default".to_string(), (*default == "on").into());
+                    }
+                    (FieldKind::Text | FieldKind::Table, Some(default)) => {
+                        obj.insert("default".to_string(), default.clone());
+                    }
+                    (_, None) => {}
+                }
+                (field.key.to_string(), schema)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "type": "object",
+            "properties": properties,
+        })
+    }
+}
+
+#[test]
+fn test_schema_save_keeps_comments_and_masked_secrets() {
+    use std::str::FromStr;
+
+    let schema = SchemaConfigurable::new("test")
+        .field(SchemaField::text("client_id", "Client ID").comment("New comment"))
+        .field(SchemaField::text("client_secret", "Client Secret").secret())
+        .field(SchemaField::text("bind", "Bind").validate(|value| {
+            value
+                .parse::<std::net::SocketAddr>()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }));
+    let mut doc = toml_edit::Document::from_str(
+        "# Existing comment\nclient_id = \"1\"\nclient_secret = \"secret\"\n",
+    )
+    .unwrap();
+
+    let invalid = json!({ "client_id": "2", "client_secret": "XXXXXX", "bind": "nope" });
+    assert!(schema.save(&invalid, doc.as_table_mut()).is_err());
+    assert_eq!(doc["client_id"].as_str(), Some("1"));
+
+    let valid = json!({ "client_id": "2", "client_secret": "XXXXXX", "bind": "127.0.0.1:80" });
+    schema.save(&valid, doc.as_table_mut()).unwrap();
+    assert_eq!(doc["client_id"].as_str(), Some("2"));
+    assert_eq!(doc["client_secret"].as_str(), Some("secret"));
+    assert!(doc.to_string().contains("# Existing comment\nclient_id"));
+}
+
+#[cfg(test)]
+impl SchemaConfigurable {
+    pub(crate) fn keys(&self) -> std::collections::BTreeSet<&'static str> {
+        self.fields.iter().map(|field| field.key).collect()
+    }
+}
+
+/// Field names of a struct deriving `Deserialize`, to check a schema against the settings
+/// which read its file.
+#[cfg(test)]
+pub(crate) fn struct_fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    use serde::de::{self, Visitor};
+
+    struct Fields(Option<&'static [&'static str]>);
+
+    impl<'de> de::Deserializer<'de> for &mut Fields {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0 = Some(fields);
+            Err(de::Error::custom("only reading the fields"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields = Fields(None);
+    let _ = T::deserialize(&mut fields);
+    fields.0.expect("a struct")
+}
+
+#[test]
+fn test_table_fields_are_checked_and_keep_their_comments() {
+    use std::str::FromStr;
+
+    #[derive(Deserialize)]
+    #[allow(unused)]
+    struct Limits {
+        per_minute: u32,
+    }
+
+    let schema = SchemaConfigurable::new("test")
+        .field(SchemaField::table::<Limits>("limits", "Limits").comment("New comment"));
+    let mut doc =
+        toml_edit::Document::from_str("a = 1\n\n# Existing comment\n[limits]\nper_minute = 1\n")
+            .unwrap();
+
+    let section: Box<dyn toml_edit::TableLike> = Box::new(doc.as_table().clone());
+    let vars = schema.vars(&section).unwrap();
+    assert_eq!(vars["limits"], "per_minute = 1");
+
+    let invalid = json!({ "limits": "per_minute = \"often\"" });
+    assert!(schema.save(&invalid, doc.as_table_mut()).is_err());
+    assert_eq!(doc["limits"]["per_minute"].as_integer(), Some(1));
+
+    let valid = json!({ "limits": "per_minute = 2" });
+    schema.save(&valid, doc.as_table_mut()).unwrap();
+    assert_eq!(
+        doc.to_string(),
+        "a = 1\n\n# Existing comment\n[limits]\nper_minute = 2\n"
+    );
+
+    schema
+        .save(&json!({ "limits": "" }), doc.as_table_mut())
+        .unwrap();
+    assert_eq!(doc.to_string(), "a = 1\n");
+}
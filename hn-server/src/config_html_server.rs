@@ -1,44 +1,67 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use axum::routing::post;
-use axum::Extension;
-use axum::{extract::State, response::Html, routing::get, Router};
+use axum::{extract::Query, extract::State, response::Html, routing::get, Router};
+use axum::{Extension, Json};
 use axum_server::service::SendService;
 
 use minijinja::context;
 use tower_http::services::{ServeDir, ServeFile};
 use tower_http::trace::TraceLayer;
 
+use crate::app_server_plugins::{ConfigDiagnostics, PublicServerStatus};
 use crate::config::{Configurable, Settings};
 use crate::http::OrInternalError;
 use crate::{config, prelude::*};
+use hn_app::_ecs_::*;
 
+mod admin_api;
 pub mod app;
+mod auth;
 mod data_browser;
 mod dev_paths;
 pub mod discord;
-mod edit;
+pub(crate) mod edit;
 mod templates;
 
+/// Whether `/dev` is served, from `dev_mode` when the config server started.
+#[derive(Clone, Copy)]
+struct DevMode(bool);
+
 #[instrument(skip_all)]
 async fn get_root_path(
     templates: templates::Templates,
     config: State<Arc<config::Settings>>,
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(DevMode(dev_mode)): Extension<DevMode>,
+    Extension(session): Extension<auth::ConfigSession>,
 ) -> HttpResult {
     // let config_server_bind_address = "home(config_server_bind_address) value"; // &initial_app.config_server_bind_address;
+    let all_diagnostics = read_diagnostics(&app_ctx).await.err_500()?;
     let mut confs = Vec::<minijinja::value::Value>::new();
     for entry in config.entries() {
         let section_name = entry.configurable.section_name();
         let view_html = render_view_html(&templates, &entry, None).await?.0;
+        let diagnostics = all_diagnostics
+            .for_section(&section_name)
+            .collect::<Vec<_>>();
 
         confs.push(context! {
             section_name,
             view_html,
+            diagnostics,
         });
     }
 
     templates
-        .render("home.html.j2", context!(confs))
+        .render(
+            "home.html.j2",
+            context! {
+                confs,
+                dev_mode,
+                csrf_token => session.csrf_token,
+            },
+        )
         .err_500()
         .map(Html::from)
 }
@@ -107,6 +130,101 @@ async fn render_edit_html(
     ))
 }
 
+fn render_diff_html(
+    templates: &templates::Templates,
+    section_name: &str,
+    lines: Result<Vec<config::history::DiffLine>, JSON>,
+) -> HttpResult {
+    let (lines, err) = match lines {
+        Ok(lines) => (lines, None),
+        Err(err) => (Vec::new(), Some(err)),
+    };
+    Ok(Html(
+        templates
+            .render(
+                "setting-section-diff.html.j2",
+                context! {
+                    section_name,
+                    lines,
+                    err,
+                },
+            )
+            .err_500()?,
+    ))
+}
+
+async fn render_history_html(
+    templates: &templates::Templates,
+    entry: &config::SettingEntry<'_, '_>,
+) -> HttpResult {
+    let section_name = entry.configurable.section_name();
+    let versions = entry.history().await.err_500()?;
+
+    Ok(Html(
+        templates
+            .render(
+                "setting-section-history.html.j2",
+                context! {
+                    section_name,
+                    versions,
+                },
+            )
+            .err_500()?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct HistoryVersionParams {
+    version: String,
+}
+
+#[instrument(skip_all)]
+async fn get_public_server_status(
+    templates: templates::Templates,
+    Extension(app_ctx): Extension<AppCtx>,
+) -> HttpResult {
+    let (tx, rx) = tokio::sync::oneshot::channel::<PublicServerStatus>();
+    let tx = std::sync::Mutex::new(Some(tx));
+    app_ctx.run_system(
+        "get public server status",
+        move |uv_status: UniqueView<PublicServerStatus>| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(uv_status.clone());
+            }
+        },
+    );
+    let status = rx
+        .await
+        .context("receiving public server status")
+        .err_500()?;
+
+    templates
+        .render("public-server-status.html.j2", context!(status))
+        .err_500()
+        .map(Html::from)
+}
+
+async fn read_diagnostics(app_ctx: &AppCtx) -> Result<ConfigDiagnostics> {
+    let (tx, rx) = tokio::sync::oneshot::channel::<ConfigDiagnostics>();
+    let tx = std::sync::Mutex::new(Some(tx));
+    app_ctx.run_system(
+        "get config diagnostics",
+        move |uv_diagnostics: UniqueView<ConfigDiagnostics>| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(uv_diagnostics.clone());
+            }
+        },
+    );
+    rx.await.context("receiving config diagnostics")
+}
+
+#[instrument(skip_all)]
+async fn get_diagnostics(
+    Extension(app_ctx): Extension<AppCtx>,
+) -> HttpResult<Json<ConfigDiagnostics>> {
+    read_diagnostics(&app_ctx).await.err_500().map(Json)
+}
+
 fn setup_configurable(
     router: Router<Arc<Settings>>,
     c: Arc<Box<dyn Configurable>>,
@@ -114,6 +232,10 @@ fn setup_configurable(
     let section_name = c.section_name();
     let edit_path = &format!("/;edit-{section_name}");
     let view_path = &format!("/;view-{section_name}");
+    let preview_path = &format!("/;preview-{section_name}");
+    let history_path = &format!("/;history-{section_name}");
+    let history_diff_path = &format!("/;history-diff-{section_name}");
+    let rollback_path = &format!("/;rollback-{section_name}");
 
     router
         // GET edit form
@@ -168,6 +290,64 @@ fn setup_configurable(
                 }
             }),
         )
+        // POST values to see what saving them would change
+        .route(
+            preview_path,
+            post({
+                let section_name = section_name.clone();
+                move |templates: templates::Templates, config: State<Arc<config::Settings>>, params: axum::Form<serde_json::Value>| async move {
+                    let entry = config.get_entry(&section_name)
+                        .with_context(|| format!("section {section_name:?} not found"))
+                        .err_400()?;
+                    let fn_result = entry.preview_with(&params.0).await.err_500()?;
+                    render_diff_html(&templates, &section_name, fn_result)
+                }
+            }),
+        )
+        // GET earlier versions of the file
+        .route(
+            history_path,
+            get({
+                let section_name = section_name.clone();
+                move |templates: templates::Templates, config: State<Arc<config::Settings>>| async move {
+                    let entry = config.get_entry(&section_name)
+                        .with_context(|| format!("section {section_name:?} not found"))
+                        .err_400()?;
+                    let resp: HttpResult = render_history_html(&templates, &entry).await;
+                    resp
+                }
+            }),
+        )
+        // GET what rolling back to a version would change
+        .route(
+            history_diff_path,
+            get({
+                let section_name = section_name.clone();
+                move |templates: templates::Templates, config: State<Arc<config::Settings>>, Query(params): Query<HistoryVersionParams>| async move {
+                    let entry = config.get_entry(&section_name)
+                        .with_context(|| format!("section {section_name:?} not found"))
+                        .err_400()?;
+                    let lines = entry.history_diff(&params.version).await.err_400()?;
+                    render_diff_html(&templates, &section_name, Ok(lines))
+                }
+            }),
+        )
+        // POST write an earlier version back, which the config watcher then reloads
+        .route(
+            rollback_path,
+            post({
+                let section_name = section_name.clone();
+                move |templates: templates::Templates, config: State<Arc<config::Settings>>, params: axum::Form<HistoryVersionParams>| async move {
+                    let entry = config.get_entry(&section_name)
+                        .with_context(|| format!("section {section_name:?} not found"))
+                        .err_400()?;
+                    let updated = entry.rollback(&params.version).await.err_500()?;
+                    let ok = JSON::String(format!("Rolled back to version {}", params.version));
+                    let resp: HttpResult = render_view_html(&templates, &entry, Some((ok, updated))).await;
+                    resp
+                }
+            }),
+        )
 }
 
 pub async fn start(config: Arc<config::Settings>, app_ctx: AppCtx) -> Result<()> {
@@ -183,7 +363,7 @@ pub async fn start(config: Arc<config::Settings>, app_ctx: AppCtx) -> Result<()>
     let config_server_bind_address = initial_app
         .config_server_bind_address
         .clone()
-        .unwrap_or_else(|| "0.0.0.0:3000".to_string());
+        .unwrap_or_else(|| "127.0.0.1:3000".to_string());
 
     let addr: SocketAddr = config_server_bind_address.parse().with_context(|| {
         format!("parsing config_server_bind_address {config_server_bind_address:?}")
@@ -197,13 +377,18 @@ pub async fn start(config: Arc<config::Settings>, app_ctx: AppCtx) -> Result<()>
     };
 
     // build our application with a single route
-    let mut app = Router::<Arc<Settings>>::new().route("/", get(get_root_path));
+    let mut app = Router::<Arc<Settings>>::new()
+        .route("/", get(get_root_path))
+        .route("/;public-server-status", get(get_public_server_status))
+        .route("/;diagnostics", get(get_diagnostics));
     let templates = templates::Templates::new(templates_dir, initial_app.dev_mode.unwrap_or(true));
 
     #[allow(deprecated)]
     for conf in config.configurables().cloned() {
         app = setup_configurable(app, conf);
     }
+    app = auth::add_login_routes(app);
+    let dev_mode = initial_app.dev_mode == Some(true);
 
     let app = app
         .fallback_service(
@@ -214,21 +399,67 @@ pub async fn start(config: Arc<config::Settings>, app_ctx: AppCtx) -> Result<()>
         .layer(TraceLayer::new_for_http().make_span_with(|request: &http::Request<_>| {
             info_span!("config-request", method = %request.method(), uri = %request.uri())
         }))
-        .layer(templates.axum_layer())
+        .layer(templates.clone().axum_layer())
         .with_state::<()>(config.clone());
 
-    tracing::warn!("Config server starting on http://{addr}");
+    // unlike the public server, the config server only picks up new certificates on restart
+    let tls_pems = initial_app
+        .tls
+        .read_pems(config.config_files_directory())
+        .context("reading tls certificate for config server")?;
+    let scheme = if tls_pems.is_some() { "https" } else { "http" };
+    tracing::warn!("Config server starting on {scheme}://{addr}");
 
-    let app = Router::<Arc<Settings>>::new()
-        .nest("/dev", dev_paths::create_dev_router())
-        .nest("/data", data_browser::create_data_browser_router(app_ctx))
+    let has_admin_login = initial_app
+        .admin_password
+        .as_deref()
+        .map_or(false, |password| !password.is_empty())
+        || initial_app
+            .admin_device_keys
+            .as_deref()
+            .map_or(false, |keys| !keys.trim().is_empty());
+    // like a notebook server, the first login is by a link only shown to whoever started it
+    let setup_token = (!has_admin_login).then(auth::random_token);
+    if let Some(ref token) = setup_token {
+        tracing::warn!(
+            "No admin_password is set for the config server, log in at {scheme}://{addr}/;login?token={token}"
+        );
+    }
+    let auth = Arc::new(auth::ConfigAuth::new(setup_token, tls_pems.is_some()));
+
+    let mut outer = Router::<Arc<Settings>>::new();
+    if dev_mode {
+        // opens files and proxies traces, so only when asked for
+        outer = outer.nest("/dev", dev_paths::create_dev_router());
+    }
+    let app = outer
+        .nest(
+            "/data",
+            data_browser::create_data_browser_router(app_ctx, templates),
+        )
+        .nest("/api", admin_api::create_admin_api_router())
         // divided up so we don't trace the requests to the dev server
         .fallback_service(app.into_service())
+        .layer(axum::middleware::from_fn_with_state(
+            auth.clone(),
+            auth::require_session,
+        ))
+        .layer(Extension(auth))
+        .layer(Extension(DevMode(dev_mode)))
         .with_state(config.clone());
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    match tls_pems {
+        Some(pems) => {
+            axum_server::bind_rustls(addr, pems.rustls_config().await?)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,238 @@
+//! JSON versions of the config pages, for scripting changes like provisioning Discord
+//! credentials, described at `/api/openapi.json`.
+//!
+//! Requests need a session like the pages do, e.g. from `/;login/device`, and `PUT` also needs
+//! its CSRF token in `X-CSRF-Token`.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+use serde_json::json;
+
+use super::auth;
+use crate::{config::Settings, http::OrInternalError, prelude::*};
+
+pub(super) fn create_admin_api_router() -> Router<Arc<Settings>> {
+    Router::new()
+        .route("/openapi.json", get(get_openapi))
+        .route("/settings", get(get_sections))
+        .route("/settings/:section", get(get_section).put(put_section))
+}
+
+#[instrument(skip_all)]
+async fn get_sections(config: State<Arc<Settings>>) -> Json<Vec<String>> {
+    Json(
+        config
+            .entries()
+            .map(|entry| entry.configurable.section_name().into_owned())
+            .collect(),
+    )
+}
+
+/// [crate::config::Configurable::vars], with `overrides` like the edit forms get.
+#[instrument(skip(config))]
+async fn get_section(
+    config: State<Arc<Settings>>,
+    Path(section): Path<String>,
+) -> HttpResult<Json<JSON>> {
+    let entry = config
+        .get_entry(&section)
+        .with_context(|| format!("section {section:?} not found"))
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+    entry.get_view_json().await.err_500().map(Json)
+}
+
+/// Fields left out keep their value, and the errors are the ones the edit form shows.
+#[instrument(skip(config, body))]
+async fn put_section(
+    config: State<Arc<Settings>>,
+    Path(section): Path<String>,
+    Json(body): Json<JSON>,
+) -> HttpResult<Response> {
+    let entry = config
+        .get_entry(&section)
+        .with_context(|| format!("section {section:?} not found"))
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+    let body = body
+        .as_object()
+        .context("expected an object of settings")
+        .err_400()?;
+
+    let schema = entry.configurable.json_schema();
+    if let Some(properties) = schema.get("properties").and_then(JSON::as_object) {
+        let unknown = body
+            .keys()
+            .filter(|key| !properties.contains_key(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Ok(unprocessable(json!(format!(
+                "unknown settings: {}",
+                unknown.join(", ")
+            ))));
+        }
+    }
+
+    let current = entry.get_view_json().await.err_500()?;
+    let fields = match form_values(&current, body) {
+        Ok(fields) => fields,
+        Err(err) => return Ok(unprocessable(json!(err))),
+    };
+    let (ok, updated) = match entry.save_with(&fields).await.err_500()? {
+        Ok(ok_updated) => ok_updated,
+        Err(err) => return Ok(unprocessable(err)),
+    };
+    let settings = entry.get_view_json().await.err_500()?;
+
+    Ok(Json(json!({
+        "ok": ok,
+        "updated": updated,
+        "settings": settings,
+    }))
+    .into_response())
+}
+
+fn unprocessable(error: JSON) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(json!({ "error": error })),
+    )
+        .into_response()
+}
+
+/// `save` takes what a form would submit, so the current values are submitted along with
+/// the body, booleans become checkbox values, numbers become text, and objects for tables
+/// like `tls` become TOML.
+fn form_values(current: &JSON, body: &serde_json::Map<String, JSON>) -> Result<JSON, String> {
+    let mut fields = serde_json::Map::new();
+    let current = current.as_object().into_iter().flatten();
+    for (key, value) in current.chain(body.iter()) {
+        let value = match value {
+            JSON::String(_) => value.clone(),
+            JSON::Bool(true) => JSON::from("on"),
+            JSON::Number(number) => JSON::from(number.to_string()),
+            // unchecked boxes aren't submitted
+            JSON::Bool(false) | JSON::Null => {
+                fields.remove(key);
+                continue;
+            }
+            // the body only has keys from the schema
+            JSON::Object(table) if body.contains_key(key) => {
+                JSON::from(toml_edit::ser::to_string(table).map_err(|err| format!("{key}: {err}"))?)
+            }
+            // `fields`, `overrides`, and other template values
+            JSON::Array(_) | JSON::Object(_) => continue,
+        };
+        fields.insert(key.clone(), value);
+    }
+    Ok(JSON::Object(fields))
+}
+
+#[instrument(skip_all)]
+async fn get_openapi(config: State<Arc<Settings>>) -> Json<JSON> {
+    Json(openapi(&config))
+}
+
+/// OpenAPI 3 description of each registered section's endpoints.
+fn openapi(settings: &Settings) -> JSON {
+    let mut paths = serde_json::Map::new();
+    let mut schemas = serde_json::Map::new();
+    for entry in settings.entries() {
+        let section = entry.configurable.section_name();
+        let schema_ref = json!({ "$ref": format!("#/components/schemas/{section}") });
+        schemas.insert(section.to_string(), entry.configurable.json_schema());
+        paths.insert(
+            format!("/api/settings/{section}"),
+            json!({
+                "get": {
+                    "summary": format!("Current {section} settings, secrets masked"),
+                    "operationId": format!("get-{section}"),
+                    "responses": {
+                        "200": {
+                            "description": "Settings, with `overrides` naming where overridden keys were set",
+                            "content": { "application/json": { "schema": schema_ref } },
+                        },
+                    },
+                },
+                "put": {
+                    "summary": format!("Save {section} settings, fields left out keep their value"),
+                    "operationId": format!("put-{section}"),
+                    "security": [{ "session": [], "csrf": [] }],
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": schema_ref } },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Saved, `updated` is whether the file changed",
+                            "content": { "application/json": { "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "ok": {},
+                                    "updated": { "type": "boolean" },
+                                    "settings": schema_ref,
+                                },
+                            } } },
+                        },
+                        "422": {
+                            "description": "Invalid settings, nothing was saved",
+                            "content": { "application/json": { "schema": {
+                                "type": "object",
+                                "properties": { "error": {} },
+                            } } },
+                        },
+                    },
+                },
+            }),
+        );
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Here Now config admin API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "security": [{ "session": [] }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": auth::SESSION_COOKIE },
+                "csrf": { "type": "apiKey", "in": "header", "name": auth::CSRF_HEADER },
+            },
+        },
+    })
+}
+
+#[test]
+fn test_form_values_and_openapi() {
+    let current = json!({
+        "client_id": "1",
+        "client_secret": "XXXXXX",
+        "dev_mode": true,
+        "fields": [],
+        "overrides": {},
+    });
+    let body = json!({ "client_id": 2, "dev_mode": false, "tls": { "mode": "self-signed" } });
+    assert_eq!(
+        form_values(&current, body.as_object().unwrap()),
+        Ok(json!({
+            "client_id": "2",
+            "client_secret": "XXXXXX",
+            "tls": "mode = \"self-signed\"\n",
+        }))
+    );
+
+    let settings =
+        Settings::new(std::path::PathBuf::new()).with(super::discord::discord_settings());
+    let openapi = openapi(&settings);
+    let discord = &openapi["components"]["schemas"]["discord"];
+    assert_eq!(discord["properties"]["client_secret"]["format"], "password");
+    assert!(openapi["paths"]["/api/settings/discord"]["put"].is_object());
+}
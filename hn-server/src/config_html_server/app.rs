@@ -1,8 +1,8 @@
-use serde_json::json;
-use toml_edit::{Formatted, Item, Value};
-
-use super::{edit, Configurable};
+use crate::app_server_plugins::RateLimitSettings;
+use crate::config::schema::{SchemaConfigurable, SchemaField};
+use crate::ecs::import_export::plugin::{AuditConfig, DatabaseSettings};
 use crate::prelude::*;
+use crate::tls::TlsSettings;
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct AppConfiguration {
@@ -14,112 +14,129 @@ pub struct AppConfiguration {
     /// e.g. `"0.0.0.0:8001"`
     pub config_server_bind_address: Option<String>,
     pub dev_mode: Option<bool>,
+    /// Hashed when saved from the config server, see [super::auth]
+    pub admin_password: Option<String>,
+    /// Base64 public keys of devices which can log in to the config server
+    pub admin_device_keys: Option<String>,
+    /// `[tls]` table, shared with the public server.
+    #[serde(default)]
+    pub tls: TlsSettings,
 }
 
-#[derive(Debug)]
-pub struct AppSettings;
-
-impl Configurable for AppSettings {
-    fn template(&self) -> HTMXPartial {
-        htmx_partial!("app.configurable.html.j2")
-    }
-
-    fn section_name(&self) -> Cow<'static, str> {
-        "here-now-app".into()
-    }
+pub fn app_settings() -> SchemaConfigurable {
+    SchemaConfigurable::new("here-now-app")
+        .template(htmx_partial!("app.configurable.html.j2"))
+        .field(
+            SchemaField::text("public_bind_address", "Public Bind Address")
+                .help("This is where the server will bind to when it starts, like <code>127.0.0.1:8000</code>.")
+                .comment("Where the public interface binds like `127.0.0.1`.")
+                .default("0.0.0.0:9000")
+                .normalize(bind_address)
+                .validate(socket_address),
+        )
+        .field(
+            SchemaField::text("public_host_base_url", "Public Host Base URL")
+                .help("This is where the server will be accessible for logging in, client calls, and where webhooks from Discord and Slack will access.")
+                .comment("Where the public interface will be accessed on the internet.")
+                .default("http://localhost:9000")
+                .normalize(|value| value.trim_end_matches('/').to_string())
+                .validate(|value| {
+                    if value.starts_with("http://") || value.starts_with("https://") {
+                        Ok(())
+                    } else {
+                        Err("expected a URL starting with http:// or https://".to_string())
+                    }
+                }),
+        )
+        .field(
+            SchemaField::text("config_server_bind_address", "Config server bind address")
+                .comment("Where this interface binds, only reachable from this machine by default.")
+                .default("127.0.0.1:3000")
+                .normalize(bind_address)
+                .validate(socket_address),
+        )
+        .field(
+            SchemaField::checkbox("dev_mode", "Dev mode")
+                .help("Also serves <code>/dev</code> from the config server after a restart.")
+                .comment("Whether or not to enable dev features of the server."),
+        )
+        .field(
+            SchemaField::checkbox("public_metrics", "Public metrics")
+                .help("Serves Prometheus <code>/metrics</code> from the public server. It isn't authenticated, so only enable it when that port isn't reachable from the internet.")
+                .comment("Serve Prometheus `/metrics` on the public interface, it isn't authenticated."),
+        )
+        .field(
+            SchemaField::text("admin_password", "Admin password")
+                .help("Required to log in to this page. Until it's set, log in with the link printed when the server starts.")
+                .comment("Hashed password for logging in to the config server.")
+                .secret()
+                .normalize(super::auth::hash_admin_password),
+        )
+        .field(
+            SchemaField::text("admin_device_keys", "Admin device keys")
+                .help("Public keys of devices which can log in by signing a challenge from <code>/;login/challenge</code>, separated by commas.")
+                .comment("Public keys of devices which can log in to the config server."),
+        )
+        .field(
+            SchemaField::table::<TlsSettings>("tls", "TLS")
+                .help("Like <code>mode = \"self-signed\"</code>, or <code>mode = \"files\"</code> with <code>cert_path</code> and <code>key_path</code>. Empty serves plain http.")
+                .comment("TLS for the public server, `mode` is \"off\", \"files\", or \"self-signed\"."),
+        )
+        .field(
+            SchemaField::table::<RateLimitSettings>("rate_limit", "Rate limits")
+                .help("Tables like <code>[per_ip]</code> with <code>per_minute</code> and <code>burst</code>. Empty uses the defaults.")
+                .comment("Limits on requests to the public server."),
+        )
+        .field(
+            SchemaField::table::<DatabaseSettings>("database", "Database")
+                .help("<code>path</code> relative to this folder, and <code>idle_device_minutes</code> to load devices only when they're used.")
+                .comment("Where the database is kept, and when devices are loaded."),
+        )
+        .field(
+            SchemaField::table::<AuditConfig>("audit", "Audit log")
+                .help("<code>enabled</code> and <code>retention_days</code> for the log of saved changes.")
+                .comment("Recording changes saved to the database."),
+        )
+}
 
-    #[instrument(skip_all)]
-    fn vars(&self, toml: &Box<dyn toml_edit::TableLike>) -> Result<JSON> {
-        let public_bind_address = toml
-            .get("public_bind_address")
-            .map(|item| item.as_str())
-            .unwrap_or_default();
-        let public_host_base_url = toml
-            .get("public_host_base_url")
-            .map(|item| item.as_str())
-            .unwrap_or_default();
-        let config_server_bind_address = toml
-            .get("config_server_bind_address")
-            .map(|item| item.as_str())
-            .unwrap_or_default();
-        let dev_mode = toml
-            .get("dev_mode")
-            .map(|item| item.as_bool())
-            .unwrap_or_default();
-        // How to get the comments surrounding the code:
-        // toml.key_decor("dev_mode")
-        // dbg!(&dev_mode);
+fn bind_address(value: &str) -> String {
+    value
+        .trim_start_matches("http://")
+        .trim_end_matches('/')
+        .to_string()
+}
 
-        Ok(json!({
-            "public_bind_address": public_bind_address,
-            "public_host_base_url": public_host_base_url,
-            "config_server_bind_address": config_server_bind_address,
-            "dev_mode": dev_mode,
-        }))
-    }
+fn socket_address(value: &str) -> Result<(), String> {
+    value
+        .parse::<std::net::SocketAddr>()
+        .map(|_| ())
+        .map_err(|err| format!("expected an address like 0.0.0.0:8000 ({err})"))
+}
 
-    fn save(
-        &self,
-        json: &serde_json::Value,
-        toml: &mut dyn toml_edit::TableLike,
-    ) -> Result<JSON, JSON> {
-        let public_bind_address = json
-            .get("public_bind_address")
-            .and_then(|a| a.as_str())
-            .unwrap_or_default()
-            .trim()
-            .trim_start_matches("http://")
-            .trim_end_matches('/');
-        let public_host_base_url = json
-            .get("public_host_base_url")
-            .and_then(|a| a.as_str())
-            .unwrap_or_default()
-            .trim()
-            .trim_end_matches('/');
-        let config_server_bind_address = json
-            .get("config_server_bind_address")
-            .and_then(|a| a.as_str())
-            .unwrap_or_default()
-            .trim()
-            .trim_start_matches("http://")
-            .trim_end_matches('/');
-        let dev_mode = json
-            .get("dev_mode")
-            .and_then(|a| a.as_str())
-            .map(|str| str == "on")
-            .unwrap_or_default();
+#[test]
+fn test_schema_keys_are_read_by_the_settings() {
+    use crate::app_server_plugins::AppServerSettings;
+    use crate::config::{schema::struct_fields, Configurable};
+    use crate::ecs::import_export::plugin::DatabaseTable;
 
-        edit::update_toml_key(
-            toml,
-            "public_bind_address",
-            Item::Value(Value::String(Formatted::new(public_bind_address.into()))),
-            Some("Where the public interface binds like `127.0.0.1`.".to_string()),
-            false,
-        );
-        edit::update_toml_key(
-            toml,
-            "public_host_base_url",
-            Item::Value(Value::String(Formatted::new(public_host_base_url.into()))),
-            Some("Where the public interface will be accessed on the internet.".to_string()),
-            false,
-        );
-        edit::update_toml_key(
-            toml,
-            "config_server_bind_address",
-            Item::Value(Value::String(Formatted::new(
-                config_server_bind_address.into(),
-            ))),
-            Some("Where this interface binds.".to_string()),
-            false,
-        );
-        edit::update_toml_key(
-            toml,
-            "dev_mode",
-            Item::Value(Value::Boolean(Formatted::new(dev_mode.into()))),
-            Some("Whether or not to enable dev features of the server.".to_string()),
-            false,
-        );
+    let schema = app_settings();
+    // each reads the parts of `here-now-app.toml` it needs
+    let read: std::collections::BTreeSet<_> = [
+        struct_fields::<AppConfiguration>(),
+        struct_fields::<AppServerSettings>(),
+        struct_fields::<DatabaseTable>(),
+    ]
+    .concat()
+    .into_iter()
+    .collect();
+    assert_eq!(schema.keys(), read);
 
-        Ok(json!({ "saved": true }))
-    }
+    let mut doc = toml_edit::Document::new();
+    let mut fields = schema.default_fields();
+    fields["tls"] = "mode = \"self-signed\"".into();
+    fields["database"] = "idle_device_minutes = 30".into();
+    schema.save(&fields, doc.as_table_mut()).unwrap();
+    toml_edit::de::from_document::<AppConfiguration>(doc.clone()).unwrap();
+    toml_edit::de::from_document::<AppServerSettings>(doc.clone()).unwrap();
+    toml_edit::de::from_document::<DatabaseTable>(doc).unwrap();
 }
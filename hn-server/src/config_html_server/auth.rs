@@ -0,0 +1,610 @@
+//! Admin login for the config server, by the `admin_password` of `here-now-app.toml` or by a
+//! device whose key is listed in `admin_device_keys`.
+//!
+//! Logging in sets a session cookie, and every request other than GET must also send the
+//! session's CSRF token in the `X-CSRF-Token` header, which `home.html.j2` adds to all htmx
+//! requests through `hx-headers`.
+//!
+//! Repeated failed password (or setup token) logins lock them for every client, with a
+//! doubling lockout up to [MAX_LOGIN_LOCKOUT]. That's on purpose: there's one admin, the config
+//! server only listens on this machine by default, and behind a proxy every client would share
+//! an IP anyway, while keying by IP would let guesses from many addresses through. Someone
+//! locking out the admin this way doesn't lock them out of the server, since device logins
+//! through `/;login/device` aren't counted or locked.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use minijinja::context;
+use rand::RngCore;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use super::{app::AppConfiguration, templates};
+use crate::{config::Settings, http::OrInternalError, prelude::*};
+
+pub(super) const SESSION_COOKIE: &str = "hn_config_session";
+pub(super) const CSRF_HEADER: &str = "x-csrf-token";
+const SESSION_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
+const CHALLENGE_DURATION: Duration = Duration::from_secs(5 * 60);
+/// Slows down guessing, there's only one admin so no per-client limit
+const FAILED_LOGIN_DELAY: Duration = Duration::from_millis(500);
+/// Failed logins in a row before logins are locked, for a doubling time up to
+/// [MAX_LOGIN_LOCKOUT]
+const FAILED_LOGINS_BEFORE_LOCKOUT: u32 = 5;
+const MAX_LOGIN_LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+const PASSWORD_HASH_PREFIX: &str = "pbkdf2-sha256$";
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+
+/// Reachable without a session.
+const PUBLIC_PATHS: &[&str] = &[
+    "/;login",
+    "/;login/challenge",
+    "/;login/device",
+    "/config-html-server.css",
+    "/duckyhn.png",
+    "/unpkg.com_htmx.org@1.9.2_dist_htmx.min.js",
+];
+
+pub(super) struct ConfigAuth {
+    /// Signs device challenges, new on each start like the public server's keys
+    local_keys: hn_keys::LocalKeys,
+    /// Logs in while `admin_password` isn't set, printed on start
+    setup_token: Option<String>,
+    secure_cookie: bool,
+    sessions: Mutex<HashMap<String, ConfigSession>>,
+    challenges: Mutex<HashMap<String, Instant>>,
+    /// Held while checking a password, so attempts from every client are counted in order,
+    /// shared by all clients, see the module docs
+    login_backoff: tokio::sync::Mutex<LoginBackoff>,
+}
+
+/// Failed password and setup token logins, counted for all clients together.
+#[derive(Debug, Default)]
+struct LoginBackoff {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl LoginBackoff {
+    /// How long until logins are allowed again.
+    fn locked_for(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        if let Some(over) = self.failures.checked_sub(FAILED_LOGINS_BEFORE_LOCKOUT) {
+            let lockout = FAILED_LOGIN_DELAY
+                .saturating_mul(2u32.saturating_pow(over))
+                .min(MAX_LOGIN_LOCKOUT);
+            self.locked_until = Some(now + lockout);
+        }
+    }
+}
+
+/// Added to the extensions of requests with a valid session.
+#[derive(Clone, Debug)]
+pub(super) struct ConfigSession {
+    pub csrf_token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeviceChallenge {
+    challenge: String,
+    public_key: hn_keys::PublicKeyKind,
+}
+
+/// Sent encrypted by the device to `/;login/device`.
+#[derive(Deserialize)]
+struct DeviceLogin {
+    challenge: String,
+}
+
+#[derive(Serialize)]
+struct DeviceLoginResponse {
+    csrf_token: String,
+}
+
+impl ConfigAuth {
+    pub(super) fn new(setup_token: Option<String>, secure_cookie: bool) -> Self {
+        ConfigAuth {
+            local_keys: hn_keys::init(),
+            setup_token,
+            secure_cookie,
+            sessions: Default::default(),
+            challenges: Default::default(),
+            login_backoff: Default::default(),
+        }
+    }
+
+    fn session_from_headers(&self, headers: &HeaderMap) -> Option<ConfigSession> {
+        let token = cookie_value(headers, SESSION_COOKIE)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(token) {
+            Some(session) if session.expires_at > Instant::now() => Some(session.clone()),
+            Some(_) => {
+                sessions.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the `Set-Cookie` value and the session's CSRF token.
+    fn start_session(&self) -> (HeaderValue, String) {
+        let token = random_token();
+        let session = ConfigSession {
+            csrf_token: random_token(),
+            expires_at: Instant::now() + SESSION_DURATION,
+        };
+        let csrf_token = session.csrf_token.clone();
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(token.clone(), session);
+
+        let secure = if self.secure_cookie { "; Secure" } else { "" };
+        let cookie = format!(
+            "{SESSION_COOKIE}={token}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{secure}",
+            SESSION_DURATION.as_secs()
+        );
+        (
+            HeaderValue::from_str(&cookie).expect("cookie is ascii"),
+            csrf_token,
+        )
+    }
+
+    fn end_session(&self, headers: &HeaderMap) {
+        if let Some(token) = cookie_value(headers, SESSION_COOKIE) {
+            self.sessions.lock().unwrap().remove(token);
+        }
+    }
+
+    fn new_challenge(&self) -> String {
+        let challenge = random_token();
+        let mut challenges = self.challenges.lock().unwrap();
+        let now = Instant::now();
+        challenges.retain(|_, expires_at| *expires_at > now);
+        challenges.insert(challenge.clone(), now + CHALLENGE_DURATION);
+        challenge
+    }
+
+    /// Each challenge can only be used once.
+    fn take_challenge(&self, challenge: &str) -> bool {
+        let expires_at = self.challenges.lock().unwrap().remove(challenge);
+        expires_at.map_or(false, |expires_at| expires_at > Instant::now())
+    }
+
+    /// Checks the password (or setup token) unless logins are locked after too many failures,
+    /// in which case the time until they're allowed again is returned.
+    async fn attempt_login(
+        &self,
+        admin: &AppConfiguration,
+        attempt: &str,
+    ) -> Result<bool, Duration> {
+        let mut backoff = self.login_backoff.lock().await;
+        if let Some(locked_for) = backoff.locked_for(Instant::now()) {
+            return Err(locked_for);
+        }
+        let stored = admin
+            .admin_password
+            .clone()
+            .filter(|stored| !stored.is_empty());
+        let setup_token = self.setup_token.clone();
+        let attempt = attempt.to_string();
+        // hashing takes long enough to hold up other requests on this thread
+        let correct = tokio::task::spawn_blocking(move || {
+            check_password(stored.as_deref(), setup_token.as_deref(), &attempt)
+        })
+        .await
+        .unwrap_or_else(|err| {
+            error!(?err, "checking config server password");
+            false
+        });
+        if correct {
+            *backoff = LoginBackoff::default();
+            return Ok(true);
+        }
+        backoff.failed(Instant::now());
+        // still holding the lock, so concurrent guesses wait too
+        tokio::time::sleep(FAILED_LOGIN_DELAY).await;
+        Ok(false)
+    }
+}
+
+/// Against the setup token while `admin_password` isn't set.
+fn check_password(stored: Option<&str>, setup_token: Option<&str>, attempt: &str) -> bool {
+    match stored {
+        Some(stored) => verify_admin_password(stored, attempt),
+        None => setup_token.map_or(false, |token| constant_eq(token, attempt)),
+    }
+}
+
+pub(super) fn add_login_routes(router: Router<Arc<Settings>>) -> Router<Arc<Settings>> {
+    router
+        .route("/;login", get(get_login).post(post_login))
+        .route("/;logout", post(post_logout))
+        .route("/;login/challenge", get(get_device_challenge))
+        .route("/;login/device", post(post_device_login))
+}
+
+/// Sends requests without a session to the login page, and rejects changes without the
+/// session's CSRF token.
+pub(super) async fn require_session<B>(
+    State(auth): State<Arc<ConfigAuth>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if PUBLIC_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let Some(session) = auth.session_from_headers(req.headers()) else {
+        return login_required(&req);
+    };
+
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        let sent = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if !sent.map_or(false, |sent| constant_eq(sent, &session.csrf_token)) {
+            warn!(method = %req.method(), uri = %req.uri(), "rejected config request without csrf token");
+            return (StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response();
+        }
+    }
+
+    req.extensions_mut().insert(session);
+    next.run(req).await
+}
+
+fn login_required<B>(req: &Request<B>) -> Response {
+    if req.headers().contains_key("hx-request") {
+        // htmx would swap a redirected page into the section
+        return (
+            StatusCode::UNAUTHORIZED,
+            [("hx-redirect", "/;login")],
+            "Login required",
+        )
+            .into_response();
+    }
+    // scripts using the admin API want the status rather than the login page
+    if req.method() == Method::GET && !req.uri().path().starts_with("/api/") {
+        return (StatusCode::SEE_OTHER, [(header::LOCATION, "/;login")]).into_response();
+    }
+    (StatusCode::UNAUTHORIZED, "Login required").into_response()
+}
+
+async fn read_admin_settings(settings: &Settings) -> Result<AppConfiguration> {
+    Ok(settings
+        .get_entry("here-now-app")
+        .context("app settings section")?
+        .get_toml_and_parse::<AppConfiguration>()
+        .await
+        .context("reading admin settings")?
+        .unwrap_or_default())
+}
+
+fn render_login(
+    templates: &templates::Templates,
+    auth: &ConfigAuth,
+    error: Option<&str>,
+) -> HttpResult {
+    templates
+        .render(
+            "login.html.j2",
+            context! {
+                error,
+                setup => auth.setup_token.is_some(),
+            },
+        )
+        .err_500()
+        .map(Html::from)
+}
+
+#[instrument(skip_all)]
+async fn get_login(
+    templates: templates::Templates,
+    config: State<Arc<Settings>>,
+    Extension(auth): Extension<Arc<ConfigAuth>>,
+    Query(LoginQuery { token }): Query<LoginQuery>,
+) -> HttpResult<Response> {
+    // the link printed on start
+    if let Some(token) = token {
+        let admin = read_admin_settings(&config).await.err_500()?;
+        return match auth.attempt_login(&admin, &token).await {
+            Ok(true) => {
+                let (cookie, _csrf_token) = auth.start_session();
+                Ok(logged_in_redirect(cookie))
+            }
+            Ok(false) => render_login(&templates, &auth, Some("That login link has expired."))
+                .map(|html| (StatusCode::UNAUTHORIZED, html).into_response()),
+            Err(locked_for) => render_locked_login(&templates, &auth, locked_for),
+        };
+    }
+    render_login(&templates, &auth, None).map(IntoResponse::into_response)
+}
+
+#[instrument(skip_all)]
+async fn post_login(
+    templates: templates::Templates,
+    config: State<Arc<Settings>>,
+    Extension(auth): Extension<Arc<ConfigAuth>>,
+    axum::Form(LoginForm { password }): axum::Form<LoginForm>,
+) -> HttpResult<Response> {
+    let admin = read_admin_settings(&config).await.err_500()?;
+    match auth.attempt_login(&admin, &password).await {
+        Ok(true) => {
+            let (cookie, _csrf_token) = auth.start_session();
+            Ok(logged_in_redirect(cookie))
+        }
+        Ok(false) => {
+            warn!("failed config server login");
+            render_login(&templates, &auth, Some("Incorrect password."))
+                .map(|html| (StatusCode::UNAUTHORIZED, html).into_response())
+        }
+        Err(locked_for) => render_locked_login(&templates, &auth, locked_for),
+    }
+}
+
+fn render_locked_login(
+    templates: &templates::Templates,
+    auth: &ConfigAuth,
+    locked_for: Duration,
+) -> HttpResult<Response> {
+    let secs = locked_for.as_secs_f64().ceil() as u64;
+    warn!(secs, "config server login attempted while locked");
+    let error = format!(
+        "Too many failed logins, try again in {secs} seconds or log in with an admin device."
+    );
+    render_login(templates, auth, Some(&error)).map(|html| {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, secs.to_string())],
+            html,
+        )
+            .into_response()
+    })
+}
+
+fn logged_in_redirect(cookie: HeaderValue) -> Response {
+    (
+        StatusCode::SEE_OTHER,
+        [
+            (header::SET_COOKIE, cookie),
+            (header::LOCATION, HeaderValue::from_static("/")),
+        ],
+    )
+        .into_response()
+}
+
+#[instrument(skip_all)]
+async fn post_logout(Extension(auth): Extension<Arc<ConfigAuth>>, headers: HeaderMap) -> Response {
+    auth.end_session(&headers);
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0");
+    (
+        [
+            (header::SET_COOKIE.as_str(), cookie.as_str()),
+            ("hx-redirect", "/;login"),
+        ],
+        "Logged out",
+    )
+        .into_response()
+}
+
+#[instrument(skip_all)]
+async fn get_device_challenge(
+    Extension(auth): Extension<Arc<ConfigAuth>>,
+) -> Json<DeviceChallenge> {
+    Json(DeviceChallenge {
+        challenge: auth.new_challenge(),
+        public_key: auth.local_keys.public_key().clone(),
+    })
+}
+
+/// The body is a [hn_keys::net::WireMessage] of [DeviceLogin] sent to the challenge's
+/// `public_key`, from a key listed in `admin_device_keys`.
+#[instrument(skip_all)]
+async fn post_device_login(
+    config: State<Arc<Settings>>,
+    Extension(auth): Extension<Arc<ConfigAuth>>,
+    body: axum::body::Bytes,
+) -> HttpResult<Response> {
+    let wire_message = hn_keys::net::WireMessage::from_bytes(&body).err_400()?;
+    let message = auth
+        .local_keys
+        .recv::<DeviceLogin>(&wire_message)
+        .map_err(|err| (StatusCode::UNAUTHORIZED, format!("Bad Signature: {err:?}")))?;
+    if !auth.take_challenge(&message.data().challenge) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Unknown or expired challenge".to_string(),
+        ));
+    }
+
+    let admin = read_admin_settings(&config).await.err_500()?;
+    let sender = device_key_string(message.sender()).err_500()?;
+    let authorized = admin
+        .admin_device_keys
+        .as_deref()
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .any(|key| !key.is_empty() && constant_eq(key, &sender));
+    if !authorized {
+        warn!(?sender, "device not in admin_device_keys tried to log in");
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Device key is not in admin_device_keys".to_string(),
+        ));
+    }
+
+    let (cookie, csrf_token) = auth.start_session();
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Json(DeviceLoginResponse { csrf_token }),
+    )
+        .into_response())
+}
+
+/// The base64 key, as it's written in `admin_device_keys`.
+fn device_key_string(key: &hn_keys::PublicKeyKind) -> Result<String> {
+    let json = serde_json::to_value(key).context("serializing device key")?;
+    match key {
+        hn_keys::PublicKeyKind::X25519HkdfSha256(_) => json
+            .get("X25519HkdfSha256")
+            .and_then(JSON::as_str)
+            .map(String::from)
+            .context("device key as string"),
+    }
+}
+
+fn cookie_value<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// 32 random bytes, hex encoded
+pub(super) fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn constant_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Normalizes the `admin_password` field, so only the hash is saved.
+pub fn hash_admin_password(value: &str) -> String {
+    // masked or already hashed values are left for the schema to skip
+    if value.is_empty()
+        || value == "none"
+        || value.chars().all(|c| c == 'X')
+        || value.starts_with(PASSWORD_HASH_PREFIX)
+    {
+        return value.to_string();
+    }
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(value.as_bytes(), &salt, PASSWORD_HASH_ITERATIONS, &mut hash);
+    format!(
+        "{PASSWORD_HASH_PREFIX}{PASSWORD_HASH_ITERATIONS}${}${}",
+        hex::encode(salt),
+        hex::encode(hash)
+    )
+}
+
+/// Unhashed values are compared as they are, for passwords set by environment variable.
+fn verify_admin_password(stored: &str, attempt: &str) -> bool {
+    let Some(hashed) = stored.strip_prefix(PASSWORD_HASH_PREFIX) else {
+        return constant_eq(stored, attempt);
+    };
+    let mut parts = hashed.split('$');
+    let (Some(iterations), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        warn!("admin_password hash is malformed");
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse::<u32>(),
+        hex::decode(salt),
+        hex::decode(hash),
+    ) else {
+        warn!("admin_password hash is malformed");
+        return false;
+    };
+    let mut derived = vec![0u8; hash.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(attempt.as_bytes(), &salt, iterations.max(1), &mut derived);
+    derived.ct_eq(&hash).into()
+}
+
+#[test]
+fn test_admin_password_hash() {
+    let hashed = hash_admin_password("correct horse");
+    assert!(hashed.starts_with(PASSWORD_HASH_PREFIX));
+    assert!(verify_admin_password(&hashed, "correct horse"));
+    assert!(!verify_admin_password(&hashed, "correct horsf"));
+    // masked form values aren't hashed
+    assert_eq!(hash_admin_password("XXXXXX"), "XXXXXX");
+    assert_eq!(hash_admin_password(&hashed), hashed);
+    // hashed before the pbkdf2 crate was used, RFC 7914's PBKDF2-HMAC-SHA256 vector
+    assert!(verify_admin_password(
+        "pbkdf2-sha256$1$73616c74$55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+        "passwd"
+    ));
+}
+
+#[test]
+fn test_failed_logins_lock_everyone_out() {
+    let now = Instant::now();
+    let mut backoff = LoginBackoff::default();
+    for _ in 0..FAILED_LOGINS_BEFORE_LOCKOUT - 1 {
+        backoff.failed(now);
+        assert_eq!(backoff.locked_for(now), None);
+    }
+    backoff.failed(now);
+    assert_eq!(backoff.locked_for(now), Some(FAILED_LOGIN_DELAY));
+    backoff.failed(now);
+    assert_eq!(backoff.locked_for(now), Some(FAILED_LOGIN_DELAY * 2));
+    for _ in 0..32 {
+        backoff.failed(now);
+    }
+    assert_eq!(backoff.locked_for(now), Some(MAX_LOGIN_LOCKOUT));
+    assert_eq!(backoff.locked_for(now + MAX_LOGIN_LOCKOUT), None);
+}
+
+#[tokio::test]
+async fn test_locked_logins_reject_the_right_password_until_unlocked() {
+    let auth = ConfigAuth::new(Some("setup".to_string()), false);
+    let admin = AppConfiguration::default();
+
+    assert_eq!(auth.attempt_login(&admin, "wrong").await, Ok(false));
+    assert_eq!(auth.attempt_login(&admin, "setup").await, Ok(true));
+
+    // as if other clients had failed, it's the same lockout for everyone
+    let now = Instant::now();
+    let mut backoff = auth.login_backoff.lock().await;
+    for _ in 0..FAILED_LOGINS_BEFORE_LOCKOUT + 2 {
+        backoff.failed(now);
+    }
+    drop(backoff);
+    assert!(auth.attempt_login(&admin, "setup").await.is_err());
+
+    // device logins are how the admin gets in meanwhile
+    let challenge = auth.new_challenge();
+    assert!(auth.take_challenge(&challenge));
+
+    auth.login_backoff.lock().await.locked_until = Some(Instant::now());
+    assert_eq!(auth.attempt_login(&admin, "setup").await, Ok(true));
+}
@@ -1,12 +1,24 @@
 use std::{collections::HashMap, time::SystemTime};
 
-use axum::{extract::Path, response::Html, routing::get, Extension, Router};
+use axum::{
+    extract::{Path, Query},
+    response::Html,
+    routing::{get, post},
+    Extension, Json, Router,
+};
 use derive_codegen::Codegen;
 use hn_app::_ecs_::*;
+use hn_app::app_ctx::LocalDatabase;
+use hn_app::database_plugin::audit::{self, AuditEntry};
+use hn_app::database_plugin::export::ExportStatus;
+use hn_app::database_plugin::problems::{ImportProblems, RepairOptions, RepairSummary};
 use tokio::sync::oneshot;
 
+use super::templates;
 use crate::{config::Settings, ecs::HintedID, http::OrInternalError, prelude::*, svelte_templates};
 
+mod entity;
+
 #[derive(Serialize, Codegen)]
 #[codegen(tags = "data-browser")]
 #[codegen(template = "data-collections")]
@@ -41,6 +53,8 @@ struct CollectionRow {
     content: serde_json::value::Value,
     // #[codegen(ts_as = "undefined | Record<string, unknown>")]
     ecs_content: Option<String>,
+    /// Page with the entity's components and actions, see [entity]
+    detail_href: Option<String>,
     // sort_options: Vec<String>
 }
 
@@ -59,7 +73,10 @@ fn generate_svelte_templates() {
         .print();
 }
 
-pub(super) fn create_data_browser_router(app_ctx: AppCtx) -> Router<Arc<Settings>> {
+pub(super) fn create_data_browser_router(
+    app_ctx: AppCtx,
+    templates: templates::Templates,
+) -> Router<Arc<Settings>> {
     let router = Router::<Arc<Settings>>::new();
 
     let templates_path = get_crate_path()
@@ -69,23 +86,41 @@ pub(super) fn create_data_browser_router(app_ctx: AppCtx) -> Router<Arc<Settings
 
     router
         .route("/", get(get_home))
+        .route("/history", get(get_recent_history))
+        .route("/history/:document_id", get(get_document_history))
+        .route("/problems", get(get_problems))
+        .route("/problems/repair", post(post_repair_problems))
         .route("/:collection_id", get(get_collection))
+        .route("/:collection_id/:id", get(entity::get_entity))
+        .route(
+            "/:collection_id/:id/:action",
+            post(entity::post_entity_action),
+        )
         .layer(Extension(app_ctx))
+        .layer(templates.axum_layer())
         .layer(Extension(svelte_templates::SvelteTemplates {
             dev_path: Arc::new(templates_path),
         }))
 }
 
 async fn get_home(
-    // Extension(app_ctx): Extension<AppCtx>,
+    Extension(app_ctx): Extension<AppCtx>,
     Extension(templates): Extension<svelte_templates::SvelteTemplates>,
 ) -> HttpResult {
+    let problems = app_ctx
+        .get_unique::<ImportProblems>("show whether documents failed to import")
+        .await;
     render_home(
         &templates,
         PageHeader {
             title: "Data Browser".to_string(),
             links: get_links(),
-            warning: None,
+            warning: (!problems.0.is_empty()).then(|| {
+                format!(
+                    "{} problem(s) importing documents, see <b>Problems</b>.",
+                    problems.0.len()
+                )
+            }),
         },
     )
     .await
@@ -105,7 +140,12 @@ async fn render_home(
 }
 
 fn get_links() -> std::vec::Vec<(&'static str, &'static str)> {
-    return vec![("Credentials", "/data/creds"), ("Devices", "/data/devices")];
+    return vec![
+        ("Credentials", "/data/creds"),
+        ("Devices", "/data/devices"),
+        ("History", "/data/history"),
+        ("Problems", "/data/problems"),
+    ];
 }
 
 async fn get_all_rows<
@@ -114,23 +154,32 @@ async fn get_all_rows<
         + Sync
         + Serialize,
 >(
-    db: &bonsai_::local::Database,
+    local_database: &LocalDatabase,
     app_ctx: &AppCtx,
+    collection_id: &str,
 ) -> HttpResult<Vec<CollectionRow>> {
     let (tx, rx) = oneshot::channel::<HashMap<HintedID, Option<String>>>();
 
-    let results = C::all(db)
-        .query()
-        .err_500()?
-        .into_iter()
-        .map(|cred| -> Result<CollectionRow> {
-            Ok(CollectionRow {
-                id: cred.header.id,
-                content: serde_json::to_value(cred.contents).context("cred to json value")?,
-                ecs_content: None,
-            })
+    let db = local_database.get_database();
+    let db = db.as_err_arc_ref().err_500()?;
+    let results = local_database
+        .sealed(|| {
+            C::all(db)
+                .query()
+                .context("reading documents")?
+                .into_iter()
+                .map(|cred| -> Result<CollectionRow> {
+                    Ok(CollectionRow {
+                        detail_href: Some(format!("/data/{collection_id}/{}", cred.header.id)),
+                        id: cred.header.id,
+                        // sensitive fields are shown as a placeholder
+                        content: hn_app::sealed::redacted(|| serde_json::to_value(cred.contents))
+                            .context("cred to json value")?,
+                        ecs_content: None,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
         })
-        .collect::<Result<Vec<_>, _>>()
         .err_500()?;
 
     let empty_ids = results
@@ -180,17 +229,18 @@ async fn get_collection(
     Extension(templates): Extension<svelte_templates::SvelteTemplates>,
     Path((collection_id,)): Path<(String,)>,
 ) -> HttpResult {
-    let db = app_ctx.get_database().await;
-    let db = db.as_err_arc_ref().err_500()?;
+    let local_database = app_ctx.get_local_database().await;
 
     let (label, rows) = match collection_id.as_str() {
         "creds" => (
             "Credentials",
-            get_all_rows::<ecs::import_export::CredBundle>(db, &app_ctx).await?,
+            get_all_rows::<ecs::import_export::CredBundle>(&local_database, &app_ctx, "creds")
+                .await?,
         ),
         "devices" => (
             "Devices",
-            get_all_rows::<ecs::import_export::DeviceBundle>(db, &app_ctx).await?,
+            get_all_rows::<ecs::import_export::DeviceBundle>(&local_database, &app_ctx, "devices")
+                .await?,
         ),
         other => {
             return render_home(
@@ -213,7 +263,7 @@ async fn get_collection(
                 header: PageHeader {
                     title: format!("Collection / {label}"),
                     links: get_links(),
-                    warning: None,
+                    warning: export_warning(&app_ctx).await,
                 },
                 rows,
             },
@@ -222,3 +272,186 @@ async fn get_collection(
         .err_500()
         .map(Html)
 }
+
+/// Shown while changes are failing to save, since the collection may be out of date.
+async fn export_warning(app_ctx: &AppCtx) -> Option<String> {
+    let status = app_ctx
+        .get_unique::<ExportStatus>("show whether changes are saving")
+        .await;
+    let mut warnings = Vec::new();
+    if let Some(error) = status.last_error {
+        warnings.push(format!(
+            "Saving changes failed {} time(s), {} document(s) waiting to retry: <code>{}</code>",
+            status.failed_attempts,
+            status.pending,
+            html_escape(&error),
+        ));
+    }
+    if !status.batch_errors.is_empty() {
+        warnings.push(format!(
+            "{} change(s) could not be saved: <code>{}</code>",
+            status.batch_errors.len(),
+            html_escape(&status.batch_errors.join("\n")),
+        ));
+    }
+    (!warnings.is_empty()).then(|| warnings.join("<br>"))
+}
+
+fn html_escape(str: &str) -> String {
+    str.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Most recent entries shown on the history page
+const RECENT_HISTORY_LIMIT: u32 = 200;
+
+#[instrument(skip_all)]
+async fn get_recent_history(
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(templates): Extension<svelte_templates::SvelteTemplates>,
+) -> HttpResult {
+    let db = app_ctx.get_database().await;
+    let db = db.as_err_arc_ref().err_500()?;
+    let entries = audit::recent_entries(db, RECENT_HISTORY_LIMIT).err_500()?;
+    render_history(&templates, "History".to_string(), entries)
+}
+
+#[instrument(skip_all)]
+async fn get_document_history(
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(templates): Extension<svelte_templates::SvelteTemplates>,
+    Path((document_id,)): Path<(String,)>,
+) -> HttpResult {
+    let id = HintedID::try_from(document_id.as_str()).err_400()?;
+    let db = app_ctx.get_database().await;
+    let db = db.as_err_arc_ref().err_500()?;
+    let entries = audit::document_history(db, &id).err_500()?;
+    render_history(&templates, format!("History / {id}"), entries)
+}
+
+/// Reuses the collection page, with a row per entry, newest first.
+fn render_history(
+    templates: &svelte_templates::SvelteTemplates,
+    title: String,
+    entries: Vec<AuditEntry>,
+) -> HttpResult {
+    let warning = entries
+        .is_empty()
+        .then(|| "No changes recorded, is <code>[audit] enabled</code>?".to_string());
+    let rows = entries
+        .into_iter()
+        .map(|entry| -> Result<CollectionRow> {
+            let at = entry
+                .at
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |dur| dur.as_secs());
+            Ok(CollectionRow {
+                ecs_content: Some(format!(
+                    "{} of {} changed at {at} (unix), because: {}",
+                    entry.component, entry.collection, entry.reason
+                )),
+                id: entry.document.clone(),
+                content: serde_json::to_value(&entry).context("audit entry to json value")?,
+                detail_href: None,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .err_500()?;
+
+    let template = svelte_template!("data-browser/collection-page.template.compiled.cjs");
+    templates
+        .render_svelte_into_html_page(
+            &template,
+            CollectionPage {
+                header: PageHeader {
+                    title,
+                    links: get_links(),
+                    warning,
+                },
+                rows,
+            },
+        )
+        .context("rendering data browser page")
+        .err_500()
+        .map(Html)
+}
+
+/// Reuses the collection page, with a row per problem.
+#[instrument(skip_all)]
+async fn get_problems(
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(templates): Extension<svelte_templates::SvelteTemplates>,
+) -> HttpResult {
+    let problems = app_ctx
+        .get_unique::<ImportProblems>("list documents which failed to import")
+        .await;
+    let mut rows = Vec::new();
+    // documents whose id couldn't be read have no row
+    let mut unreadable_ids = Vec::new();
+    for problem in problems.0.iter() {
+        let Ok(id) = HintedID::try_from(problem.document.as_str()) else {
+            unreadable_ids.push(format!(
+                "<li><code>{}</code> in {}</li>",
+                html_escape(&problem.document),
+                html_escape(&problem.collection)
+            ));
+            continue;
+        };
+        rows.push(CollectionRow {
+            id,
+            content: serde_json::to_value(problem)
+                .context("import problem to json value")
+                .err_500()?,
+            ecs_content: Some(format!("{} in {}", problem.bundle, problem.collection)),
+            detail_href: None,
+        });
+    }
+
+    let warning = if problems.0.is_empty() {
+        "No problems importing documents.".to_string()
+    } else {
+        let mut warning = "Repair with <code>POST /data/problems/repair</code>, which saves \
+            documents again without missing links. Add <code>?delete_undecodable=true</code> to \
+            also delete documents which can't be read, sending the session's <code>X-CSRF-Token</code> \
+            from <code>/;login/device</code>. Documents which can't be unsealed are always kept, \
+            restore the database secret to read them. Duplicate ids need fixing by hand."
+            .to_string();
+        if !unreadable_ids.is_empty() {
+            warning.push_str(&format!(
+                " Documents with unreadable ids: <ul>{}</ul>",
+                unreadable_ids.join("")
+            ));
+        }
+        warning
+    };
+
+    let template = svelte_template!("data-browser/collection-page.template.compiled.cjs");
+    templates
+        .render_svelte_into_html_page(
+            &template,
+            CollectionPage {
+                header: PageHeader {
+                    title: "Problems".to_string(),
+                    links: get_links(),
+                    warning: Some(warning),
+                },
+                rows,
+            },
+        )
+        .context("rendering data browser page")
+        .err_500()
+        .map(Html)
+}
+
+#[instrument(skip_all)]
+async fn post_repair_problems(
+    Extension(app_ctx): Extension<AppCtx>,
+    Query(options): Query<RepairOptions>,
+) -> HttpResult<Json<RepairSummary>> {
+    app_ctx
+        .repair_import_problems(options)
+        .await
+        .err_500()
+        .map(Json)
+}
@@ -0,0 +1,644 @@
+//! A page per device or credential, with its document next to its components in the ECS, and
+//! admin actions which change the components through [AppCtx::run_system] so the change is
+//! saved like any other. The reason given to `run_system` is what the audit log records.
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    extract::Path,
+    response::{Html, IntoResponse, Response},
+    Extension, Form,
+};
+use hn_app::_ecs_::*;
+use minijinja::context;
+use tokio::sync::oneshot;
+
+use crate::{
+    app_server_plugins::{refresh_discord_token, DiscordClientID, DiscordClientSecret},
+    config_html_server::{auth::ConfigSession, templates},
+    ecs::{
+        import_export::{authorized_key_view_key, CredBundle, DeviceBundle},
+        HintedID,
+    },
+    http::OrInternalError,
+    prelude::*,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum EntityCollection {
+    Devices,
+    Creds,
+}
+
+impl EntityCollection {
+    fn from_path(collection_id: &str) -> HttpResult<Self> {
+        match collection_id {
+            "devices" => Ok(EntityCollection::Devices),
+            "creds" => Ok(EntityCollection::Creds),
+            other => Err((
+                http::StatusCode::NOT_FOUND,
+                format!("collection {other:?} not found"),
+            )),
+        }
+    }
+
+    fn path(self) -> &'static str {
+        match self {
+            EntityCollection::Devices => "devices",
+            EntityCollection::Creds => "creds",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            EntityCollection::Devices => "Devices",
+            EntityCollection::Creds => "Credentials",
+        }
+    }
+
+    /// Adds the entity if its document isn't loaded yet.
+    async fn load(self, app_ctx: &AppCtx, id: &HintedID) -> Result<()> {
+        match self {
+            EntityCollection::Devices => app_ctx.load::<DeviceBundle>(vec![id.clone()]).await?,
+            EntityCollection::Creds => app_ctx.load::<CredBundle>(vec![id.clone()]).await?,
+        };
+        Ok(())
+    }
+
+    /// Pretty printed, with sensitive fields shown as a placeholder
+    async fn read_document(self, app_ctx: &AppCtx, id: &HintedID) -> Result<Option<String>> {
+        let local_database = app_ctx.get_local_database().await;
+        let db = local_database.get_database();
+        let db = db.as_err_arc_ref()?;
+        local_database.sealed(|| {
+            let contents = match self {
+                EntityCollection::Devices => DeviceBundle::get(id, db)
+                    .context("reading device document")?
+                    .map(|doc| {
+                        hn_app::sealed::redacted(|| serde_json::to_string_pretty(&doc.contents))
+                    }),
+                EntityCollection::Creds => CredBundle::get(id, db)
+                    .context("reading cred document")?
+                    .map(|doc| {
+                        hn_app::sealed::redacted(|| serde_json::to_string_pretty(&doc.contents))
+                    }),
+            };
+            contents.transpose().context("document to json")
+        })
+    }
+}
+
+/// What the ECS has for an entity, without the tokens of creds.
+#[derive(Serialize)]
+struct EcsSnapshot {
+    entity: String,
+    authorized_keys: Vec<AuthorizedKeySnapshot>,
+    linked_creds: Vec<String>,
+    /// Devices linking to this cred
+    linked_by: Vec<String>,
+    /// Like "in 5 minutes"
+    expires: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuthorizedKeySnapshot {
+    label: Option<String>,
+    dev_info: Option<String>,
+    /// See [authorized_key_view_key], which is also how revoking names the key
+    key: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct EntityActionForm {
+    /// From [AuthorizedKeySnapshot::key]
+    key: Option<String>,
+    cred: Option<String>,
+}
+
+#[instrument(skip_all)]
+pub(super) async fn get_entity(
+    templates: templates::Templates,
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(session): Extension<ConfigSession>,
+    Path((collection_id, id)): Path<(String, String)>,
+) -> HttpResult<Response> {
+    let collection = EntityCollection::from_path(&collection_id)?;
+    let id = HintedID::try_from(id.as_str()).err_400()?;
+    render_entity(&templates, &app_ctx, &session, collection, &id, Ok(None)).await
+}
+
+#[instrument(skip_all)]
+pub(super) async fn post_entity_action(
+    templates: templates::Templates,
+    Extension(app_ctx): Extension<AppCtx>,
+    Extension(session): Extension<ConfigSession>,
+    Path((collection_id, id, action)): Path<(String, String, String)>,
+    Form(form): Form<EntityActionForm>,
+) -> HttpResult<Response> {
+    let collection = EntityCollection::from_path(&collection_id)?;
+    let id = HintedID::try_from(id.as_str()).err_400()?;
+    collection.load(&app_ctx, &id).await.err_500()?;
+
+    let flash = match (collection, action.as_str()) {
+        (EntityCollection::Devices, "revoke-key") => {
+            let key = form.key.context("key to revoke").err_400()?;
+            revoke_key(&app_ctx, id.clone(), key).await.err_500()?
+        }
+        (EntityCollection::Devices, "unlink-cred") => {
+            let cred = form.cred.context("cred to unlink").err_400()?;
+            let cred = HintedID::try_from(cred.as_str()).err_400()?;
+            unlink_cred(&app_ctx, id.clone(), cred).await.err_500()?
+        }
+        (EntityCollection::Devices, "delete") => {
+            match delete_device(&app_ctx, id.clone()).await.err_500()? {
+                // the page would only show the document until it's deleted
+                Ok(()) => {
+                    return Ok(([("hx-redirect", "/data/devices")], "Deleted").into_response())
+                }
+                Err(err) => Err(err),
+            }
+        }
+        (EntityCollection::Creds, "refresh") => {
+            refresh_cred(&app_ctx, id.clone()).await.err_500()?
+        }
+        (_, other) => {
+            return Err((
+                http::StatusCode::NOT_FOUND,
+                format!("no {other:?} action for {}", collection.path()),
+            ))
+        }
+    };
+
+    render_entity(
+        &templates,
+        &app_ctx,
+        &session,
+        collection,
+        &id,
+        flash.map(Some),
+    )
+    .await
+}
+
+/// `flash` is shown as ok or error above the entity.
+async fn render_entity(
+    templates: &templates::Templates,
+    app_ctx: &AppCtx,
+    session: &ConfigSession,
+    collection: EntityCollection,
+    id: &HintedID,
+    flash: Result<Option<String>, String>,
+) -> HttpResult<Response> {
+    collection.load(app_ctx, id).await.err_500()?;
+    let document = collection.read_document(app_ctx, id).await.err_500()?;
+    let ecs = read_snapshot(app_ctx, id.clone()).await.err_500()?;
+    let (ok, err) = match flash {
+        Ok(ok) => (ok, None),
+        Err(err) => (None, Some(err)),
+    };
+
+    templates
+        .render(
+            "data-entity.html.j2",
+            context! {
+                collection => collection.path(),
+                label => collection.label(),
+                id => id.to_string(),
+                document,
+                ecs,
+                ok,
+                err,
+                csrf_token => session.csrf_token.clone(),
+            },
+        )
+        .err_500()
+        .map(|html| Html(html).into_response())
+}
+
+fn find_entity(v_hinted_id: &View<HintedID>, id: &HintedID) -> Option<EntityId> {
+    v_hinted_id
+        .iter()
+        .with_id()
+        .find_map(|(entity_id, hinted_id)| (hinted_id == id).then_some(entity_id))
+}
+
+fn hinted_ids(v_hinted_id: &View<HintedID>, entities: &[EntityId]) -> Vec<String> {
+    entities
+        .iter()
+        .filter_map(|entity| v_hinted_id.get(*entity).ok())
+        .map(|hinted_id| hinted_id.to_string())
+        .collect()
+}
+
+fn describe_expiry(expires_at: SystemTime) -> String {
+    match expires_at.duration_since(SystemTime::now()) {
+        Ok(left) => format!("in {} minutes", left.as_secs() / 60),
+        Err(err) => format!("{} minutes ago", err.duration().as_secs() / 60),
+    }
+}
+
+async fn read_snapshot(app_ctx: &AppCtx, id: HintedID) -> Result<Option<EcsSnapshot>> {
+    let (tx, rx) = oneshot::channel::<Option<EcsSnapshot>>();
+    let once = std::sync::Mutex::new(Some((id, tx)));
+    app_ctx.run_system(
+        "collect ecs components for the data browser",
+        move |v_hinted_id: View<HintedID>,
+              v_authorized_keys: View<ecs::AuthorizedKeys>,
+              v_linked_creds: View<ecs::Linked<ecs::CredTag>>,
+              v_discord_cred: View<ecs::EcsDiscordCred>| {
+            let Some((id, tx)) = once.lock().unwrap().take() else {
+                error!("unexpected second execution");
+                return;
+            };
+            let snapshot = find_entity(&v_hinted_id, &id).map(|entity| EcsSnapshot {
+                entity: format!("{entity:?}"),
+                authorized_keys: v_authorized_keys
+                    .get(entity)
+                    .map(|authorized| {
+                        authorized
+                            .keys
+                            .iter()
+                            .filter_map(|authorized| {
+                                Some(AuthorizedKeySnapshot {
+                                    label: authorized.label.clone(),
+                                    dev_info: authorized.dev_info.clone(),
+                                    key: authorized_key_view_key(&authorized.key).ok()?,
+                                })
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                linked_creds: v_linked_creds
+                    .get(entity)
+                    .map(|linked| hinted_ids(&v_hinted_id, &linked.items))
+                    .unwrap_or_default(),
+                linked_by: (&v_hinted_id, &v_linked_creds)
+                    .iter()
+                    .filter(|(_, linked)| linked.items.contains(&entity))
+                    .map(|(device_id, _)| device_id.to_string())
+                    .collect(),
+                expires: v_discord_cred
+                    .get(entity)
+                    .ok()
+                    .map(|cred| describe_expiry(cred.expires_at)),
+            });
+            let _ = tx.send(snapshot);
+        },
+    );
+    rx.await.context("receive back components from ECS")
+}
+
+/// Removes every authorization of `key` from the device.
+async fn revoke_key(
+    app_ctx: &AppCtx,
+    device_id: HintedID,
+    key: String,
+) -> Result<Result<String, String>> {
+    let (tx, rx) = oneshot::channel::<Result<String, String>>();
+    let once = std::sync::Mutex::new(Some((device_id, key, tx)));
+    app_ctx.run_system(
+        "admin revoked an authorized key in the data browser",
+        move |v_hinted_id: View<HintedID>, mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+            let Some((device_id, key, tx)) = once.lock().unwrap().take() else {
+                error!("unexpected second execution");
+                return;
+            };
+            let result = find_entity(&v_hinted_id, &device_id)
+                .and_then(|entity| (&mut vm_authorized_keys).get(entity).ok())
+                .ok_or_else(|| format!("{device_id} has no authorized keys"))
+                .and_then(|mut authorized| {
+                    let is_key = |authorized: &ecs::AuthorizedKey| {
+                        authorized_key_view_key(&authorized.key).ok().as_ref() == Some(&key)
+                    };
+                    if !authorized.keys.iter().any(is_key) {
+                        return Err("Key was already revoked".to_string());
+                    }
+                    authorized
+                        .as_mut()
+                        .keys
+                        .retain(|authorized| !is_key(authorized));
+                    info!(?device_id, "admin revoked an authorized key");
+                    Ok("Revoked key".to_string())
+                });
+            let _ = tx.send(result);
+        },
+    );
+    rx.await.context("receive back revoke result from ECS")
+}
+
+async fn unlink_cred(
+    app_ctx: &AppCtx,
+    device_id: HintedID,
+    cred_id: HintedID,
+) -> Result<Result<String, String>> {
+    let (tx, rx) = oneshot::channel::<Result<String, String>>();
+    let once = std::sync::Mutex::new(Some((device_id, cred_id, tx)));
+    app_ctx.run_system(
+        "admin unlinked a credential in the data browser",
+        move |v_hinted_id: View<HintedID>,
+              mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>| {
+            let Some((device_id, cred_id, tx)) = once.lock().unwrap().take() else {
+                error!("unexpected second execution");
+                return;
+            };
+            let cred_entity = find_entity(&v_hinted_id, &cred_id);
+            let result = find_entity(&v_hinted_id, &device_id)
+                .and_then(|entity| (&mut vm_linked_creds).get(entity).ok())
+                .ok_or_else(|| format!("{device_id} has no linked credentials"))
+                .and_then(|mut linked| match cred_entity {
+                    Some(cred_entity) if linked.items.contains(&cred_entity) => {
+                        linked.as_mut().items.retain(|item| *item != cred_entity);
+                        info!(?device_id, ?cred_id, "admin unlinked a credential");
+                        Ok(format!("Unlinked {cred_id}"))
+                    }
+                    _ => Err(format!("{cred_id} was already unlinked")),
+                });
+            let _ = tx.send(result);
+        },
+    );
+    rx.await.context("receive back unlink result from ECS")
+}
+
+/// Deleting the entity deletes its document at the next save, see [hn_app::database_plugin].
+async fn delete_device(app_ctx: &AppCtx, device_id: HintedID) -> Result<Result<(), String>> {
+    let (tx, rx) = oneshot::channel::<Result<(), String>>();
+    let once = std::sync::Mutex::new(Some((device_id, tx)));
+    app_ctx.run_system(
+        "admin deleted a device in the data browser",
+        move |mut all_storages: AllStoragesViewMut| {
+            let Some((device_id, tx)) = once.lock().unwrap().take() else {
+                error!("unexpected second execution");
+                return;
+            };
+            let entity = all_storages.run(
+                |v_hinted_id: View<HintedID>, v_device: View<ecs::DeviceTag>| {
+                    find_entity(&v_hinted_id, &device_id)
+                        .filter(|entity| v_device.contains(*entity))
+                },
+            );
+            let result = match entity {
+                Some(entity) => {
+                    all_storages.delete_entity(entity);
+                    info!(?device_id, "admin deleted a device");
+                    Ok(())
+                }
+                None => Err(format!("{device_id} is not a loaded device")),
+            };
+            let _ = tx.send(result);
+        },
+    );
+    rx.await.context("receive back delete result from ECS")
+}
+
+/// Exchanges the cred's refresh token with Discord now, rather than when the access token
+/// expires.
+async fn refresh_cred(app_ctx: &AppCtx, cred_id: HintedID) -> Result<Result<String, String>> {
+    let (tx, rx) = oneshot::channel::<Option<String>>();
+    let once = std::sync::Mutex::new(Some((cred_id.clone(), tx)));
+    app_ctx.run_system(
+        "read refresh token for the data browser",
+        move |v_hinted_id: View<HintedID>, v_discord_cred: View<ecs::EcsDiscordCred>| {
+            if let Some((cred_id, tx)) = once.lock().unwrap().take() {
+                let refresh_token = find_entity(&v_hinted_id, &cred_id)
+                    .and_then(|entity| v_discord_cred.get(entity).ok())
+                    .map(|cred| cred.refresh_token.clone());
+                let _ = tx.send(refresh_token);
+            }
+        },
+    );
+    let Some(refresh_token) = rx.await.context("receive back refresh token from ECS")? else {
+        return Ok(Err(format!("{cred_id} is not a loaded Discord credential")));
+    };
+
+    let client_id = app_ctx
+        .get_unique::<DiscordClientID>("to refresh a discord credential")
+        .await;
+    let client_secret = app_ctx
+        .get_unique::<DiscordClientSecret>("to refresh a discord credential")
+        .await;
+    let (client_id, client_secret) = match (
+        client_id.0.as_err_arc_ref(),
+        client_secret.0.as_err_arc_ref(),
+    ) {
+        (Ok(client_id), Ok(client_secret)) => (client_id, client_secret),
+        (Err(err), _) | (_, Err(err)) => return Ok(Err(format!("{err:#}"))),
+    };
+
+    let token = match refresh_discord_token(client_id, client_secret, &refresh_token).await {
+        Ok(token) => token,
+        // shown in the page, refresh_discord_token leaves Discord's response body out
+        Err(err) => {
+            warn!(?err, ?cred_id, "admin credential refresh failed");
+            return Ok(Err(format!("{err:#}")));
+        }
+    };
+    let expires_at = SystemTime::now() + Duration::from_secs(token.expires_in);
+
+    let (tx, rx) = oneshot::channel::<Result<String, String>>();
+    let once = std::sync::Mutex::new(Some((cred_id, token, tx)));
+    app_ctx.run_system(
+        "admin forced a credential refresh in the data browser",
+        move |v_hinted_id: View<HintedID>, mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>| {
+            let Some((cred_id, token, tx)) = once.lock().unwrap().take() else {
+                error!("unexpected second execution");
+                return;
+            };
+            let result = find_entity(&v_hinted_id, &cred_id)
+                .and_then(|entity| (&mut vm_discord_cred).get(entity).ok())
+                .map(|mut cred| {
+                    let cred = cred.as_mut();
+                    cred.access_token = token.access_token;
+                    cred.refresh_token = token.refresh_token;
+                    cred.expires_at = expires_at;
+                    info!(?cred_id, "admin refreshed a credential");
+                    format!("Refreshed, expires {}", describe_expiry(expires_at))
+                })
+                // e.g. evicted while waiting on Discord
+                .ok_or_else(|| format!("{cred_id} was unloaded before the refresh was saved"));
+            let _ = tx.send(result);
+        },
+    );
+    rx.await.context("receive back refresh result from ECS")
+}
+
+#[cfg(test)]
+struct TestPlugin {
+    sender: hn_app::app_ctx::CommandSender,
+    db_path: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl Plugin for TestPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(hn_app::app_ctx::AppCtxPlugin(self.sender.clone()))
+            .add_plugin(crate::ecs::import_export::plugin::SavePlugin::for_test(
+                &self.db_path,
+            ));
+    }
+}
+
+/// The document as saved by the update after the last command.
+#[cfg(test)]
+async fn saved_device(app_ctx: &AppCtx, id: &HintedID) -> Option<JSON> {
+    // commands run one after another, each followed by an update
+    let (tx, rx) = oneshot::channel::<()>();
+    let once = std::sync::Mutex::new(Some(tx));
+    app_ctx.run_system("wait for the last update", move |_: EntitiesView| {
+        if let Some(tx) = once.lock().unwrap().take() {
+            let _ = tx.send(());
+        }
+    });
+    rx.await.unwrap();
+    let document = EntityCollection::Devices
+        .read_document(app_ctx, id)
+        .await
+        .unwrap()?;
+    Some(serde_json::from_str(&document).unwrap())
+}
+
+#[tokio::test]
+async fn test_admin_actions_change_components_and_documents() {
+    let db_path = std::env::temp_dir().join(format!("hn-data-browser-{}.bonsaidb", xid::new()));
+    let (sender, recv) = tokio::sync::mpsc::unbounded_channel();
+    let mut app = shipyard_app::App::new();
+    let workload = app.add_plugin_workload(TestPlugin {
+        sender,
+        db_path: db_path.clone(),
+    });
+    let app_ctx = app
+        .world
+        .run(|uv_app_ctx: UniqueView<AppCtx>| uv_app_ctx.clone());
+
+    let device_id = HintedID::generate("dev");
+    let cred_id = HintedID::generate("cred");
+    let revoked_key = hn_keys::init().public_key().clone();
+    let kept_key = hn_keys::init().public_key().clone();
+    app.world.run(
+        |mut entities: EntitiesViewMut,
+         mut vm_hinted_id: ViewMut<HintedID>,
+         mut vm_cred_tag: ViewMut<ecs::CredTag>,
+         mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>,
+         mut vm_device_tag: ViewMut<ecs::DeviceTag>,
+         mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>,
+         mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+            let cred = entities.add_entity(
+                (&mut vm_hinted_id, &mut vm_cred_tag, &mut vm_discord_cred),
+                (
+                    cred_id.clone(),
+                    ecs::CredTag::Discord,
+                    ecs::EcsDiscordCred {
+                        access_token: "access".to_string(),
+                        refresh_token: "refresh".to_string(),
+                        expires_at: SystemTime::now(),
+                    },
+                ),
+            );
+            let authorized_key = |key: &hn_keys::PublicKeyKind| ecs::AuthorizedKey {
+                label: None,
+                dev_info: None,
+                key: key.clone(),
+            };
+            entities.add_entity(
+                (
+                    &mut vm_hinted_id,
+                    &mut vm_device_tag,
+                    &mut vm_linked_creds,
+                    &mut vm_authorized_keys,
+                ),
+                (
+                    device_id.clone(),
+                    ecs::DeviceTag,
+                    ecs::Linked::new_with([cred]),
+                    ecs::AuthorizedKeys {
+                        keys: vec![authorized_key(&revoked_key), authorized_key(&kept_key)],
+                    },
+                ),
+            );
+        },
+    );
+    tokio::spawn(hn_app::app_ctx::start_loop(
+        app,
+        workload,
+        recv,
+        |_: &shipyard_app::App| {},
+    ));
+
+    let device = saved_device(&app_ctx, &device_id)
+        .await
+        .expect("device is saved");
+    assert_eq!(
+        device["c_authorized_keys"]["keys"]
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_eq!(
+        device["c_linked_creds"]["items"],
+        serde_json::json!([cred_id.to_string()])
+    );
+
+    let revoked = authorized_key_view_key(&revoked_key).unwrap();
+    assert_eq!(
+        revoke_key(&app_ctx, device_id.clone(), revoked.clone())
+            .await
+            .unwrap(),
+        Ok("Revoked key".to_string())
+    );
+    assert!(revoke_key(&app_ctx, device_id.clone(), revoked)
+        .await
+        .unwrap()
+        .is_err());
+    let snapshot = read_snapshot(&app_ctx, device_id.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    let keys = snapshot
+        .authorized_keys
+        .iter()
+        .map(|authorized| authorized.key.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec![authorized_key_view_key(&kept_key).unwrap()]);
+    let device = saved_device(&app_ctx, &device_id).await.unwrap();
+    assert_eq!(
+        device["c_authorized_keys"]["keys"]
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    assert_eq!(
+        unlink_cred(&app_ctx, device_id.clone(), cred_id.clone())
+            .await
+            .unwrap(),
+        Ok(format!("Unlinked {cred_id}"))
+    );
+    let snapshot = read_snapshot(&app_ctx, device_id.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(snapshot.linked_creds.is_empty());
+    let device = saved_device(&app_ctx, &device_id).await.unwrap();
+    assert_eq!(device["c_linked_creds"]["items"], serde_json::json!([]));
+
+    assert_eq!(
+        delete_device(&app_ctx, device_id.clone()).await.unwrap(),
+        Ok(())
+    );
+    assert!(read_snapshot(&app_ctx, device_id.clone())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(saved_device(&app_ctx, &device_id).await.is_none());
+    assert!(
+        EntityCollection::Creds
+            .read_document(&app_ctx, &cred_id)
+            .await
+            .unwrap()
+            .is_some(),
+        "deleting a device keeps its creds"
+    );
+
+    let _ = std::fs::remove_dir_all(&db_path);
+    let _ = std::fs::remove_file(db_path.with_extension("secret"));
+}
@@ -6,10 +6,14 @@ use hn_app::_ecs_::*;
 use std::{
     collections::{hash_map, HashMap},
     marker::PhantomData,
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
+mod overrides;
+
+pub use overrides::{section_of, ConfigOverride, ConfigOverrides, ConfigSource};
+
 #[derive(Component)]
 #[track(All)]
 pub struct ConfigDirectoryPath(pub Option<PathBuf>);
@@ -26,6 +30,8 @@ pub struct ConfigDirectoryPlugin {
     pub default_path: Option<PathBuf>,
     /// used if applicable, if applicable, defaults to 2 seconds
     pub polling_duration: Option<Duration>,
+    /// Layered over [TypedConfigFilePlugin] files, see [ConfigOverrides::from_env_and_args].
+    pub overrides: ConfigOverrides,
 }
 
 /// Unique component
@@ -145,6 +151,11 @@ impl<E: std::fmt::Display> std::fmt::Display for ConfigFileContentError<E> {
             ConfigFileContentError::LoaderError(err) => {
                 write!(&mut f, "Error while loading: {err}")
             }
+            ConfigFileContentError::ReadError(err)
+                if err.kind() == std::io::ErrorKind::NotFound =>
+            {
+                write!(&mut f, "File does not exist")
+            }
             ConfigFileContentError::ReadError(err) => {
                 write!(&mut f, "Error while reading file: {err}")
             }
@@ -163,18 +174,34 @@ impl Plugin for ConfigDirectoryPlugin {
         app.add_tracked_value(internal::ConfigDirectoryFileContent {
             path_to_version_and_body: Default::default(),
         });
+        app.add_tracked_value(self.overrides.clone());
         use notify::Watcher;
         let mut watcher = notify::recommended_watcher(
             move |a: std::result::Result<notify::Event, notify::Error>| match a {
+                // our own reads show up as access events
+                Ok(event) if event.kind.is_access() => {}
                 Ok(event) => {
                     debug!(?event.paths, "watcher got an event, so we're scheduling a system");
                     ctx.schedule_system_dedup(
                         "load file contents for directory",
                         format!("{:?}", event.paths),
-                        move |mut uvm_tracker: UniqueViewMut<
+                        move |mut uvm_watcher: UniqueViewMut<ConfigFilesWatcher>,
+                              mut uvm_tracker: UniqueViewMut<
                             internal::ConfigDirectoryFileContent,
                         >| {
-                            internal::load_the_file_contents(&event.paths, &mut uvm_tracker);
+                            let mut paths = Vec::new();
+                            for path in event.paths.iter() {
+                                if uvm_watcher.watch_count.contains_key(path) {
+                                    paths.push(path.clone());
+                                } else if uvm_watcher.dir_watch_count.contains_key(path) {
+                                    // a directory was created (again), so its files are new
+                                    paths.extend(uvm_watcher.as_mut().rewatch_dir(path));
+                                }
+                                // otherwise other files in the directories, like swap files
+                            }
+                            if !paths.is_empty() {
+                                internal::load_the_file_contents(&paths, &mut uvm_tracker);
+                            }
                         },
                     );
                 }
@@ -200,17 +227,159 @@ impl Plugin for ConfigDirectoryPlugin {
         app.add_unique(ConfigFilesWatcher {
             watcher,
             watch_count: Default::default(),
+            dir_watch_count: Default::default(),
+            watched_dir: None,
         });
+        app.add_system(watch_directory_system);
     }
 }
 
+/// Files are watched through their directories rather than by themselves, so files which are
+/// created later, or replaced by editors writing a new file over them, are still seen.
 #[derive(Component)]
 struct ConfigFilesWatcher {
-    // watch_list: HashMap<PathBuf, HashSet<EntityId>>,
+    /// Files which [ConfigFilePlugin]s read
     watch_count: HashMap<PathBuf, usize>,
+    dir_watch_count: HashMap<PathBuf, usize>,
+    /// The config directory, watched even if no file is read from it, yet
+    watched_dir: Option<PathBuf>,
     watcher: notify::RecommendedWatcher,
 }
 
+impl ConfigFilesWatcher {
+    fn watch_dir(&mut self, dir: &Path) {
+        use notify::Watcher;
+        let count = self.dir_watch_count.entry(dir.to_path_buf()).or_default();
+        if *count == 0 {
+            let _span = debug_span!("watcher add watched directory", ?dir).entered();
+            if let Err(err) = self.watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+                // e.g. a sub directory which doesn't exist, yet
+                warn!(?err, ?dir, "failed to watch config directory");
+            }
+        }
+        *count += 1;
+    }
+
+    fn unwatch_dir(&mut self, dir: &Path) {
+        use notify::Watcher;
+        let Some(count) = self.dir_watch_count.get_mut(dir) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.dir_watch_count.remove(dir);
+            let _ = self.watcher.unwatch(dir);
+        }
+    }
+
+    /// Watches a directory again after it was created, returns the files to read from it.
+    fn rewatch_dir(&mut self, dir: &Path) -> Vec<PathBuf> {
+        use notify::Watcher;
+        if !dir.is_dir() {
+            return Vec::new();
+        }
+        let _ = self.watcher.unwatch(dir);
+        if let Err(err) = self.watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+            warn!(?err, ?dir, "failed to watch config directory");
+        }
+        self.watch_count
+            .keys()
+            .filter(|file_path| file_path.parent() == Some(dir))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns whether the file wasn't watched, yet.
+    fn watch_file(&mut self, file_path: &Path) -> bool {
+        let count = self.watch_count.entry(file_path.to_path_buf()).or_default();
+        *count += 1;
+        if *count > 1 {
+            return false;
+        }
+        if let Some(parent) = file_path.parent() {
+            self.watch_dir(parent);
+        }
+        true
+    }
+
+    fn unwatch_file(&mut self, file_path: &Path) {
+        let Some(count) = self.watch_count.get_mut(file_path) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.watch_count.remove(file_path);
+            if let Some(parent) = file_path.parent() {
+                self.unwatch_dir(parent);
+            }
+        }
+    }
+}
+
+fn watch_directory_system(
+    uv_dir: UniqueView<ConfigFilesDirectory>,
+    mut uvm_watcher: UniqueViewMut<ConfigFilesWatcher>,
+) {
+    if uv_dir.is_inserted_or_modified() {
+        let new_dir = uv_dir.path.as_deref().map(canonical_dir);
+        if uvm_watcher.watched_dir == new_dir {
+            return;
+        }
+        let watcher = uvm_watcher.as_mut();
+        if let Some(old_dir) = watcher.watched_dir.take() {
+            watcher.unwatch_dir(&old_dir);
+        }
+        if let Some(ref dir) = new_dir {
+            if !dir.is_dir() {
+                error!(?dir, "config directory does not exist");
+            }
+            watcher.watch_dir(dir);
+        }
+        watcher.watched_dir = new_dir;
+    }
+}
+
+/// Symlinks in the directory path are resolved, so paths in watcher events match.
+fn canonical_dir(dir: &Path) -> PathBuf {
+    match dir.canonicalize() {
+        Ok(path) => path,
+        Err(err) => {
+            warn!(?err, ?dir, "config directory could not be canonicalized");
+            dir.to_path_buf()
+        }
+    }
+}
+
+/// Files may not exist, yet, so only the directory is canonicalized and the rest of the path
+/// is normalized, to match the paths in watcher events.
+fn config_file_path(dir: &Path, relative_path: &str) -> PathBuf {
+    let mut path = canonical_dir(dir);
+    for component in Path::new(relative_path).components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                path.pop();
+            }
+            other => path.push(other),
+        }
+    }
+    path
+}
+
+#[test]
+fn test_config_file_path_matches_watcher_paths() {
+    let dir = std::env::temp_dir();
+    let canonical = dir.canonicalize().unwrap();
+    assert_eq!(
+        config_file_path(&dir, "./tls/../missing.toml"),
+        canonical.join("missing.toml")
+    );
+    assert_eq!(
+        config_file_path(&dir, "tls/cert.pem").parent(),
+        Some(canonical.join("tls").as_path())
+    );
+}
+
 pub use config_file_plugin::ConfigFilePlugin;
 
 mod config_file_plugin {
@@ -219,6 +388,7 @@ mod config_file_plugin {
     use crate::prelude::*;
     use hn_app::_ecs_::*;
 
+    use super::config_file_path;
     use super::{
         internal, ConfigDirectoryPlugin, ConfigFileContent, ConfigFileContentError,
         ConfigFileContentInner, ConfigFilesDirectory, ConfigFilesWatcher, ReadConfigFile,
@@ -283,7 +453,11 @@ mod config_file_plugin {
                 let target = uvm_file_content.as_mut();
                 target.content_opt =
                     update_with.map(|(version, full_path, read_res)| ConfigFileContentInner {
-                        is_unlinked: false,
+                        // deleted or renamed away, or never created
+                        is_unlinked: matches!(
+                            read_res,
+                            Err(err) if err.kind() == std::io::ErrorKind::NotFound
+                        ),
                         version,
                         content: Arc::new(
                             read_res
@@ -313,77 +487,37 @@ mod config_file_plugin {
     ) {
         let for_confg_file = type_name::<C>();
         let _span = info_span!("watch_system", ?for_confg_file).entered();
-        use notify::Watcher;
         if uv_dir.is_inserted_or_modified() || uv_config.is_inserted_or_modified() {
             debug!(?for_confg_file, "detected config change");
-            match &uv_dir.path {
-                Some(dir) => {
-                    let joined = dir.join(uv_config.relative_path());
-                    let new_watch_path = joined
-                        .canonicalize()
-                        .todo(f!("expect canonicalization of {joined:?}"));
-                    if let Some(ref watched_path) = uvm_file_path.watch_path {
-                        if &new_watch_path == watched_path {
-                            // still same path
-                            return;
-                        }
-                    } else {
-                        // update with new watch path
-                        uvm_file_path.watch_path = Some(new_watch_path);
-                    }
-                }
-                None => {
-                    if uvm_file_path.watch_path.is_none() {
-                        // nothing to do
-                        return;
-                    }
-                }
+            let new_watch_path = uv_dir
+                .path
+                .as_deref()
+                .map(|dir| config_file_path(dir, uv_config.relative_path()));
+            if uvm_file_path.watch_path == new_watch_path {
+                // still same path, or nothing to do
+                return;
             }
 
             // we must update watchers and count
-
-            let ConfigFilesWatcher {
-                ref mut watcher,
-                ref mut watch_count,
-            } = uvm_watcher.as_mut();
+            let watcher = uvm_watcher.as_mut();
 
             if let Some(watch_path) = uvm_file_path.watch_path.as_ref() {
                 let _span = debug_span!("removing old watch path", file_path=?watch_path).entered();
-                if let Some(1) = watch_count.get(watch_path) {
-                    watch_count.remove(watch_path);
-                    let _ = watcher.unwatch(watch_path);
-                } else {
-                    watch_count.entry(watch_path.clone()).and_modify(|c| {
-                        if *c > 0 {
-                            *c -= 1
-                        }
-                    });
-                }
+                watcher.unwatch_file(watch_path);
             }
 
-            if let Some(ref base_dir) = uv_dir.path {
-                debug!(?base_dir, "loading config file paths via directory");
-                let watch_path = base_dir.join(uv_config.relative_path());
-                let watch_path = watch_path
-                    .canonicalize()
-                    .todo(f!("canonicalizing path ({watch_path:?}) to config file"));
-
+            if let Some(watch_path) = new_watch_path {
                 debug!(file_path=?watch_path, "adding path to watch");
-
-                let cur = watch_count.entry(watch_path.clone()).or_default();
-                if *cur == 0 {
-                    debug_span!("watcher add watched file", file_path=?watch_path).in_scope(|| {
-                        watcher
-                            .watch(&watch_path, notify::RecursiveMode::NonRecursive)
-                            .todo(f!("watching file"))
-                    });
-
-                    // notify on first load
-                    internal::load_the_file_contents(&[watch_path], &mut uvm_dir_file_content)
+                if watcher.watch_file(&watch_path) {
+                    // notify on first load, files which don't exist are loaded as unlinked
+                    internal::load_the_file_contents(
+                        &[watch_path.clone()],
+                        &mut uvm_dir_file_content,
+                    )
                 }
-
-                *cur += 1;
+                uvm_file_path.watch_path = Some(watch_path);
             } else {
+                uvm_file_path.watch_path = None;
                 // no linked path
                 // hmm: this will happen for every config file plugin, but maybe that's fine...
                 uvm_dir_file_content.path_to_version_and_body.clear();
@@ -392,6 +526,206 @@ mod config_file_plugin {
     }
 }
 
+pub use typed_config_file_plugin::{
+    TomlConfigFile, TypedConfig, TypedConfigError, TypedConfigFilePlugin,
+};
+
+mod typed_config_file_plugin {
+    use std::any::type_name;
+    use std::marker::PhantomData;
+    use std::str::FromStr;
+
+    use serde::de::DeserializeOwned;
+
+    use crate::prelude::*;
+    use hn_app::_ecs_::*;
+
+    use super::{ConfigFileContent, ConfigFilePlugin, ConfigOverrides, ReadConfigFile};
+
+    /// Reads a toml file into `T` with a [ConfigFilePlugin] and its [ConfigOverrides], requires
+    /// one [super::ConfigDirectoryPlugin].
+    ///
+    /// Systems should depend on the [TypedConfig] unique rather than the file content, since
+    /// it's only modified when the deserialized value (or its error) changes.
+    pub struct TypedConfigFilePlugin<T> {
+        relative_path: &'static str,
+        _mark: PhantomData<T>,
+    }
+
+    impl<T> TypedConfigFilePlugin<T> {
+        pub fn new(relative_path: &'static str) -> Self {
+            Self {
+                relative_path,
+                _mark: PhantomData,
+            }
+        }
+    }
+
+    impl<T> Plugin for TypedConfigFilePlugin<T>
+    where
+        T: DeserializeOwned + PartialEq + Debug + Send + Sync + 'static,
+    {
+        fn build(&self, app: &mut AppBuilder) {
+            app.add_plugin(ConfigFilePlugin(TomlConfigFile::<T> {
+                relative_path: self.relative_path,
+                _mark: PhantomData,
+            }));
+            app.add_tracked_value(TypedConfig::<T>(Arc::new(Err(TypedConfigError {
+                field: None,
+                message: "not loaded, yet".to_string(),
+            }))));
+            app.add_system(deserialize_system::<T>);
+        }
+    }
+
+    /// The file read by [TypedConfigFilePlugin], kept as a [toml_edit::Document] so its
+    /// [ConfigFileContent] still reports files which aren't valid toml.
+    #[derive(Component)]
+    #[track(All)]
+    pub struct TomlConfigFile<T> {
+        relative_path: &'static str,
+        _mark: PhantomData<T>,
+    }
+
+    impl<T> Clone for TomlConfigFile<T> {
+        fn clone(&self) -> Self {
+            Self {
+                relative_path: self.relative_path,
+                _mark: PhantomData,
+            }
+        }
+    }
+
+    impl<T: Send + Sync + 'static> ReadConfigFile for TomlConfigFile<T> {
+        type Content = toml_edit::Document;
+        type Error = anyhow::Error;
+
+        fn relative_path(&self) -> &str {
+            self.relative_path
+        }
+
+        fn load(&self, bytes: &[u8]) -> Result<Self::Content, Self::Error> {
+            let str = String::from_utf8(bytes.to_vec()).with_context(|| "loading toml config")?;
+            let doc =
+                toml_edit::Document::from_str(&str).with_context(|| "parsing toml as toml")?;
+            Ok(doc)
+        }
+    }
+
+    /// Unique, the whole file deserialized.
+    #[derive(Component, Debug)]
+    #[track(All)]
+    pub struct TypedConfig<T: Send + Sync + 'static>(pub Arc<Result<T, TypedConfigError>>);
+
+    impl<T: Send + Sync + 'static> TypedConfig<T> {
+        /// For filling in [ArcResult] uniques from the settings.
+        pub fn get<U>(&self, f: impl FnOnce(&T) -> U) -> Result<U> {
+            match self.0.as_ref() {
+                Ok(value) => Ok(f(value)),
+                Err(err) => Err(anyhow::Error::new(err.clone())),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    impl<T: DeserializeOwned + Send + Sync + 'static> TypedConfig<T> {
+        /// Deserialized like a file's contents, without overrides.
+        pub(crate) fn from_toml(toml: &str) -> Self {
+            let doc = toml_edit::Document::from_str(toml).expect("valid toml");
+            TypedConfig(Arc::new(deserialize::<T>(doc)))
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct TypedConfigError {
+        /// Dotted path like `tls.mode`, `None` when the file couldn't be read or parsed
+        pub field: Option<String>,
+        pub message: String,
+    }
+
+    impl std::fmt::Display for TypedConfigError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self.field {
+                Some(ref field) => write!(f, "Invalid `{field}`: {}", self.message),
+                None => f.write_str(&self.message),
+            }
+        }
+    }
+
+    impl std::error::Error for TypedConfigError {}
+
+    fn deserialize_system<T>(
+        uv_file: UniqueView<TomlConfigFile<T>>,
+        uv_content: UniqueView<ConfigFileContent<TomlConfigFile<T>>>,
+        uv_overrides: UniqueView<ConfigOverrides>,
+        mut uvm_typed: UniqueViewMut<TypedConfig<T>>,
+    ) where
+        T: DeserializeOwned + PartialEq + Debug + Send + Sync + 'static,
+    {
+        if uv_content.is_inserted_or_modified() || uv_overrides.is_inserted_or_modified() {
+            let name = type_name::<T>();
+            let new_res = match uv_content.get_content() {
+                None => Err(TypedConfigError {
+                    field: None,
+                    message: "not loaded, yet".to_string(),
+                }),
+                Some(inner) => match inner.content.as_ref() {
+                    Ok(doc) => {
+                        let mut doc = doc.clone();
+                        let section = super::section_of(uv_file.relative_path);
+                        uv_overrides
+                            .apply(&section, doc.as_table_mut())
+                            .and_then(|()| deserialize::<T>(doc))
+                    }
+                    Err(err) => Err(TypedConfigError {
+                        field: None,
+                        message: format!("{err}"),
+                    }),
+                },
+            };
+
+            if let Err(ref err) = new_res {
+                warn!(?name, %err, "failed to deserialize config file");
+            }
+
+            if uvm_typed.0.as_ref() != &new_res {
+                debug!(?name, "config value changed");
+                // as_mut marks it for modified
+                uvm_typed.as_mut().0 = Arc::new(new_res);
+            }
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(doc: toml_edit::Document) -> Result<T, TypedConfigError> {
+        serde_path_to_error::deserialize(toml_edit::de::Deserializer::new(doc)).map_err(|err| {
+            let path = err.path().to_string();
+            TypedConfigError {
+                // missing fields are reported on the table which is missing them
+                field: (path != ".").then_some(path),
+                message: err.into_inner().to_string().trim().to_string(),
+            }
+        })
+    }
+
+    #[test]
+    fn test_deserialize_reports_field_path() {
+        #[derive(Debug, Deserialize)]
+        struct Settings {
+            #[allow(unused)]
+            server: Server,
+        }
+        #[derive(Debug, Deserialize)]
+        struct Server {
+            #[allow(unused)]
+            bind_address: std::net::SocketAddr,
+        }
+
+        let doc = toml_edit::Document::from_str("[server]\nbind_address = 8080\n").unwrap();
+        let err = deserialize::<Settings>(doc).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("server.bind_address"));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -447,6 +781,72 @@ mod tests {
         }
     }
 
+    /// Runs scheduled systems until the file has `value = expected`, or is unlinked for `None`.
+    async fn wait_for_value(
+        app: &App,
+        recv: &mut tokio::sync::mpsc::UnboundedReceiver<app_ctx::Command>,
+        expected: Option<i64>,
+    ) {
+        let read = || {
+            app.world.run(
+                |uv_content: UniqueView<super::ConfigFileContent<TestConfig>>| {
+                    let content = uv_content.get_content().expect("file is watched");
+                    if content.is_unlinked {
+                        return None;
+                    }
+                    let doc = content.content.as_ref().as_ref().ok()?;
+                    doc.get("value").and_then(|value| value.as_integer())
+                },
+            )
+        };
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+        while read() != expected {
+            let command = tokio::time::timeout_at(deadline, recv.recv())
+                .await
+                .unwrap_or_else(|_| panic!("watcher didn't see {expected:?}"))
+                .expect("command channel is open");
+            let name = format!("command-{}", xid::new());
+            WorkloadBuilder::new(name.clone())
+                .with_system(command.system)
+                .add_to_world(&app.world)
+                .expect("adding workload");
+            app.world.run_workload(name).expect("running workload");
+            app.update();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watcher_sees_created_replaced_and_deleted_files() {
+        let dir = std::env::temp_dir().join(format!("hn-config-watch-{}", xid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("test.toml");
+        let (sender, mut recv) = tokio::sync::mpsc::unbounded_channel();
+        let app = test_ecs::test_app3(
+            AppCtxPlugin(sender),
+            super::ConfigDirectoryPlugin {
+                default_path: Some(dir.clone()),
+                ..Default::default()
+            },
+            super::ConfigFilePlugin(TestConfig),
+        );
+        app.update();
+        wait_for_value(&app, &mut recv, None).await;
+
+        std::fs::write(&file_path, "value = 1").unwrap();
+        wait_for_value(&app, &mut recv, Some(1)).await;
+
+        // editors write a new file and rename it over the old one
+        let tmp_path = dir.join("test.toml.tmp");
+        std::fs::write(&tmp_path, "value = 2").unwrap();
+        std::fs::rename(&tmp_path, &file_path).unwrap();
+        wait_for_value(&app, &mut recv, Some(2)).await;
+
+        std::fs::remove_file(&file_path).unwrap();
+        wait_for_value(&app, &mut recv, None).await;
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
     #[tokio::test]
     #[ignore = "this test doesn't stop, it's just for testing"]
     async fn test() {
@@ -457,7 +857,7 @@ mod tests {
             AppCtxPlugin(sender),
             super::ConfigDirectoryPlugin {
                 default_path: Some(default_conf_folder),
-                polling_duration: None,
+                ..Default::default()
             },
             super::ConfigFilePlugin(TestConfig),
             TestPlugin,
@@ -0,0 +1,211 @@
+//! Values set outside of the config files, layered over them in order: file, then
+//! `HERE_NOW__SECTION__KEY` environment variables, then `--set section.key=value` flags.
+//!
+//! The section is the config file name without `.toml`, with `-` written as `_` in environment
+//! variables, so `HERE_NOW__DISCORD__CLIENT_SECRET` sets `client_secret` of `discord.toml` and
+//! `HERE_NOW__HERE_NOW_APP__TLS__MODE` sets `mode` in the `[tls]` table of `here-now-app.toml`.
+use std::str::FromStr;
+
+use toml_edit::{Item, TableLike, Value};
+
+use crate::prelude::*;
+use hn_app::_ecs_::*;
+
+use super::TypedConfigError;
+
+const ENV_PREFIX: &str = "HERE_NOW__";
+const SET_FLAG: &str = "--set";
+
+/// Unique, added by [super::ConfigDirectoryPlugin].
+#[derive(Component, Clone, Debug, Default)]
+#[track(All)]
+pub struct ConfigOverrides(Vec<ConfigOverride>);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigOverride {
+    /// Config file name without `.toml`, like `here-now-app`
+    pub section: String,
+    /// Dotted key within the file, like `tls.mode`
+    pub key: String,
+    pub value: String,
+    pub source: ConfigSource,
+}
+
+/// Where the effective value of a key came from.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConfigSource {
+    Environment { var: String },
+    CommandLine { flag: String },
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Environment { var } => write!(f, "environment variable {var}"),
+            ConfigSource::CommandLine { flag } => write!(f, "command line {flag}"),
+        }
+    }
+}
+
+impl ConfigOverrides {
+    /// Reads `HERE_NOW__` variables from the environment and `--set` flags from `args`,
+    /// ignoring other arguments.
+    pub fn from_env_and_args(args: &[String]) -> Result<Self> {
+        Self::from_vars_and_args(std::env::vars(), args)
+    }
+
+    pub fn from_vars_and_args(
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: &[String],
+    ) -> Result<Self> {
+        let mut overrides = Vec::new();
+        let mut vars = vars
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+            .collect::<Vec<_>>();
+        // environment order isn't stable, and later overrides win
+        vars.sort();
+        for (var, value) in vars {
+            let mut parts = var[ENV_PREFIX.len()..].split("__");
+            let section = parts
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+                .replace('_', "-");
+            let key = parts.map(str::to_lowercase).collect::<Vec<_>>().join(".");
+            if section.is_empty() || key.is_empty() || key.split('.').any(str::is_empty) {
+                anyhow::bail!("expected {var} to look like HERE_NOW__SECTION__KEY");
+            }
+            overrides.push(ConfigOverride {
+                section,
+                key,
+                value,
+                source: ConfigSource::Environment { var },
+            });
+        }
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let assignment = if arg == SET_FLAG {
+                args.next()
+                    .with_context(|| format!("expected section.key=value after {SET_FLAG}"))?
+            } else if let Some(assignment) = arg.strip_prefix("--set=") {
+                assignment
+            } else {
+                continue;
+            };
+            let (path, value) = assignment.split_once('=').with_context(|| {
+                format!("expected {assignment:?} to look like section.key=value")
+            })?;
+            let (section, key) = path
+                .split_once('.')
+                .with_context(|| format!("expected {path:?} to look like section.key"))?;
+            overrides.push(ConfigOverride {
+                section: section.to_string(),
+                key: key.to_string(),
+                value: value.to_string(),
+                source: ConfigSource::CommandLine {
+                    flag: format!("{SET_FLAG} {path}"),
+                },
+            });
+        }
+
+        Ok(ConfigOverrides(overrides))
+    }
+
+    /// The effective override of each key in the section, later ones win.
+    pub fn for_section<'a>(&'a self, section: &'a str) -> impl Iterator<Item = &'a ConfigOverride> {
+        self.0.iter().enumerate().filter_map(move |(i, item)| {
+            let overridden_later = self.0[i + 1..]
+                .iter()
+                .any(|later| later.section == item.section && later.key == item.key);
+            (item.section == section && !overridden_later).then_some(item)
+        })
+    }
+
+    pub fn source_of(&self, section: &str, key: &str) -> Option<&ConfigSource> {
+        self.for_section(section)
+            .find(|item| item.key == key)
+            .map(|item| &item.source)
+    }
+
+    /// Sets the overridden keys of the section in the table, adding tables along the way.
+    pub fn apply(&self, section: &str, table: &mut dyn TableLike) -> Result<(), TypedConfigError> {
+        for item in self.for_section(section) {
+            set_key(table, &item.key, &item.value).map_err(|err| TypedConfigError {
+                field: Some(item.key.clone()),
+                message: format!("{err:#} (set by {})", item.source),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Config file name without `.toml`, used to find its overrides.
+pub fn section_of(relative_path: &str) -> String {
+    std::path::Path::new(relative_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| relative_path.to_string())
+}
+
+fn set_key(table: &mut dyn TableLike, key: &str, raw: &str) -> Result<()> {
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let last = parts.pop().context("empty key")?;
+    let mut table = table;
+    for part in parts {
+        table = table
+            .entry(part)
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .with_context(|| format!("expected {part:?} to be a table"))?;
+    }
+    let value = override_value(table.get(last), raw);
+    table.insert(last, Item::Value(value));
+    Ok(())
+}
+
+/// Raw values are read as toml (e.g. `true` or `8000`), except where the file has a string
+/// or they aren't valid toml, so secrets which look like numbers stay strings.
+fn override_value(existing: Option<&Item>, raw: &str) -> Value {
+    let existing_is_str = existing.map_or(false, |item| item.is_str());
+    match Value::from_str(raw) {
+        Ok(value) if !existing_is_str && !value.is_str() => value,
+        _ => Value::from(raw),
+    }
+}
+
+#[test]
+fn test_overrides_layer_env_then_args() {
+    let vars = [
+        ("HERE_NOW__DISCORD__CLIENT_SECRET", "from-env"),
+        ("HERE_NOW__HERE_NOW_APP__TLS__MODE", "self-signed"),
+        ("PATH", "/usr/bin"),
+    ]
+    .map(|(var, value)| (var.to_string(), value.to_string()));
+    let args = [
+        "--set",
+        "discord.client_secret=from-args",
+        "--set=here-now-app.dev_mode=true",
+    ]
+    .map(String::from);
+    let overrides = ConfigOverrides::from_vars_and_args(vars, &args).unwrap();
+
+    let mut doc =
+        toml_edit::Document::from_str("client_id = \"123\"\nclient_secret = \"file\"\n").unwrap();
+    overrides.apply("discord", doc.as_table_mut()).unwrap();
+    assert_eq!(doc["client_secret"].as_str(), Some("from-args"));
+    assert_eq!(doc["client_id"].as_str(), Some("123"));
+    assert_eq!(
+        overrides.source_of("discord", "client_secret"),
+        Some(&ConfigSource::CommandLine {
+            flag: "--set discord.client_secret".to_string()
+        })
+    );
+
+    let mut doc = toml_edit::Document::new();
+    overrides.apply("here-now-app", doc.as_table_mut()).unwrap();
+    assert_eq!(doc["tls"]["mode"].as_str(), Some("self-signed"));
+    assert_eq!(doc["dev_mode"].as_bool(), Some(true));
+}
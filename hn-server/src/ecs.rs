@@ -1,13 +1,12 @@
 //! Data stored to disk
 
-use std::marker::PhantomData;
-
 use crate::prelude::*;
 use hn_app::{_ecs_::*, ecs_bundle};
 
 pub mod import_export;
 pub use import_export::plugin::SavePlugin;
 
+pub use hn_app::database_plugin::Linked;
 pub use hn_app::HintedID;
 
 #[ecs_component("Device")]
@@ -20,12 +19,6 @@ pub enum CredTag {
     Discord,
 }
 
-#[ecs_component("Device")]
-pub struct Linked<Tag: 'static> {
-    pub items: Vec<EntityId>,
-    _mark: PhantomData<Tag>,
-}
-
 #[ecs_component("Device")]
 #[ecs_bundle(DeviceTag)]
 #[derive(Debug, Default)]
@@ -43,29 +36,13 @@ pub struct AuthorizedKey {
     pub key: hn_keys::PublicKeyKind,
 }
 
-impl<Tag: 'static> Linked<Tag> {
-    pub fn new() -> Self {
-        Self {
-            items: Vec::new(),
-            _mark: PhantomData,
-        }
-    }
-    pub fn new_with<I>(entity_id: I) -> Self
-    where
-        I: IntoIterator<Item = EntityId>,
-    {
-        Self {
-            items: entity_id.into_iter().collect(),
-            _mark: PhantomData,
-        }
-    }
-}
-
 #[ecs_bundle(CredTag)]
 #[ecs_component("Cred")]
 #[derive(Debug)]
 pub struct EcsDiscordCred {
+    #[sensitive]
     pub access_token: String,
+    #[sensitive]
     pub refresh_token: String,
     pub expires_at: std::time::SystemTime,
 }
@@ -1,45 +1,93 @@
-use std::marker::PhantomData;
-
 use super::HintedID;
 use crate::prelude::bonsai_::*;
 use crate::prelude::*;
-use hn_app::ecs_bundle;
+use bonsaidb::core::document::{BorrowedDocument, Emit};
+use hn_app::database_plugin::audit::AuditEntry;
+use hn_app::database_plugin::lazy::view_contents;
+use hn_app::database_plugin::migrations::{DocumentValue, DocumentVersion, Migration};
+use hn_app::database_plugin::{BundleField, BundleLinks, LinkedBundle};
+use hn_app::{_ecs_::View, ecs_bundle, BundleSync};
 
 #[derive(schema::Schema)]
-#[schema(name = "DBSchema", collections = [CredBundle, DeviceBundle])]
+#[schema(name = "DBSchema", collections = [CredBundle, DeviceBundle, AuditEntry])]
 pub struct DBSchema;
 
-pub mod export;
-pub mod import;
+pub mod backup;
 pub mod plugin;
 
 /// Stored in BonsaiDB
-#[derive(schema::Collection)]
-#[collection(name = "ecs-devices", primary_key = HintedID)]
+#[derive(schema::Collection, BundleSync)]
+#[collection(name = "ecs-devices", primary_key = HintedID, views = [DevicesByAuthorizedKey])]
+#[bundle_sync(tag = ecs::DeviceTag, version = 1, migrations = device_migrations)]
 // Bundled in the ECS
 #[ecs_bundle(DeviceTag)]
 #[derive(Debug)]
 pub struct DeviceBundle {
-    // what is the component for this again?
+    #[bundle_sync(version)]
+    #[serde(rename = "_v", default)]
+    version: DocumentVersion,
+    #[bundle_sync(component = ecs::Linked<ecs::CredTag>)]
     c_linked_creds: LinkedBundle<CredBundle>,
-    #[serde(default)]
     c_authorized_keys: ecs::AuthorizedKeys,
 }
-#[ecs_bundle]
-#[derive(Debug, Default)]
-pub struct LinkedBundle<Bundle: 'static> {
-    items: Vec<HintedID>,
-    #[serde(skip_serializing, default)]
-    _mark: PhantomData<Bundle>,
+
+/// Devices which a key is authorized for, see [authorized_key_view_key].
+#[derive(Clone, Debug, schema::View)]
+#[view(collection = DeviceBundle, name = "by-authorized-key", key = String)]
+pub struct DevicesByAuthorizedKey;
+
+impl schema::ViewSchema for DevicesByAuthorizedKey {
+    type View = Self;
+
+    fn map(&self, document: &BorrowedDocument<'_>) -> schema::ViewMapResult<Self::View> {
+        let Some(device) = view_contents::<DeviceBundle>(document) else {
+            return Ok(schema::view::map::Mappings::none());
+        };
+        device
+            .c_authorized_keys
+            .keys
+            .iter()
+            .filter_map(|authorized| authorized_key_view_key(&authorized.key).ok())
+            .map(|key| document.header.emit_key(key))
+            .collect()
+    }
+}
+
+/// Key of [DevicesByAuthorizedKey] for a public key.
+pub fn authorized_key_view_key(key: &hn_keys::PublicKeyKind) -> Result<String> {
+    serde_json::to_string(key).context("serializing public key for view")
+}
+
+fn device_migrations() -> Vec<Migration> {
+    vec![Migration {
+        from_version: 0,
+        description: "add empty authorized keys to devices saved before keys existed",
+        migrate: |doc: &mut DocumentValue| {
+            if doc.get("c_authorized_keys").is_none() {
+                doc.insert_serialized("c_authorized_keys", &ecs::AuthorizedKeys::default())?;
+            }
+            Ok(())
+        },
+    }]
 }
 
 /// Stored in BonsaiDB
-#[derive(schema::Collection)]
+#[derive(schema::Collection, BundleSync)]
 #[collection(name = "ecs-creds", primary_key = HintedID)]
+#[bundle_sync(
+    tag = ecs::CredTag,
+    tag_value = ecs::CredTag::Discord,
+    version = 1,
+    migrations = cred_migrations
+)]
 // Bundled in the ECS
 #[ecs_bundle(CredTag)]
 #[derive(Debug)]
 pub struct CredBundle {
+    #[bundle_sync(version)]
+    #[serde(rename = "_v", default)]
+    version: DocumentVersion,
+    #[bundle_sync(component = ecs::EcsDiscordCred)]
     kind: CredBundleKind,
 }
 #[ecs_bundle(CredTag)]
@@ -47,3 +95,61 @@ pub struct CredBundle {
 pub enum CredBundleKind {
     Discord { c_discord_cred: ecs::EcsDiscordCred },
 }
+
+fn cred_migrations() -> Vec<Migration> {
+    vec![Migration {
+        from_version: 0,
+        description: "seal discord tokens which were saved in plain text",
+        // plain values are still read, and saving the migrated document seals them
+        migrate: |_doc: &mut DocumentValue| Ok(()),
+    }]
+}
+
+impl BundleField<ecs::EcsDiscordCred> for CredBundleKind {
+    fn into_component(self, _links: &BundleLinks) -> Result<ecs::EcsDiscordCred> {
+        match self {
+            CredBundleKind::Discord { c_discord_cred } => Ok(c_discord_cred),
+        }
+    }
+
+    fn from_component(
+        component: &ecs::EcsDiscordCred,
+        _v_hinted_id: &View<HintedID>,
+    ) -> Result<Self> {
+        Ok(CredBundleKind::Discord {
+            c_discord_cred: component.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_device_bundle_migrates_from_v0() {
+    use hn_app::database_plugin::migrations::{encode, migrate_bytes};
+
+    #[derive(Serialize)]
+    struct DeviceBundleV0 {
+        c_linked_creds: LinkedBundleV0,
+    }
+    #[derive(Serialize)]
+    struct LinkedBundleV0 {
+        items: Vec<HintedID>,
+    }
+
+    let bytes = encode(&DeviceBundleV0 {
+        c_linked_creds: LinkedBundleV0 {
+            items: vec![HintedID::generate("cred")],
+        },
+    })
+    .unwrap();
+    let (device, from_version) = migrate_bytes::<DeviceBundle>(&bytes).unwrap();
+    assert_eq!(from_version, Some(0));
+    assert_eq!(device.version, DocumentVersion(1));
+    assert!(device.c_authorized_keys.keys.is_empty());
+    assert_eq!(device.c_linked_creds.items.len(), 1);
+
+    let (_, from_version) = migrate_bytes::<DeviceBundle>(&encode(&device).unwrap()).unwrap();
+    assert_eq!(
+        from_version, None,
+        "current documents are not migrated again"
+    );
+}
@@ -0,0 +1,485 @@
+//! `hn-server backup export <file>` and `hn-server backup restore <file>`
+//!
+//! Backups are JSON lines: a header followed by one line per document of [super::DBSchema],
+//! including the audit log. Documents are read through the bundle migrations when exporting
+//! and again when restoring, so older backups can be restored after bundles change.
+//!
+//! Sensitive fields stay sealed, so restoring needs the same database secret (`--secret`).
+//! Without `--db` and `--secret`, the ones the server uses are found from `--config-dir`,
+//! `HERE_NOW_CONFIG_FOLDER`, or the platform config directory, like when starting the server.
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{CredBundle, CredBundleKind, DeviceBundle, HintedID};
+use crate::config::config_directory_setup;
+use crate::prelude::bonsai_::*;
+use crate::prelude::*;
+use bonsaidb::core::connection::LowLevelConnection;
+use bonsaidb::core::document::DocumentId;
+use bonsaidb::core::schema::Collection;
+use bonsaidb::core::transaction::{Operation, Transaction};
+use hn_app::database_plugin::audit::AuditEntry;
+use hn_app::database_plugin::migrations::{migrate_json, read_documents, MigrationReport};
+use hn_app::database_plugin::BundleSync;
+use hn_app::sealed::{self, SealingKey};
+
+const BACKUP_FORMAT: &str = "here-now-backup";
+/// Bump when the layout of the lines changes, not when bundles change.
+///
+/// 2: added [BackupLine::Audit]
+const BACKUP_FORMAT_VERSION: u32 = 2;
+
+const USAGE: &str = "usage:
+  hn-server backup export <file> [--exclude-secrets] [--config-dir <dir>] [--db <path>] [--secret <path>]
+  hn-server backup restore <file> [--config-dir <dir>] [--db <path>] [--secret <path>]";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum BackupLine {
+    Header {
+        format: String,
+        format_version: u32,
+        created_at_unix: u64,
+        /// Discord tokens were blanked out, so those creds need to be linked again.
+        secrets_excluded: bool,
+    },
+    Document {
+        collection: String,
+        id: HintedID,
+        contents: JSON,
+    },
+    /// Entry of the audit log, sensitive values were already replaced when it was recorded.
+    Audit { id: u64, entry: AuditEntry },
+}
+
+/// Runs the `backup` subcommand with the arguments following it.
+pub fn run_cli(args: &[String]) -> Result<()> {
+    let mut db_path: Option<PathBuf> = None;
+    let mut secret_path: Option<PathBuf> = None;
+    let mut exclude_secrets = false;
+    let mut config_dir_args = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config-dir" => {
+                let dir = args
+                    .next()
+                    .context("expected a directory after --config-dir")?;
+                config_dir_args = vec![arg.clone(), dir.clone()];
+            }
+            other if other.starts_with("--config-dir=") => {
+                config_dir_args = vec![arg.clone()];
+            }
+            "--db" => {
+                db_path = Some(args.next().context("expected a path after --db")?.into());
+            }
+            "--secret" => {
+                secret_path = Some(
+                    args.next()
+                        .context("expected a path after --secret")?
+                        .into(),
+                );
+            }
+            "--exclude-secrets" => exclude_secrets = true,
+            other if other.starts_with("--") => anyhow::bail!("unknown option {other}\n{USAGE}"),
+            other => positional.push(other),
+        }
+    }
+    let config_dir = || config_directory_setup::find_config_directory(&config_dir_args);
+    let db_path = match db_path {
+        Some(db_path) => db_path,
+        None => super::plugin::configured_db_path(&config_dir()?),
+    };
+    let secret_path = match secret_path {
+        Some(secret_path) => secret_path,
+        None => super::plugin::configured_secret_path(&config_dir()?),
+    };
+    // a new secret couldn't unseal anything in the database
+    let key = SealingKey::load(&secret_path)?;
+
+    match positional.as_slice() {
+        ["export", file] => {
+            let db = open_database(&db_path)?;
+            let file = std::fs::File::create(file)
+                .with_context(|| format!("creating backup file {file:?}"))?;
+            let count = export(&db, &key, std::io::BufWriter::new(file), exclude_secrets)?;
+            info!(count, ?db_path, exclude_secrets, "exported backup");
+        }
+        ["restore", file] => {
+            anyhow::ensure!(!exclude_secrets, "--exclude-secrets only applies to export");
+            let db = open_database(&db_path)?;
+            let file = std::fs::File::open(file)
+                .with_context(|| format!("opening backup file {file:?}"))?;
+            let count = restore(&db, &key, std::io::BufReader::new(file))?;
+            info!(count, ?db_path, "restored backup");
+        }
+        _ => anyhow::bail!("{USAGE}"),
+    }
+
+    Ok(())
+}
+
+fn open_database(path: &Path) -> Result<local::Database> {
+    let mut storage_conf = local::config::StorageConfiguration::default();
+    storage_conf.path = Some(path.to_path_buf());
+    local::Database::open::<super::DBSchema>(storage_conf)
+        .with_context(|| format!("opening database at {path:?}"))
+}
+
+/// Returns the number of documents written. Sensitive fields are written sealed with `key`.
+fn export(
+    db: &local::Database,
+    key: &SealingKey,
+    out: impl Write,
+    exclude_secrets: bool,
+) -> Result<usize> {
+    sealed::with_key(Some(key), || export_documents(db, out, exclude_secrets))
+}
+
+fn export_documents(
+    db: &local::Database,
+    mut out: impl Write,
+    exclude_secrets: bool,
+) -> Result<usize> {
+    let header = BackupLine::Header {
+        format: BACKUP_FORMAT.to_string(),
+        format_version: BACKUP_FORMAT_VERSION,
+        created_at_unix: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |dur| dur.as_secs()),
+        secrets_excluded: exclude_secrets,
+    };
+    write_line(&mut out, &header)?;
+
+    let mut count = export_collection::<CredBundle>(db, &mut out, |cred| {
+        if exclude_secrets {
+            cred.exclude_secrets();
+        }
+    })?;
+    count += export_collection::<DeviceBundle>(db, &mut out, |_| {})?;
+    count += export_audit(db, &mut out)?;
+    out.flush().context("flushing backup")?;
+
+    Ok(count)
+}
+
+fn export_collection<B: BundleSync + Serialize>(
+    db: &local::Database,
+    out: &mut impl Write,
+    mut redact: impl FnMut(&mut B),
+) -> Result<usize> {
+    // never write migrated documents back while backing up
+    let mut report = MigrationReport {
+        dry_run: true,
+        ..Default::default()
+    };
+    let documents = read_documents::<B>(db, &mut report)?;
+    if let Some(failed) = report
+        .collections
+        .iter()
+        .flat_map(|c| c.failed.iter())
+        .next()
+    {
+        anyhow::bail!(
+            "document {} could not be read, so it would be missing from the backup: {}",
+            failed.id,
+            failed.error
+        );
+    }
+
+    let count = documents.len();
+    for (id, mut bundle) in documents {
+        redact(&mut bundle);
+        let contents =
+            serde_json::to_value(&bundle).with_context(|| format!("serializing document {id}"))?;
+        write_line(
+            out,
+            &BackupLine::Document {
+                collection: B::collection_name().to_string(),
+                id,
+                contents,
+            },
+        )?;
+    }
+
+    Ok(count)
+}
+
+fn export_audit(db: &local::Database, out: &mut impl Write) -> Result<usize> {
+    let entries = AuditEntry::all(db)
+        .query()
+        .context("getting all audit entries")?;
+    let count = entries.len();
+    for doc in entries {
+        write_line(
+            out,
+            &BackupLine::Audit {
+                id: doc.header.id,
+                entry: doc.contents,
+            },
+        )?;
+    }
+
+    Ok(count)
+}
+
+fn write_line(out: &mut impl Write, line: &BackupLine) -> Result<()> {
+    serde_json::to_writer(&mut *out, line).context("writing backup line")?;
+    writeln!(out).context("writing backup line")
+}
+
+impl CredBundle {
+    fn exclude_secrets(&mut self) {
+        match &mut self.kind {
+            CredBundleKind::Discord { c_discord_cred } => {
+                c_discord_cred.access_token.clear();
+                c_discord_cred.refresh_token.clear();
+                // treated as expired, so it gets refreshed or linked again
+                c_discord_cred.expires_at = UNIX_EPOCH;
+            }
+        }
+    }
+}
+
+/// Only restores into an empty database, and only writes once every line was read and
+/// every link between documents was verified. Everything is written in one transaction,
+/// so a failed restore leaves the database empty.
+///
+/// Returns the number of documents restored. Sensitive fields are unsealed with `key`.
+fn restore(db: &local::Database, key: &SealingKey, input: impl BufRead) -> Result<usize> {
+    sealed::with_key(Some(key), || restore_documents(db, input))
+}
+
+fn restore_documents(db: &local::Database, input: impl BufRead) -> Result<usize> {
+    ensure_empty::<CredBundle>(db)?;
+    ensure_empty::<DeviceBundle>(db)?;
+    ensure_empty::<AuditEntry>(db)?;
+
+    let mut lines = input.lines().enumerate();
+    let (_, header) = lines.next().context("backup is empty")?;
+    match serde_json::from_str(&header.context("reading header")?).context("parsing header")? {
+        BackupLine::Header {
+            format,
+            format_version,
+            secrets_excluded,
+            ..
+        } => {
+            anyhow::ensure!(
+                format == BACKUP_FORMAT,
+                "not a backup, format is {format:?}"
+            );
+            anyhow::ensure!(
+                format_version <= BACKUP_FORMAT_VERSION,
+                "backup format version {format_version} is newer than {BACKUP_FORMAT_VERSION}"
+            );
+            if secrets_excluded {
+                warn!(
+                    "backup was made without secrets, discord creds will need to be linked again"
+                );
+            }
+        }
+        _ => anyhow::bail!("expected backup to start with a header"),
+    }
+
+    let mut creds = Vec::<(HintedID, CredBundle)>::new();
+    let mut devices = Vec::<(HintedID, DeviceBundle)>::new();
+    let mut audit = Vec::<(u64, AuditEntry)>::new();
+    for (index, line) in lines {
+        let line_number = index + 1;
+        let line = line.with_context(|| format!("reading line {line_number}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (collection, id, contents) = match serde_json::from_str(&line)
+            .with_context(|| format!("parsing line {line_number}"))?
+        {
+            BackupLine::Header { .. } => anyhow::bail!("unexpected header on line {line_number}"),
+            BackupLine::Audit { id, entry } => {
+                audit.push((id, entry));
+                continue;
+            }
+            BackupLine::Document {
+                collection,
+                id,
+                contents,
+            } => (collection, id, contents),
+        };
+
+        if collection == CredBundle::collection_name().to_string() {
+            creds.push((id, parse_contents(contents, line_number)?));
+        } else if collection == DeviceBundle::collection_name().to_string() {
+            devices.push((id, parse_contents(contents, line_number)?));
+        } else {
+            anyhow::bail!("unknown collection {collection:?} on line {line_number}");
+        }
+    }
+
+    let cred_ids = creds.iter().map(|(id, _)| id).collect::<HashSet<_>>();
+    for (id, device) in devices.iter() {
+        for linked in device.c_linked_creds.items.iter() {
+            anyhow::ensure!(
+                cred_ids.contains(linked),
+                "device {id} links to cred {linked}, which is not in the backup"
+            );
+        }
+    }
+
+    let count = creds.len() + devices.len() + audit.len();
+    let mut transaction = Transaction::new();
+    push_documents(&mut transaction, creds)?;
+    push_documents(&mut transaction, devices)?;
+    for (id, entry) in audit {
+        let contents = AuditEntry::serialize(&entry)
+            .with_context(|| format!("serializing audit entry {id}"))?;
+        transaction.push(Operation::overwrite(
+            AuditEntry::collection_name(),
+            DocumentId::new(id).with_context(|| format!("encoding id of audit entry {id}"))?,
+            contents,
+        ));
+    }
+    db.apply_transaction(transaction)
+        .context("writing restored documents")?;
+
+    Ok(count)
+}
+
+fn ensure_empty<C: SerializedCollection>(db: &local::Database) -> Result<()> {
+    let existing = C::all(db)
+        .count()
+        .with_context(|| format!("counting {}", C::collection_name()))?;
+    anyhow::ensure!(
+        existing == 0,
+        "can only restore into an empty database, but {} has {existing} documents",
+        C::collection_name()
+    );
+    Ok(())
+}
+
+/// Documents from older backups are migrated to the current version of the bundle.
+fn parse_contents<B: BundleSync + serde::de::DeserializeOwned>(
+    contents: JSON,
+    line_number: usize,
+) -> Result<B> {
+    let (bundle, from_version) = migrate_json::<B>(contents)
+        .with_context(|| format!("reading line {line_number} as {}", B::collection_name()))?;
+    if let Some(from_version) = from_version {
+        info!(line_number, from_version, "migrated backup document");
+    }
+    Ok(bundle)
+}
+
+fn push_documents<B: BundleSync>(
+    transaction: &mut Transaction,
+    documents: Vec<(HintedID, B)>,
+) -> Result<()> {
+    for (id, bundle) in documents {
+        let contents =
+            B::serialize(&bundle).with_context(|| format!("serializing document {id}"))?;
+        transaction.push(Operation::overwrite(
+            B::collection_name(),
+            DocumentId::new(id.clone()).with_context(|| format!("encoding id of document {id}"))?,
+            contents,
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_backup_round_trip_without_secrets() {
+    use hn_app::database_plugin::migrations::DocumentVersion;
+    use hn_app::database_plugin::LinkedBundle;
+
+    let key = SealingKey::derive(b"backup test secret");
+    let temp_dir = std::env::temp_dir().join(format!("hn-backup-{}", xid::new()));
+    let cred_id = HintedID::generate("cred");
+    let device_id = HintedID::generate("dev");
+
+    let source = open_database(&temp_dir.join("source.bonsaidb")).unwrap();
+    CredBundle::overwrite(
+        &cred_id,
+        CredBundle {
+            version: DocumentVersion(CredBundle::VERSION),
+            kind: CredBundleKind::Discord {
+                c_discord_cred: ecs::EcsDiscordCred {
+                    access_token: "secret-access".to_string(),
+                    refresh_token: "secret-refresh".to_string(),
+                    expires_at: SystemTime::now(),
+                },
+            },
+        },
+        &source,
+    )
+    .unwrap();
+    DeviceBundle::overwrite(
+        &device_id,
+        DeviceBundle {
+            version: DocumentVersion(DeviceBundle::VERSION),
+            c_linked_creds: LinkedBundle::new(vec![cred_id.clone()]),
+            c_authorized_keys: Default::default(),
+        },
+        &source,
+    )
+    .unwrap();
+    AuditEntry {
+        document: device_id.clone(),
+        collection: DeviceBundle::collection_name().to_string(),
+        component: "c_linked_creds".to_string(),
+        previous: None,
+        new: Some(serde_json::json!([cred_id.to_string()])),
+        at: SystemTime::now(),
+        reason: "test".to_string(),
+    }
+    .push_into(&source)
+    .unwrap();
+
+    let mut backup = Vec::new();
+    assert_eq!(export(&source, &key, &mut backup, true).unwrap(), 3);
+    let backup_str = String::from_utf8(backup.clone()).unwrap();
+    assert!(!backup_str.contains("secret-"), "secrets are excluded");
+
+    let restored = open_database(&temp_dir.join("restored.bonsaidb")).unwrap();
+    assert_eq!(restore(&restored, &key, backup.as_slice()).unwrap(), 3);
+    let device = DeviceBundle::get(&device_id, &restored).unwrap().unwrap();
+    assert_eq!(device.contents.c_linked_creds.items, vec![cred_id.clone()]);
+    let history = hn_app::database_plugin::audit::document_history(&restored, &device_id).unwrap();
+    assert_eq!(history.len(), 1, "the audit log is restored");
+    assert!(
+        restore(&restored, &key, backup.as_slice()).is_err(),
+        "only restores into an empty database"
+    );
+
+    // a backup missing the linked cred is rejected
+    let dangling = backup_str
+        .lines()
+        .filter(|line| !line.contains("ecs-creds"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let empty = open_database(&temp_dir.join("empty.bonsaidb")).unwrap();
+    assert!(restore(&empty, &key, dangling.as_bytes()).is_err());
+    assert_eq!(CredBundle::all(&empty).count().unwrap(), 0);
+
+    // a document saved before devices had keys is migrated while restoring
+    let old_device_id = HintedID::generate("dev");
+    let mut old_device = serde_json::from_str::<JSON>(
+        backup_str
+            .lines()
+            .find(|line| line.contains("ecs-devices"))
+            .unwrap(),
+    )
+    .unwrap();
+    old_device["id"] = serde_json::to_value(&old_device_id).unwrap();
+    let contents = old_device["contents"].as_object_mut().unwrap();
+    contents.remove("_v");
+    contents.remove("c_authorized_keys");
+    let old_backup = format!("{backup_str}{old_device}\n");
+    assert_eq!(restore(&empty, &key, old_backup.as_bytes()).unwrap(), 4);
+    let migrated = DeviceBundle::get(&old_device_id, &empty).unwrap().unwrap();
+    assert_eq!(migrated.contents.version.0, DeviceBundle::VERSION);
+    assert!(migrated.contents.c_authorized_keys.keys.is_empty());
+
+    drop((source, restored, empty));
+    let _ = std::fs::remove_dir_all(&temp_dir);
+}
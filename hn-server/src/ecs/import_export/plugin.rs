@@ -1,24 +1,872 @@
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::prelude::*;
+use crate::{app_server_plugins::AppServerConfigFile, config_plugins, prelude::*};
 use hn_app::_ecs_::*;
-use hn_app::database_plugin::{LastImport, LocalDatabasePlugin};
+use hn_app::database_plugin::audit::AuditSettings;
+use hn_app::database_plugin::{BundleSyncPlugin, DatabasePath, LocalDatabasePlugin};
 
-#[derive(Default)]
-pub struct SavePlugin(());
+/// Set to `1` to report which documents would be migrated, without saving any changes.
+const MIGRATIONS_DRY_RUN_ENV_VAR: &str = "HERE_NOW_MIGRATIONS_DRY_RUN";
+/// Overrides `[database] path` of `here-now-app.toml`.
+const DATABASE_PATH_ENV_VAR: &str = "HERE_NOW_DATABASE_PATH";
+/// Relative to the config directory, sensitive fields can't be read without it.
+const DATABASE_SECRET_FILE: &str = "secrets/database.secret";
+/// Pairing sessions refer to the device entity until they expire, so devices stay loaded
+/// for at least as long as a pairing lasts.
+const MIN_DEVICE_IDLE: Duration = Duration::from_secs(10 * 60);
+
+pub struct SavePlugin {
+    db_path: PathBuf,
+    secret_path: PathBuf,
+    audit: AuditSettings,
+    dry_run_migrations: bool,
+    /// Load devices when referenced instead of at startup, see `[database] idle_device_minutes`
+    device_idle_after: Option<Duration>,
+    /// Follow `[database] path` changes in `here-now-app.toml`
+    watch_config: bool,
+}
+
+/// `[database]` in `here-now-app.toml`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct DatabaseSettings {
+    /// Relative to the config directory.
+    pub path: Option<PathBuf>,
+    /// Load devices only when they're referenced, and unload them after this many minutes
+    /// unused. Unset loads every device at startup. Applies on restart.
+    pub idle_device_minutes: Option<u64>,
+}
+
+impl DatabaseSettings {
+    fn device_idle_after(&self) -> Option<Duration> {
+        self.idle_device_minutes
+            .map(|minutes| Duration::from_secs(minutes * 60).max(MIN_DEVICE_IDLE))
+    }
+}
+
+/// `[audit]` in `here-now-app.toml`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub retention_days: Option<u64>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: Some(90),
+        }
+    }
+}
+
+impl AuditConfig {
+    fn settings(&self) -> AuditSettings {
+        AuditSettings {
+            enabled: self.enabled,
+            max_age: self
+                .retention_days
+                .map(|days| std::time::Duration::from_secs(days * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// Only the `[database]` and `[audit]` tables of `here-now-app.toml`
+#[derive(Default, Deserialize)]
+pub(crate) struct DatabaseTable {
+    #[serde(default)]
+    database: DatabaseSettings,
+    #[serde(default)]
+    audit: AuditConfig,
+}
+
+/// In order of precedence: the env var, `[database] path`, then the platform data directory.
+fn resolve_db_path(config_dir: Option<&Path>, settings: &DatabaseSettings) -> PathBuf {
+    if let Some(path) = std::env::var_os(DATABASE_PATH_ENV_VAR) {
+        return PathBuf::from(path);
+    }
+    match (&settings.path, config_dir) {
+        (Some(path), Some(config_dir)) => config_dir.join(path),
+        (Some(path), None) => path.clone(),
+        (None, _) => platform_db_path(),
+    }
+}
+
+/// Prefers where the database was kept before the platform data directory, if it's still there.
+fn platform_db_path() -> PathBuf {
+    let platform = platform_data_dir().join("server.bonsaidb");
+    let legacy = get_crate_path().join("../data/my-db.bonsaidb");
+    if !platform.exists() && legacy.exists() {
+        warn!(
+            ?legacy,
+            ?platform,
+            "using the database at its previous location, consider moving it or setting [database] path"
+        );
+        return legacy;
+    }
+    platform
+}
+
+fn platform_data_dir() -> PathBuf {
+    match directories::ProjectDirs::from("app", "here-now", "here-now-server") {
+        Some(dirs) => dirs.data_dir().to_path_buf(),
+        None => {
+            warn!("no platform data directory found, using the working directory for data");
+            PathBuf::from("data")
+        }
+    }
+}
+
+fn default_db_path() -> PathBuf {
+    resolve_db_path(None, &DatabaseSettings::default())
+}
+
+fn default_secret_path() -> PathBuf {
+    platform_data_dir().join("database.secret")
+}
+
+/// The database the server opens with this config directory, e.g. for the backup command.
+pub(crate) fn configured_db_path(config_dir: &Path) -> PathBuf {
+    resolve_db_path(Some(config_dir), &read_database_table(config_dir).database)
+}
+
+/// The secret the server seals with for this config directory.
+pub(crate) fn configured_secret_path(config_dir: &Path) -> PathBuf {
+    config_dir.join(DATABASE_SECRET_FILE)
+}
+
+fn read_database_table(config_dir: &Path) -> DatabaseTable {
+    std::fs::read_to_string(config_dir.join("here-now-app.toml"))
+        .context("reading here-now-app.toml")
+        .and_then(|str| {
+            toml_edit::de::from_str::<DatabaseTable>(&str)
+                .context("expected database and audit to be tables with a path and retention_days")
+        })
+        .unwrap_or_else(|err| {
+            warn!(
+                ?err,
+                "using the default database location and audit settings"
+            );
+            DatabaseTable::default()
+        })
+}
+
+impl Default for SavePlugin {
+    fn default() -> Self {
+        Self {
+            db_path: default_db_path(),
+            secret_path: default_secret_path(),
+            audit: AuditConfig::default().settings(),
+            dry_run_migrations: std::env::var(MIGRATIONS_DRY_RUN_ENV_VAR)
+                .map_or(false, |value| value == "1" || value == "true"),
+            device_idle_after: None,
+            watch_config: false,
+        }
+    }
+}
+
+impl SavePlugin {
+    /// Opens the database configured in `here-now-app.toml`, and reopens it when that changes.
+    pub fn from_config_dir(config_dir: &Path) -> Self {
+        let table = read_database_table(config_dir);
+
+        Self {
+            db_path: resolve_db_path(Some(config_dir), &table.database),
+            secret_path: configured_secret_path(config_dir),
+            audit: table.audit.settings(),
+            device_idle_after: table.database.device_idle_after(),
+            watch_config: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl SavePlugin {
+    /// A new database at `db_path`, with its secret generated next to it.
+    pub(crate) fn for_test(db_path: &Path) -> Self {
+        Self {
+            db_path: db_path.to_path_buf(),
+            secret_path: db_path.with_extension("secret"),
+            audit: AuditSettings::default(),
+            dry_run_migrations: false,
+            device_idle_after: None,
+            watch_config: false,
+        }
+    }
+
+    pub(crate) fn with_audit(mut self, audit: AuditSettings) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub(crate) fn with_device_idle_after(mut self, idle_after: Duration) -> Self {
+        self.device_idle_after = Some(idle_after);
+        self
+    }
+}
 
 impl Plugin for SavePlugin {
     fn build(&self, app: &mut AppBuilder) {
         let _span = tracing::info_span!("ecs::SavePlugin::build").entered();
         app.add_plugin(LocalDatabasePlugin::<super::DBSchema> {
-            path: get_crate_path().join("../data/my-db.bonsaidb"),
+            path: self.db_path.clone(),
+            dry_run_migrations: self.dry_run_migrations,
+            secret_path: self.secret_path.clone(),
+            audit: self.audit.clone(),
             mark: PhantomData,
         });
-        app.depends_on_unique::<LastImport>("to see what was initially imported");
-        // initial load
-        if let Err(err) = app.app.run(super::import::import_all) {
-            tracing::error!(?err, "failed to import previous values");
+        // creds first, so devices can link to them
+        app.add_plugin(BundleSyncPlugin::<super::CredBundle>::default());
+        app.add_plugin(match self.device_idle_after {
+            // nothing links to devices, so they can be loaded on demand
+            Some(idle_after) => BundleSyncPlugin::<super::DeviceBundle>::lazy(idle_after),
+            None => BundleSyncPlugin::<super::DeviceBundle>::default(),
+        });
+        if self.watch_config {
+            app.add_system(index_database_settings_system);
+        }
+        info!(path = ?self.db_path, "database location");
+    }
+}
+
+#[tracing::instrument(skip_all)]
+fn index_database_settings_system(
+    uv_dir: UniqueView<config_plugins::ConfigFilesDirectory>,
+    uv_config: UniqueView<config_plugins::ConfigFileContent<AppServerConfigFile>>,
+    uv_overrides: UniqueView<config_plugins::ConfigOverrides>,
+    mut uvm_path: UniqueViewMut<DatabasePath>,
+    mut uvm_audit: UniqueViewMut<AuditSettings>,
+) {
+    if !(uv_dir.is_inserted_or_modified()
+        || uv_config.is_inserted_or_modified()
+        || uv_overrides.is_inserted_or_modified())
+    {
+        return;
+    }
+
+    let Some(inner) = uv_config.get_content() else {
+        // not loaded, yet
+        return;
+    };
+    let table_res = inner.content.as_err_arc_ref().and_then(|doc| {
+        let mut doc = doc.clone();
+        uv_overrides.apply("here-now-app", doc.as_table_mut())?;
+        toml_edit::de::from_document::<DatabaseTable>(doc)
+            .context("expected database and audit to be tables with a path and retention_days")
+    });
+    let table = match table_res {
+        Ok(table) => table,
+        Err(err) => {
+            // keep the current database open rather than falling back to the default
+            warn!(?err, "failed to read database settings");
+            return;
+        }
+    };
+
+    let audit = table.audit.settings();
+    if *uvm_audit != audit {
+        info!(?audit, "audit settings changed");
+        // as_mut marks it for modified
+        *uvm_audit.as_mut() = audit;
+    }
+
+    let new_path = resolve_db_path(uv_dir.path.as_deref(), &table.database);
+    if uvm_path.0 != new_path {
+        info!(from = ?uvm_path.0, to = ?new_path, "database location changed");
+        // as_mut marks it for modified
+        uvm_path.as_mut().0 = new_path;
+    }
+}
+
+#[test]
+fn test_deleted_entities_stay_deleted_after_restart() {
+    use super::DeviceBundle;
+    use crate::ecs::{self, HintedID};
+    use bonsaidb::core::schema::SerializedCollection;
+    use hn_app::app_ctx::LocalDatabase;
+    use hn_app::database_plugin::problems::{ImportProblem, ImportProblems, ProblemKind};
+    use std::time::SystemTime;
+
+    let db_path = std::env::temp_dir().join(format!("hn-save-plugin-{}.bonsaidb", xid::new()));
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&db_path));
+    let ((_, kept_cred), (removed_device, removed_cred), (unlinked_device, unlinked_cred)) =
+        app.world.run(
+            |mut entities: EntitiesViewMut,
+             mut vm_hinted_id: ViewMut<HintedID>,
+             mut vm_cred_tag: ViewMut<ecs::CredTag>,
+             mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>,
+             mut vm_device_tag: ViewMut<ecs::DeviceTag>,
+             mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>,
+             mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+                let mut add_device_with_cred = |label: &str| {
+                    let cred = entities.add_entity(
+                        (&mut vm_hinted_id, &mut vm_cred_tag, &mut vm_discord_cred),
+                        (
+                            HintedID::generate("cred"),
+                            ecs::CredTag::Discord,
+                            ecs::EcsDiscordCred {
+                                access_token: format!("{label}-access"),
+                                refresh_token: format!("{label}-refresh"),
+                                expires_at: SystemTime::now(),
+                            },
+                        ),
+                    );
+                    let device = entities.add_entity(
+                        (
+                            &mut vm_hinted_id,
+                            &mut vm_device_tag,
+                            &mut vm_linked_creds,
+                            &mut vm_authorized_keys,
+                        ),
+                        (
+                            HintedID::generate("dev"),
+                            ecs::DeviceTag,
+                            ecs::Linked::new_with([cred]),
+                            ecs::AuthorizedKeys::default(),
+                        ),
+                    );
+                    (device, cred)
+                };
+                (
+                    add_device_with_cred("kept"),
+                    add_device_with_cred("removed"),
+                    add_device_with_cred("unlinked"),
+                )
+            },
+        );
+    app.update();
+    let [kept_cred_id, unlinked_device_id] = app.world.run(|v_hinted_id: View<HintedID>| {
+        [kept_cred, unlinked_device].map(|entity| v_hinted_id.get(entity).unwrap().clone())
+    });
+
+    app.world.run(|mut all_storages: AllStoragesViewMut| {
+        all_storages.delete_entity(removed_device);
+        all_storages.delete_entity(removed_cred);
+        // the device stays, and is saved again without the link
+        all_storages.delete_entity(unlinked_cred);
+    });
+    // removing a bundle component also deletes the document, and the link is dropped on import
+    app.world
+        .run(|mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>| {
+            vm_discord_cred.remove(kept_cred);
+        });
+    app.update();
+    let db = app
+        .world
+        .run(|uv_local_database: UniqueView<LocalDatabase>| uv_local_database.get_database());
+    let unlinked_device = DeviceBundle::get(&unlinked_device_id, db.as_err_arc_ref().unwrap())
+        .unwrap()
+        .expect("device is still saved");
+    assert!(
+        unlinked_device.contents.c_linked_creds.items.is_empty(),
+        "the deleted cred is no longer listed: {:?}",
+        unlinked_device.contents.c_linked_creds
+    );
+    drop(db);
+    drop(app);
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&db_path));
+    app.world.run(
+        |v_cred_tag: View<ecs::CredTag>,
+         v_device_tag: View<ecs::DeviceTag>,
+         v_linked_creds: View<ecs::Linked<ecs::CredTag>>| {
+            assert_eq!(v_cred_tag.len(), 0, "deleted creds are not imported");
+            assert_eq!(v_device_tag.len(), 2, "deleted devices are not imported");
+            assert!(v_linked_creds.iter().all(|linked| linked.items.is_empty()));
+        },
+    );
+    app.world.run(|uv_problems: UniqueView<ImportProblems>| {
+        assert!(
+            matches!(
+                uv_problems.0.as_slice(),
+                [ImportProblem {
+                    kind: ProblemKind::MissingLink { linked, .. },
+                    ..
+                }] if *linked == kept_cred_id
+            ),
+            "the dropped link is listed for repair: {:?}",
+            uv_problems.0
+        );
+    });
+    drop(app);
+
+    let _ = std::fs::remove_dir_all(&db_path);
+    let _ = std::fs::remove_file(db_path.with_extension("secret"));
+}
+
+#[test]
+fn test_dangling_link_is_dropped_and_repaired() {
+    use super::DeviceBundle;
+    use crate::ecs::{self, HintedID};
+    use bonsaidb::core::schema::SerializedCollection;
+    use hn_app::app_ctx::LocalDatabase;
+    use hn_app::database_plugin::problems::{
+        repair_import_problems, ImportProblems, ProblemKind, RepairOptions,
+    };
+    use std::time::SystemTime;
+
+    let db_path = std::env::temp_dir().join(format!("hn-save-plugin-{}.bonsaidb", xid::new()));
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&db_path));
+    let (device, kept_cred, removed_cred) = app.world.run(
+        |mut entities: EntitiesViewMut,
+         mut vm_hinted_id: ViewMut<HintedID>,
+         mut vm_cred_tag: ViewMut<ecs::CredTag>,
+         mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>,
+         mut vm_device_tag: ViewMut<ecs::DeviceTag>,
+         mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>,
+         mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+            let mut add_cred = |label: &str| {
+                entities.add_entity(
+                    (&mut vm_hinted_id, &mut vm_cred_tag, &mut vm_discord_cred),
+                    (
+                        HintedID::generate("cred"),
+                        ecs::CredTag::Discord,
+                        ecs::EcsDiscordCred {
+                            access_token: format!("{label}-access"),
+                            refresh_token: format!("{label}-refresh"),
+                            expires_at: SystemTime::now(),
+                        },
+                    ),
+                )
+            };
+            let kept_cred = add_cred("kept");
+            let removed_cred = add_cred("removed");
+            let device = entities.add_entity(
+                (
+                    &mut vm_hinted_id,
+                    &mut vm_device_tag,
+                    &mut vm_linked_creds,
+                    &mut vm_authorized_keys,
+                ),
+                (
+                    HintedID::generate("dev"),
+                    ecs::DeviceTag,
+                    ecs::Linked::new_with([kept_cred, removed_cred]),
+                    ecs::AuthorizedKeys::default(),
+                ),
+            );
+            (device, kept_cred, removed_cred)
+        },
+    );
+    app.update();
+    let [device_id, kept_cred_id, removed_cred_id] =
+        app.world.run(|v_hinted_id: View<HintedID>| {
+            [device, kept_cred, removed_cred].map(|entity| v_hinted_id.get(entity).unwrap().clone())
+        });
+    // only the cred's document is deleted, and the cred keeps its id, so the device is
+    // saved again still linking it
+    app.world
+        .run(|mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>| {
+            vm_discord_cred.remove(removed_cred);
+        });
+    app.update();
+    drop(app);
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&db_path));
+    let get_device = || {
+        let db = app
+            .world
+            .run(|uv_local_database: UniqueView<LocalDatabase>| uv_local_database.get_database());
+        DeviceBundle::get(&device_id, db.as_err_arc_ref().unwrap())
+            .unwrap()
+            .unwrap()
+            .contents
+    };
+    assert_eq!(
+        get_device().c_linked_creds.items,
+        vec![kept_cred_id.clone(), removed_cred_id.clone()],
+        "importing doesn't change the document"
+    );
+    app.world.run(
+        |v_hinted_id: View<HintedID>,
+         v_cred_tag: View<ecs::CredTag>,
+         v_linked_creds: View<ecs::Linked<ecs::CredTag>>| {
+            assert_eq!(v_cred_tag.len(), 1, "the kept cred is still imported");
+            let linked = v_linked_creds.iter().next().expect("device is imported");
+            let linked_ids = linked
+                .items
+                .iter()
+                .map(|&cred| v_hinted_id.get(cred).unwrap().clone())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                linked_ids,
+                vec![kept_cred_id.clone()],
+                "only the link is dropped"
+            );
+        },
+    );
+    app.world.run(|uv_problems: UniqueView<ImportProblems>| {
+        assert!(
+            matches!(
+                uv_problems.0.as_slice(),
+                [problem] if matches!(
+                    &problem.kind,
+                    ProblemKind::MissingLink { linked, .. } if *linked == removed_cred_id
+                )
+            ),
+            "{:?}",
+            uv_problems.0
+        );
+    });
+
+    let summary = app
+        .world
+        .run(|all_storages: AllStoragesViewMut| {
+            repair_import_problems(&all_storages, &RepairOptions::default())
+        })
+        .unwrap();
+    assert_eq!((summary.resaved, summary.remaining), (1, 0));
+    app.update();
+    assert_eq!(
+        get_device().c_linked_creds.items,
+        vec![kept_cred_id.clone()],
+        "repairing saves the document without the link"
+    );
+    drop(app);
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&db_path));
+    app.world.run(|uv_problems: UniqueView<ImportProblems>| {
+        assert!(uv_problems.0.is_empty(), "{:?}", uv_problems.0);
+    });
+    drop(app);
+
+    let _ = std::fs::remove_dir_all(&db_path);
+    let _ = std::fs::remove_file(db_path.with_extension("secret"));
+}
+
+#[test]
+fn test_lazy_devices_are_loaded_by_key_and_saved_before_eviction() {
+    use super::{authorized_key_view_key, DeviceBundle, DevicesByAuthorizedKey};
+    use crate::ecs::{self, HintedID};
+    use bonsaidb::core::connection::Connection;
+    use hn_app::app_ctx::LocalDatabase;
+    use hn_app::database_plugin::BundleSync;
+
+    let db_path = std::env::temp_dir().join(format!("hn-save-plugin-{}.bonsaidb", xid::new()));
+    let key = hn_keys::init().public_key().clone();
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&db_path));
+    app.world.run(
+        |mut entities: EntitiesViewMut,
+         mut vm_hinted_id: ViewMut<HintedID>,
+         mut vm_device_tag: ViewMut<ecs::DeviceTag>,
+         mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>,
+         mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+            entities.add_entity(
+                (
+                    &mut vm_hinted_id,
+                    &mut vm_device_tag,
+                    &mut vm_linked_creds,
+                    &mut vm_authorized_keys,
+                ),
+                (
+                    HintedID::generate("dev"),
+                    ecs::DeviceTag,
+                    ecs::Linked::new(),
+                    ecs::AuthorizedKeys {
+                        keys: vec![ecs::AuthorizedKey {
+                            label: None,
+                            dev_info: None,
+                            key: key.clone(),
+                        }],
+                    },
+                ),
+            );
+        },
+    );
+    app.update();
+    drop(app);
+
+    // evicted as soon as it's saved
+    let app =
+        test_ecs::test_app1(SavePlugin::for_test(&db_path).with_device_idle_after(Duration::ZERO));
+    let load_by_key = || {
+        let db = app
+            .world
+            .run(|uv_local_database: UniqueView<LocalDatabase>| uv_local_database.get_database());
+        let ids = db
+            .as_err_arc_ref()
+            .unwrap()
+            .view::<DevicesByAuthorizedKey>()
+            .with_key(authorized_key_view_key(&key).unwrap())
+            .query()
+            .unwrap()
+            .into_iter()
+            .map(|mapping| mapping.source.id.deserialize::<HintedID>().unwrap())
+            .collect::<Vec<_>>();
+        app.world
+            .run(|all_storages: AllStoragesViewMut| DeviceBundle::load(&all_storages, &ids))
+            .unwrap()
+    };
+    let count_devices = || {
+        app.world
+            .run(|v_device_tag: View<ecs::DeviceTag>| v_device_tag.len())
+    };
+    assert_eq!(count_devices(), 0, "lazy devices are not imported");
+
+    let loaded = load_by_key();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(load_by_key(), loaded, "loaded devices are not loaded twice");
+    app.world
+        .run(|mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+            (&mut vm_authorized_keys).get(loaded[0]).unwrap().keys[0].label =
+                Some("laptop".to_string());
+        });
+    app.update();
+    assert_eq!(count_devices(), 0, "idle devices are evicted");
+
+    let reloaded = load_by_key();
+    assert_eq!(reloaded.len(), 1, "evicting keeps the document");
+    app.world
+        .run(|v_authorized_keys: View<ecs::AuthorizedKeys>| {
+            let label = &v_authorized_keys.get(reloaded[0]).unwrap().keys[0].label;
+            assert_eq!(label.as_deref(), Some("laptop"), "changes are saved first");
+        });
+    drop(app);
+
+    let _ = std::fs::remove_dir_all(&db_path);
+    let _ = std::fs::remove_file(db_path.with_extension("secret"));
+}
+
+#[tokio::test]
+async fn test_exports_are_audited_with_command_reasons() {
+    use crate::ecs::{self, HintedID};
+    use bonsaidb::core::schema::SerializedCollection;
+    use hn_app::app_ctx::{AppCtx, AppCtxPlugin, CommandSender, LocalDatabase};
+    use hn_app::database_plugin::audit::{self, AuditEntry};
+    use std::time::SystemTime;
+    use tokio::sync::oneshot;
+
+    struct TestPlugin {
+        sender: CommandSender,
+        db_path: PathBuf,
+    }
+
+    impl Plugin for TestPlugin {
+        fn build(&self, app: &mut AppBuilder) {
+            let audit = AuditConfig {
+                enabled: true,
+                retention_days: Some(30),
+            };
+            app.add_plugin(AppCtxPlugin(self.sender.clone()))
+                .add_plugin(SavePlugin::for_test(&self.db_path).with_audit(audit.settings()));
         }
-        app.add_reset_system(super::export::export_all, "save changes to disk");
     }
+
+    /// Runs `system` as a command, and returns once the update after it saved the changes.
+    async fn run_and_save(
+        app_ctx: &AppCtx,
+        reason: &'static str,
+        system: impl Fn(ViewMut<ecs::EcsDiscordCred>) + Send + Sync + 'static,
+    ) {
+        let (tx, rx) = oneshot::channel::<()>();
+        let once = std::sync::Mutex::new(Some(tx));
+        app_ctx.run_system(
+            reason,
+            move |vm_discord_cred: ViewMut<ecs::EcsDiscordCred>| {
+                if let Some(tx) = once.lock().unwrap().take() {
+                    system(vm_discord_cred);
+                    let _ = tx.send(());
+                }
+            },
+        );
+        rx.await.unwrap();
+        // sent after the command ran, so it runs after the update which followed it
+        let (tx, rx) = oneshot::channel::<()>();
+        let once = std::sync::Mutex::new(Some(tx));
+        app_ctx.run_system("wait for the update", move |_: EntitiesView| {
+            if let Some(tx) = once.lock().unwrap().take() {
+                let _ = tx.send(());
+            }
+        });
+        rx.await.unwrap();
+    }
+
+    let db_path = std::env::temp_dir().join(format!("hn-save-plugin-{}.bonsaidb", xid::new()));
+    let (sender, recv) = tokio::sync::mpsc::unbounded_channel();
+    let mut app = shipyard_app::App::new();
+    let workload = app.add_plugin_workload(TestPlugin {
+        sender,
+        db_path: db_path.clone(),
+    });
+    let app_ctx = app
+        .world
+        .run(|uv_app_ctx: UniqueView<AppCtx>| uv_app_ctx.clone());
+
+    let cred_id = HintedID::generate("cred");
+    let db = app
+        .world
+        .run(|uv_local_database: UniqueView<LocalDatabase>| uv_local_database.get_database());
+    // older than retention_days, so it's pruned by the first update
+    AuditEntry {
+        document: cred_id.clone(),
+        collection: "ecs-creds".to_string(),
+        component: "kind".to_string(),
+        previous: None,
+        new: None,
+        at: SystemTime::now() - Duration::from_secs(31 * 24 * 60 * 60),
+        reason: "expired".to_string(),
+    }
+    .push_into(db.as_err_arc_ref().unwrap())
+    .unwrap();
+    let cred = app.world.run(
+        |mut entities: EntitiesViewMut,
+         mut vm_hinted_id: ViewMut<HintedID>,
+         mut vm_cred_tag: ViewMut<ecs::CredTag>,
+         mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>| {
+            entities.add_entity(
+                (&mut vm_hinted_id, &mut vm_cred_tag, &mut vm_discord_cred),
+                (
+                    cred_id.clone(),
+                    ecs::CredTag::Discord,
+                    ecs::EcsDiscordCred {
+                        access_token: "first-access".to_string(),
+                        refresh_token: "first-refresh".to_string(),
+                        expires_at: SystemTime::now(),
+                    },
+                ),
+            )
+        },
+    );
+    tokio::spawn(hn_app::app_ctx::start_loop(
+        app,
+        workload,
+        recv,
+        |_: &shipyard_app::App| {},
+    ));
+
+    run_and_save(
+        &app_ctx,
+        "refresh test cred",
+        move |mut vm_discord_cred: ViewMut<ecs::EcsDiscordCred>| {
+            let mut discord_cred = (&mut vm_discord_cred).get(cred).unwrap();
+            discord_cred.as_mut().access_token = "second-access".to_string();
+        },
+    )
+    .await;
+
+    let history = audit::document_history(db.as_err_arc_ref().unwrap(), &cred_id).unwrap();
+    let [refreshed, created] = history.as_slice() else {
+        panic!("expected the created and refreshed entries, the expired one pruned: {history:?}");
+    };
+    assert_eq!(
+        (created.component.as_str(), created.reason.as_str()),
+        ("kind", "initial update")
+    );
+    assert!(created.previous.is_none() && created.new.is_some());
+    assert_eq!(refreshed.document, cred_id);
+    assert_eq!(refreshed.collection, "ecs-creds");
+    assert_eq!(
+        (refreshed.component.as_str(), refreshed.reason.as_str()),
+        ("kind", "refresh test cred")
+    );
+    assert_eq!(refreshed.previous, created.new);
+    assert_ne!(
+        refreshed.previous, refreshed.new,
+        "fingerprints change with the token"
+    );
+
+    let recorded = serde_json::to_string(&history).unwrap();
+    assert!(recorded.contains("«sealed»"));
+    assert!(
+        !recorded.contains("-access") && !recorded.contains("-refresh"),
+        "sensitive values are redacted: {recorded}"
+    );
+
+    let _ = std::fs::remove_dir_all(&db_path);
+    let _ = std::fs::remove_file(db_path.with_extension("secret"));
+}
+
+#[test]
+fn test_changing_database_path_reimports() {
+    use super::DeviceBundle;
+    use crate::ecs::{self, HintedID};
+    use bonsaidb::core::schema::SerializedCollection;
+    use hn_app::app_ctx::LocalDatabase;
+
+    let temp_dir = std::env::temp_dir().join(format!("hn-save-plugin-{}", xid::new()));
+    let first_path = temp_dir.join("first.bonsaidb");
+    let second_path = temp_dir.join("second.bonsaidb");
+    let add_device = |app: &shipyard_app::App| {
+        let id = HintedID::generate("dev");
+        app.world.run(
+            |mut entities: EntitiesViewMut,
+             mut vm_hinted_id: ViewMut<HintedID>,
+             mut vm_device_tag: ViewMut<ecs::DeviceTag>,
+             mut vm_linked_creds: ViewMut<ecs::Linked<ecs::CredTag>>,
+             mut vm_authorized_keys: ViewMut<ecs::AuthorizedKeys>| {
+                entities.add_entity(
+                    (
+                        &mut vm_hinted_id,
+                        &mut vm_device_tag,
+                        &mut vm_linked_creds,
+                        &mut vm_authorized_keys,
+                    ),
+                    (
+                        id.clone(),
+                        ecs::DeviceTag,
+                        ecs::Linked::new(),
+                        ecs::AuthorizedKeys::default(),
+                    ),
+                );
+            },
+        );
+        app.update();
+        id
+    };
+    let imported_devices = |app: &shipyard_app::App| {
+        app.world.run(
+            |v_hinted_id: View<HintedID>, v_device_tag: View<ecs::DeviceTag>| {
+                (&v_hinted_id, &v_device_tag)
+                    .iter()
+                    .map(|(id, _)| id.clone())
+                    .collect::<Vec<_>>()
+            },
+        )
+    };
+    let set_path = |app: &shipyard_app::App, path: &Path| {
+        app.world.run(|mut uvm_path: UniqueViewMut<DatabasePath>| {
+            uvm_path.as_mut().0 = path.to_path_buf();
+        });
+        app.update();
+    };
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&second_path));
+    let second_device = add_device(&app);
+    drop(app);
+
+    let app = test_ecs::test_app1(SavePlugin::for_test(&first_path));
+    let first_device = add_device(&app);
+    set_path(&app, &second_path);
+    assert_eq!(
+        imported_devices(&app),
+        vec![second_device.clone()],
+        "devices of the first database are dropped, and the second one's imported"
+    );
+
+    // can't be created inside a file
+    let blocked = temp_dir.join("file");
+    std::fs::write(&blocked, "not a directory").unwrap();
+    set_path(&app, &blocked.join("third.bonsaidb"));
+    assert_eq!(imported_devices(&app), vec![second_device.clone()]);
+    let added_device = add_device(&app);
+    let db = app
+        .world
+        .run(|uv_local_database: UniqueView<LocalDatabase>| uv_local_database.get_database());
+    let db = db.as_err_arc_ref().expect("the second database stays open");
+    assert!(
+        DeviceBundle::get(&added_device, db).unwrap().is_some(),
+        "changes are still saved to the second database"
+    );
+    assert!(DeviceBundle::get(&first_device, db).unwrap().is_none());
+    drop(app);
+
+    let _ = std::fs::remove_dir_all(&temp_dir);
 }
@@ -1,6 +1,7 @@
 use hn_app::_result_::ResultExt;
 use hn_app::app_ctx;
 use shipyard_app::AppBuilder;
+use std::sync::Arc;
 use tokio::{self};
 
 mod data;
@@ -13,15 +14,33 @@ pub mod ecs;
 pub mod http;
 pub mod quickjs;
 pub mod svelte_templates;
+mod tls;
 
 #[tokio::main]
 async fn main() {
     // can we make this configurable with reloading?
     hn_tracing::expect_init_logger("hn-server");
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("backup") {
+        if let Err(err) = ecs::import_export::backup::run_cli(&args[1..]) {
+            eprintln!("backup failed: {err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let settings = match setup_config(&args).await {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("failed to set up config: {err:?}");
+            std::process::exit(1);
+        }
+    };
+
     let mut app = shipyard_app::App::new();
     let (sender, recv) = tokio::sync::mpsc::unbounded_channel();
-    let main_plugin = MainPlugin(sender);
+    let main_plugin = MainPlugin { sender, settings };
     let workload = app.add_plugin_workload(main_plugin);
     let main_loop = tokio::spawn(app_ctx::start_loop(
         app,
@@ -33,20 +52,38 @@ async fn main() {
     main_loop.await.todo(format_args!("app loop exit error"));
 }
 
-struct MainPlugin(app_ctx::CommandSender);
+/// Finds or creates the config directory, with a default file for each section.
+async fn setup_config(args: &[String]) -> anyhow::Result<Arc<config::Settings>> {
+    let overrides = config_plugins::ConfigOverrides::from_env_and_args(args)?;
+    let config_dir = config::config_directory_setup::init_config_directory(args)?;
+    let settings = config::Settings::new(config_dir)
+        .with(config_html_server::app::app_settings())
+        .with(config_html_server::discord::discord_settings())
+        .with_overrides(overrides);
+    settings.write_default_files().await?;
+    Ok(Arc::new(settings))
+}
+
+struct MainPlugin {
+    sender: app_ctx::CommandSender,
+    settings: Arc<config::Settings>,
+}
 
 impl shipyard_app::Plugin for MainPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        let config_dir = crate::config::config_directory_setup::init_config_directory();
+        let config_dir = self.settings.config_files_directory();
 
-        app.add_plugin(app_ctx::AppCtxPlugin(self.0.clone()))
+        app.add_plugin(app_ctx::AppCtxPlugin(self.sender.clone()))
             .add_plugin(config_plugins::ConfigDirectoryPlugin {
-                default_path: Some(config_dir.clone()),
+                default_path: Some(config_dir.to_path_buf()),
+                overrides: self.settings.overrides().clone(),
                 ..Default::default()
             })
             .add_plugin(app_server_plugins::AppServerPlugin::default())
-            .add_plugin(ecs::SavePlugin::default())
-            .add_plugin(config_html_server_plugins::ConfigHtmlServerPlugin { config_dir });
+            .add_plugin(ecs::SavePlugin::from_config_dir(config_dir))
+            .add_plugin(config_html_server_plugins::ConfigHtmlServerPlugin {
+                settings: self.settings.clone(),
+            });
     }
 }
 
@@ -55,13 +92,11 @@ mod config;
 mod config_html_server;
 
 mod config_html_server_plugins {
-    use std::path::PathBuf;
-
     use crate::{config::Settings, config_html_server, prelude::*};
     use hn_app::_ecs_::*;
 
     pub struct ConfigHtmlServerPlugin {
-        pub config_dir: PathBuf,
+        pub settings: Arc<Settings>,
     }
 
     /// Unique for other plugins to access settings.
@@ -72,13 +107,9 @@ mod config_html_server_plugins {
 
     impl Plugin for ConfigHtmlServerPlugin {
         fn build(&self, app: &mut AppBuilder) {
-            let config_dir = crate::config::config_directory_setup::init_config_directory();
-            let settings = Settings::new(config_dir)
-                .with(config_html_server::app::AppSettings)
-                .with(config_html_server::discord::DiscordSettings);
-            tracing::info!("loaded {settings:#?}");
+            let arc_settings = self.settings.clone();
+            tracing::info!("loaded {arc_settings:#?}");
             let ctx = app.ctx();
-            let arc_settings = Arc::new(settings);
             ctx.spawn(config_html_server::start(arc_settings.clone(), ctx.clone()));
             app.add_unique(ConfigSettings(arc_settings));
         }
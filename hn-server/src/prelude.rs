@@ -74,7 +74,7 @@ pub(crate) use htmx_partial;
 
 /// Dev version with auto reloading from disk
 /// Future: use macro to replace with static versions
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct HTMXPartial {
     pub(crate) template_file: &'static str,
 }
@@ -0,0 +1,259 @@
+//! Optional TLS termination for the public and config servers.
+//!
+//! Configured with the `[tls]` table of `here-now-app.toml`. The public server watches
+//! the certificate and key files and swaps them in without restarting, while the config
+//! server only reads them when it starts.
+use std::{path::Path, sync::Mutex};
+
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::{config_plugins::ReadConfigFile, prelude::*};
+use hn_app::_ecs_::*;
+
+/// Where [TlsSettings::SelfSigned] certificates are generated, relative to the config directory.
+pub const SELF_SIGNED_CERT_PATH: &str = "tls/self-signed-cert.pem";
+pub const SELF_SIGNED_KEY_PATH: &str = "tls/self-signed-key.pem";
+
+/// `[tls]` in `here-now-app.toml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum TlsSettings {
+    /// Serve plain http, e.g. when behind a reverse proxy which terminates TLS.
+    #[default]
+    Off,
+    /// PEM encoded certificate chain and private key, relative to the config directory.
+    Files { cert_path: String, key_path: String },
+    /// Generate a certificate for local development, which browsers will warn about.
+    SelfSigned {
+        #[serde(default = "default_self_signed_hostnames")]
+        hostnames: Vec<String>,
+    },
+}
+
+fn default_self_signed_hostnames() -> Vec<String> {
+    vec!["localhost".to_string(), "127.0.0.1".to_string()]
+}
+
+impl TlsSettings {
+    /// `(cert_path, key_path)` relative to the config directory, `None` when TLS is off.
+    pub fn pem_paths(&self) -> Option<(&str, &str)> {
+        match self {
+            TlsSettings::Off => None,
+            TlsSettings::Files {
+                cert_path,
+                key_path,
+            } => Some((cert_path, key_path)),
+            TlsSettings::SelfSigned { .. } => Some((SELF_SIGNED_CERT_PATH, SELF_SIGNED_KEY_PATH)),
+        }
+    }
+
+    /// Generates the self-signed certificate if it's configured and doesn't exist, yet.
+    pub fn prepare(&self, config_dir: &Path) -> Result<()> {
+        let TlsSettings::SelfSigned { hostnames } = self else {
+            return Ok(());
+        };
+
+        let cert_path = config_dir.join(SELF_SIGNED_CERT_PATH);
+        let key_path = config_dir.join(SELF_SIGNED_KEY_PATH);
+        if cert_path.exists() && key_path.exists() {
+            return Ok(());
+        }
+
+        let cert = rcgen::generate_simple_self_signed(hostnames.clone())
+            .context("generating self-signed certificate")?;
+        if let Some(parent) = cert_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("creating directory for certificate at {parent:?}"))?;
+        }
+        std::fs::write(
+            &cert_path,
+            cert.serialize_pem()
+                .context("serializing self-signed certificate")?,
+        )
+        .with_context(|| format!("writing self-signed certificate to {cert_path:?}"))?;
+        write_private_key(&key_path, cert.serialize_private_key_pem().as_bytes())
+            .with_context(|| format!("writing self-signed key to {key_path:?}"))?;
+        warn!(?cert_path, ?hostnames, "generated self-signed certificate");
+
+        Ok(())
+    }
+
+    /// For servers which only read the certificate when starting.
+    pub fn read_pems(&self, config_dir: &Path) -> Result<Option<TlsPems>> {
+        self.prepare(config_dir)?;
+        let Some((cert_path, key_path)) = self.pem_paths() else {
+            return Ok(None);
+        };
+
+        let read = |relative_path: &str| {
+            let path = config_dir.join(relative_path);
+            std::fs::read(&path).with_context(|| format!("reading {path:?}"))
+        };
+
+        Ok(Some(TlsPems {
+            cert: read(cert_path)?,
+            key: read(key_path)?,
+        }))
+    }
+}
+
+/// Only readable by the owner, like the database secret.
+#[cfg(unix)]
+fn write_private_key(path: &Path, key: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // mode only applies to new files, e.g. not to a key left from a previous certificate
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(key)
+}
+
+#[cfg(not(unix))]
+fn write_private_key(path: &Path, key: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, key)
+}
+
+#[derive(Clone, PartialEq)]
+pub struct TlsPems {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl Debug for TlsPems {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never log the private key
+        f.debug_struct("TlsPems")
+            .field("cert_len", &self.cert.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsPems {
+    pub async fn rustls_config(&self) -> Result<RustlsConfig> {
+        RustlsConfig::from_pem(self.cert.clone(), self.key.clone())
+            .await
+            .context("creating rustls config from pem")
+    }
+}
+
+/// Filled in once the server loads its first certificate, so later certificates
+/// can be swapped in without dropping connections.
+#[derive(Clone, Default)]
+pub struct ReloadableTls(Arc<Mutex<Option<RustlsConfig>>>);
+
+impl ReloadableTls {
+    pub async fn load(&self, pems: &TlsPems) -> Result<RustlsConfig> {
+        let existing = self.0.lock().unwrap().clone();
+        match existing {
+            Some(config) => {
+                config
+                    .reload_from_pem(pems.cert.clone(), pems.key.clone())
+                    .await
+                    .context("reloading rustls config from pem")?;
+                Ok(config)
+            }
+            None => {
+                let config = pems.rustls_config().await?;
+                *self.0.lock().unwrap() = Some(config.clone());
+                Ok(config)
+            }
+        }
+    }
+}
+
+/// Certificate chain watched with [crate::config_plugins::ConfigFilePlugin].
+#[derive(Component, Clone)]
+#[track(All)]
+pub struct TlsCertFile {
+    pub relative_path: String,
+}
+
+/// Private key watched with [crate::config_plugins::ConfigFilePlugin].
+#[derive(Component, Clone)]
+#[track(All)]
+pub struct TlsKeyFile {
+    pub relative_path: String,
+}
+
+fn load_pem(bytes: &[u8]) -> Result<Vec<u8>> {
+    let str = std::str::from_utf8(bytes).context("expected pem file to be utf-8")?;
+    anyhow::ensure!(
+        str.contains("-----BEGIN "),
+        "expected pem file to contain a `-----BEGIN` block"
+    );
+    Ok(bytes.to_vec())
+}
+
+impl ReadConfigFile for TlsCertFile {
+    type Content = Vec<u8>;
+    type Error = anyhow::Error;
+
+    fn relative_path(&self) -> &str {
+        &self.relative_path
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Self::Content, Self::Error> {
+        load_pem(bytes).context("loading tls certificate")
+    }
+}
+
+impl ReadConfigFile for TlsKeyFile {
+    type Content = Vec<u8>;
+    type Error = anyhow::Error;
+
+    fn relative_path(&self) -> &str {
+        &self.relative_path
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Self::Content, Self::Error> {
+        load_pem(bytes).context("loading tls private key")
+    }
+}
+
+#[test]
+fn test_tls_settings_from_toml() {
+    #[derive(Deserialize)]
+    struct Table {
+        #[serde(default)]
+        tls: TlsSettings,
+    }
+
+    let parse = |str: &str| toml_edit::de::from_str::<Table>(str).unwrap().tls;
+    assert_eq!(parse(""), TlsSettings::Off);
+    assert_eq!(
+        parse("[tls]\nmode = \"files\"\ncert_path = \"a.pem\"\nkey_path = \"b.pem\""),
+        TlsSettings::Files {
+            cert_path: "a.pem".to_string(),
+            key_path: "b.pem".to_string()
+        }
+    );
+    assert_eq!(
+        parse("[tls]\nmode = \"self-signed\"").pem_paths(),
+        Some((SELF_SIGNED_CERT_PATH, SELF_SIGNED_KEY_PATH))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_self_signed_key_is_only_readable_by_owner() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("hn-tls-{}", xid::new()));
+    TlsSettings::SelfSigned {
+        hostnames: vec!["localhost".to_string()],
+    }
+    .prepare(&dir)
+    .unwrap();
+    let mode = std::fs::metadata(dir.join(SELF_SIGNED_KEY_PATH))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -210,7 +210,9 @@ const Collection_page_template = create_ssr_component(($$result, $$props, $$bind
 	$$result.css.add(css);
 
 	return `${validate_component(Header, "Header").$$render($$result, { header }, {}, {})} <div class="rows svelte-zde1bn">${each(rows, row => {
-		return `<div class="collection-row svelte-zde1bn"${add_attribute("id", row.id, 0)}><a href="${"#" + escape(row.id, true)}" class="title svelte-zde1bn">${escape(row.id)}</a> <pre>${sanitizeHTML(devStringify(row.content).replace(/(token:\s*"\w{3})([^"]+?)(\w{3}")/g, (_, start, secret, end) => start + secret.replace(/./g, "*") + end)).replace(
+		return `<div class="collection-row svelte-zde1bn"${add_attribute("id", row.id, 0)}><a href="${"#" + escape(row.id, true)}" class="title svelte-zde1bn">${escape(row.id)}</a> ${row.detail_href
+		? `<a${add_attribute("href", row.detail_href, 0)}>Details</a>`
+		: ``} <pre>${sanitizeHTML(devStringify(row.content).replace(/(token:\s*"\w{3})([^"]+?)(\w{3}")/g, (_, start, secret, end) => start + secret.replace(/./g, "*") + end)).replace(
 			// replace things like cred_awhuhawduihaw with a link to the corresponding collection with target to the id
 			/"((\w{2,})_\w+)"/g,
 			(_, id, shorthand) => `<a href="/data/${shorthand_lookup[shorthand] ?? `${shorthand}s`}#${id}">${id}</a>`
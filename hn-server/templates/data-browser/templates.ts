@@ -33,6 +33,7 @@ export type CollectionRow = {
   /** `#[codegen(ts_as = "unknown")]` */
   content: unknown;
   ecs_content?: string | undefined | null | null | undefined;
+  detail_href?: string | undefined | null | null | undefined;
 };
 /** `#[codegen(tags = "data-browser")]` */
 export function CollectionRow(inner: CollectionRow): CollectionRow {